@@ -0,0 +1,102 @@
+#![feature(test)]
+extern crate test;
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use crabcore::{config::Config, crabstore::CrabStore, merge::MergePolicy, table::Table};
+use rand::prelude::*;
+use test::Bencher;
+
+const RECORDS: u64 = 8192;
+
+// What runs alongside the selects
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Background {
+    Idle,
+    Updating,
+    Merging,
+}
+
+/*
+    Select latency on an idle table as the baseline, then while another
+    thread keeps updating it, with and without merges running alongside.
+    Merging shouldn't move the numbers much: the merge only takes the
+    buffer pool lock to copy pages out.
+*/
+fn select_latency(b: &mut Bencher, background: Background) {
+    let mut crabstore = CrabStore::in_memory_with_config(Config {
+        merge_policy: MergePolicy::Manual,
+        flush_interval: None,
+        ..Default::default()
+    });
+    crabstore.open();
+
+    let table = crabstore.create_table("Bench", 5, 0).unwrap();
+
+    for i in 0..RECORDS {
+        table.insert_query(&[i, i, i, i, i], None).unwrap();
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let writer = (background != Background::Idle)
+        .then(|| spawn_writer(&table, &stop, background == Background::Merging));
+
+    let mut rng = StdRng::seed_from_u64(165);
+
+    b.iter(|| {
+        let key = rng.gen_range(0..RECORDS);
+        table.select_query(key, 0, &[1, 1, 1, 1, 1], None).unwrap()
+    });
+
+    stop.store(true, Ordering::Relaxed);
+    if let Some(writer) = writer {
+        writer.join().unwrap();
+    }
+
+    drop(table);
+    crabstore.close();
+}
+
+fn spawn_writer(table: &Arc<Table>, stop: &Arc<AtomicBool>, merge: bool) -> JoinHandle<()> {
+    let table = Arc::clone(table);
+    let stop = Arc::clone(stop);
+
+    thread::spawn(move || {
+        let mut round = 0;
+
+        while !stop.load(Ordering::Relaxed) {
+            for i in (0..RECORDS).step_by(4) {
+                table
+                    .update_query(i, &[None, Some(round), None, Some(i), None], None)
+                    .unwrap();
+            }
+
+            if merge {
+                table.merge_all();
+            }
+
+            round += 1;
+        }
+    })
+}
+
+#[bench]
+fn select_idle(b: &mut Bencher) {
+    select_latency(b, Background::Idle);
+}
+
+#[bench]
+fn select_while_updating(b: &mut Bencher) {
+    select_latency(b, Background::Updating);
+}
+
+#[bench]
+fn select_while_merging(b: &mut Bencher) {
+    select_latency(b, Background::Merging);
+}
@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use parking_lot::RwLock;
+
+use crate::{config::Config, crabstore::CrabStore, storage::Storage, table::Table};
+
+/*
+    One writer per database. Every `flush_interval` it writes back up to
+    `flush_batch` dirty pages per table, and every `checkpoint_interval` it
+    checkpoints every table so a crash only loses work since the last one.
+*/
+pub struct BackgroundWriter {
+    handle: Option<(JoinHandle<()>, Sender<()>)>,
+}
+
+impl BackgroundWriter {
+    pub fn spawn(
+        tables: &Arc<RwLock<HashMap<String, Arc<Table>>>>,
+        storage: Storage,
+        config: &Config,
+    ) -> Self {
+        let flush_interval = match config.flush_interval {
+            Some(interval) => interval,
+            None => return BackgroundWriter { handle: None },
+        };
+
+        let tables = Arc::clone(tables);
+        let flush_batch = config.flush_batch;
+        let checkpoint_interval = config.checkpoint_interval;
+        let (send, recv) = channel::<()>();
+
+        let handle = thread::spawn(move || {
+            let mut last_checkpoint = Instant::now();
+
+            loop {
+                match recv.recv_timeout(flush_interval) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+
+                let (table_names, tables): (Vec<String>, Vec<Arc<Table>>) = tables
+                    .read()
+                    .iter()
+                    .map(|(name, table)| (name.clone(), Arc::clone(table)))
+                    .unzip();
+
+                match checkpoint_interval {
+                    Some(interval) if last_checkpoint.elapsed() >= interval => {
+                        for table in tables.iter() {
+                            table.checkpoint();
+                        }
+
+                        CrabStore::persist_table_index(&storage, table_names);
+
+                        last_checkpoint = Instant::now();
+                    }
+                    _ => {
+                        for table in tables.iter() {
+                            table.flush_dirty(flush_batch);
+                        }
+                    }
+                }
+            }
+        });
+
+        BackgroundWriter {
+            handle: Some((handle, send)),
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.handle.is_some()
+    }
+
+    pub fn stop(&mut self) {
+        if let Some((handle, send)) = self.handle.take() {
+            drop(send);
+            handle.join().expect("Failed to join background writer");
+        }
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,192 @@
+use std::{
+    io,
+    ops::AddAssign,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use parking_lot::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::{storage_backend::StorageBackend, PAGE_SIZE};
+
+// Pages compared and copied at a time
+pub const BACKUP_BATCH: usize = 64;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    pub tables: Vec<String>,
+    pub blobs_written: usize,
+    // Pages that differed from the destination and were copied
+    pub pages_written: usize,
+    // Pages the destination already had, left alone
+    pub pages_unchanged: usize,
+}
+
+impl AddAssign for BackupReport {
+    fn add_assign(&mut self, rhs: Self) {
+        self.tables.extend(rhs.tables);
+        self.blobs_written += rhs.blobs_written;
+        self.pages_written += rhs.pages_written;
+        self.pages_unchanged += rhs.pages_unchanged;
+    }
+}
+
+/*
+    What backups need to know about a table's data file. Every page written
+    since the last backup started is flagged, so backing up to the same
+    place again only copies those. While a backup copies a checkpoint,
+    writes keep going: the first write to a page the backup hasn't read yet
+    keeps the page's old contents for it.
+*/
+#[derive(Debug)]
+pub(crate) struct PageTracker {
+    // Tells this open table apart from others backed up to the same place
+    incarnation: u64,
+    state: RwLock<TrackerState>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TrackerState {
+    // One bit per page
+    changed: Vec<AtomicU64>,
+    // Generation of the checkpoint the last backup copied
+    backed_up: Option<u64>,
+    capture: Option<Mutex<Capture>>,
+}
+
+#[derive(Debug)]
+struct Capture {
+    // Pages the backup still has to read
+    pending: FxHashSet<usize>,
+    // Old contents of pending pages written since the backup started
+    preserved: FxHashMap<usize, Box<[u8; PAGE_SIZE]>>,
+}
+
+impl Default for PageTracker {
+    fn default() -> Self {
+        PageTracker {
+            incarnation: rand::random(),
+            state: Default::default(),
+        }
+    }
+}
+
+impl PageTracker {
+    pub fn incarnation(&self) -> u64 {
+        self.incarnation
+    }
+
+    // Whether only the pages written since the backup of `generation` need copying
+    pub fn backed_up(&self, incarnation: u64, generation: u64) -> bool {
+        incarnation == self.incarnation && self.state.read().backed_up == Some(generation)
+    }
+
+    /*
+        Flags `pages` as changed and keeps what a running backup needs of
+        them. The returned guard has to be held until they're written.
+    */
+    pub fn write(
+        &self,
+        pages: &[usize],
+        backend: &dyn StorageBackend,
+    ) -> io::Result<RwLockReadGuard<TrackerState>> {
+        let last = match pages.iter().max() {
+            Some(last) => last / 64,
+            None => return Ok(self.state.read()),
+        };
+
+        let mut state = self.state.read();
+
+        if last >= state.changed.len() {
+            drop(state);
+
+            let mut grown = self.state.write();
+            let len = grown.changed.len().max(last + 1);
+            grown.changed.resize_with(len, Default::default);
+
+            state = RwLockWriteGuard::downgrade(grown);
+        }
+
+        for page in pages {
+            state.changed[page / 64].fetch_or(1 << (page % 64), Ordering::Relaxed);
+        }
+
+        if let Some(capture) = state.capture.as_ref() {
+            let mut capture = capture.lock();
+
+            for page in pages {
+                if capture.pending.remove(page) {
+                    let mut old = Box::new([0; PAGE_SIZE]);
+                    backend.read_page(*page, &mut old)?;
+                    capture.preserved.insert(*page, old);
+                }
+            }
+        }
+
+        Ok(state)
+    }
+
+    /*
+        Starts capturing a checkpoint of `generation` with `pages` pages.
+        Returns the pages to copy, only the ones changed since the last
+        backup if `incremental`. Nothing may be written meanwhile.
+    */
+    pub fn start(&self, generation: u64, pages: usize, incremental: bool) -> Vec<usize> {
+        let mut state = self.state.write();
+
+        let copy = if incremental {
+            state
+                .changed
+                .iter()
+                .enumerate()
+                .flat_map(|(i, bits)| {
+                    let bits = bits.load(Ordering::Relaxed);
+                    (0..64)
+                        .filter(move |x| bits & 1 << x != 0)
+                        .map(move |x| i * 64 + x)
+                })
+                .filter(|x| *x < pages)
+                .collect()
+        } else {
+            (0..pages).collect::<Vec<_>>()
+        };
+
+        state.changed.clear();
+        state.backed_up = Some(generation);
+        state.capture = Some(Mutex::new(Capture {
+            pending: copy.iter().copied().collect(),
+            preserved: Default::default(),
+        }));
+
+        copy
+    }
+
+    // Reads pages of the captured checkpoint, each one at most once
+    pub fn read(
+        &self,
+        pages: &mut [(usize, &mut [u8; PAGE_SIZE])],
+        backend: &dyn StorageBackend,
+    ) -> io::Result<()> {
+        let state = self.state.read();
+        let mut capture = state.capture.as_ref().expect("No backup is running").lock();
+
+        let mut unwritten = Vec::new();
+
+        for (page_id, page) in pages.iter_mut() {
+            match capture.preserved.remove(page_id) {
+                Some(old) => **page = *old,
+                None => {
+                    capture.pending.remove(page_id);
+                    unwritten.push((*page_id, &mut **page));
+                }
+            }
+        }
+
+        // Writes to these wait for the capture lock, so they can't land halfway through
+        backend.read_pages(&mut unwritten)
+    }
+
+    pub fn finish(&self) {
+        self.state.write().capture = None;
+    }
+}
@@ -0,0 +1,119 @@
+use std::{env, path::PathBuf, process};
+
+use crabcore::{
+    crabstore::CrabStore,
+    dump::{self, DumpOptions},
+};
+
+const USAGE: &str = "\
+usage: crabstore import <database> <table> <file> [options]
+       crabstore export <database> <table> <file> [options]
+
+options:
+    --format csv|native   file format, csv by default
+    --columns 0,2,1       table column of each field in the file
+    --header              the csv file has a line of column names
+    --key <column>        primary key of a table created by import, 0 by default";
+
+struct Args {
+    command: String,
+    database: PathBuf,
+    table: String,
+    file: PathBuf,
+    options: DumpOptions,
+    key: usize,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut args = env::args().skip(1);
+    let mut positional = Vec::new();
+    let mut options = DumpOptions::default();
+    let mut key = 0;
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{arg} needs a value"));
+
+        match arg.as_str() {
+            "--format" => options.format = value()?.parse()?,
+            "--columns" => {
+                options.columns = value()?
+                    .split(',')
+                    .map(|x| x.trim().parse().map_err(|_| format!("Bad column \"{x}\"")))
+                    .collect::<Result<_, _>>()?
+            }
+            "--header" => options.header = true,
+            "--key" => key = value()?.parse().map_err(|_| "Bad key column")?,
+            "-h" | "--help" => return Err(String::new()),
+            x if x.starts_with("--") => return Err(format!("Unknown option {x}")),
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 4 {
+        return Err(String::new());
+    }
+
+    let mut positional = positional.into_iter();
+
+    Ok(Args {
+        command: positional.next().unwrap(),
+        database: positional.next().unwrap().into(),
+        table: positional.next().unwrap(),
+        file: positional.next().unwrap().into(),
+        options,
+        key,
+    })
+}
+
+fn run(args: Args) -> Result<usize, String> {
+    let mut crabstore = CrabStore::new(args.database);
+    crabstore.try_open().map_err(|e| e.to_string())?;
+
+    let result = match args.command.as_str() {
+        "import" => {
+            if !crabstore.has_table(&args.table) {
+                let columns = match args.options.columns.len() {
+                    0 => dump::field_count(&args.file, args.options.format)
+                        .map_err(|e| e.to_string())?,
+                    x => x,
+                };
+
+                crabstore
+                    .create_table(&args.table, columns, args.key)
+                    .map_err(|e| e.to_string())?;
+            }
+
+            crabstore.import(&args.table, &args.file, &args.options)
+        }
+        "export" => crabstore.export(&args.table, &args.file, &args.options),
+        x => return Err(format!("Unknown command \"{x}\"")),
+    };
+
+    crabstore.close();
+
+    result.map_err(|e| e.to_string())
+}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(e) => {
+            if !e.is_empty() {
+                eprintln!("{e}\n");
+            }
+
+            eprintln!("{USAGE}");
+            process::exit(2);
+        }
+    };
+
+    let command = args.command.clone();
+
+    match run(args) {
+        Ok(rows) => println!("{command}ed {rows} rows"),
+        Err(e) => {
+            eprintln!("{e}");
+            process::exit(1);
+        }
+    }
+}
@@ -1,29 +1,48 @@
 use std::{
     borrow::Borrow,
+    fmt,
     hash::BuildHasherDefault,
+    mem::size_of,
     sync::{
         atomic::{self, Ordering},
         Arc, RwLock,
     },
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use memmap2::Mmap;
+use parking_lot::{Condvar, Mutex, MutexGuard};
 use rustc_hash::{FxHashMap, FxHasher};
 
-use crate::{disk_manager::DiskManager, page::PhysicalPage};
+use crate::{
+    config::Config,
+    disk_manager::DiskManager,
+    page::PhysicalPage,
+    replacement_policy::{AccessHint, ReplacementPolicy, ReplacementPolicyKind},
+    stats::BufferPoolStats,
+    PAGE_SIZE,
+};
 
 #[derive(Debug)]
 pub struct BufferPoolFrame {
     page_id: atomic::AtomicUsize,
     dirty: atomic::AtomicBool,
+    pins: atomic::AtomicUsize,
     page: RwLock<PhysicalPage>,
 }
 
+impl Default for BufferPoolFrame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl BufferPoolFrame {
     pub fn new() -> Self {
         BufferPoolFrame {
             page_id: (!0).into(),
             dirty: false.into(),
+            pins: 0.into(),
             page: RwLock::new(PhysicalPage::default()),
         }
     }
@@ -34,12 +53,59 @@ impl BufferPoolFrame {
             .expect("Failed to acquire lock, lock poisoning?");
 
         disk.write_page(self.page_id.load(Ordering::Relaxed), &page.page);
-        disk.flush();
 
         self.dirty.store(false, Ordering::Relaxed);
         self.page_id.store(!0, Ordering::Relaxed);
     }
 
+    /*
+        Writes the page back without unmapping it. Writers mark the frame dirty
+        while holding the page lock, so clearing the flag under the read lock
+        can't lose a concurrent write.
+    */
+    pub fn write_back(&self, disk: &DiskManager) -> bool {
+        let page = self
+            .page
+            .read()
+            .expect("Failed to acquire lock, lock poisoning?");
+
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return false;
+        }
+
+        disk.write_page(self.page_id.load(Ordering::Relaxed), &page.page);
+
+        true
+    }
+
+    // Batched `write_back`, returns how many of the frames were dirty
+    pub fn write_back_all(frames: &[PageGuard], disk: &DiskManager) -> usize {
+        let pages = frames
+            .iter()
+            .map(|x| {
+                x.frame()
+                    .page
+                    .read()
+                    .expect("Failed to acquire lock, lock poisoning?")
+            })
+            .collect::<Vec<_>>();
+
+        let requests = frames
+            .iter()
+            .zip(pages.iter())
+            .filter(|(frame, _)| frame.frame().dirty.swap(false, Ordering::AcqRel))
+            .map(|(frame, page)| (frame.get_page_id(), &page.page))
+            .collect::<Vec<_>>();
+
+        disk.write_pages(&requests);
+
+        requests.len()
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
     pub fn mark_dirty(&self) {
         self.dirty.store(true, Ordering::Relaxed);
     }
@@ -48,6 +114,10 @@ impl BufferPoolFrame {
         self.page_id.load(Ordering::Relaxed)
     }
 
+    pub fn is_pinned(&self) -> bool {
+        self.pins.load(Ordering::Acquire) > 0
+    }
+
     pub fn slot(&self, slot: usize) -> u64 {
         let page = self
             .page
@@ -58,39 +128,232 @@ impl BufferPoolFrame {
     }
 
     pub fn write_slot(&self, slot: usize, value: u64) {
-        self.mark_dirty();
         let mut page = self
             .page
             .write()
             .expect("Couldn't lock physical page, poisoned?");
 
         page.write_slot(slot, value);
+        self.mark_dirty();
     }
 
     pub fn raw(&self) -> &RwLock<PhysicalPage> {
         &self.page
     }
 }
+/*
+    Counts the frames that became unpinned, so waiters can tell if they
+    missed one. Unpinning only takes the lock when someone is waiting, and
+    wakes up one waiter per freed frame.
+*/
+#[derive(Debug, Default)]
+struct PinNotifier {
+    unpins: atomic::AtomicU64,
+    waiters: atomic::AtomicUsize,
+    lock: Mutex<()>,
+    unpinned: Condvar,
+}
+
+impl PinNotifier {
+    fn unpins(&self) -> u64 {
+        self.unpins.load(Ordering::SeqCst)
+    }
+
+    fn notify(&self) {
+        self.unpins.fetch_add(1, Ordering::SeqCst);
+
+        // Either a waiter sees the new count before waiting, or it's counted here
+        if self.waiters.load(Ordering::SeqCst) > 0 {
+            let _lock = self.lock.lock();
+            self.unpinned.notify_one();
+        }
+    }
+
+    // Returns right away if a frame was unpinned since `seen` was read
+    fn wait(&self, seen: u64, timeout: Duration) {
+        let mut lock = self.lock.lock();
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+
+        if self.unpins.load(Ordering::SeqCst) == seen {
+            self.unpinned.wait_for(&mut lock, timeout);
+        }
+
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+enum Pinned {
+    Frame {
+        frame: Arc<BufferPoolFrame>,
+        notifier: Arc<PinNotifier>,
+    },
+    // Read-only tables read straight out of the mapped table file
+    Mapped {
+        map: Arc<Mmap>,
+        page_id: usize,
+    },
+}
+
+/*
+    A pinned frame. The frame can't be evicted while a guard for it is alive,
+    dropping the last guard wakes up anyone waiting in the buffer pool for a
+    free frame.
+*/
+#[derive(Debug)]
+pub struct PageGuard(Pinned);
+
+impl PageGuard {
+    fn pin(frame: &Arc<BufferPoolFrame>, notifier: &Arc<PinNotifier>) -> Self {
+        frame.pins.fetch_add(1, Ordering::AcqRel);
+
+        PageGuard(Pinned::Frame {
+            frame: Arc::clone(frame),
+            notifier: Arc::clone(notifier),
+        })
+    }
+
+    fn mapped(map: &Arc<Mmap>, page_id: usize) -> Self {
+        PageGuard(Pinned::Mapped {
+            map: Arc::clone(map),
+            page_id,
+        })
+    }
+
+    // Panics for mapped pages, they have no frame
+    pub fn frame(&self) -> &BufferPoolFrame {
+        match &self.0 {
+            Pinned::Frame { frame, .. } => frame,
+            Pinned::Mapped { .. } => panic!("Tried to access the frame of a read-only page"),
+        }
+    }
+
+    pub fn slot(&self, slot: usize) -> u64 {
+        match &self.0 {
+            Pinned::Frame { frame, .. } => frame.slot(slot),
+            Pinned::Mapped { map, page_id } => {
+                let offset = page_id * PAGE_SIZE + slot * size_of::<u64>();
+
+                // Only pages wholly past the end of the file get pinned short
+                map.get(offset..offset + size_of::<u64>())
+                    .map(|x| u64::from_ne_bytes(x.try_into().unwrap()))
+                    .unwrap_or(0)
+            }
+        }
+    }
+
+    pub fn write_slot(&self, slot: usize, value: u64) {
+        self.frame().write_slot(slot, value);
+    }
+
+    pub fn get_page_id(&self) -> usize {
+        match &self.0 {
+            Pinned::Frame { frame, .. } => frame.get_page_id(),
+            Pinned::Mapped { page_id, .. } => *page_id,
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        match &self.0 {
+            Pinned::Frame { frame, .. } => frame.is_dirty(),
+            Pinned::Mapped { .. } => false,
+        }
+    }
+
+    pub fn mark_dirty(&self) {
+        self.frame().mark_dirty();
+    }
+
+    pub fn raw(&self) -> &RwLock<PhysicalPage> {
+        self.frame().raw()
+    }
+}
+
+impl Clone for PageGuard {
+    fn clone(&self) -> Self {
+        match &self.0 {
+            Pinned::Frame { frame, notifier } => PageGuard::pin(frame, notifier),
+            Pinned::Mapped { map, page_id } => PageGuard::mapped(map, *page_id),
+        }
+    }
+}
+
+impl Drop for PageGuard {
+    fn drop(&mut self) {
+        if let Pinned::Frame { frame, notifier } = &self.0 {
+            if frame.pins.fetch_sub(1, Ordering::AcqRel) == 1 {
+                notifier.notify();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BufferPoolError {
+    Exhausted { frames: usize, waited: Duration },
+    ReadOnly,
+    // The mapped table file ends partway through the page
+    Truncated { page_id: usize },
+}
+
+impl fmt::Display for BufferPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BufferPoolError::Exhausted { frames, waited } => write!(
+                f,
+                "Buffer pool exhausted, all {frames} frames stayed pinned for {waited:?}"
+            ),
+            BufferPoolError::ReadOnly => write!(f, "Buffer pool is read-only"),
+            BufferPoolError::Truncated { page_id } => {
+                write!(f, "Table file ends partway through page {page_id}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BufferPoolError {}
+
 #[derive(Debug)]
 pub struct BufferPool {
     disk: Arc<DiskManager>,
     size: usize,
     page_frame_map: FxHashMap<usize, usize>,
     frames: Vec<Arc<BufferPoolFrame>>,
-    clock_refs: Vec<bool>,
-    clock_hand: usize,
+    free_frames: Vec<usize>,
+    policy: Box<dyn ReplacementPolicy>,
+    notifier: Arc<PinNotifier>,
+    evict_timeout: Duration,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+    dirty_evictions: u64,
+    flushes: u64,
+    mapped: Option<Arc<Mmap>>,
 }
 
 impl BufferPool {
     pub fn new(disk: Arc<DiskManager>, size: usize) -> Self {
+        BufferPool::with_policy(disk, size, ReplacementPolicyKind::default())
+    }
+
+    pub fn from_config(disk: Arc<DiskManager>, config: &Config) -> Self {
+        let mut bufferpool =
+            BufferPool::with_policy(disk, config.bufferpool_size, config.replacement_policy);
+        bufferpool.set_evict_timeout(config.evict_timeout);
+        bufferpool
+    }
+
+    pub fn with_policy(disk: Arc<DiskManager>, size: usize, policy: ReplacementPolicyKind) -> Self {
+        // Mapped pages are read in place, so there is nothing to cache
+        let mapped = disk.mapped();
+        let size = if mapped.is_some() { 0 } else { size };
+
         let mut frames = Vec::with_capacity(size);
         let page_frame_map =
             FxHashMap::with_capacity_and_hasher(size, BuildHasherDefault::<FxHasher>::default());
-        let mut clock_refs = Vec::with_capacity(size);
 
         for _ in 0..size {
             frames.push(Arc::new(BufferPoolFrame::new()));
-            clock_refs.push(false);
         }
 
         BufferPool {
@@ -98,105 +361,277 @@ impl BufferPool {
             size,
             page_frame_map,
             frames,
-            clock_refs,
-            clock_hand: 0,
-        }
-    }
-
-    fn find_evict_victim(&mut self) -> usize {
-        let evict_start_time = std::time::Instant::now();
-        let victim = loop {
-            if self.clock_refs[self.clock_hand]
-                || Arc::strong_count(&self.frames[self.clock_hand]) > 1
-            {
-                self.clock_refs[self.clock_hand] = false;
-                self.clock_hand = (self.clock_hand + 1) % self.size;
-                if Duration::from_secs(1) < evict_start_time.elapsed() {
-                    panic!("Evicting a page took more than 1 second! Buffer pool is too small!");
-                }
-                continue;
-            }
+            free_frames: (0..size).rev().collect(),
+            policy: policy.build(size),
+            notifier: Arc::new(PinNotifier::default()),
+            evict_timeout: Config::default().evict_timeout,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+            dirty_evictions: 0,
+            flushes: 0,
+            mapped,
+        }
+    }
 
-            break self.clock_hand;
-        };
+    pub fn set_evict_timeout(&mut self, timeout: Duration) {
+        self.evict_timeout = timeout;
+    }
 
-        self.clock_hand = (self.clock_hand + 1) % self.size;
+    // Pins only go up while the buffer pool is locked, so a frame found
+    // unpinned here stays unpinned until the caller unlocks the pool
+    fn find_evict_victim(&mut self, incoming: usize) -> Option<usize> {
+        if let Some(free) = self.free_frames.pop() {
+            return Some(free);
+        }
 
-        victim
+        let frames = &self.frames;
+
+        self.policy
+            .find_victim(incoming, &|frame| !frames[frame].is_pinned())
     }
 
     pub fn flush_all(&mut self) {
-        for i in 0..self.size {
-            if self.frames[i].dirty.load(Ordering::Relaxed)
-                && Arc::strong_count(&self.frames[i]) < 2
-            {
-                self.frames[i].flush(self.disk.borrow());
-                self.page_frame_map
-                    .remove(&self.frames[i].page_id.load(Ordering::Relaxed));
+        let victims = (0..self.size)
+            .filter(|i| self.frames[*i].is_dirty() && !self.frames[*i].is_pinned())
+            .collect::<Vec<usize>>();
+
+        {
+            let pages = victims
+                .iter()
+                .map(|i| {
+                    self.frames[*i]
+                        .page
+                        .read()
+                        .expect("Failed to acquire lock, lock poisoning?")
+                })
+                .collect::<Vec<_>>();
+
+            let requests = victims
+                .iter()
+                .zip(pages.iter())
+                .map(|(i, page)| (self.frames[*i].get_page_id(), &page.page))
+                .collect::<Vec<_>>();
+
+            self.disk.write_pages(&requests);
+        }
+
+        for i in victims {
+            let page_id = self.frames[i].get_page_id();
+
+            self.frames[i].dirty.store(false, Ordering::Relaxed);
+            self.frames[i].page_id.store(!0, Ordering::Relaxed);
+            self.flushes += 1;
+            self.page_frame_map.remove(&page_id);
+            self.policy.record_evict(i, page_id);
+            self.free_frames.push(i);
+        }
+
+        self.disk.sync();
+    }
+
+    // Pins up to `max` dirty frames so they can be written back without
+    // holding the buffer pool lock
+    pub fn dirty_frames(&self, max: usize) -> Vec<PageGuard> {
+        self.dirty_frames_from(0, max).0
+    }
+
+    // `dirty_frames` starting at frame `start`, also returns the frame to
+    // continue from, None once every frame was looked at
+    pub fn dirty_frames_from(&self, start: usize, max: usize) -> (Vec<PageGuard>, Option<usize>) {
+        let mut dirty = Vec::new();
+
+        for (i, frame) in self.frames.iter().enumerate().skip(start) {
+            if dirty.len() == max {
+                return (dirty, Some(i));
             }
+
+            if frame.is_dirty() && frame.get_page_id() != !0 {
+                dirty.push(PageGuard::pin(frame, &self.notifier));
+            }
+        }
+
+        (dirty, None)
+    }
+
+    // Write-backs happen outside the buffer pool lock, callers report them here
+    pub fn record_flushes(&mut self, count: usize) {
+        self.flushes += count as u64;
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            size: self.size,
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            dirty_evictions: self.dirty_evictions,
+            flushes: self.flushes,
+            dirty_pages: self.frames.iter().filter(|x| x.is_dirty()).count(),
+            pinned_pages: self.pinned_frames(),
         }
-        self.disk.flush();
     }
 
     fn evict(&mut self, victim: usize) {
         let frame = &self.frames[victim];
+        let page_id = frame.page_id.load(Ordering::Relaxed);
 
-        self.page_frame_map
-            .remove(&frame.page_id.load(Ordering::Relaxed));
+        if page_id == !0 {
+            return;
+        }
+
+        self.page_frame_map.remove(&page_id);
+        self.evictions += 1;
 
         if frame.dirty.load(Ordering::Relaxed) {
             frame.flush(self.disk.borrow());
+            self.dirty_evictions += 1;
         }
 
         frame.dirty.store(false, Ordering::Relaxed);
 
         frame.page_id.store(!0, Ordering::Relaxed);
+
+        self.policy.record_evict(victim, page_id);
+    }
+
+    // Drops the pages without writing them back, returns the ones that are still pinned
+    pub fn discard(&mut self, pages: &[usize]) -> Vec<usize> {
+        let mut pinned = Vec::new();
+
+        for page_id in pages.iter() {
+            let victim = match self.page_frame_map.get(page_id) {
+                Some(victim) => *victim,
+                None => continue,
+            };
+
+            let frame = &self.frames[victim];
+
+            // Pins only go up while the buffer pool is locked
+            if frame.is_pinned() {
+                pinned.push(*page_id);
+                continue;
+            }
+
+            frame.dirty.store(false, Ordering::Relaxed);
+            frame.page_id.store(!0, Ordering::Relaxed);
+
+            self.page_frame_map.remove(page_id);
+            self.policy.record_evict(victim, *page_id);
+            self.free_frames.push(victim);
+        }
+
+        pinned
     }
 
     pub fn is_page_mapped(&self, page_id: usize) -> bool {
         self.page_frame_map.contains_key(&page_id)
     }
 
-    pub fn new_page(&mut self) -> Arc<BufferPoolFrame> {
-        let new_page_id = self.disk.reserve_page();
+    // Starts background reads for the pages that aren't already resident
+    pub fn prefetch(&self, pages: &[usize]) {
+        if self.mapped.is_some() {
+            return;
+        }
+
+        let pages = pages
+            .iter()
+            .copied()
+            .filter(|x| *x != !0 && !self.page_frame_map.contains_key(x))
+            .collect::<Vec<usize>>();
+
+        if !pages.is_empty() {
+            self.disk.prefetch(&pages);
+        }
+    }
+
+    pub fn pinned_frames(&self) -> usize {
+        self.frames.iter().filter(|x| x.is_pinned()).count()
+    }
 
-        let victim = self.find_evict_victim();
+    // Without waiting, `None` if every frame is pinned
+    fn try_new_page(&mut self) -> Result<Option<PageGuard>, BufferPoolError> {
+        if self.mapped.is_some() {
+            return Err(BufferPoolError::ReadOnly);
+        }
+
+        // A page that was never loaded isn't in any policy's history
+        let victim = match self.find_evict_victim(!0) {
+            Some(victim) => victim,
+            None => return Ok(None),
+        };
+
+        let new_page_id = self.disk.reserve_page();
 
         self.evict(victim);
 
-        let frame = Arc::clone(&self.frames[victim]);
+        let frame = PageGuard::pin(&self.frames[victim], &self.notifier);
 
-        frame.page_id.store(new_page_id, Ordering::Relaxed);
+        self.frames[victim]
+            .page_id
+            .store(new_page_id, Ordering::Relaxed);
         self.page_frame_map.insert(new_page_id, victim);
+        self.policy
+            .record_load(victim, new_page_id, AccessHint::Random);
 
-        frame
+        Ok(Some(frame))
     }
 
-    pub fn get_page(&mut self, page_id: usize) -> Arc<BufferPoolFrame> {
+    // Without waiting, `None` if the page isn't resident and every frame is pinned
+    fn try_get_page(
+        &mut self,
+        page_id: usize,
+        hint: AccessHint,
+    ) -> Result<Option<PageGuard>, BufferPoolError> {
         if page_id == !0 {
             panic!("Tried to load invalid page");
         }
+
+        if let Some(map) = &self.mapped {
+            let start = page_id * PAGE_SIZE;
+
+            // Pages past the end of the file were never written and read as
+            // zeroes, like `MappedBackend::read_page`, but a page cut off
+            // partway means the file was truncated
+            if start < map.len() && map.len() < start + PAGE_SIZE {
+                return Err(BufferPoolError::Truncated { page_id });
+            }
+
+            self.hits += 1;
+            return Ok(Some(PageGuard::mapped(map, page_id)));
+        }
+
         if let Some(frame_id) = self.page_frame_map.get(&page_id) {
-            self.clock_refs[*frame_id] = true;
-            let frame = &self.frames[*frame_id];
-            return Arc::clone(frame);
+            self.hits += 1;
+            self.policy.record_access(*frame_id, hint);
+            return Ok(Some(PageGuard::pin(
+                &self.frames[*frame_id],
+                &self.notifier,
+            )));
         }
 
-        let victim = self.find_evict_victim();
+        let victim = match self.find_evict_victim(page_id) {
+            Some(victim) => victim,
+            None => return Ok(None),
+        };
+
+        self.misses += 1;
         self.evict(victim);
 
-        let frame = Arc::clone(&self.frames[victim]);
+        let frame = PageGuard::pin(&self.frames[victim], &self.notifier);
 
-        frame.page_id.store(page_id, Ordering::Relaxed);
+        self.frames[victim]
+            .page_id
+            .store(page_id, Ordering::Relaxed);
 
-        let mut page = frame
+        let mut page = self.frames[victim]
             .page
             .write()
             .expect("Failed to acquire RwLock, poisoned?");
 
         self.disk.read_page(page_id, &mut page.page);
 
-        self.clock_refs[victim] = true;
+        self.policy.record_load(victim, page_id, hint);
 
         drop(page);
 
@@ -204,6 +639,91 @@ impl BufferPool {
             .try_insert(page_id, victim)
             .expect("Tried to re-map existing page in bufferpool");
 
-        frame
+        Ok(Some(frame))
+    }
+}
+
+/*
+    Page faults go through the locked buffer pool. When every frame is pinned
+    they wait for one to be unpinned with the pool unlocked, so the queries
+    holding those pins can get at the pool to finish. Reads give up with
+    `BufferPoolError::Exhausted` after the pool's eviction timeout, writes
+    that can't be backed out of wait for as long as it takes.
+*/
+pub trait LockedBufferPool {
+    fn get_page(&mut self, page_id: usize) -> Result<PageGuard, BufferPoolError>;
+
+    fn get_page_with_hint(
+        &mut self,
+        page_id: usize,
+        hint: AccessHint,
+    ) -> Result<PageGuard, BufferPoolError>;
+
+    fn new_page(&mut self) -> Result<PageGuard, BufferPoolError>;
+
+    // Only for callers that hold no other pins, or they could be waiting on themselves
+    fn wait_for_page(&mut self, page_id: usize) -> PageGuard;
+}
+
+impl LockedBufferPool for MutexGuard<'_, BufferPool> {
+    fn get_page(&mut self, page_id: usize) -> Result<PageGuard, BufferPoolError> {
+        self.get_page_with_hint(page_id, AccessHint::Random)
+    }
+
+    fn get_page_with_hint(
+        &mut self,
+        page_id: usize,
+        hint: AccessHint,
+    ) -> Result<PageGuard, BufferPoolError> {
+        let timeout = self.evict_timeout;
+        wait_for_frame(self, Some(timeout), |bp| bp.try_get_page(page_id, hint))
+    }
+
+    fn new_page(&mut self) -> Result<PageGuard, BufferPoolError> {
+        let timeout = self.evict_timeout;
+        wait_for_frame(self, Some(timeout), BufferPool::try_new_page)
+    }
+
+    fn wait_for_page(&mut self, page_id: usize) -> PageGuard {
+        // Only writes wait, and mapped tables are never written
+        wait_for_frame(self, None, |bp| {
+            bp.try_get_page(page_id, AccessHint::Random)
+        })
+        .expect("Waiting for a frame without a timeout can't fail")
+    }
+}
+
+// Retries `f` until it finds a frame, unlocking the pool while it waits for one
+fn wait_for_frame<T>(
+    bp: &mut MutexGuard<'_, BufferPool>,
+    timeout: Option<Duration>,
+    mut f: impl FnMut(&mut BufferPool) -> Result<Option<T>, BufferPoolError>,
+) -> Result<T, BufferPoolError> {
+    let start = Instant::now();
+
+    loop {
+        // Read before looking for a frame, any unpin after that wakes us up
+        let unpins = bp.notifier.unpins();
+
+        if let Some(found) = f(bp)? {
+            return Ok(found);
+        }
+
+        let waited = start.elapsed();
+
+        let remaining = match timeout {
+            Some(timeout) if waited >= timeout => {
+                return Err(BufferPoolError::Exhausted {
+                    frames: bp.size,
+                    waited,
+                })
+            }
+            Some(timeout) => timeout - waited,
+            None => Duration::MAX,
+        };
+
+        let notifier = Arc::clone(&bp.notifier);
+
+        MutexGuard::unlocked(bp, || notifier.wait(unpins, remaining));
     }
 }
@@ -0,0 +1,47 @@
+use std::time::Duration;
+
+use crate::{
+    merge::MergePolicy, replacement_policy::ReplacementPolicyKind,
+    storage_backend::StorageBackendKind, BUFFERPOOL_SIZE,
+};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub bufferpool_size: usize,
+    pub replacement_policy: ReplacementPolicyKind,
+    pub storage_backend: StorageBackendKind,
+    // How long a page fault waits for a pinned frame to be released
+    pub evict_timeout: Duration,
+    // None disables the background writer
+    pub flush_interval: Option<Duration>,
+    pub flush_batch: usize,
+    pub checkpoint_interval: Option<Duration>,
+    // Base pages read ahead by scans, 0 disables prefetching
+    pub prefetch_depth: usize,
+    // Maps table files instead of caching them, tables reject mutating queries
+    pub read_only: bool,
+    pub merge_policy: MergePolicy,
+    // Threads merging ranges, shared by every table of a database
+    pub merge_workers: usize,
+    // Whether persisting a table waits for its running merges or abandons them
+    pub finish_merges: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bufferpool_size: BUFFERPOOL_SIZE,
+            replacement_policy: ReplacementPolicyKind::default(),
+            storage_backend: StorageBackendKind::default(),
+            evict_timeout: Duration::from_secs(10),
+            flush_interval: Some(Duration::from_millis(100)),
+            flush_batch: 32,
+            checkpoint_interval: Some(Duration::from_secs(30)),
+            prefetch_depth: 4,
+            read_only: false,
+            merge_policy: MergePolicy::default(),
+            merge_workers: 2,
+            finish_merges: true,
+        }
+    }
+}
@@ -1,173 +1,463 @@
 use std::{
     collections::HashMap,
-    fs::{self, File},
-    io::{self, BufWriter, Read, Seek, Write},
+    fs,
     path::{Path, PathBuf},
     sync::Arc,
 };
 
-use rkyv::ser::{
-    serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-    Serializer,
+use parking_lot::RwLock;
+
+use crate::{
+    background_writer::BackgroundWriter,
+    backup::BackupReport,
+    config::Config,
+    dump::{self, DumpOptions},
+    error::CrabError,
+    merge_scheduler::MergeScheduler,
+    migration::{self, MigrationReport, FORMAT_VERSION},
+    mvcc::Clock,
+    stats::DatabaseStats,
+    storage::{decode, Storage, TableFile, CATALOG_FILE},
+    table::Table,
+    transaction::{Isolation, Transaction},
 };
 
-use crate::table::Table;
-
-#[derive(Clone, Default)]
+#[derive(Default)]
 pub struct CrabStore {
     pub directory: PathBuf,
-    tables: HashMap<String, Arc<Table>>,
+    pub config: Config,
+    tables: Arc<RwLock<HashMap<String, Arc<Table>>>>,
+    background_writer: Option<BackgroundWriter>,
+    merge_scheduler: Option<Arc<MergeScheduler>>,
+    // Set for in-memory databases and open single file ones, otherwise files go in `directory`
+    storage: Option<Storage>,
+    // `directory` is the path of a single database file
+    single_file: bool,
+    // Snapshot and commit timestamps of every transaction on the database
+    clock: Arc<Clock>,
 }
 
 impl CrabStore {
-    pub fn load_table_index(file: &Path) -> Vec<String> {
-        let crab_file = File::options().read(true).open(file);
-
-        if crab_file.is_err() && crab_file.as_ref().unwrap_err().kind() == io::ErrorKind::NotFound {
-            File::create(file).expect("Failed to find database file");
-
-            return Vec::new();
+    pub fn load_table_index(storage: &Storage) -> Result<Vec<String>, CrabError> {
+        let crab_bytes = match storage
+            .blob(CATALOG_FILE)
+            .read()
+            .map_err(|e| CrabError::io(CATALOG_FILE, e))?
+        {
+            Some(contents) => contents,
+            None => return Ok(Vec::new()),
+        };
+
+        if crab_bytes.version != FORMAT_VERSION {
+            return Err(CrabError::Version {
+                file: CATALOG_FILE.into(),
+                found: crab_bytes.version,
+            });
         }
 
-        let mut crab_file = crab_file.unwrap();
-
-        crab_file.rewind().unwrap();
-
-        let mut crab_bytes = Vec::new();
-        crab_file.read_to_end(&mut crab_bytes).unwrap();
-
-        unsafe {
-            rkyv::from_bytes_unchecked::<Vec<String>>(&crab_bytes)
-                .expect("Failed to deserialize database file")
-        }
+        decode::<Vec<String>>(CATALOG_FILE, &crab_bytes.bytes)
     }
 
-    pub fn persist_table_index(file: &Path, table_names: Vec<String>) {
-        let mut crab_file = File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(file)
-            .expect("Failed to open database file");
-
-        crab_file.rewind().unwrap();
+    // Written after the tables it lists, so it never names one without a checkpoint
+    pub fn persist_table_index(storage: &Storage, table_names: Vec<String>) {
+        let bytes =
+            rkyv::to_bytes::<_, 1024>(&table_names).expect("Unable to serialize table names");
 
-        let mut bufwriter = BufWriter::new(crab_file);
-
-        bufwriter.rewind().unwrap();
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(bufwriter),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&table_names)
-            .expect("Unable to serialize table names");
-
-        let (buf, _, _) = serializer.into_components();
-
-        buf.into_inner().flush().unwrap();
+        storage
+            .blob(CATALOG_FILE)
+            // Tables check their own generations, the catalog isn't part of one
+            .write(0, &bytes)
+            .expect("Failed to write database file");
     }
 
     pub fn database_filename(directory: &Path) -> PathBuf {
-        directory.join(Path::new("crab_dt.CRAB"))
+        directory.join(Path::new(CATALOG_FILE))
     }
 
     pub fn table_filename(directory: &Path, table: &str) -> PathBuf {
-        let mut table_file = table.to_string();
-        table_file.push_str("_db.CRAB");
-
-        directory.join(Path::new(&table_file))
+        directory.join(TableFile::Data.name(table))
     }
 
     pub fn page_dir_filename(directory: &Path, table: &str) -> PathBuf {
-        let mut pd_file = table.to_string();
-        pd_file.push_str("_pd.CRAB");
-
-        directory.join(Path::new(&pd_file))
+        directory.join(TableFile::PageDirectory.name(table))
     }
 
     pub fn index_filename(directory: &Path, table: &str) -> PathBuf {
-        let mut id_file = table.to_string();
-        id_file.push_str("_id.CRAB");
-
-        directory.join(Path::new(&id_file))
+        directory.join(TableFile::Index.name(table))
     }
 
     pub fn range_filename(directory: &Path, table: &str) -> PathBuf {
-        let mut rd_file = table.to_string();
-        rd_file.push_str("_rd.CRAB");
-
-        directory.join(Path::new(&rd_file))
+        directory.join(TableFile::RangeDirectory.name(table))
     }
 }
 
 impl CrabStore {
     pub fn new(directory: PathBuf) -> Self {
+        CrabStore::with_config(directory, Config::default())
+    }
+
+    pub fn with_config(directory: PathBuf, config: Config) -> Self {
         CrabStore {
             directory,
-            tables: HashMap::new(),
+            config,
+            tables: Arc::new(RwLock::new(HashMap::new())),
+            background_writer: None,
+            merge_scheduler: None,
+            storage: None,
+            single_file: false,
+            clock: Default::default(),
         }
     }
 
-    pub fn create_table(&mut self, name: &str, num_columns: usize, key_index: usize) -> Arc<Table> {
+    // The whole database lives in the file at `path`
+    pub fn single_file(path: PathBuf) -> Self {
+        CrabStore::single_file_with_config(path, Config::default())
+    }
+
+    pub fn single_file_with_config(path: PathBuf, config: Config) -> Self {
+        CrabStore {
+            single_file: true,
+            ..CrabStore::with_config(path, config)
+        }
+    }
+
+    pub fn in_memory() -> Self {
+        CrabStore::in_memory_with_config(Config::default())
+    }
+
+    pub fn in_memory_with_config(config: Config) -> Self {
+        CrabStore {
+            storage: Some(Storage::in_memory()),
+            ..CrabStore::with_config(PathBuf::default(), config)
+        }
+    }
+
+    // Maps every table file, any number of processes can open the same database this way
+    pub fn open_read_only(&mut self) {
+        self.config.read_only = true;
+        self.open();
+    }
+
+    pub fn writable(&self) -> Result<(), CrabError> {
+        if self.config.read_only {
+            return Err(CrabError::ReadOnly(self.directory.display().to_string()));
+        }
+
+        Ok(())
+    }
+
+    pub fn storage(&self) -> Storage {
+        match &self.storage {
+            Some(storage) => storage.clone(),
+            None => Storage::Directory(self.directory.clone()),
+        }
+    }
+
+    pub fn create_table(
+        &mut self,
+        name: &str,
+        num_columns: usize,
+        key_index: usize,
+    ) -> Result<Arc<Table>, CrabError> {
+        self.writable()?;
+
         let table = Arc::new(Table::new(
             name.to_string(),
             num_columns,
             key_index,
-            &CrabStore::table_filename(&self.directory, name),
-            &CrabStore::page_dir_filename(&self.directory, name),
-            &CrabStore::index_filename(&self.directory, name),
-            &CrabStore::range_filename(&self.directory, name),
+            &self.storage(),
+            &self.config,
+            &self.clock,
         ));
-        self.tables.insert(name.to_string(), Arc::clone(&table));
-        table
+        table.attach_merge_scheduler(&self.merge_scheduler());
+        self.tables
+            .write()
+            .insert(name.to_string(), Arc::clone(&table));
+        Ok(table)
     }
 
     pub fn drop_table(&mut self, name: &str) -> bool {
-        self.tables.remove(name);
+        if self.config.read_only {
+            return false;
+        }
+
+        if let Some(table) = self.tables.write().remove(name) {
+            table.detach_merge_scheduler();
+        }
+
         true
     }
 
+    // Started on first use, `close` stops it
+    fn merge_scheduler(&mut self) -> Arc<MergeScheduler> {
+        let workers = self.config.merge_workers;
+
+        Arc::clone(
+            self.merge_scheduler
+                .get_or_insert_with(|| Arc::new(MergeScheduler::new(workers))),
+        )
+    }
+
+    // A transaction on this database, a snapshot one takes its snapshot when it begins
+    pub fn transaction(&self, isolation: Isolation) -> Transaction {
+        Transaction::with_clock(isolation, &self.clock)
+    }
+
     pub fn get_table(&self, name: &str) -> Arc<Table> {
-        Arc::clone(self.tables.get(name).expect("Table not found"))
+        Arc::clone(self.tables.read().get(name).expect("Table not found"))
+    }
+
+    pub fn has_table(&self, name: &str) -> bool {
+        self.tables.read().contains_key(name)
+    }
+
+    // Loads the rows in the file at `path` into an existing table, returns how many were read
+    pub fn import(
+        &self,
+        table: &str,
+        path: &Path,
+        options: &DumpOptions,
+    ) -> Result<usize, CrabError> {
+        let table = self
+            .tables
+            .read()
+            .get(table)
+            .cloned()
+            .ok_or_else(|| CrabError::Missing(table.into()))?;
+
+        dump::import(&table, path, options)
+    }
+
+    // Writes every row of `table` to the file at `path`, returns how many were written
+    pub fn export(
+        &self,
+        table: &str,
+        path: &Path,
+        options: &DumpOptions,
+    ) -> Result<usize, CrabError> {
+        let table = self
+            .tables
+            .read()
+            .get(table)
+            .cloned()
+            .ok_or_else(|| CrabError::Missing(table.into()))?;
+
+        dump::export(&table, path, options)
     }
 
     pub fn open(&mut self) {
-        fs::create_dir_all(&self.directory).expect("Failed to create database directories.");
+        self.try_open()
+            .unwrap_or_else(|e| panic!("Failed to open database: {e}"));
+    }
 
-        let table_names =
-            CrabStore::load_table_index(&CrabStore::database_filename(&self.directory));
+    pub fn try_open(&mut self) -> Result<(), CrabError> {
+        // Existing database files open as a single file without having to ask
+        if self.single_file || self.directory.is_file() {
+            self.single_file = true;
+            self.storage = Some(
+                Storage::single_file(&self.directory, self.config.read_only)
+                    .map_err(|e| CrabError::io(&self.directory.display().to_string(), e))?,
+            );
+        }
+
+        let storage = self.storage();
+
+        if !self.config.read_only {
+            storage
+                .create()
+                .map_err(|e| CrabError::io(&self.directory.display().to_string(), e))?;
+        }
+
+        // Read-only databases can't be upgraded, loading them reports the old version
+        if !self.config.read_only {
+            migration::migrate(&storage, false)?;
+        }
+
+        let table_names = CrabStore::load_table_index(&storage)?;
+
+        // Nothing is opened unless every table loads
+        let mut loaded = HashMap::new();
 
         for name in table_names.iter() {
-            self.tables.insert(
+            loaded.insert(
                 name.to_string(),
-                Arc::new(Table::load(
-                    name,
-                    &CrabStore::table_filename(&self.directory, name),
-                    &CrabStore::page_dir_filename(&self.directory, name),
-                    &CrabStore::index_filename(&self.directory, name),
-                    &CrabStore::range_filename(&self.directory, name),
-                )),
+                Arc::new(Table::load(name, &storage, &self.config, &self.clock)?),
             );
         }
+
+        if !self.config.read_only {
+            let scheduler = self.merge_scheduler();
+
+            for table in loaded.values() {
+                table.attach_merge_scheduler(&scheduler);
+            }
+        }
+
+        self.tables.write().extend(loaded);
+
+        if !self.config.read_only {
+            self.background_writer =
+                Some(BackgroundWriter::spawn(&self.tables, storage, &self.config));
+        }
+
+        Ok(())
+    }
+
+    // What opening the database would migrate, without changing anything
+    pub fn plan_migration(&self) -> Result<MigrationReport, CrabError> {
+        let storage = if self.storage.is_none() && (self.single_file || self.directory.is_file()) {
+            Storage::single_file(&self.directory, true)
+                .map_err(|e| CrabError::io(&self.directory.display().to_string(), e))?
+        } else {
+            self.storage()
+        };
+
+        migration::migrate(&storage, true)
+    }
+
+    pub fn stats(&self) -> DatabaseStats {
+        let mut tables = self
+            .tables
+            .read()
+            .iter()
+            .map(|(name, table)| (name.clone(), table.stats()))
+            .collect::<Vec<_>>();
+
+        tables.sort_by(|a, b| a.0.cmp(&b.0));
+
+        DatabaseStats { tables }
+    }
+
+    pub fn checkpoint(&self) {
+        if self.config.read_only {
+            return;
+        }
+
+        let tables = self.tables.read();
+
+        for table in tables.values() {
+            table.checkpoint();
+        }
+
+        CrabStore::persist_table_index(&self.storage(), tables.keys().cloned().collect());
+    }
+
+    /*
+        Copies a consistent snapshot of every table into `dest` while queries
+        keep running. Writes to a table only wait while it's checkpointed,
+        and backing up to the same directory again only copies the pages
+        written since, so it's cheap.
+    */
+    pub fn backup(&self, dest: &Path) -> Result<BackupReport, CrabError> {
+        let storage = Storage::Directory(dest.into());
+        storage
+            .create()
+            .map_err(|e| CrabError::io(&dest.display().to_string(), e))?;
+
+        let mut tables = self
+            .tables
+            .read()
+            .iter()
+            .map(|(name, table)| (name.clone(), Arc::clone(table)))
+            .collect::<Vec<_>>();
+
+        tables.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // Tables dropped since the last backup to `dest`
+        for name in CrabStore::load_table_index(&storage)? {
+            if tables.iter().all(|x| x.0 != name) {
+                remove_table_files(&storage, &name)?;
+            }
+        }
+
+        let mut report = BackupReport::default();
+
+        for (_, table) in tables.iter() {
+            report += table.backup(&storage)?;
+        }
+
+        CrabStore::persist_table_index(&storage, tables.into_iter().map(|x| x.0).collect());
+
+        Ok(report)
+    }
+
+    // Replaces whatever database is in `dest` with the one backed up in `backup`
+    pub fn restore(backup: &Path, dest: &Path) -> Result<BackupReport, CrabError> {
+        let mut source = CrabStore::with_config(
+            backup.into(),
+            Config {
+                read_only: true,
+                ..Default::default()
+            },
+        );
+
+        source.try_open()?;
+
+        for name in CrabStore::load_table_index(&Storage::Directory(dest.into()))? {
+            remove_table_files(&Storage::Directory(dest.into()), &name)?;
+        }
+
+        let report = source.backup(dest);
+        source.close();
+
+        report
     }
 
     pub fn close(&mut self) {
-        let table_names = self.tables.keys().cloned().collect::<Vec<String>>();
+        if let Some(mut writer) = self.background_writer.take() {
+            writer.stop();
+        }
 
-        CrabStore::persist_table_index(&CrabStore::database_filename(&self.directory), table_names);
+        let mut tables = self.tables.write();
 
-        for table in self.tables.values() {
+        for table in tables.values() {
             table.persist();
         }
 
-        self.tables.clear();
+        if !self.config.read_only {
+            CrabStore::persist_table_index(&self.storage(), tables.keys().cloned().collect());
+        }
+
+        tables.clear();
+
+        // Every table has detached by now, so nothing is left running on it
+        if let Some(scheduler) = self.merge_scheduler.take() {
+            scheduler.shutdown();
+        }
+
+        if self.single_file {
+            self.storage = None;
+        }
     }
 
-    fn delete(path: String) {
-        fs::remove_dir_all(path).unwrap();
+    pub fn delete(path: String) {
+        if Path::new(&path).is_file() {
+            fs::remove_file(path).unwrap();
+        } else {
+            fs::remove_dir_all(path).unwrap();
+        }
     }
 }
+
+fn remove_table_files(storage: &Storage, table: &str) -> Result<(), CrabError> {
+    for file in [
+        TableFile::Data,
+        TableFile::PageDirectory,
+        TableFile::RangeDirectory,
+        TableFile::Index,
+        TableFile::Backup,
+    ] {
+        let name = file.name(table);
+
+        if let Some(path) = storage.path(&name) {
+            match fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                    return Err(CrabError::io(&name, e))
+                }
+                _ => (),
+            }
+        }
+    }
+
+    Ok(())
+}
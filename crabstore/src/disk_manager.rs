@@ -1,70 +1,147 @@
 use std::{
-    fs::*,
-    io::{self, Write},
+    io, mem,
     path::Path,
-    sync::atomic::{AtomicUsize, Ordering},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
 };
 
-#[cfg(target_os = "linux")]
-use std::os::unix::prelude::FileExt;
-
-#[cfg(target_os = "windows")]
-use std::os::windows::prelude::FileExt;
-
+use memmap2::Mmap;
 use parking_lot::Mutex;
 
-use crate::PAGE_SIZE;
+use crate::{
+    backup::PageTracker,
+    read_ahead::ReadAhead,
+    stats::DiskStats,
+    storage_backend::{StorageBackend, StorageBackendKind},
+    BUFFERPOOL_SIZE, PAGE_SIZE,
+};
 #[derive(Debug)]
 pub struct DiskManager {
-    file: Mutex<File>,
+    backend: Arc<dyn StorageBackend>,
     next_free_page: AtomicUsize,
+    // Pages below `next_free_page` that can be handed out again
+    free_pages: Mutex<Vec<usize>>,
+    // Freed since the last checkpoint, which may still reference them
+    released_pages: Mutex<Vec<usize>>,
+    reads: AtomicU64,
+    writes: AtomicU64,
+    syncs: AtomicU64,
+    read_ahead: ReadAhead,
+    backups: PageTracker,
 }
 
 impl DiskManager {
     pub fn new(file_path: &Path) -> Result<Self, io::Error> {
-        Ok(DiskManager {
-            file: Mutex::new(
-                OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .create(true)
-                    .open(file_path)?,
-            ),
+        DiskManager::with_backend(file_path, StorageBackendKind::default())
+    }
+
+    pub fn with_backend(file_path: &Path, kind: StorageBackendKind) -> Result<Self, io::Error> {
+        Ok(DiskManager::from_backend(kind.open(file_path)?.into()))
+    }
+
+    pub fn from_backend(backend: Arc<dyn StorageBackend>) -> Self {
+        DiskManager {
+            backend,
             next_free_page: 1.into(),
-        })
+            free_pages: Mutex::new(Vec::new()),
+            released_pages: Mutex::new(Vec::new()),
+            reads: 0.into(),
+            writes: 0.into(),
+            syncs: 0.into(),
+            read_ahead: ReadAhead::new(BUFFERPOOL_SIZE),
+            backups: Default::default(),
+        }
+    }
+
+    pub fn sync(&self) {
+        self.syncs.fetch_add(1, Ordering::Relaxed);
+        self.backend.sync().expect("Failed to sync file to disk");
     }
 
-    pub fn flush(&self) {
-        let mut file = self.file.lock();
-        file.flush().expect("Failed to flush file to disk");
+    pub fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) {
+        if self.read_ahead.take(page_id, page) {
+            return;
+        }
+
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.backend
+            .read_page(page_id, page)
+            .expect("Failed to read page");
+    }
+
+    pub fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) {
+        let _backup = self
+            .backups
+            .write(&[page_id], &*self.backend)
+            .expect("Failed to read page");
+
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.backend
+            .write_page(page_id, page)
+            .expect("Failed to write page");
+
+        self.read_ahead.invalidate(page_id);
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> usize {
-        let file = self.file.lock();
-        file.seek_read(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to read page")
+    // Reads bypass the read ahead cache, so the pages must not have been prefetched
+    pub fn read_pages(&self, pages: &mut [(usize, &mut [u8; PAGE_SIZE])]) {
+        self.reads.fetch_add(pages.len() as u64, Ordering::Relaxed);
+        self.backend
+            .read_pages(pages)
+            .expect("Failed to read pages");
+    }
+
+    pub fn write_pages(&self, pages: &[(usize, &[u8; PAGE_SIZE])]) {
+        let page_ids = pages.iter().map(|x| x.0).collect::<Vec<_>>();
+        let _backup = self
+            .backups
+            .write(&page_ids, &*self.backend)
+            .expect("Failed to read pages");
+
+        self.writes.fetch_add(pages.len() as u64, Ordering::Relaxed);
+        self.backend
+            .write_pages(pages)
+            .expect("Failed to write pages");
+
+        for (page_id, _) in pages.iter() {
+            self.read_ahead.invalidate(*page_id);
+        }
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> usize {
-        let file = self.file.lock();
-        file.read_at(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to read page")
+    pub(crate) fn backups(&self) -> &PageTracker {
+        &self.backups
     }
 
-    #[cfg(target_os = "windows")]
-    pub fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> usize {
-        let file = self.file.lock();
-        file.seek_write(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to write page")
+    // Pages of the checkpoint a running backup copies, as they were when it started
+    pub(crate) fn read_backup_pages(
+        &self,
+        pages: &mut [(usize, &mut [u8; PAGE_SIZE])],
+    ) -> io::Result<()> {
+        self.reads.fetch_add(pages.len() as u64, Ordering::Relaxed);
+        self.backups.read(pages, &*self.backend)
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> usize {
-        let file = self.file.lock();
-        file.write_at(page, (page_id * PAGE_SIZE) as u64)
-            .expect("Failed to write page")
+    pub fn mapped(&self) -> Option<Arc<Mmap>> {
+        self.backend.mapped()
+    }
+
+    // Queues background reads, the pages must not be resident in a buffer pool
+    pub fn prefetch(&self, pages: &[usize]) {
+        self.read_ahead.request(&self.backend, pages);
+    }
+
+    pub fn stats(&self) -> DiskStats {
+        DiskStats {
+            reads: self.reads.load(Ordering::Relaxed),
+            writes: self.writes.load(Ordering::Relaxed),
+            syncs: self.syncs.load(Ordering::Relaxed),
+            prefetch_reads: self.read_ahead.reads(),
+            prefetch_hits: self.read_ahead.hits(),
+            pages: self.free_page_pointer(),
+            free_pages: self.free_pages.lock().len() + self.released_pages.lock().len(),
+        }
     }
 
     pub fn reserve_page(&self) -> usize {
@@ -82,4 +159,42 @@ impl DiskManager {
     pub fn set_free_page_pointer(&self, ptr: usize) {
         self.next_free_page.store(ptr, Ordering::Relaxed)
     }
+
+    // Reuses free pages before growing the file, the pages needn't be contiguous
+    pub fn allocate_pages(&self, count: usize) -> Vec<usize> {
+        let mut free_pages = self.free_pages.lock();
+        let reused = free_pages.len().min(count);
+        let split = free_pages.len() - reused;
+        let mut pages = free_pages.split_off(split);
+
+        drop(free_pages);
+
+        let start = self.reserve_range(count - reused);
+        pages.extend(start..start + count - reused);
+
+        pages
+    }
+
+    // Pages nothing references any more, their contents must already be cleared
+    pub fn release_pages(&self, pages: &[usize]) {
+        self.released_pages.lock().extend_from_slice(pages);
+    }
+
+    /*
+        Released pages stay unused until a checkpoint that doesn't reference
+        them is complete, since recovering an earlier one could still read
+        them. Checkpoints take the released pages before persisting anything
+        and recycle them once the header is written.
+    */
+    pub(crate) fn take_released_pages(&self) -> Vec<usize> {
+        mem::take(&mut self.released_pages.lock())
+    }
+
+    pub(crate) fn recycle_pages(&self, pages: Vec<usize>) {
+        self.free_pages.lock().extend(pages);
+    }
+
+    pub fn set_free_pages(&self, pages: Vec<usize>) {
+        *self.free_pages.lock() = pages;
+    }
 }
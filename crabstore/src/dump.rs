@@ -0,0 +1,383 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use crate::{error::CrabError, table::Table};
+
+pub const DUMP_MAGIC: [u8; 8] = *b"CRABCOLS";
+pub const DUMP_VERSION: u32 = 1;
+
+// Rows per block of a native dump, and per batch handed to the table on import
+pub const DUMP_BLOCK_ROWS: usize = 8192;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DumpFormat {
+    // One row per line, comma separated
+    #[default]
+    Csv,
+    /*
+        DUMP_MAGIC, version and column count, then blocks of up to
+        DUMP_BLOCK_ROWS rows: a row count followed by each column's values
+        in turn. A block of zero rows ends the file. Everything is little
+        endian, counts and values are u64 and the header fields u32.
+    */
+    Native,
+}
+
+impl FromStr for DumpFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(DumpFormat::Csv),
+            "native" | "crab" | "columnar" => Ok(DumpFormat::Native),
+            _ => Err(format!("Unknown dump format \"{s}\"")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DumpOptions {
+    pub format: DumpFormat,
+    /*
+        Field i of the file is table column columns[i]. Empty means every
+        column in table order. Imports have to map every column exactly once,
+        exports can write any subset in any order.
+    */
+    pub columns: Vec<usize>,
+    // CSV files start with a line of column names
+    pub header: bool,
+}
+
+impl DumpOptions {
+    fn columns(&self, table: &Table) -> Result<Vec<usize>, CrabError> {
+        if self.columns.is_empty() {
+            return Ok((0..table.columns()).collect());
+        }
+
+        if let Some(column) = self.columns.iter().find(|x| **x >= table.columns()) {
+            return Err(CrabError::Mapping(format!(
+                "column {column} is out of range, the table has {} columns",
+                table.columns()
+            )));
+        }
+
+        Ok(self.columns.clone())
+    }
+
+    // Where each table column comes from in a file row
+    fn import_positions(&self, table: &Table) -> Result<Vec<usize>, CrabError> {
+        let columns = self.columns(table)?;
+        let mut positions = vec![None; table.columns()];
+
+        for (field, column) in columns.iter().enumerate() {
+            if positions[*column].replace(field).is_some() {
+                return Err(CrabError::Mapping(format!(
+                    "column {column} is mapped twice"
+                )));
+            }
+        }
+
+        positions
+            .iter()
+            .enumerate()
+            .map(|(column, x)| {
+                x.ok_or_else(|| CrabError::Mapping(format!("column {column} isn't mapped")))
+            })
+            .collect()
+    }
+}
+
+// Fields in each row of the file at `path`
+pub fn field_count(path: &Path, format: DumpFormat) -> Result<usize, CrabError> {
+    let file = path.display().to_string();
+    let mut reader = BufReader::new(File::open(path).map_err(|e| CrabError::io(&file, e))?);
+
+    match format {
+        DumpFormat::Csv => {
+            let mut line = String::new();
+            reader
+                .read_line(&mut line)
+                .map_err(|e| CrabError::io(&file, e))?;
+
+            Ok(line.trim().split(',').count())
+        }
+        DumpFormat::Native => read_header(&mut reader, &file),
+    }
+}
+
+/*
+    Inserts every row of the file at `path`, returns how many were read.
+    Rows go in through `Table::bulk_insert` a batch at a time, so a bad row
+    or a key the table already has stops the import with every earlier batch
+    kept and the rest of its own batch discarded.
+*/
+pub fn import(table: &Table, path: &Path, options: &DumpOptions) -> Result<usize, CrabError> {
+    table.writable()?;
+
+    let file = path.display().to_string();
+    let positions = options.import_positions(table)?;
+    let reader = BufReader::new(File::open(path).map_err(|e| CrabError::io(&file, e))?);
+
+    match options.format {
+        DumpFormat::Csv => import_csv(table, reader, &file, &positions, options.header),
+        DumpFormat::Native => import_native(table, reader, &file, &positions),
+    }
+}
+
+// Writes every live row to the file at `path`, returns how many were written
+pub fn export(table: &Table, path: &Path, options: &DumpOptions) -> Result<usize, CrabError> {
+    let file = path.display().to_string();
+    let columns = options.columns(table)?;
+    let mut writer = BufWriter::new(File::create(path).map_err(|e| CrabError::io(&file, e))?);
+
+    let rows = match options.format {
+        DumpFormat::Csv => export_csv(table, &mut writer, &columns, options.header, &file),
+        DumpFormat::Native => export_native(table, &mut writer, &columns, &file),
+    }?;
+
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .and_then(|x| x.sync_all())
+        .map_err(|e| CrabError::io(&file, e))?;
+
+    Ok(rows)
+}
+
+fn import_csv(
+    table: &Table,
+    reader: impl BufRead,
+    file: &str,
+    positions: &[usize],
+    header: bool,
+) -> Result<usize, CrabError> {
+    let fields = positions.len();
+    let mut batch = Vec::with_capacity(DUMP_BLOCK_ROWS);
+    let mut rows = 0;
+
+    for (i, line) in reader.lines().enumerate().skip(header as usize) {
+        let line = line.map_err(|e| CrabError::io(file, e))?;
+        let parse_error = |error: String| CrabError::Parse {
+            file: file.into(),
+            line: i + 1,
+            error,
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let values = line
+            .split(',')
+            .map(|x| {
+                let x = x.trim().trim_matches('"');
+                x.parse::<u64>()
+                    .map_err(|e| parse_error(format!("\"{x}\": {e}")))
+            })
+            .collect::<Result<Vec<u64>, CrabError>>()?;
+
+        if values.len() != fields {
+            return Err(parse_error(format!(
+                "expected {fields} fields, found {}",
+                values.len()
+            )));
+        }
+
+        batch.push(positions.iter().map(|x| values[*x]).collect());
+
+        if batch.len() == DUMP_BLOCK_ROWS {
+            table.bulk_insert(&batch)?;
+            rows += batch.len();
+            batch.clear();
+        }
+    }
+
+    table.bulk_insert(&batch)?;
+
+    Ok(rows + batch.len())
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+// Returns the column count
+fn read_header(reader: &mut impl Read, file: &str) -> Result<usize, CrabError> {
+    let corrupt = |error: &str| CrabError::Corrupt {
+        file: file.into(),
+        error: error.into(),
+    };
+
+    let mut magic = [0; 8];
+    reader
+        .read_exact(&mut magic)
+        .map_err(|_| corrupt("file is too short"))?;
+
+    if magic != DUMP_MAGIC {
+        return Err(corrupt("not a native dump"));
+    }
+
+    let version = read_u32(reader).map_err(|e| CrabError::io(file, e))?;
+
+    if version != DUMP_VERSION {
+        return Err(corrupt(&format!("unsupported dump version {version}")));
+    }
+
+    Ok(read_u32(reader).map_err(|e| CrabError::io(file, e))? as usize)
+}
+
+fn import_native(
+    table: &Table,
+    mut reader: impl Read,
+    file: &str,
+    positions: &[usize],
+) -> Result<usize, CrabError> {
+    let fields = read_header(&mut reader, file)?;
+
+    if fields != positions.len() {
+        return Err(CrabError::Mapping(format!(
+            "the dump has {fields} columns, {} are mapped",
+            positions.len()
+        )));
+    }
+
+    let mut rows = 0;
+    let mut columns = vec![Vec::new(); fields];
+
+    loop {
+        let count = read_u64(&mut reader).map_err(|e| CrabError::io(file, e))? as usize;
+
+        if count == 0 {
+            return Ok(rows);
+        }
+
+        if count > DUMP_BLOCK_ROWS {
+            return Err(CrabError::Corrupt {
+                file: file.into(),
+                error: format!("block of {count} rows"),
+            });
+        }
+
+        for column in columns.iter_mut() {
+            column.clear();
+
+            for _ in 0..count {
+                column.push(read_u64(&mut reader).map_err(|e| CrabError::io(file, e))?);
+            }
+        }
+
+        let batch = (0..count)
+            .map(|row| positions.iter().map(|x| columns[*x][row]).collect())
+            .collect::<Vec<Vec<u64>>>();
+
+        table.bulk_insert(&batch)?;
+        rows += count;
+    }
+}
+
+fn export_csv(
+    table: &Table,
+    writer: &mut impl Write,
+    columns: &[usize],
+    header: bool,
+    file: &str,
+) -> Result<usize, CrabError> {
+    if header {
+        let names = columns
+            .iter()
+            .map(|x| format!("column{x}"))
+            .collect::<Vec<_>>();
+
+        writeln!(writer, "{}", names.join(",")).map_err(|e| CrabError::io(file, e))?;
+    }
+
+    let mut rows = 0;
+    let mut result = Ok(());
+
+    table.scan(columns, |values| {
+        if result.is_err() {
+            return;
+        }
+
+        let line = values
+            .iter()
+            .map(|x| x.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+
+        result = writeln!(writer, "{line}");
+        rows += 1;
+    })?;
+
+    result.map(|_| rows).map_err(|e| CrabError::io(file, e))
+}
+
+fn write_block(writer: &mut impl Write, block: &[Vec<u64>]) -> io::Result<()> {
+    writer.write_all(&(block[0].len() as u64).to_le_bytes())?;
+
+    for column in block.iter() {
+        for value in column.iter() {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+    }
+
+    Ok(())
+}
+
+fn export_native(
+    table: &Table,
+    writer: &mut impl Write,
+    columns: &[usize],
+    file: &str,
+) -> Result<usize, CrabError> {
+    let io = |e| CrabError::io(file, e);
+
+    writer.write_all(&DUMP_MAGIC).map_err(io)?;
+    writer.write_all(&DUMP_VERSION.to_le_bytes()).map_err(io)?;
+    writer
+        .write_all(&(columns.len() as u32).to_le_bytes())
+        .map_err(io)?;
+
+    let mut rows = 0;
+    let mut block = vec![Vec::with_capacity(DUMP_BLOCK_ROWS); columns.len()];
+    let mut result = Ok(());
+
+    table.scan(columns, |values| {
+        if result.is_err() {
+            return;
+        }
+
+        for (column, value) in block.iter_mut().zip(values.iter()) {
+            column.push(*value);
+        }
+
+        rows += 1;
+
+        if rows % DUMP_BLOCK_ROWS == 0 {
+            result = write_block(writer, &block);
+            block.iter_mut().for_each(|x| x.clear());
+        }
+    })?;
+
+    result.map_err(io)?;
+
+    if rows % DUMP_BLOCK_ROWS != 0 {
+        write_block(writer, &block).map_err(io)?;
+    }
+
+    writer.write_all(&0u64.to_le_bytes()).map_err(io)?;
+
+    Ok(rows)
+}
@@ -0,0 +1,124 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+#[derive(Debug, Default)]
+pub struct RetiredPages {
+    // Tail pages to take out of the page directory
+    pub tail_pages: Vec<usize>,
+    // Column pages nothing references any more
+    pub pages: Vec<usize>,
+}
+
+impl RetiredPages {
+    pub fn is_empty(&self) -> bool {
+        self.tail_pages.is_empty() && self.pages.is_empty()
+    }
+
+    fn append(&mut self, other: &mut RetiredPages) {
+        self.tail_pages.append(&mut other.tail_pages);
+        self.pages.append(&mut other.pages);
+    }
+}
+
+#[derive(Default)]
+struct EpochState {
+    current: u64,
+    // Epoch -> queries pinning it
+    pinned: BTreeMap<u64, usize>,
+    retired: VecDeque<(u64, RetiredPages)>,
+}
+
+/*
+    Epoch based reclamation of the pages a merge replaces. Queries pin the
+    current epoch for as long as they might follow page ids read before a
+    merge published, and pages retired in an epoch are only handed back by
+    `collect` once every query pinned at or before it has finished.
+*/
+#[derive(Default)]
+pub struct Epochs {
+    state: Mutex<EpochState>,
+}
+
+pub struct EpochGuard {
+    epochs: Arc<Epochs>,
+    epoch: u64,
+}
+
+impl Epochs {
+    pub fn pin(self: &Arc<Self>) -> EpochGuard {
+        let mut state = self.state.lock();
+        let epoch = state.current;
+
+        *state.pinned.entry(epoch).or_default() += 1;
+
+        EpochGuard {
+            epochs: Arc::clone(self),
+            epoch,
+        }
+    }
+
+    // Called once nothing new can reach `pages`, queries already running still might
+    pub fn retire(&self, pages: RetiredPages) {
+        if pages.is_empty() {
+            return;
+        }
+
+        let mut state = self.state.lock();
+        let epoch = state.current;
+
+        state.retired.push_back((epoch, pages));
+        state.current += 1;
+    }
+
+    // Everything retired before the oldest pinned epoch
+    pub fn collect(&self) -> RetiredPages {
+        let mut state = self.state.lock();
+        let oldest = state.pinned.keys().next().copied().unwrap_or(u64::MAX);
+        let mut pages = RetiredPages::default();
+
+        while matches!(state.retired.front(), Some((epoch, _)) if *epoch < oldest) {
+            let (_, mut retired) = state.retired.pop_front().unwrap();
+            pages.append(&mut retired);
+        }
+
+        pages
+    }
+
+    pub fn pinned(&self) -> usize {
+        self.state.lock().pinned.values().sum()
+    }
+
+    // Pages retired but not collected yet
+    pub fn retired(&self) -> usize {
+        self.state
+            .lock()
+            .retired
+            .iter()
+            .map(|(_, x)| x.tail_pages.len() + x.pages.len())
+            .sum()
+    }
+}
+
+impl Drop for EpochGuard {
+    fn drop(&mut self) {
+        let mut state = self.epochs.state.lock();
+
+        if let Some(count) = state.pinned.get_mut(&self.epoch) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.pinned.remove(&self.epoch);
+            }
+        }
+    }
+}
+
+impl EpochGuard {
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+}
@@ -0,0 +1,95 @@
+use std::{fmt, io};
+
+use crate::bufferpool::BufferPoolError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CrabError {
+    ReadOnly(String),
+    Io {
+        file: String,
+        error: String,
+    },
+    Missing(String),
+    // The file doesn't hold a valid archive
+    Corrupt {
+        file: String,
+        error: String,
+    },
+    // Written in a format version this build can't read without migrating
+    Version {
+        file: String,
+        found: u32,
+    },
+    // The file was written by a different checkpoint than the table header
+    Inconsistent {
+        file: String,
+        expected: u64,
+        found: u64,
+    },
+    // An imported file doesn't hold what it claims, `line` counts from 1
+    Parse {
+        file: String,
+        line: usize,
+        error: String,
+    },
+    // File fields don't map onto the table's columns
+    Mapping(String),
+    DuplicateKey {
+        table: String,
+        key: u64,
+    },
+    // Every frame stayed pinned, the query can be retried once some are released
+    BufferPool(BufferPoolError),
+    // Transactions kept the table locked, the query can be retried once they finish
+    Locked(String),
+}
+
+impl CrabError {
+    pub fn io(file: &str, error: io::Error) -> Self {
+        CrabError::Io {
+            file: file.into(),
+            error: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for CrabError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CrabError::ReadOnly(name) => write!(f, "\"{name}\" is open read-only"),
+            CrabError::Io { file, error } => write!(f, "I/O error on \"{file}\": {error}"),
+            CrabError::Missing(file) => write!(f, "\"{file}\" is missing"),
+            CrabError::Corrupt { file, error } => write!(f, "\"{file}\" is corrupt: {error}"),
+            CrabError::Version { file, found } => write!(
+                f,
+                "\"{file}\" is format version {found}, this build reads version {}",
+                crate::migration::FORMAT_VERSION
+            ),
+            CrabError::Inconsistent {
+                file,
+                expected,
+                found,
+            } => write!(
+                f,
+                "\"{file}\" is from generation {found}, expected generation {expected}"
+            ),
+            CrabError::Parse { file, line, error } => {
+                write!(f, "\"{file}\" line {line}: {error}")
+            }
+            CrabError::Mapping(error) => write!(f, "Invalid column mapping: {error}"),
+            CrabError::DuplicateKey { table, key } => {
+                write!(f, "\"{table}\" already has a record with key {key}")
+            }
+            CrabError::BufferPool(error) => write!(f, "{error}"),
+            CrabError::Locked(table) => write!(f, "\"{table}\" is locked by a transaction"),
+        }
+    }
+}
+
+impl From<BufferPoolError> for CrabError {
+    fn from(error: BufferPoolError) -> Self {
+        CrabError::BufferPool(error)
+    }
+}
+
+impl std::error::Error for CrabError {}
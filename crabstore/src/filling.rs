@@ -0,0 +1,101 @@
+use std::{
+    ops::Range,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::Mutex;
+use rustc_hash::FxHashMap;
+
+use crate::rid::RID;
+
+/*
+    Hands out base RIDs and keeps track of the base pages inserts are
+    writing to. Merges copy base pages, so an insert landing in one after it
+    was copied would be lost with the old page. They leave pages with
+    inserts in flight alone, and give up if one got a new RID before the
+    merged pages were published.
+*/
+#[derive(Default)]
+pub struct FillingPages {
+    next_rid: AtomicU64,
+    // Base page -> inserts still writing to it
+    writing: Mutex<FxHashMap<usize, usize>>,
+}
+
+// Reserved RIDs, their pages stop filling once every guard holding them is dropped
+pub struct FillGuard {
+    filling: Arc<FillingPages>,
+    rids: Range<u64>,
+}
+
+impl FillingPages {
+    pub fn new(next_rid: u64) -> Self {
+        FillingPages {
+            next_rid: next_rid.into(),
+            writing: Default::default(),
+        }
+    }
+
+    pub fn next_rid(&self) -> u64 {
+        self.next_rid.load(Ordering::Relaxed)
+    }
+
+    pub fn reserve(self: &Arc<Self>, count: u64) -> FillGuard {
+        let mut writing = self.writing.lock();
+        let first = self.next_rid.fetch_add(count, Ordering::Relaxed);
+        let rids = first..first + count;
+
+        for page in pages(&rids) {
+            *writing.entry(page).or_default() += 1;
+        }
+
+        FillGuard {
+            filling: Arc::clone(self),
+            rids,
+        }
+    }
+
+    pub fn any_writing(&self, mut pages: impl Iterator<Item = usize>) -> bool {
+        let writing = self.writing.lock();
+        pages.any(|x| writing.contains_key(&x))
+    }
+
+    // Whether any of `pages` got an RID handed out since `next_rid` was `since`
+    pub fn any_reserved_since(&self, since: u64, mut pages: impl Iterator<Item = usize>) -> bool {
+        let reserved = self::pages(&(since..self.next_rid()));
+        pages.any(|x| reserved.contains(&x))
+    }
+}
+
+impl FillGuard {
+    pub fn first(&self) -> RID {
+        self.rids.start.into()
+    }
+}
+
+impl Drop for FillGuard {
+    fn drop(&mut self) {
+        let mut writing = self.filling.writing.lock();
+
+        for page in pages(&self.rids) {
+            if let Some(count) = writing.get_mut(&page) {
+                *count -= 1;
+
+                if *count == 0 {
+                    writing.remove(&page);
+                }
+            }
+        }
+    }
+}
+
+fn pages(rids: &Range<u64>) -> Range<usize> {
+    if rids.is_empty() {
+        return 0..0;
+    }
+
+    RID(rids.start).page()..RID(rids.end - 1).page() + 1
+}
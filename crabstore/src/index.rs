@@ -1,40 +1,31 @@
-use crate::rid::RID;
-use core::fmt;
-use rkyv::{
-    de::deserializers::SharedDeserializeMap,
-    ser::{
-        serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-        Serializer,
-    },
-    Deserialize,
-};
-use std::{
-    collections::BTreeMap,
-    io::{BufWriter, Read, Write},
-    ops::RangeBounds,
-    path::PathBuf,
+use crate::{
+    error::CrabError,
+    rid::RID,
+    storage::{decode, Blob},
 };
-use std::{fs::File, path::Path};
+use core::fmt;
+use rkyv::AlignedVec;
+use std::{collections::BTreeMap, ops::RangeBounds};
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug)]
 //change to BTreeMap when we need to implement ranges
 pub struct Index {
-    path: PathBuf,
+    blob: Blob,
     indices: Vec<Option<BTreeMap<u64, Vec<RID>>>>,
 }
 
 impl fmt::Display for Index {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         for (i, v) in self.indices.iter().enumerate() {
-            write!(f, "Index on Column {}:\n", i).unwrap();
+            writeln!(f, "Index on Column {}:", i).unwrap();
             match v {
                 Some(v) => {
                     for (key, value) in v.iter() {
-                        write!(f, "Key: {} | Value: {:?}\n", key, value)?;
+                        writeln!(f, "Key: {} | Value: {:?}", key, value)?;
                     }
                 }
                 None => {
-                    write!(f, "None\n").unwrap();
+                    writeln!(f, "None").unwrap();
                 }
             }
         }
@@ -44,61 +35,30 @@ impl fmt::Display for Index {
 }
 
 impl Index {
-    pub fn new(key_index: usize, num_columns: usize, path: &Path) -> Self {
+    pub fn new(key_index: usize, num_columns: usize, blob: Blob) -> Self {
         let mut indices = Vec::with_capacity(num_columns);
         indices.resize_with(num_columns, Default::default);
         indices[key_index] = Some(BTreeMap::new());
 
-        Index {
-            path: path.into(),
-            indices,
-        }
+        Index { blob, indices }
     }
 
-    pub fn load(path: &Path) -> Self {
-        let mut id_file = File::options()
-            .read(true)
-            .open(path)
-            .expect("Unable to open index file");
-
-        let mut id_bytes = Vec::new();
+    pub fn load(blob: Blob, generation: u64) -> Result<Self, CrabError> {
+        let id_bytes = blob
+            .read_generation(generation)?
+            .ok_or_else(|| CrabError::Missing(blob.name().into()))?;
 
-        id_file
-            .read_to_end(&mut id_bytes)
-            .expect("Unable to read index file");
+        let indices = decode::<Vec<Option<BTreeMap<u64, Vec<RID>>>>>(blob.name(), &id_bytes)?;
 
-        let archived =
-            unsafe { rkyv::archived_root::<Vec<Option<BTreeMap<u64, Vec<RID>>>>>(&id_bytes) };
-
-        Index {
-            path: path.into(),
-            indices: archived
-                .deserialize(&mut SharedDeserializeMap::new())
-                .expect("Failed to deserialize page directory"),
-        }
+        Ok(Index { blob, indices })
     }
 
-    pub fn persist(&self) {
-        let id_file = File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(&self.path)
-            .expect("Unable to open index file");
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(BufWriter::new(id_file)),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&self.indices)
-            .expect("Unable to serialize indexes");
-
-        let (buf, _, _) = serializer.into_components();
+    pub fn blob(&self) -> &Blob {
+        &self.blob
+    }
 
-        buf.into_inner().flush().expect("Failed to flush indices");
+    pub fn to_bytes(&self) -> AlignedVec {
+        rkyv::to_bytes::<_, 4096>(&self.indices).expect("Unable to serialize indexes")
     }
 
     pub fn update_index(&mut self, column_number: usize, value: u64, rid: RID) {
@@ -113,6 +73,17 @@ impl Index {
         }
     }
 
+    // Adds many entries at once, sorted first so the tree is filled in order
+    pub fn bulk_update_index(&mut self, column_number: usize, entries: &mut [(u64, RID)]) {
+        if let Some(ref mut index) = self.indices[column_number] {
+            entries.sort_unstable();
+
+            for (value, rid) in entries.iter() {
+                index.entry(*value).or_insert_with(Vec::new).push(*rid);
+            }
+        }
+    }
+
     pub fn remove_index(&mut self, column_number: usize, value: u64, rid: RID) {
         if let Some(ref mut index) = self.indices[column_number] {
             if let Some(ref mut rids) = index.get_mut(&value) {
@@ -142,6 +113,10 @@ impl Index {
         })
     }
 
+    pub fn has_index(&self, column_number: usize) -> bool {
+        self.indices[column_number].is_some()
+    }
+
     pub fn create_index(&mut self, column_number: usize) {
         self.indices[column_number] = Some(BTreeMap::new());
     }
@@ -1,7 +1,6 @@
 #![feature(new_uninit)]
 #![feature(map_try_insert)]
 #![feature(get_mut_unchecked)]
-#![feature(return_position_impl_trait_in_trait)]
 #![feature(thread_id_value)]
 
 use std::mem::size_of;
@@ -9,8 +8,6 @@ use std::mem::size_of;
 const PAGE_SIZE: usize = 4096;
 const PAGE_SLOTS: usize = PAGE_SIZE / size_of::<i64>();
 const PAGE_RANGE_COUNT: usize = 16;
-const PAGE_RANGE_SIZE: usize = PAGE_SIZE * PAGE_RANGE_COUNT;
-const RANGE_PAGE_COUNT: usize = PAGE_RANGE_SIZE / PAGE_SIZE;
 
 const NUM_METADATA_COLUMNS: usize = 5;
 const METADATA_INDIRECTION: usize = 0;
@@ -25,28 +22,51 @@ const METADATA_SCHEMA_ENCODING: usize = 4;
 // 0xFF...FF
 const RID_INVALID: u64 = !0;
 
-// usually 16, but 32 to
-// allow for shared bufferpool with merge thread
+// Frames per table, merges copy pages out rather than pinning them
 const BUFFERPOOL_SIZE: usize = 256;
 
+pub mod background_writer;
+pub mod backup;
 pub mod bufferpool;
+pub mod config;
 pub mod crabstore;
 pub mod disk_manager;
+pub mod dump;
+pub mod epoch;
+pub mod error;
+mod filling;
 pub mod index;
 pub mod lock_manager;
-mod merge;
+pub mod merge;
+pub mod merge_scheduler;
+pub mod migration;
+pub mod mvcc;
 pub mod page;
 mod page_directory;
 mod range_directory;
+mod read_ahead;
 pub mod record;
+pub mod replacement_policy;
 pub mod rid;
+pub mod single_file;
+pub mod stats;
+pub mod storage;
+pub mod storage_backend;
 pub mod table;
 pub mod transaction;
 pub mod transaction_worker;
 
 #[cfg(test)]
 mod tests {
-    use crate::crabstore::CrabStore;
+    use std::sync::atomic::Ordering;
+
+    use crate::{
+        config::Config,
+        crabstore::CrabStore,
+        merge::MergePolicy,
+        transaction::{Isolation, Query, Transaction},
+        PAGE_RANGE_COUNT, PAGE_SLOTS,
+    };
     use tempfile::tempdir;
 
     #[test]
@@ -59,21 +79,18 @@ mod tests {
 
     #[test]
     fn create_table() {
-        let dir = tempdir().expect("Failed to get temp directory");
-        let mut db = CrabStore::new(dir.path().into());
+        let mut db = CrabStore::in_memory();
         db.open();
-        db.create_table("test_table", 2, 0);
+        db.create_table("test_table", 2, 0).unwrap();
         db.close();
     }
 
     #[test]
     fn get_table() {
-        let dir = tempdir().expect("Failed to get temp directory");
-
-        let mut db = CrabStore::new(dir.path().into());
+        let mut db = CrabStore::in_memory();
         db.open();
 
-        db.create_table("test_table", 2, 0);
+        db.create_table("test_table", 2, 0).unwrap();
         db.get_table("test_table");
 
         db.close();
@@ -88,22 +105,184 @@ mod tests {
 
     #[test]
     fn check_aliasing() {
-        let dir = tempdir().expect("Failed to get temp directory");
-
-        let mut db = CrabStore::new(dir.path().into());
+        let mut db = CrabStore::in_memory();
         db.open();
-        let table1 = db.create_table("test_table", 2, 0);
+        let table1 = db.create_table("test_table", 2, 0).unwrap();
         let table2 = db.get_table("test_table");
-        table1.insert_query(&[1, 2], None);
-        table2.insert_query(&[3, 4], None);
+        table1.insert_query(&[1, 2], None).unwrap();
+        table2.insert_query(&[3, 4], None).unwrap();
+        assert_eq!(
+            table1.select_query(1, 0, &[1, 1], None).unwrap(),
+            table2.select_query(1, 0, &[1, 1], None).unwrap()
+        );
+        assert_eq!(
+            table1.select_query(2, 0, &[1, 1], None).unwrap(),
+            table2.select_query(2, 0, &[1, 1], None).unwrap()
+        );
+        db.close();
+    }
+
+    #[test]
+    fn in_memory_reopen() {
+        let mut db = CrabStore::in_memory();
+        db.open();
+
+        let table = db.create_table("memory", 3, 0).unwrap();
+
+        for i in 0..2000 {
+            table.insert_query(&[i, i + 1, i + 2], None).unwrap();
+        }
+
+        for i in (0..2000).step_by(2) {
+            table.update_query(i, &[None, Some(0), None], None).unwrap();
+        }
+
+        drop(table);
+        db.close();
+        db.open();
+
+        let table = db.get_table("memory");
+
+        assert_eq!(
+            table.select_query(10, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [10, 0, 12]
+        );
         assert_eq!(
-            table1.select_query(1, 0, &[1, 1], None),
-            table2.select_query(1, 0, &[1, 1], None)
+            table.select_query(11, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [11, 12, 13]
         );
         assert_eq!(
-            table1.select_query(2, 0, &[1, 1], None),
-            table2.select_query(2, 0, &[1, 1], None)
+            table.sum_query(0, 1999, 1, None).unwrap(),
+            (2..=2000).step_by(2).sum::<u64>()
         );
+
+        drop(table);
         db.close();
     }
+
+    #[test]
+    fn merge_panics_are_counted() {
+        let mut db = CrabStore::in_memory_with_config(Config {
+            merge_policy: MergePolicy::Manual,
+            ..Default::default()
+        });
+        db.open();
+
+        let table = db.create_table("panics", 2, 0).unwrap();
+
+        for i in 0..1000 {
+            table.insert_query(&[i, i], None).unwrap();
+        }
+
+        for i in 0..1000 {
+            table.update_query(i, &[None, Some(0)], None).unwrap();
+        }
+
+        // A tail page the page directory doesn't know about
+        let tail = table
+            .range_dir
+            .lock()
+            .get(0)
+            .current_tail_page
+            .swap(!1, Ordering::SeqCst);
+
+        assert_eq!(table.force_merge(0), 0);
+
+        let stats = table.merge_stats();
+        assert_eq!(stats.merges_panicked, 1);
+        assert_eq!(stats.ranges_merged, 0);
+        assert!(stats
+            .last_panic
+            .unwrap()
+            .starts_with("Merge of range 0 panicked: Bad page ID"));
+
+        table
+            .range_dir
+            .lock()
+            .get(0)
+            .current_tail_page
+            .store(tail, Ordering::SeqCst);
+
+        assert!(table.force_merge(0) > 0);
+        assert_eq!(
+            table.select_query(5, 0, &[1, 1], None).unwrap()[0].columns,
+            [5, 0]
+        );
+
+        drop(table);
+        db.close();
+    }
+
+    #[test]
+    fn unallocated_ranges_are_skipped() {
+        let mut db = CrabStore::in_memory();
+        db.open();
+
+        let table = db.create_table("unallocated", 2, 0).unwrap();
+
+        for i in 0..10 {
+            table.insert_query(&[i, i * 2], None).unwrap();
+        }
+
+        // Like inserts that aborted before allocating the next range
+        drop(
+            table
+                .filling
+                .reserve((PAGE_RANGE_COUNT * PAGE_SLOTS) as u64),
+        );
+
+        assert_eq!(
+            table.select_query(8, 1, &[1, 1], None).unwrap()[0].columns,
+            [4, 8]
+        );
+        assert!(table.select_query(9, 1, &[1, 1], None).unwrap().is_empty());
+
+        table.build_index(1).unwrap();
+        assert_eq!(
+            table.select_query(18, 1, &[1, 1], None).unwrap()[0].columns,
+            [9, 18]
+        );
+
+        drop(table);
+        db.close();
+    }
+
+    #[test]
+    fn snapshots_stay_in_their_database() {
+        let mut first = CrabStore::in_memory();
+        let mut second = CrabStore::in_memory();
+        first.open();
+        second.open();
+
+        let table = first.create_table("first", 2, 0).unwrap();
+        let other = second.create_table("second", 2, 0).unwrap();
+        table.insert_query(&[1, 1], None).unwrap();
+
+        let mut snapshot = first.transaction(Isolation::Snapshot);
+        snapshot.begin();
+
+        // Nothing on the second database waits for the first one's snapshot
+        for i in 0..10 {
+            other.insert_query(&[i, i], None).unwrap();
+            other.update_query(i, &[None, Some(0)], None).unwrap();
+        }
+
+        other.versions.collect();
+        assert!(other.versions.is_empty());
+        assert!(table.versions.clock().oldest_snapshot().is_some());
+
+        // Its timestamps mean nothing to the other database
+        assert!(snapshot
+            .execute(&Query::Update(1, [None, Some(0)].into()), &other)
+            .is_none());
+
+        let mut unbound = Transaction::with_isolation(Isolation::Snapshot);
+        unbound.begin();
+        assert!(unbound.execute(&Query::Sum(0, 9, 1), &other).is_some());
+        assert!(unbound.execute(&Query::Sum(1, 1, 1), &table).is_none());
+
+        drop((table, other));
+        first.close();
+        second.close();
+    }
 }
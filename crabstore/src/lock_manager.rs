@@ -1,32 +1,319 @@
-use std::{collections::HashMap, hash::BuildHasherDefault};
-
-use parking_lot::{
-    lock_api::{RawRwLock, RawRwLockFair, RawRwLockRecursive, RawRwLockUpgrade},
-    Mutex, RwLock,
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    hash::BuildHasherDefault,
+    str::FromStr,
+    time::{Duration, Instant},
 };
+
+use parking_lot::{const_mutex, Condvar, Mutex};
 use rustc_hash::{FxHashMap, FxHasher};
 
-use crate::rid::RID;
+use crate::{rid::RID, stats::LockStats};
 
+/*
+    Records are locked shared or exclusive. Before that, the transaction
+    takes the matching intention lock on the table and the record's page
+    range, which a lock on the whole range or table conflicts with.
+*/
 #[derive(Copy, PartialEq, Clone, Eq, Debug)]
 pub enum LockType {
+    IntentionShared,
+    IntentionExclusive,
     Shared,
     Exclusive,
 }
 
+impl LockType {
+    // What a transaction locks the table and range with before locking a record this way
+    pub fn intention(self) -> LockType {
+        match self {
+            LockType::IntentionShared | LockType::Shared => LockType::IntentionShared,
+            LockType::IntentionExclusive | LockType::Exclusive => LockType::IntentionExclusive,
+        }
+    }
+
+    pub fn compatible(self, other: LockType) -> bool {
+        use LockType::*;
+
+        matches!(
+            (self, other),
+            (
+                IntentionShared,
+                IntentionShared | IntentionExclusive | Shared
+            ) | (IntentionExclusive, IntentionShared | IntentionExclusive)
+                | (Shared, IntentionShared | Shared)
+        )
+    }
+
+    // Whether holding this grants everything `other` does, on the locked resource and below it
+    pub fn covers(self, other: LockType) -> bool {
+        use LockType::*;
+
+        match self {
+            Exclusive => true,
+            Shared => matches!(other, IntentionShared | Shared),
+            IntentionExclusive => matches!(other, IntentionShared | IntentionExclusive),
+            IntentionShared => other == IntentionShared,
+        }
+    }
+
+    // The weakest lock covering both
+    pub fn join(self, other: LockType) -> LockType {
+        if self.covers(other) {
+            self
+        } else if other.covers(self) {
+            other
+        } else {
+            LockType::Exclusive
+        }
+    }
+}
+
+// What a lock is taken on, from coarsest to finest
+#[derive(Copy, PartialEq, Clone, Eq, Debug, Hash)]
+pub enum LockTarget {
+    Table,
+    Range(usize),
+    Record(RID),
+    // Every value of a column, for reads over more keys than they lock block by block
+    Column(usize),
+    /*
+        A block of values of a column, the values shifted right by
+        `KEY_BLOCK_BITS`. Reads lock the keys they look for shared, writes
+        lock the keys they add or remove intention exclusive, so nothing
+        moves into a key range a transaction read before it ends while
+        writes to the same keys don't wait for each other.
+    */
+    Keys(usize, u64),
+    // One value of a column, in the block above
+    Key(usize, u64),
+}
+
+pub const KEY_BLOCK_BITS: u32 = 8;
+
+// What a transaction does when a lock it wants is held by another one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockPolicy {
+    // Gives up right away
+    NoWait,
+    /*
+        Waits up to the timeout. A wait that would close a cycle of waiting
+        transactions aborts the youngest transaction in it instead.
+    */
+    Wait(Duration),
+    /*
+        Only waits, up to the timeout, for younger transactions and gives up
+        on older ones, so waits never form a cycle.
+    */
+    WaitDie(Duration),
+}
+
+impl Default for LockPolicy {
+    fn default() -> Self {
+        LockPolicy::Wait(Duration::from_secs(1))
+    }
+}
+
+// "no_wait", or "wait:1000" and "wait_die:1000" with the timeout in milliseconds
+impl FromStr for LockPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let (kind, value) = s.split_once(':').unwrap_or((&s, ""));
+
+        let timeout = || {
+            value
+                .trim()
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .map_err(|_| format!("Lock policy \"{s}\" needs a timeout"))
+        };
+
+        match kind.trim() {
+            "no_wait" | "nowait" => Ok(LockPolicy::NoWait),
+            "wait" => Ok(LockPolicy::Wait(timeout()?)),
+            "wait_die" => Ok(LockPolicy::WaitDie(timeout()?)),
+            _ => Err(format!("Unknown lock policy \"{s}\"")),
+        }
+    }
+}
+
+/*
+    The transaction run asking for a lock. `owner` tells runs apart, `age`
+    stays the same when a transaction is retried so that it eventually
+    becomes the oldest and stops losing conflicts.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Locker {
+    pub owner: u64,
+    pub age: u64,
+    pub policy: LockPolicy,
+}
+
 pub struct LockHandle {
-    pub rid: RID,
+    pub target: LockTarget,
     pub lock_type: LockType,
+    pub owner: u64,
 }
 
 impl LockHandle {
-    fn new(rid: RID, lock_type: LockType) -> Self {
-        LockHandle { rid, lock_type }
+    fn new(target: LockTarget, lock_type: LockType, owner: u64) -> Self {
+        LockHandle {
+            target,
+            lock_type,
+            owner,
+        }
     }
 }
 
+#[derive(Clone, Copy)]
+struct Holder {
+    owner: u64,
+    age: u64,
+    lock_type: LockType,
+}
+
+#[derive(Default)]
+struct LockState {
+    holders: Vec<Holder>,
+}
+
+impl LockState {
+    // Holders other than `owner` that keep it from locking this way
+    fn blockers(&self, lock_type: LockType, owner: u64) -> Vec<Holder> {
+        self.holders
+            .iter()
+            .filter(|x| x.owner != owner && !x.lock_type.compatible(lock_type))
+            .copied()
+            .collect()
+    }
+
+    fn held(&self, lock_type: LockType) -> bool {
+        self.holders.iter().any(|x| x.lock_type == lock_type)
+    }
+}
+
+struct Waiter {
+    age: u64,
+    waits_for: Vec<u64>,
+}
+
+struct WaitsFor {
+    waiters: BTreeMap<u64, Waiter>,
+    // Waiters aborted to break a deadlock that haven't noticed yet
+    victims: BTreeSet<u64>,
+}
+
+/*
+    Transactions waiting for locks and the ones they wait for, shared by every
+    table since one transaction can lock records of several. Always taken
+    after a lock table, never before.
+*/
+static WAITS_FOR: Mutex<WaitsFor> = const_mutex(WaitsFor {
+    waiters: BTreeMap::new(),
+    victims: BTreeSet::new(),
+});
+
+// How often a waiter checks whether it was picked as a deadlock victim
+const VICTIM_POLL: Duration = Duration::from_millis(5);
+
+enum Wait {
+    Waiting,
+    Victim,
+}
+
+impl WaitsFor {
+    // Records that `locker` waits for `blockers`, unless that closes a cycle it's the victim of
+    fn wait(&mut self, locker: &Locker, blockers: Vec<u64>) -> Wait {
+        if self.victims.remove(&locker.owner) {
+            self.waiters.remove(&locker.owner);
+            return Wait::Victim;
+        }
+
+        self.waiters.insert(
+            locker.owner,
+            Waiter {
+                age: locker.age,
+                waits_for: blockers,
+            },
+        );
+
+        let cycle = match self.cycle(locker.owner) {
+            Some(x) => x,
+            None => return Wait::Waiting,
+        };
+
+        // The youngest has the least work to redo
+        let victim = cycle
+            .into_iter()
+            .max_by_key(|x| (self.waiters[x].age, *x))
+            .unwrap();
+
+        if victim == locker.owner {
+            self.waiters.remove(&locker.owner);
+            return Wait::Victim;
+        }
+
+        // It stops waiting on its own once it notices
+        self.waiters.remove(&victim);
+        self.victims.insert(victim);
+        Wait::Waiting
+    }
+
+    fn stop_waiting(&mut self, owner: u64) {
+        self.waiters.remove(&owner);
+        self.victims.remove(&owner);
+    }
+
+    // Waiters on a cycle through `start`, if there is one
+    fn cycle(&self, start: u64) -> Option<Vec<u64>> {
+        let mut path = vec![start];
+        let mut next = vec![self.waiters[&start].waits_for.clone()];
+        let mut seen = BTreeSet::new();
+
+        while let Some(edges) = next.last_mut() {
+            let to = match edges.pop() {
+                Some(x) => x,
+                None => {
+                    next.pop();
+                    path.pop();
+                    continue;
+                }
+            };
+
+            if to == start {
+                return Some(path);
+            }
+
+            if let Some(waiter) = self.waiters.get(&to) {
+                if seen.insert(to) {
+                    path.push(to);
+                    next.push(waiter.waits_for.clone());
+                }
+            }
+        }
+
+        None
+    }
+}
+
+// Records go to shards round robin, so the runs of them queries lock spread over all of them
+const LOCK_SHARDS: usize = 64;
+
+// Locked records of one shard, waiters sleep on the shard of the record they want
+struct Shard {
+    locks: Mutex<FxHashMap<LockTarget, LockState>>,
+    released: Condvar,
+}
+
+/*
+    Locks on a table, its ranges and its records. Only what some transaction
+    holds has an entry, it's dropped with the last lock on it.
+*/
 pub struct LockManager {
-    locks: Mutex<FxHashMap<RID, RwLock<()>>>,
+    shards: Box<[Shard]>,
+    stats: Mutex<LockStats>,
 }
 
 impl Default for LockManager {
@@ -38,63 +325,230 @@ impl Default for LockManager {
 impl LockManager {
     pub fn new() -> Self {
         Self {
-            locks: Mutex::new(FxHashMap::with_capacity_and_hasher(
-                4096,
-                BuildHasherDefault::<FxHasher>::default(),
-            )),
+            shards: (0..LOCK_SHARDS)
+                .map(|_| Shard {
+                    locks: Mutex::new(FxHashMap::with_capacity_and_hasher(
+                        64,
+                        BuildHasherDefault::<FxHasher>::default(),
+                    )),
+                    released: Condvar::new(),
+                })
+                .collect(),
+            stats: Default::default(),
         }
     }
 
-    pub fn upgrade_shared(&self, handle: &mut LockHandle) -> bool {
-        let guard = self.locks.lock();
-        let lock = guard.get(&handle.rid).unwrap();
-        let locked = unsafe {
-            lock.raw().unlock_shared();
-            lock.raw().try_lock_exclusive()
+    fn shard(&self, target: LockTarget) -> &Shard {
+        let shard = match target {
+            LockTarget::Table => 0,
+            LockTarget::Range(range) => range,
+            LockTarget::Column(column) => column,
+            LockTarget::Keys(column, block) => column.wrapping_add(block as usize),
+            LockTarget::Key(column, key) => column.wrapping_add(key as usize),
+            LockTarget::Record(rid) => rid.0 as usize,
         };
 
-        if locked {
-            handle.lock_type = LockType::Exclusive;
+        &self.shards[shard % LOCK_SHARDS]
+    }
+
+    // Takes `target` for `locker`, waiting for it as its policy allows. None if it gave up.
+    pub fn lock(
+        &self,
+        target: LockTarget,
+        lock_type: LockType,
+        locker: &Locker,
+    ) -> Option<LockHandle> {
+        self.acquire(target, lock_type, locker)
+            .then(|| LockHandle::new(target, lock_type, locker.owner))
+    }
+
+    pub fn try_lock(
+        &self,
+        target: LockTarget,
+        lock_type: LockType,
+        owner: u64,
+    ) -> Option<LockHandle> {
+        let locker = Locker {
+            owner,
+            age: owner,
+            policy: LockPolicy::NoWait,
+        };
+
+        self.lock(target, lock_type, &locker)
+    }
+
+    // Strengthens a held lock to cover `lock_type` too, keeping it as it was if that fails
+    pub fn upgrade(&self, handle: &mut LockHandle, lock_type: LockType, locker: &Locker) -> bool {
+        let lock_type = handle.lock_type.join(lock_type);
+        let upgraded = self.acquire(handle.target, lock_type, locker);
+
+        if upgraded {
+            handle.lock_type = lock_type;
         }
 
-        locked
+        upgraded
     }
 
-    pub fn try_lock(&self, rid: RID, lock_type: LockType) -> Option<LockHandle> {
-        let mut guard = self.locks.lock();
-        let lock = guard.entry(rid).or_insert(RwLock::new(()));
+    /*
+        Replaces a transaction's intention lock on a range with a shared or
+        exclusive one, so it doesn't need locks on the records in it anymore.
+        Never waits, the transaction keeps its record locks if it can't
+        escalate right away.
+    */
+    pub fn escalate(&self, handle: &mut LockHandle, lock_type: LockType, locker: &Locker) -> bool {
+        let locker = Locker {
+            policy: LockPolicy::NoWait,
+            ..*locker
+        };
+        let escalated = self.upgrade(handle, lock_type, &locker);
 
-        unsafe {
-            match lock_type {
-                LockType::Shared => {
-                    if !lock.raw().try_lock_shared() {
-                        None
-                    } else {
-                        Some(LockHandle::new(rid, lock_type))
-                    }
+        if escalated {
+            self.stats.lock().escalations += 1;
+        }
+
+        escalated
+    }
+
+    fn acquire(&self, target: LockTarget, lock_type: LockType, locker: &Locker) -> bool {
+        let timeout = match locker.policy {
+            LockPolicy::NoWait => Duration::ZERO,
+            LockPolicy::Wait(x) | LockPolicy::WaitDie(x) => x,
+        };
+        let deadline = Instant::now() + timeout;
+        let shard = self.shard(target);
+        let mut locks = shard.locks.lock();
+        let mut waited = false;
+        let mut in_graph = false;
+
+        let granted = loop {
+            let state = locks.entry(target).or_default();
+            let blockers = state.blockers(lock_type, locker.owner);
+
+            if blockers.is_empty() {
+                // Upgrades already hold it
+                match state.holders.iter_mut().find(|x| x.owner == locker.owner) {
+                    Some(holder) => holder.lock_type = lock_type,
+                    None => state.holders.push(Holder {
+                        owner: locker.owner,
+                        age: locker.age,
+                        lock_type,
+                    }),
                 }
-                LockType::Exclusive => {
-                    if !lock.raw().try_lock_exclusive() {
-                        None
-                    } else {
-                        Some(LockHandle::new(rid, lock_type))
+
+                break true;
+            }
+
+            let wait = match locker.policy {
+                LockPolicy::NoWait => false,
+                LockPolicy::WaitDie(_) => blockers.into_iter().all(|x| x.age > locker.age),
+                LockPolicy::Wait(_) => {
+                    let blockers = blockers.into_iter().map(|x| x.owner).collect();
+                    in_graph = true;
+
+                    match WAITS_FOR.lock().wait(locker, blockers) {
+                        Wait::Waiting => true,
+                        Wait::Victim => {
+                            self.stats.lock().deadlocks += 1;
+                            break false;
+                        }
                     }
                 }
+            };
+
+            if !wait {
+                if locker.policy != LockPolicy::NoWait {
+                    self.stats.lock().deadlocks += 1;
+                }
+                break false;
+            }
+
+            let now = Instant::now();
+
+            if now >= deadline {
+                self.stats.lock().timeouts += 1;
+                break false;
+            }
+
+            if !waited {
+                waited = true;
+                self.stats.lock().waits += 1;
+            }
+
+            shard
+                .released
+                .wait_until(&mut locks, deadline.min(now + VICTIM_POLL));
+        };
+
+        if in_graph {
+            WAITS_FOR.lock().stop_waiting(locker.owner);
+        }
+
+        granted
+    }
+
+    /*
+        Whether a transaction holds any of `rids` exclusively, or their range
+        or table, so it could still roll them back
+    */
+    pub fn any_exclusive(&self, rids: impl IntoIterator<Item = RID>) -> bool {
+        let exclusive = |target| {
+            self.shard(target)
+                .locks
+                .lock()
+                .get(&target)
+                .map_or(false, |lock| lock.held(LockType::Exclusive))
+        };
+        let mut ranges = Vec::new();
+
+        if exclusive(LockTarget::Table) {
+            return true;
+        }
+
+        for rid in rids {
+            if exclusive(LockTarget::Record(rid)) {
+                return true;
+            }
+
+            if !ranges.contains(&rid.page_range()) {
+                ranges.push(rid.page_range());
             }
         }
+
+        ranges.into_iter().any(|x| exclusive(LockTarget::Range(x)))
     }
 
     pub fn unlock(&self, lock_handle: &LockHandle) {
-        let guard = self.locks.lock();
-        let lock = guard
-            .get(&lock_handle.rid)
+        let shard = self.shard(lock_handle.target);
+        let mut locks = shard.locks.lock();
+        let lock = locks
+            .get_mut(&lock_handle.target)
             .expect("Invalid unlock requested from Lock Manager");
 
-        unsafe {
-            match lock_handle.lock_type {
-                LockType::Shared => lock.raw().unlock_shared_fair(),
-                LockType::Exclusive => lock.raw().unlock_exclusive_fair(),
-            }
+        lock.holders.retain(|x| x.owner != lock_handle.owner);
+
+        if lock.holders.is_empty() {
+            locks.remove(&lock_handle.target);
+        }
+
+        shard.released.notify_all();
+    }
+
+    pub fn stats(&self) -> LockStats {
+        let locked_records = self
+            .shards
+            .iter()
+            .map(|x| {
+                x.locks
+                    .lock()
+                    .keys()
+                    .filter(|x| matches!(x, LockTarget::Record(_)))
+                    .count()
+            })
+            .sum();
+
+        LockStats {
+            locked_records,
+            ..*self.stats.lock()
         }
     }
 }
@@ -1,207 +1,735 @@
 use std::{
+    any::Any,
+    collections::hash_map::Entry,
     hash::BuildHasherDefault,
+    panic::{self, AssertUnwindSafe},
+    str::FromStr,
     sync::{
-        atomic::Ordering,
+        atomic::{AtomicBool, Ordering},
         mpsc::{channel, Sender},
         Arc,
     },
-    thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime},
 };
 
 use parking_lot::{Mutex, RwLock};
-use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
+use rustc_hash::{FxHashMap, FxHasher};
 
 use crate::{
-    bufferpool::BufferPool, disk_manager::DiskManager, page::Page, page_directory::PageDirectory,
-    range_directory::RangeDirectory, rid::RID, table::Table, METADATA_BASE_RID,
-    METADATA_INDIRECTION, METADATA_RID, NUM_METADATA_COLUMNS, NUM_STATIC_COLUMNS, PAGE_RANGE_COUNT,
-    PAGE_SLOTS, RID_INVALID,
+    bufferpool::{BufferPool, BufferPoolError, LockedBufferPool},
+    disk_manager::DiskManager,
+    epoch::{Epochs, RetiredPages},
+    filling::FillingPages,
+    lock_manager::LockManager,
+    merge_scheduler::MergeScheduler,
+    mvcc::Versions,
+    page::{Page, PhysicalPage},
+    page_directory::PageDirectory,
+    range_directory::RangeDirectory,
+    rid::RID,
+    stats::MergeStats,
+    table::Table,
+    METADATA_BASE_RID, METADATA_INDIRECTION, METADATA_PAGE_HEADER, METADATA_RID,
+    METADATA_SCHEMA_ENCODING, NUM_METADATA_COLUMNS, NUM_STATIC_COLUMNS, PAGE_RANGE_COUNT,
+    PAGE_SIZE, PAGE_SLOTS, RID_INVALID,
 };
 
+// When the merge scheduler merges a range on its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergePolicy {
+    // Once this many of the range's tail pages have filled up
+    TailPages(usize),
+    // Every range with a full tail page, this often
+    Interval(Duration),
+    // Like Interval, but only if no query ran in the last period
+    Idle(Duration),
+    // Only when asked to by force_merge or merge_all
+    Manual,
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy::TailPages(4)
+    }
+}
+
+// "tail_pages:4", "interval:500" and "idle:500" in milliseconds, or "manual"
+impl FromStr for MergePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_ascii_lowercase();
+        let (kind, value) = s.split_once(':').unwrap_or((&s, ""));
+
+        let number = || {
+            value
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| format!("Merge policy \"{s}\" needs a number"))
+        };
+
+        match kind.trim() {
+            "tail_pages" | "pages" => Ok(MergePolicy::TailPages(number()?.max(1) as usize)),
+            "interval" => Ok(MergePolicy::Interval(Duration::from_millis(number()?))),
+            "idle" => Ok(MergePolicy::Idle(Duration::from_millis(number()?))),
+            "manual" => Ok(MergePolicy::Manual),
+            _ => Err(format!("Unknown merge policy \"{s}\"")),
+        }
+    }
+}
+
+pub enum MergeRequest {
+    // Another tail page of the range filled up
+    TailPageFull(usize),
+    // Merge these ranges now, replies with the records consolidated
+    Force(Vec<usize>, Sender<usize>),
+    // The range had this many unmerged tail pages when the table was attached
+    Resume(usize, usize),
+}
+
+// A base page being merged, its new page directory entry and copies of the rewritten columns
+struct MergedPage {
+    columns: Arc<[usize]>,
+    pages: Vec<PhysicalPage>,
+    // Deleted records are left alone
+    rids: PhysicalPage,
+}
+
+// Pages a merge allocated for the base columns it rewrites
+fn allocated_pages(merged: &FxHashMap<usize, MergedPage>) -> Vec<usize> {
+    merged
+        .values()
+        .flat_map(|x| x.columns[NUM_STATIC_COLUMNS..].iter().copied())
+        .collect()
+}
+
+// Tail RID in `slot` of tail page `page`, slot 0 is the oldest
+fn tail_rid(page: usize, slot: usize) -> u64 {
+    (page * PAGE_SLOTS + (PAGE_SLOTS - 2) - slot) as u64
+}
+
+// Everything a merge touches, shared with the table
+pub(crate) struct Merger {
+    page_dir: Arc<RwLock<PageDirectory>>,
+    range_dir: Arc<Mutex<RangeDirectory>>,
+    disk: Arc<DiskManager>,
+    main_bufferpool: Arc<Mutex<BufferPool>>,
+    num_columns: usize,
+    stats: Arc<Mutex<MergeStats>>,
+    epochs: Arc<Epochs>,
+    lock_manager: Arc<LockManager>,
+    versions: Arc<Versions>,
+    abandon: Arc<AtomicBool>,
+    filling: Arc<FillingPages>,
+}
+
 impl Table {
-    pub fn spawn_merge_thread(
-        page_directory: &Arc<RwLock<PageDirectory>>,
-        range_directory: &Arc<Mutex<RangeDirectory>>,
-        disk_manager: &Arc<DiskManager>,
-        main_bufferpool: &Arc<Mutex<BufferPool>>,
-        num_columns: usize,
-    ) -> (JoinHandle<()>, Sender<usize>) {
-        let page_dir_clone = Arc::clone(page_directory);
-        let disk_manager_clone = Arc::clone(disk_manager);
-        let range_dir_clone = Arc::clone(range_directory);
-        let main_bp_clone = Arc::clone(main_bufferpool);
+    // Merges of this table run on `scheduler` from now on, under the table's merge policy
+    pub fn attach_merge_scheduler(&self, scheduler: &Arc<MergeScheduler>) {
+        // Read-only tables never accumulate tail pages to merge
+        if self.is_read_only() {
+            return;
+        }
+
+        let table = scheduler.register(
+            Merger::new(self),
+            self.merge_policy,
+            Arc::clone(&self.queries),
+        );
+
+        if let Some((old, old_table)) = self
+            .merge_scheduler
+            .lock()
+            .replace((Arc::clone(scheduler), table))
+        {
+            old.unregister(old_table);
+        }
+
+        // Merges that were pending when the table was last persisted
+        let ranges = self.range_dir.lock().next_range_id();
+
+        for range in 0..ranges {
+            let pages = self.unmerged_tail_pages(range);
+
+            if pages > 0 {
+                scheduler.request(table, MergeRequest::Resume(range, pages));
+            }
+        }
+    }
+
+    // Waits for merges of this table that are already running, queued ones are dropped
+    pub fn detach_merge_scheduler(&self) {
+        if let Some((scheduler, table)) = self.merge_scheduler.lock().take() {
+            scheduler.unregister(table);
+        }
+    }
+
+    // Like detach_merge_scheduler, but running merges give up instead of publishing
+    pub fn abandon_merges(&self) {
+        self.abandon_merges.store(true, Ordering::SeqCst);
+        self.detach_merge_scheduler();
+        self.abandon_merges.store(false, Ordering::SeqCst);
+    }
+
+    // Full tail pages of `range` that no merge has consolidated yet
+    pub fn unmerged_tail_pages(&self, range: usize) -> usize {
+        // Merges only cut the chain with the range directory locked
+        let range_dir = self.range_dir.lock();
+
+        if range >= range_dir.next_range_id() {
+            return 0;
+        }
+
+        let range = range_dir.get(range);
+        let merged_until = range.merged_until.load(Ordering::SeqCst);
+        // A page that can't be faulted in right now ends the count early
+        let previous = |page_id| {
+            self.get_page_by_id(page_id)
+                .read_last_tail(&mut self.bufferpool.lock())
+                .map_or(RID_INVALID as usize, |x| x as usize)
+        };
+
+        let mut tail_page_id = previous(range.current_tail_page.load(Ordering::SeqCst));
+        let mut pages = 0;
+
+        while tail_page_id != merged_until && tail_page_id != RID_INVALID as usize {
+            pages += 1;
+            tail_page_id = previous(tail_page_id);
+        }
+
+        pages
+    }
+
+    fn send_merge_request(&self, request: MergeRequest) -> bool {
+        match self.merge_scheduler.lock().as_ref() {
+            Some((scheduler, table)) => scheduler.request(*table, request),
+            None => false,
+        }
+    }
+
+    pub(crate) fn tail_page_full(&self, range: usize) {
+        self.send_merge_request(MergeRequest::TailPageFull(range));
+    }
+
+    /*
+        Merges every full tail page of `range` into its base pages and waits
+        for it to finish, whatever the merge policy. Returns how many records
+        were consolidated, 0 for tables without a merge scheduler.
+    */
+    pub fn force_merge(&self, range: usize) -> usize {
+        self.force_merge_ranges(vec![range])
+    }
+
+    pub fn merge_all(&self) -> usize {
+        let ranges = self.range_dir.lock().next_range_id();
+        self.force_merge_ranges((0..ranges).collect())
+    }
+
+    fn force_merge_ranges(&self, ranges: Vec<usize>) -> usize {
         let (send, recv) = channel();
-        let handle = thread::spawn(move || {
-            let num_columns = num_columns;
-            let main_bufferpool = main_bp_clone;
-            let page_dir = page_dir_clone;
-            let range_dir = range_dir_clone;
-            let disk = disk_manager_clone;
-            let recv = recv;
-            let mut seen: FxHashSet<u64> = FxHashSet::with_capacity_and_hasher(
-                PAGE_SLOTS * PAGE_RANGE_COUNT,
-                BuildHasherDefault::<FxHasher>::default(),
-            );
-            let mut merged: FxHashMap<usize, Arc<[usize]>> = FxHashMap::with_capacity_and_hasher(
-                PAGE_SLOTS * PAGE_RANGE_COUNT,
-                BuildHasherDefault::<FxHasher>::default(),
-            );
-            let mut rangecounts: FxHashMap<usize, usize> = FxHashMap::with_capacity_and_hasher(
-                PAGE_SLOTS * PAGE_RANGE_COUNT,
-                BuildHasherDefault::<FxHasher>::default(),
-            );
-
-            loop {
-                let merge_range = loop {
-                    let range_update = recv.recv();
-
-                    if range_update.is_err() {
-                        return;
-                    }
-
-                    let range_update: usize = range_update.unwrap();
-
-                    *rangecounts.entry(range_update).or_default() += 1;
-
-                    if *rangecounts.get(&range_update).unwrap() >= 4 {
-                        *rangecounts.get_mut(&range_update).unwrap() = 0;
-                        break range_update;
-                    }
-                };
 
-                //println!("Merge request received for range {merge_range}");
+        if !self.send_merge_request(MergeRequest::Force(ranges, send)) {
+            return 0;
+        }
+
+        recv.recv().unwrap_or(0)
+    }
+
+    // Reclaims pages retired by merges that no running query can still read
+    pub fn reclaim_pages(&self) -> usize {
+        Merger::new(self).reclaim()
+    }
 
-                let range_dir = range_dir.lock();
-                let range = range_dir.get(merge_range);
-                let merge_from = range.current_tail_page.load(Ordering::SeqCst);
+    pub fn merge_stats(&self) -> MergeStats {
+        self.merge_stats.lock().clone()
+    }
+}
 
-                let last_page = Page::new(
-                    page_dir
-                        .read()
-                        .get_page(merge_from)
-                        .expect("Bad page ID for Page Range encountered in merge"),
-                )
-                .read_last_tail(&mut main_bufferpool.lock())
-                    as usize;
+impl Merger {
+    fn new(table: &Table) -> Self {
+        Merger {
+            page_dir: Arc::clone(&table.page_dir),
+            range_dir: Arc::clone(&table.range_dir),
+            disk: Arc::clone(&table.disk),
+            main_bufferpool: Arc::clone(&table.bufferpool),
+            num_columns: table.num_columns,
+            stats: Arc::clone(&table.merge_stats),
+            epochs: Arc::clone(&table.epochs),
+            lock_manager: Arc::clone(&table.lock_manager),
+            versions: Arc::clone(&table.versions),
+            abandon: Arc::clone(&table.abandon_merges),
+            filling: Arc::clone(&table.filling),
+        }
+    }
 
-                let merge_stop_at = range.merged_until.load(Ordering::SeqCst);
+    // Copies pages out of the main buffer pool, locking it once for all of them
+    fn copy_pages(&self, page_ids: &[usize]) -> Result<Vec<PhysicalPage>, BufferPoolError> {
+        let mut bp = self.main_bufferpool.lock();
+
+        page_ids
+            .iter()
+            .map(|x| {
+                let guard = bp.get_page(*x)?;
+                let page = guard
+                    .raw()
+                    .read()
+                    .expect("Failed to acquire merge page lock");
+
+                Ok(PhysicalPage { page: page.page })
+            })
+            .collect()
+    }
 
-                range.merged_until.store(last_page, Ordering::SeqCst);
+    /*
+        New page directory entry for a base page and copies of the columns a
+        merge rewrites. The static columns are shared with the old entry, so
+        updates and deletes that land mid-merge still show up in the new one.
+    */
+    fn copy_base_page(&self, base_page_id: usize) -> Result<MergedPage, BufferPoolError> {
+        let total_columns = NUM_METADATA_COLUMNS + self.num_columns;
+
+        let base_cols = self
+            .page_dir
+            .read()
+            .get_page(base_page_id)
+            .expect("Merge tried to access a non-existent page id");
+
+        let new_column_ids = self.disk.allocate_pages(total_columns - NUM_STATIC_COLUMNS);
+
+        let columns = (0..total_columns)
+            .map(|i| match i {
+                METADATA_INDIRECTION | METADATA_RID | METADATA_BASE_RID => base_cols[i],
+                _ => new_column_ids[i - NUM_STATIC_COLUMNS],
+            })
+            .collect::<Arc<[usize]>>();
+
+        let mut pages = match self.copy_pages(&base_cols[METADATA_RID..]) {
+            Ok(pages) => pages,
+            Err(e) => {
+                self.disk.release_pages(&new_column_ids);
+                return Err(e);
+            }
+        };
+        let rids = pages.remove(0);
+        pages.remove(0);
+
+        Ok(MergedPage {
+            columns,
+            pages,
+            rids,
+        })
+    }
 
-                drop(range_dir);
+    fn abandoned(&self, merged: &FxHashMap<usize, MergedPage>) -> bool {
+        if !self.abandon.load(Ordering::SeqCst) {
+            return false;
+        }
 
-                let mut tail_page_id = last_page;
+        self.give_up(merged);
+        true
+    }
 
-                while tail_page_id > merge_stop_at && tail_page_id != RID_INVALID as usize {
-                    let tail_page = Page::new(
-                        page_dir
-                            .read()
-                            .get_page(tail_page_id)
-                            .expect("Bad page ID for Page Range encountered in merge"),
-                    );
+    /*
+        Nothing a merge does is visible before it publishes, so giving up
+        only means handing back the pages it allocated. They're still blank,
+        the new columns are only written right before publishing.
+    */
+    fn give_up(&self, merged: &FxHashMap<usize, MergedPage>) {
+        self.disk.release_pages(&allocated_pages(merged));
+        self.stats.lock().merges_abandoned += 1;
+    }
 
-                    for tail_slot in (0..PAGE_SLOTS).rev() {
-                        let base_rid = tail_page
-                            .get_column(&mut main_bufferpool.lock(), METADATA_BASE_RID)
-                            .slot(tail_slot);
+    // Hands back the pages of a merge that stopped anywhere before publishing
+    fn release_written(&self, merged: &FxHashMap<usize, MergedPage>) {
+        let pages = allocated_pages(merged);
 
-                        assert!(base_rid != RID_INVALID);
+        // Reused pages have to read as new ones
+        let empty = [0; PAGE_SIZE];
+        let writes = pages.iter().map(|x| (*x, &empty)).collect::<Vec<_>>();
 
-                        if seen.contains(&base_rid) {
-                            continue;
-                        }
+        self.disk.write_pages(&writes);
+        self.disk.release_pages(&pages);
+    }
 
-                        seen.insert(base_rid);
+    // Whether a base page got an insert since it might have been copied
+    fn inserted_since(&self, reserved_from: u64, merged: &FxHashMap<usize, MergedPage>) -> bool {
+        self.filling
+            .any_reserved_since(reserved_from, merged.keys().copied())
+    }
 
-                        let base_page_id = RID(base_rid).page();
+    /*
+        Tail pages are given newest first, returns how many of the newest have
+        to wait for a later merge. A page is settled once every record in it
+        was either rolled back, or written in full with the base record's
+        indirection moved on to it, no transaction is left that could still
+        roll it back and every snapshot sees it. No insert can be writing to
+        its base pages either.
+    */
+    fn unsettled_pages(&self, tails: &[(usize, Arc<[usize]>)]) -> Result<usize, BufferPoolError> {
+        let mut indirections: FxHashMap<usize, PhysicalPage> = FxHashMap::default();
+        let mut unsettled = 0;
+
+        for (i, (tail_page_id, tail_cols)) in tails.iter().enumerate() {
+            let tail = self.copy_pages(&[tail_cols[METADATA_BASE_RID], tail_cols[METADATA_RID]])?;
+            let mut records = Vec::with_capacity(PAGE_SLOTS);
+            let mut invalid = Vec::new();
+            let mut settled = true;
+
+            for slot in 0..PAGE_SLOTS {
+                let tid = tail_rid(*tail_page_id, slot);
+
+                match tail[1].slot(slot) {
+                    RID_INVALID => invalid.push((tail[0].slot(slot), tid)),
+                    x if x == tid => records.push((tail[0].slot(slot), tid)),
+                    // Handed out but not written yet
+                    _ => settled = false,
+                }
+            }
 
-                        let merged_page = Page::new(Arc::clone(
-                            merged.entry(base_page_id).or_insert_with(|| {
-                                let mut new_page_dir_entry =
-                                    Arc::new_uninit_slice(NUM_METADATA_COLUMNS + num_columns);
+            for (base_rid, _) in records.iter() {
+                let base_page_id = RID(*base_rid).page();
 
-                                let page_dir = page_dir.read();
+                if indirections.contains_key(&base_page_id) {
+                    continue;
+                }
 
-                                let base_cols = page_dir
-                                    .get_page(base_page_id)
-                                    .expect("Merge thread tried to access a non-existent page id");
+                let columns = self
+                    .page_dir
+                    .read()
+                    .get_page(base_page_id)
+                    .expect("Merge tried to access a non-existent page id");
 
-                                drop(page_dir);
+                let indirection = self.copy_pages(&[columns[METADATA_INDIRECTION]])?.remove(0);
+                indirections.insert(base_page_id, indirection);
+            }
 
-                                let new_page = Arc::get_mut(&mut new_page_dir_entry).unwrap();
-                                new_page[METADATA_INDIRECTION]
-                                    .write(base_cols[METADATA_INDIRECTION]);
-                                new_page[METADATA_BASE_RID].write(base_cols[METADATA_BASE_RID]);
-                                new_page[METADATA_RID].write(base_cols[METADATA_RID]);
+            // The indirection moves to a tail record once everything else in it is written
+            settled &= records.iter().all(|(base_rid, tid)| {
+                let indirection = indirections[&RID(*base_rid).page()].slot(RID(*base_rid).slot());
+                indirection != RID_INVALID && indirection <= *tid
+            });
 
-                                let mut new_column_ids = disk.reserve_range(
-                                    NUM_METADATA_COLUMNS - NUM_STATIC_COLUMNS + num_columns,
-                                );
+            // An insert landing in a base page after it was copied would be lost
+            settled &= !self
+                .filling
+                .any_writing(records.iter().map(|(base_rid, _)| RID(*base_rid).page()));
 
-                                for i in NUM_STATIC_COLUMNS..(NUM_METADATA_COLUMNS + num_columns) {
-                                    new_page[i].write(new_column_ids);
-                                    new_column_ids += 1;
-                                }
+            // Rolled back and deleted records too, a transaction could still undo the delete
+            let written = records.iter().chain(invalid.iter());
 
-                                let new_page_dir_entry =
-                                    unsafe { new_page_dir_entry.assume_init() };
+            settled &= !self
+                .lock_manager
+                .any_exclusive(written.clone().map(|(base_rid, _)| RID(*base_rid)));
 
-                                let bp = &mut main_bufferpool.lock();
-                                for i in NUM_STATIC_COLUMNS..(NUM_METADATA_COLUMNS + num_columns) {
-                                    let page = bp.get_page(base_cols[i]);
-                                    let page_copy = bp.get_page(new_page_dir_entry[i]);
+            // Running snapshots might still read past this version
+            settled &= written
+                .into_iter()
+                .all(|(base_rid, tid)| self.versions.is_settled(RID(*base_rid), RID(*tid)));
 
-                                    let page = page
-                                        .raw()
-                                        .read()
-                                        .expect("Failed to acquire merge page lock");
-                                    let mut page_copy = page_copy
-                                        .raw()
-                                        .write()
-                                        .expect("Failed to acquire merge page lock");
+            if !settled {
+                unsettled = i + 1;
+            }
+        }
 
-                                    // println!("{:?}", page.page);
+        Ok(unsettled)
+    }
 
-                                    page_copy.page.clone_from_slice(&page.page);
-                                }
+    /*
+        Merges the range's full tail pages that haven't been merged yet and
+        returns the number of base records that were brought up to date.
+
+        Tail and base pages are copied out of the main buffer pool a page at a
+        time and merged in private copies, which go straight to disk under new
+        page ids, so foreground queries only ever wait for the copies. Tail
+        records are applied newest first, each column of a base record taking
+        the newest tail record whose schema encoding updated it. Rolled back
+        tail records and deleted base records are skipped, and tail pages with
+        records that aren't settled yet are left for a later merge along with
+        every newer page.
+
+        The TPS of each merged base page becomes the newest tail RID merged,
+        so a base record whose indirection is at least the TPS is up to date
+        and anything newer is still read from its tail.
+        Once the new base pages are published the merged tail pages are cut
+        off the range's chain and retired along with the replaced base
+        columns, to be reclaimed when no query that might still read them is
+        left.
+    */
+    pub(crate) fn merge(&self, merge_range: usize) -> usize {
+        let mut merged: FxHashMap<usize, MergedPage> = FxHashMap::with_capacity_and_hasher(
+            PAGE_RANGE_COUNT,
+            BuildHasherDefault::<FxHasher>::default(),
+        );
+
+        match panic::catch_unwind(AssertUnwindSafe(|| {
+            self.try_merge(merge_range, &mut merged)
+        })) {
+            Ok(Ok(records)) => records,
+            // Out of frames, the range is merged again once another tail page fills up
+            Ok(Err(_)) => {
+                self.give_up(&merged);
+                0
+            }
+            Err(payload) => {
+                self.release_written(&merged);
+                panic::resume_unwind(payload)
+            }
+        }
+    }
 
-                                new_page_dir_entry
-                            }),
-                        ));
+    // Counted in the merge stats, which keep the message for the caller to look at
+    pub(crate) fn panicked(&self, range: usize, payload: &(dyn Any + Send)) {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(|x| x.as_str()))
+            .unwrap_or("non-string panic payload");
+
+        let mut stats = self.stats.lock();
+        stats.merges_panicked += 1;
+        stats.last_panic = Some(format!("Merge of range {range} panicked: {message}"));
+    }
 
-                        let bp = &mut main_bufferpool.lock();
-                        let tid = tail_page.get_column(bp, METADATA_RID).slot(tail_slot);
+    fn try_merge(
+        &self,
+        merge_range: usize,
+        merged: &mut FxHashMap<usize, MergedPage>,
+    ) -> Result<usize, BufferPoolError> {
+        let started = Instant::now();
+        let page_dir = &self.page_dir;
+        let total_columns = NUM_METADATA_COLUMNS + self.num_columns;
+
+        // Base record -> data columns already taken from a newer tail record
+        let mut seen: FxHashMap<u64, u64> = FxHashMap::with_capacity_and_hasher(
+            PAGE_SLOTS * PAGE_RANGE_COUNT,
+            BuildHasherDefault::<FxHasher>::default(),
+        );
+
+        let range_dir = self.range_dir.lock();
+
+        if merge_range >= range_dir.next_range_id() {
+            return Ok(0);
+        }
+
+        let range = range_dir.get(merge_range);
+        let merge_from = range.current_tail_page.load(Ordering::SeqCst);
+
+        let last_page = Page::new(
+            page_dir
+                .read()
+                .get_page(merge_from)
+                .expect("Bad page ID for Page Range encountered in merge"),
+        )
+        .read_last_tail(&mut self.main_bufferpool.lock())? as usize;
+
+        let merge_stop_at = range.merged_until.load(Ordering::SeqCst);
+
+        drop(range_dir);
+
+        let mut tails = Vec::new();
+        let mut tail_page_id = last_page;
+
+        // Newer tail pages have lower ids, walk back until the pages merged last time
+        while tail_page_id != merge_stop_at && tail_page_id != RID_INVALID as usize {
+            let tail_cols = page_dir
+                .read()
+                .get_page(tail_page_id)
+                .expect("Bad page ID for Page Range encountered in merge");
+
+            let previous = self.copy_pages(&[tail_cols[METADATA_PAGE_HEADER]])?[0].slot(0);
+
+            tails.push((tail_page_id, tail_cols));
+            tail_page_id = previous as usize;
+        }
+
+        // Inserts given an RID after this might land in base pages already copied
+        let reserved_from = self.filling.next_rid();
+        let unsettled = self.unsettled_pages(&tails)?;
+
+        if unsettled == tails.len() {
+            return Ok(0);
+        }
+
+        // The page right after the newest one merged is where the chain gets cut
+        let newer_tail = match unsettled {
+            0 => merge_from,
+            x => tails[x - 1].0,
+        };
+        let tails = &tails[unsettled..];
+        let tps = tail_rid(tails[0].0, PAGE_SLOTS - 1);
+        let mut retired = RetiredPages::default();
+
+        for (tail_page_id, tail_cols) in tails.iter() {
+            if self.abandoned(merged) {
+                return Ok(0);
+            }
 
-                        if merged_page.read_page_tps(bp) > tid && tid != 0 {
-                            merged_page.write_page_tps(bp, tid);
-                        }
+            let tail = self.copy_pages(tail_cols)?;
 
-                        for i in (NUM_STATIC_COLUMNS + 1)..(NUM_METADATA_COLUMNS + num_columns) {
-                            let updated_value = tail_page.get_column(bp, i).slot(tail_slot);
-                            merged_page
-                                .get_column(bp, i)
-                                .write_slot(RID(base_rid).slot(), updated_value);
-                        }
-                    }
+            retired.tail_pages.push(*tail_page_id);
+            retired.pages.extend(tail_cols.iter());
 
-                    tail_page_id = tail_page.read_last_tail(&mut main_bufferpool.lock()) as usize;
+            for tail_slot in (0..PAGE_SLOTS).rev() {
+                // Rolled back, or the record was deleted
+                if tail[METADATA_RID].slot(tail_slot) == RID_INVALID {
+                    continue;
                 }
 
-                //main_bufferpool.lock().flush_all();
+                let base_rid = tail[METADATA_BASE_RID].slot(tail_slot);
+                let base_page_id = RID(base_rid).page();
+                let base_slot = RID(base_rid).slot();
+
+                let page = match merged.entry(base_page_id) {
+                    Entry::Occupied(x) => x.into_mut(),
+                    Entry::Vacant(x) => x.insert(self.copy_base_page(base_page_id)?),
+                };
 
-                let mut page_dir = page_dir.write();
+                page.pages[METADATA_PAGE_HEADER - NUM_STATIC_COLUMNS].write_slot(0, tps);
 
-                for pair in &merged {
-                    page_dir.replace_page(*pair.0, pair.1);
+                if page.rids.slot(base_slot) == RID_INVALID {
+                    continue;
                 }
 
-                drop(page_dir);
+                let done = seen.entry(base_rid).or_default();
+                let encoding = tail[METADATA_SCHEMA_ENCODING].slot(tail_slot);
+                let columns = encoding & !*done;
+
+                *done |= encoding;
+
+                let base_encoding = &mut page.pages[METADATA_SCHEMA_ENCODING - NUM_STATIC_COLUMNS];
+                base_encoding.write_slot(base_slot, base_encoding.slot(base_slot) | encoding);
 
-                merged.clear();
-                seen.clear();
+                for i in (0..self.num_columns).filter(|x| columns & (1 << x) != 0) {
+                    page.pages[NUM_METADATA_COLUMNS + i - NUM_STATIC_COLUMNS]
+                        .write_slot(base_slot, tail[NUM_METADATA_COLUMNS + i].slot(tail_slot));
+                }
+            }
+        }
+
+        if self.abandoned(merged) {
+            return Ok(0);
+        }
+
+        if self.inserted_since(reserved_from, merged) {
+            self.give_up(merged);
+            return Ok(0);
+        }
+
+        // Pinned before publishing, faulting it in with the directories locked could wait
+        // on queries that are waiting for them
+        let newer_header = Page::new(
+            page_dir
+                .read()
+                .get_page(newer_tail)
+                .expect("Bad page ID for Page Range encountered in merge"),
+        )
+        .get_column(&mut self.main_bufferpool.lock(), METADATA_PAGE_HEADER)?;
+
+        // Nothing has the new page ids yet, so nothing in the buffer pool can be stale
+        let writes = merged
+            .values()
+            .flat_map(|page| {
+                page.columns[NUM_STATIC_COLUMNS..total_columns]
+                    .iter()
+                    .zip(page.pages.iter())
+                    .map(|(page_id, page)| (*page_id, &page.page))
+            })
+            .collect::<Vec<_>>();
+
+        self.disk.write_pages(&writes);
+
+        // Published together, so a checkpoint never sees one without the other
+        let range_dir = self.range_dir.lock();
+        let mut page_dir = page_dir.write();
+
+        // Inserts read the page directory after getting their RID, so none can slip in now
+        if self.inserted_since(reserved_from, merged) {
+            drop(page_dir);
+            drop(range_dir);
+
+            let empty = [0; PAGE_SIZE];
+            let writes = writes.iter().map(|x| (x.0, &empty)).collect::<Vec<_>>();
+
+            self.disk.write_pages(&writes);
+            self.give_up(merged);
+            return Ok(0);
+        }
+
+        for (base_page_id, page) in merged.iter() {
+            if let Some(old) = page_dir.replace_page(*base_page_id, &page.columns) {
+                retired.pages.extend(old[NUM_STATIC_COLUMNS..].iter());
             }
-        });
+        }
+
+        // The page directory has them now, nothing may hand them back
+        merged.clear();
+
+        range_dir
+            .get(merge_range)
+            .merged_until
+            .store(tails[0].0, Ordering::SeqCst);
+
+        // Nothing walks past the merged pages any more
+        newer_header.write_slot(0, RID_INVALID);
+
+        drop(page_dir);
+        drop(range_dir);
+
+        self.epochs.retire(retired);
+
+        let records = seen.values().filter(|x| **x != 0).count();
+
+        let mut stats = self.stats.lock();
+        stats.ranges_merged += 1;
+        stats.records_consolidated += records as u64;
+        stats.time_spent += started.elapsed();
+        stats.last_merge = Some(SystemTime::now());
+        drop(stats);
+
+        self.reclaim();
+
+        Ok(records)
+    }
+
+    // Hands back the retired pages no pinned query can reach, returns how many there were
+    fn reclaim(&self) -> usize {
+        let mut retired = self.epochs.collect();
+
+        if retired.is_empty() {
+            return 0;
+        }
+
+        let mut page_dir = self.page_dir.write();
+
+        for page in retired.tail_pages.drain(..) {
+            page_dir.remove_page(page);
+        }
+
+        drop(page_dir);
+
+        let pinned = self.main_bufferpool.lock().discard(&retired.pages);
+
+        // Only a write back pins pages nothing references, they go around again
+        if !pinned.is_empty() {
+            retired.pages.retain(|x| !pinned.contains(x));
+            self.epochs.retire(RetiredPages {
+                tail_pages: Vec::new(),
+                pages: pinned,
+            });
+        }
+
+        // Reused pages have to read as new ones
+        let empty = [0; PAGE_SIZE];
+        let writes = retired
+            .pages
+            .iter()
+            .map(|x| (*x, &empty))
+            .collect::<Vec<_>>();
+
+        self.disk.write_pages(&writes);
+        self.disk.release_pages(&retired.pages);
+        self.stats.lock().pages_reclaimed += retired.pages.len() as u64;
 
-        (handle, send)
+        retired.pages.len()
     }
 }
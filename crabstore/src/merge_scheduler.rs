@@ -0,0 +1,472 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{channel, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use rustc_hash::FxHashMap;
+
+use crate::merge::{MergePolicy, MergeRequest, Merger};
+
+enum Message {
+    Register {
+        table: usize,
+        merger: Arc<Merger>,
+        policy: MergePolicy,
+        queries: Arc<AtomicU64>,
+    },
+    Request(usize, MergeRequest),
+    // Replies once none of the table's merges are running
+    Unregister(usize, Sender<()>),
+    Done {
+        table: usize,
+        range: usize,
+        records: usize,
+    },
+    Shutdown,
+}
+
+/*
+    One per database, shared by all of its tables. A coordinator thread
+    applies every table's merge policy and queues the ranges that are due,
+    and a pool of `workers` threads merges them, ranges with the most
+    unmerged tail pages first. Forced merges jump the queue and a range is
+    never merged by two workers at once.
+*/
+pub struct MergeScheduler {
+    handle: Mutex<Option<(JoinHandle<()>, Sender<Message>)>>,
+    next_table: AtomicUsize,
+}
+
+impl MergeScheduler {
+    pub fn new(workers: usize) -> Self {
+        let workers = workers.max(1);
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(workers)
+            .thread_name(|i| format!("crabstore-merge-{i}"))
+            .build()
+            .expect("Failed to start merge workers");
+
+        let (send, recv) = channel();
+        let coordinator = Coordinator {
+            pool,
+            workers,
+            send: send.clone(),
+            tables: FxHashMap::default(),
+            queue: BinaryHeap::new(),
+            queued: FxHashMap::default(),
+            running: FxHashMap::default(),
+            waiters: FxHashMap::default(),
+            next_waiter: 0,
+            next_seq: 0,
+            closing: FxHashMap::default(),
+        };
+
+        let handle = thread::Builder::new()
+            .name("crabstore-merge-scheduler".into())
+            .spawn(move || coordinator.run(recv))
+            .expect("Failed to start merge scheduler");
+
+        MergeScheduler {
+            handle: Mutex::new(Some((handle, send))),
+            next_table: 0.into(),
+        }
+    }
+
+    fn send(&self, message: Message) -> bool {
+        match self.handle.lock().as_ref() {
+            Some((_, sender)) => sender.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    pub(crate) fn register(
+        &self,
+        merger: Merger,
+        policy: MergePolicy,
+        queries: Arc<AtomicU64>,
+    ) -> usize {
+        let table = self.next_table.fetch_add(1, Ordering::Relaxed);
+
+        self.send(Message::Register {
+            table,
+            merger: Arc::new(merger),
+            policy,
+            queries,
+        });
+
+        table
+    }
+
+    pub(crate) fn request(&self, table: usize, request: MergeRequest) -> bool {
+        self.send(Message::Request(table, request))
+    }
+
+    // Drops the table's queued merges and waits for its running ones
+    pub(crate) fn unregister(&self, table: usize) {
+        let (send, recv) = channel();
+
+        if self.send(Message::Unregister(table, send)) {
+            // The coordinator only hangs up without replying when it stops
+            let _ = recv.recv();
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.handle.lock().is_some()
+    }
+
+    // Waits for running merges, queued ones are dropped
+    pub fn shutdown(&self) {
+        if let Some((handle, send)) = self.handle.lock().take() {
+            let _ = send.send(Message::Shutdown);
+            handle.join().expect("Failed to join merge scheduler");
+        }
+    }
+}
+
+impl Drop for MergeScheduler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Max-heap order: priority, then whichever was queued first
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Job {
+    priority: usize,
+    seq: Reverse<u64>,
+    table: usize,
+    range: usize,
+}
+
+struct TableState {
+    merger: Arc<Merger>,
+    policy: MergePolicy,
+    queries: Arc<AtomicU64>,
+    // Range -> tail pages filled since it was last merged
+    pending: FxHashMap<usize, usize>,
+    last_run: Instant,
+    last_queries: u64,
+}
+
+impl TableState {
+    fn period(&self) -> Option<Duration> {
+        match self.policy {
+            MergePolicy::Interval(period) | MergePolicy::Idle(period) => Some(period),
+            MergePolicy::TailPages(_) | MergePolicy::Manual => None,
+        }
+    }
+}
+
+// A force request, replied to once each of its ranges has been merged
+struct Waiter {
+    remaining: usize,
+    records: usize,
+    reply: Sender<usize>,
+}
+
+struct Coordinator {
+    pool: ThreadPool,
+    workers: usize,
+    send: Sender<Message>,
+    tables: FxHashMap<usize, TableState>,
+    /*
+        Can hold stale jobs for ranges that were queued again or already
+        dispatched, `queued` is what's really waiting. Both map (table, range)
+        to the force requests that get credited when the merge finishes.
+    */
+    queue: BinaryHeap<Job>,
+    queued: FxHashMap<(usize, usize), Vec<usize>>,
+    running: FxHashMap<(usize, usize), Vec<usize>>,
+    waiters: FxHashMap<usize, Waiter>,
+    next_waiter: usize,
+    next_seq: u64,
+    // Unregistered tables that still have merges running
+    closing: FxHashMap<usize, Sender<()>>,
+}
+
+impl Coordinator {
+    fn run(mut self, recv: Receiver<Message>) {
+        let mut stopping = false;
+
+        loop {
+            let deadline = if stopping { None } else { self.next_deadline() };
+
+            let message = match deadline {
+                Some(deadline) => {
+                    match recv.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                        Ok(message) => Some(message),
+                        Err(RecvTimeoutError::Timeout) => None,
+                        Err(RecvTimeoutError::Disconnected) => return,
+                    }
+                }
+                None => match recv.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return,
+                },
+            };
+
+            match message {
+                Some(Message::Register {
+                    table,
+                    merger,
+                    policy,
+                    queries,
+                }) if !stopping => {
+                    let last_queries = queries.load(Ordering::Relaxed);
+
+                    self.tables.insert(
+                        table,
+                        TableState {
+                            merger,
+                            policy,
+                            queries,
+                            pending: FxHashMap::default(),
+                            last_run: Instant::now(),
+                            last_queries,
+                        },
+                    );
+                }
+                Some(Message::Request(table, request)) if !stopping => self.request(table, request),
+                Some(Message::Unregister(table, reply)) => {
+                    self.tables.remove(&table);
+                    self.drop_queued(|x| x == table);
+
+                    if self.running.keys().any(|x| x.0 == table) {
+                        self.closing.insert(table, reply);
+                    } else {
+                        let _ = reply.send(());
+                    }
+                }
+                Some(Message::Done {
+                    table,
+                    range,
+                    records,
+                }) => {
+                    let waiting = self.running.remove(&(table, range)).unwrap_or_default();
+                    self.credit(waiting, records);
+
+                    if !self.running.keys().any(|x| x.0 == table) {
+                        if let Some(reply) = self.closing.remove(&table) {
+                            let _ = reply.send(());
+                        }
+                    }
+                }
+                Some(Message::Shutdown) => {
+                    stopping = true;
+                    self.tables.clear();
+                    self.drop_queued(|_| true);
+                }
+                // Dropping a force request's reply makes it return 0
+                Some(_) | None => {}
+            }
+
+            if stopping {
+                if self.running.is_empty() {
+                    return;
+                }
+
+                continue;
+            }
+
+            self.poll_timers();
+            self.dispatch();
+        }
+    }
+
+    fn request(&mut self, table: usize, request: MergeRequest) {
+        let state = match self.tables.get_mut(&table) {
+            Some(state) => state,
+            None => return,
+        };
+
+        let (range, pages) = match request {
+            MergeRequest::TailPageFull(range) => (range, 1),
+            MergeRequest::Resume(range, pages) => (range, pages),
+            MergeRequest::Force(mut ranges, reply) => {
+                ranges.sort_unstable();
+                ranges.dedup();
+
+                if ranges.is_empty() {
+                    let _ = reply.send(0);
+                    return;
+                }
+
+                for range in ranges.iter() {
+                    state.pending.remove(range);
+                }
+
+                let waiter = self.next_waiter;
+                self.next_waiter += 1;
+                self.waiters.insert(
+                    waiter,
+                    Waiter {
+                        remaining: ranges.len(),
+                        records: 0,
+                        reply,
+                    },
+                );
+
+                for range in ranges {
+                    self.enqueue(table, range, usize::MAX, Some(waiter));
+                }
+
+                return;
+            }
+        };
+
+        let count = state.pending.entry(range).or_default();
+        *count += pages;
+
+        if matches!(state.policy, MergePolicy::TailPages(threshold) if *count >= threshold) {
+            let count = state.pending.remove(&range).unwrap_or_default();
+            self.enqueue(table, range, count, None);
+        }
+    }
+
+    fn enqueue(&mut self, table: usize, range: usize, priority: usize, waiter: Option<usize>) {
+        self.queued
+            .entry((table, range))
+            .or_default()
+            .extend(waiter);
+        self.queue.push(Job {
+            priority,
+            seq: Reverse(self.next_seq),
+            table,
+            range,
+        });
+        self.next_seq += 1;
+    }
+
+    fn drop_queued(&mut self, table: impl Fn(usize) -> bool) {
+        let keys = self
+            .queued
+            .keys()
+            .filter(|x| table(x.0))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            let waiting = self.queued.remove(&key).unwrap_or_default();
+            self.credit(waiting, 0);
+        }
+    }
+
+    fn credit(&mut self, waiting: Vec<usize>, records: usize) {
+        for id in waiting {
+            let waiter = match self.waiters.get_mut(&id) {
+                Some(waiter) => waiter,
+                None => continue,
+            };
+
+            waiter.records += records;
+            waiter.remaining -= 1;
+
+            if waiter.remaining == 0 {
+                let waiter = self.waiters.remove(&id).unwrap();
+                // Nobody waiting is fine
+                let _ = waiter.reply.send(waiter.records);
+            }
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.tables
+            .values()
+            .filter_map(|x| x.period().map(|period| x.last_run + period))
+            .min()
+    }
+
+    fn poll_timers(&mut self) {
+        let mut due = Vec::new();
+
+        for (table, state) in self.tables.iter_mut() {
+            let period = match state.period() {
+                Some(period) if state.last_run.elapsed() >= period => period,
+                _ => continue,
+            };
+
+            let current_queries = state.queries.load(Ordering::Relaxed);
+            let idle = current_queries == state.last_queries;
+
+            if matches!(state.policy, MergePolicy::Interval(_)) || idle {
+                due.extend(state.pending.drain().map(|x| (*table, x.0, x.1)));
+            }
+
+            state.last_queries = current_queries;
+            state.last_run += period;
+
+            // Don't try to catch up on periods missed while busy
+            if state.last_run.elapsed() >= period {
+                state.last_run = Instant::now();
+            }
+        }
+
+        for (table, range, count) in due {
+            self.enqueue(table, range, count, None);
+        }
+    }
+
+    fn dispatch(&mut self) {
+        let mut deferred = Vec::new();
+
+        while self.running.len() < self.workers {
+            let job = match self.queue.pop() {
+                Some(job) => job,
+                None => break,
+            };
+
+            let key = (job.table, job.range);
+
+            // It goes again once the running merge is done
+            if self.running.contains_key(&key) {
+                deferred.push(job);
+                continue;
+            }
+
+            let waiting = match self.queued.remove(&key) {
+                Some(waiting) => waiting,
+                None => continue,
+            };
+
+            let merger = match self.tables.get(&job.table) {
+                Some(state) => Arc::clone(&state.merger),
+                None => {
+                    self.credit(waiting, 0);
+                    continue;
+                }
+            };
+
+            self.running.insert(key, waiting);
+
+            let send = self.send.clone();
+
+            self.pool.spawn(move || {
+                // A merge that panics still has to be reported or force requests hang
+                let records = panic::catch_unwind(AssertUnwindSafe(|| merger.merge(job.range)))
+                    .unwrap_or_else(|payload| {
+                        merger.panicked(job.range, &*payload);
+                        0
+                    });
+
+                let _ = send.send(Message::Done {
+                    table: job.table,
+                    range: job.range,
+                    records,
+                });
+            });
+        }
+
+        self.queue.extend(deferred);
+    }
+}
@@ -0,0 +1,360 @@
+use std::sync::Arc;
+
+use rustc_hash::FxHashMap;
+
+use crate::{
+    error::CrabError,
+    page::PhysicalPage,
+    rid::RID,
+    storage::{decode, Storage, TableFile, CATALOG_FILE},
+    storage_backend::StorageBackendKind,
+    table::TableHeaderPage,
+    METADATA_SCHEMA_ENCODING, PAGE_SIZE, PAGE_SLOTS,
+};
+
+/*
+    Version of everything written to disk: blob headers, table header pages
+    and the layout of pages and RIDs. Bump it with any change that older
+    builds would misread, and add a migration from the previous version.
+*/
+pub const FORMAT_VERSION: u32 = 3;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    pub dry_run: bool,
+    // Files that were, or in a dry run would be, rewritten
+    pub actions: Vec<String>,
+}
+
+impl MigrationReport {
+    pub fn is_empty(&self) -> bool {
+        self.from == self.to
+    }
+}
+
+type MigrationStep = fn(&Storage, &[String], &mut MigrationReport, bool) -> Result<(), CrabError>;
+
+// Upgrades a database from `from` to `from + 1`
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: MigrationStep,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "give the baseline's bare metadata archives blob headers",
+        run: blob_headers,
+    },
+    Migration {
+        from: 1,
+        description: "record the format version in blob and table headers",
+        run: version_headers,
+    },
+    Migration {
+        from: 2,
+        description: "mark every column updated in tail record schema encodings",
+        run: tail_schema_encodings,
+    },
+];
+
+// None for a database that has never been closed or checkpointed
+pub fn database_version(storage: &Storage) -> Result<Option<u32>, CrabError> {
+    Ok(storage
+        .blob(CATALOG_FILE)
+        .read()
+        .map_err(|e| CrabError::io(CATALOG_FILE, e))?
+        .map(|x| x.version))
+}
+
+/*
+    Brings the database up to FORMAT_VERSION one migration at a time. The
+    catalog is rewritten last, so a migration that dies part way is simply
+    run again on the next open. A dry run reads everything a migration would
+    touch but writes nothing.
+*/
+pub fn migrate(storage: &Storage, dry_run: bool) -> Result<MigrationReport, CrabError> {
+    let version = database_version(storage)?.unwrap_or(FORMAT_VERSION);
+
+    let mut report = MigrationReport {
+        from: version,
+        to: FORMAT_VERSION,
+        dry_run,
+        actions: Vec::new(),
+    };
+
+    if version > FORMAT_VERSION {
+        return Err(CrabError::Version {
+            file: CATALOG_FILE.into(),
+            found: version,
+        });
+    }
+
+    if version == FORMAT_VERSION {
+        return Ok(report);
+    }
+
+    let catalog = storage
+        .blob(CATALOG_FILE)
+        .read()
+        .map_err(|e| CrabError::io(CATALOG_FILE, e))?
+        .ok_or_else(|| CrabError::Missing(CATALOG_FILE.into()))?;
+
+    let tables = decode::<Vec<String>>(CATALOG_FILE, &catalog.bytes)?;
+
+    for migration in MIGRATIONS.iter().filter(|x| x.from >= version) {
+        report.actions.push(format!(
+            "migrate from version {} to {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        ));
+
+        (migration.run)(storage, &tables, &mut report, dry_run)?;
+    }
+
+    report.actions.push(format!("rewrite {CATALOG_FILE}"));
+
+    if !dry_run {
+        storage
+            .blob(CATALOG_FILE)
+            .write_version(FORMAT_VERSION, catalog.generation, &catalog.bytes)
+            .map_err(|e| CrabError::io(CATALOG_FILE, e))?;
+    }
+
+    Ok(report)
+}
+
+/*
+    The baseline wrote bare archives and a table header without a
+    generation. Its header page reads as generation 0, the zeroes after it
+    standing in for the field, so the blobs get headers from generation 0.
+    Checking every header first keeps a database that isn't the baseline's
+    from being half rewritten.
+*/
+fn blob_headers(
+    storage: &Storage,
+    tables: &[String],
+    report: &mut MigrationReport,
+    dry_run: bool,
+) -> Result<(), CrabError> {
+    for table in tables.iter() {
+        let data_file = TableFile::Data.name(table);
+        let pages = storage
+            .open_pages_read_only(&data_file)
+            .map_err(|e| CrabError::io(&data_file, e))?;
+
+        let mut page = [0; PAGE_SIZE];
+        pages
+            .read_page(0, &mut page)
+            .map_err(|e| CrabError::io(&data_file, e))?;
+
+        let header = TableHeaderPage::from_page(&page, &data_file)?;
+
+        if header.generation != 0 {
+            return Err(CrabError::Inconsistent {
+                file: data_file,
+                expected: 0,
+                found: header.generation,
+            });
+        }
+    }
+
+    for table in tables.iter() {
+        for file in [
+            TableFile::PageDirectory,
+            TableFile::RangeDirectory,
+            TableFile::Index,
+        ] {
+            let blob = storage.blob(&file.name(table));
+
+            let contents = match blob.read().map_err(|e| CrabError::io(blob.name(), e))? {
+                Some(contents) if contents.version == 0 => contents,
+                _ => continue,
+            };
+
+            report
+                .actions
+                .push(format!("add a header to {}", blob.name()));
+
+            if !dry_run {
+                blob.write_version(1, 0, &contents.bytes)
+                    .map_err(|e| CrabError::io(blob.name(), e))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Version 1 blobs had no version in their header and table headers had no version field
+fn version_headers(
+    storage: &Storage,
+    tables: &[String],
+    report: &mut MigrationReport,
+    dry_run: bool,
+) -> Result<(), CrabError> {
+    for table in tables.iter() {
+        for file in [
+            TableFile::PageDirectory,
+            TableFile::RangeDirectory,
+            TableFile::Index,
+        ] {
+            let blob = storage.blob(&file.name(table));
+
+            let contents = match blob.read().map_err(|e| CrabError::io(blob.name(), e))? {
+                Some(contents) if contents.version < 2 => contents,
+                // Missing or already migrated by an earlier attempt
+                _ => continue,
+            };
+
+            report.actions.push(format!("rewrite {}", blob.name()));
+
+            if !dry_run {
+                blob.write_version(2, contents.generation, &contents.bytes)
+                    .map_err(|e| CrabError::io(blob.name(), e))?;
+            }
+        }
+
+        let data_file = TableFile::Data.name(table);
+        let pages = if dry_run {
+            storage.open_pages_read_only(&data_file)
+        } else {
+            storage.open_pages(&data_file, StorageBackendKind::File)
+        }
+        .map_err(|e| CrabError::io(&data_file, e))?;
+
+        let mut page = [0; PAGE_SIZE];
+        pages
+            .read_page(0, &mut page)
+            .map_err(|e| CrabError::io(&data_file, e))?;
+
+        let mut header = TableHeaderPage::from_page(&page, &data_file)?;
+
+        if header.version != 0 {
+            continue;
+        }
+
+        report
+            .actions
+            .push(format!("rewrite header page of {data_file}"));
+
+        if !dry_run {
+            header.version = 2;
+
+            pages
+                .write_page(0, &header.to_page())
+                .and_then(|_| pages.sync())
+                .map_err(|e| CrabError::io(&data_file, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+/*
+    Version 2 updates wrote the tail record's schema encoding into the base
+    record's slot, so tail encodings can't be trusted. Tail records hold every
+    column anyway, so they're marked as having updated all of them.
+*/
+fn tail_schema_encodings(
+    storage: &Storage,
+    tables: &[String],
+    report: &mut MigrationReport,
+    dry_run: bool,
+) -> Result<(), CrabError> {
+    for table in tables.iter() {
+        let data_file = TableFile::Data.name(table);
+        let pages = if dry_run {
+            storage.open_pages_read_only(&data_file)
+        } else {
+            storage.open_pages(&data_file, StorageBackendKind::File)
+        }
+        .map_err(|e| CrabError::io(&data_file, e))?;
+
+        let mut page = [0; PAGE_SIZE];
+        pages
+            .read_page(0, &mut page)
+            .map_err(|e| CrabError::io(&data_file, e))?;
+
+        let mut header = TableHeaderPage::from_page(&page, &data_file)?;
+
+        // Rewritten by an earlier attempt, only the blobs could be left
+        if header.version < 3 {
+            let blob = storage.blob(&TableFile::PageDirectory.name(table));
+
+            let directory = match blob.read().map_err(|e| CrabError::io(blob.name(), e))? {
+                Some(contents) => {
+                    decode::<FxHashMap<usize, Arc<[usize]>>>(blob.name(), &contents.bytes)?
+                }
+                None => FxHashMap::default(),
+            };
+
+            let mut encodings = PhysicalPage::default();
+
+            for slot in 0..PAGE_SLOTS {
+                encodings.write_slot(slot, (1 << header.num_columns) - 1);
+            }
+
+            let tail_pages = directory
+                .iter()
+                .filter(|(page, _)| RID::from((**page * PAGE_SLOTS) as u64).is_tail())
+                .map(|(_, columns)| (columns[METADATA_SCHEMA_ENCODING], &encodings.page))
+                .collect::<Vec<_>>();
+
+            report.actions.push(format!(
+                "rewrite {} tail schema encoding pages of {data_file}",
+                tail_pages.len()
+            ));
+
+            if !dry_run {
+                pages
+                    .write_pages(&tail_pages)
+                    .and_then(|_| pages.sync())
+                    .map_err(|e| CrabError::io(&data_file, e))?;
+            }
+        }
+
+        for file in [
+            TableFile::PageDirectory,
+            TableFile::RangeDirectory,
+            TableFile::Index,
+        ] {
+            let blob = storage.blob(&file.name(table));
+
+            let contents = match blob.read().map_err(|e| CrabError::io(blob.name(), e))? {
+                Some(contents) if contents.version < 3 => contents,
+                _ => continue,
+            };
+
+            report.actions.push(format!("rewrite {}", blob.name()));
+
+            if !dry_run {
+                blob.write_version(3, contents.generation, &contents.bytes)
+                    .map_err(|e| CrabError::io(blob.name(), e))?;
+            }
+        }
+
+        if header.version >= 3 {
+            continue;
+        }
+
+        report
+            .actions
+            .push(format!("rewrite header page of {data_file}"));
+
+        if !dry_run {
+            header.version = 3;
+
+            pages
+                .write_page(0, &header.to_page())
+                .and_then(|_| pages.sync())
+                .map_err(|e| CrabError::io(&data_file, e))?;
+        }
+    }
+
+    Ok(())
+}
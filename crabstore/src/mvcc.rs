@@ -0,0 +1,439 @@
+use std::{
+    collections::BTreeMap,
+    hash::BuildHasherDefault,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use parking_lot::{Condvar, Mutex};
+use rustc_hash::{FxHashMap, FxHasher};
+
+use crate::rid::RID;
+
+#[derive(Debug)]
+struct ClockState {
+    // Timestamp the next commit gets
+    next: u64,
+    // Start timestamp -> running snapshots
+    active: BTreeMap<u64, usize>,
+}
+
+/*
+    Start and commit timestamps, one per database and shared by its tables
+    so a transaction spanning several of them commits at a single point.
+    Commits are serialized on it, so a snapshot never starts in the middle
+    of one.
+
+    Writes outside a transaction skip versions and the clock altogether
+    while no snapshot is running. A snapshot counts itself in before waiting
+    for the untracked writes in flight, so every write either sees it coming
+    and tracks its version or finishes before it starts.
+*/
+#[derive(Debug)]
+pub struct Clock {
+    state: Mutex<ClockState>,
+    snapshots: AtomicUsize,
+    untracked: AtomicUsize,
+    // Snapshots waiting for the untracked writes in flight
+    drain_lock: Mutex<()>,
+    drained: Condvar,
+}
+
+static NEXT_OWNER: AtomicU64 = AtomicU64::new(1);
+
+// Held by a write outside a transaction that isn't tracking its version
+pub struct UntrackedWrite<'a>(&'a Clock);
+
+// Owner of the pending versions of writes outside a transaction, no snapshot sees them
+pub const NO_OWNER: u64 = 0;
+
+// Identifies the pending versions of one transaction run
+pub fn next_owner() -> u64 {
+    NEXT_OWNER.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Clock {
+            state: Mutex::new(ClockState {
+                next: 1,
+                active: BTreeMap::new(),
+            }),
+            snapshots: Default::default(),
+            untracked: Default::default(),
+            drain_lock: Mutex::new(()),
+            drained: Condvar::new(),
+        }
+    }
+}
+
+impl Clock {
+    // Some if no snapshot is running or starting, the write can skip versions until it's dropped
+    pub fn untracked_write(&self) -> Option<UntrackedWrite> {
+        if self.snapshots.load(Ordering::SeqCst) != 0 {
+            return None;
+        }
+
+        self.untracked.fetch_add(1, Ordering::SeqCst);
+
+        if self.snapshots.load(Ordering::SeqCst) != 0 {
+            self.finish_untracked();
+            return None;
+        }
+
+        Some(UntrackedWrite(self))
+    }
+
+    fn finish_untracked(&self) {
+        // Either a starting snapshot sees the count drop, or it's counted in here
+        if self.untracked.fetch_sub(1, Ordering::SeqCst) == 1
+            && self.snapshots.load(Ordering::SeqCst) != 0
+        {
+            let _lock = self.drain_lock.lock();
+            self.drained.notify_all();
+        }
+    }
+
+    /*
+        Runs `f` with a new commit timestamp and the start of the oldest
+        running snapshot, if any. The timestamp is only used up if `f` returns
+        true, so a commit that fails validation can back out.
+    */
+    pub fn commit(&self, f: impl FnOnce(u64, Option<u64>) -> bool) -> bool {
+        let mut state = self.state.lock();
+        let oldest = state.active.keys().next().copied();
+
+        if !f(state.next, oldest) {
+            return false;
+        }
+
+        state.next += 1;
+        true
+    }
+
+    pub fn oldest_snapshot(&self) -> Option<u64> {
+        self.state.lock().active.keys().next().copied()
+    }
+
+    pub fn active_snapshots(&self) -> usize {
+        self.state.lock().active.values().sum()
+    }
+}
+
+impl Drop for UntrackedWrite<'_> {
+    fn drop(&mut self) {
+        self.0.finish_untracked();
+    }
+}
+
+/*
+    What a snapshot transaction reads: everything committed before it
+    started, plus its own writes. Released when dropped.
+*/
+#[derive(Debug)]
+pub struct Snapshot {
+    clock: Arc<Clock>,
+    start: u64,
+    owner: u64,
+}
+
+impl Snapshot {
+    pub fn begin(clock: &Arc<Clock>, owner: u64) -> Snapshot {
+        clock.snapshots.fetch_add(1, Ordering::SeqCst);
+
+        let mut lock = clock.drain_lock.lock();
+
+        while clock.untracked.load(Ordering::SeqCst) != 0 {
+            clock.drained.wait(&mut lock);
+        }
+
+        drop(lock);
+
+        let mut state = clock.state.lock();
+        let start = state.next;
+
+        *state.active.entry(start).or_default() += 1;
+
+        Snapshot {
+            clock: Arc::clone(clock),
+            start,
+            owner,
+        }
+    }
+
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn owner(&self) -> u64 {
+        self.owner
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let mut state = self.clock.state.lock();
+
+        if let Some(count) = state.active.get_mut(&self.start) {
+            *count -= 1;
+
+            if *count == 0 {
+                state.active.remove(&self.start);
+            }
+        }
+
+        self.clock.snapshots.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Version {
+    Pending(u64),
+    Committed(u64),
+}
+
+impl Version {
+    fn visible(&self, snapshot: &Snapshot) -> bool {
+        match *self {
+            Version::Pending(owner) => owner == snapshot.owner,
+            Version::Committed(ts) => ts < snapshot.start,
+        }
+    }
+
+    fn visible_to_all(&self, oldest: Option<u64>) -> bool {
+        match *self {
+            Version::Pending(_) => false,
+            Version::Committed(ts) => oldest.map_or(true, |x| ts < x),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Write {
+    Insert(RID),
+    Update { base: RID, tail: RID },
+    Delete(RID),
+}
+
+impl Write {
+    pub fn base(&self) -> RID {
+        match *self {
+            Write::Insert(rid) | Write::Delete(rid) => rid,
+            Write::Update { base, .. } => base,
+        }
+    }
+}
+
+#[derive(Default)]
+struct VersionState {
+    // Base records and tail records some snapshot might not see yet
+    created: FxHashMap<RID, Version>,
+    // Base records whose deletion some snapshot might not see yet
+    deleted: FxHashMap<RID, Version>,
+    // Base record -> the last commit that wrote it, for write-write validation
+    written: FxHashMap<RID, u64>,
+    // Tail record -> the index entries (column, value, base record) its update removed
+    superseded: FxHashMap<RID, Vec<(usize, u64, RID)>>,
+    collect_at: usize,
+}
+
+/*
+    Visibility of a table's records to snapshots. Tail records already chain
+    every version of a record, this only keeps the commit timestamps of the
+    versions that a running snapshot might not see. Anything without an entry
+    was committed before every running snapshot started, so nothing here has
+    to survive a restart.
+*/
+pub struct Versions {
+    clock: Arc<Clock>,
+    state: Mutex<VersionState>,
+}
+
+impl Versions {
+    pub fn new(clock: &Arc<Clock>) -> Self {
+        Versions {
+            clock: Arc::clone(clock),
+            state: Mutex::new(VersionState {
+                created: FxHashMap::with_capacity_and_hasher(
+                    1024,
+                    BuildHasherDefault::<FxHasher>::default(),
+                ),
+                collect_at: 1024,
+                ..Default::default()
+            }),
+        }
+    }
+
+    // The clock of the database the table belongs to
+    pub fn clock(&self) -> &Arc<Clock> {
+        &self.clock
+    }
+
+    // Called before the write becomes reachable, nobody else sees it until it commits
+    pub fn begin_write(&self, write: Write, owner: u64) {
+        let mut state = self.state.lock();
+
+        match write {
+            Write::Insert(rid) => state.created.insert(rid, Version::Pending(owner)),
+            Write::Update { tail, .. } => state.created.insert(tail, Version::Pending(owner)),
+            Write::Delete(rid) => state.deleted.insert(rid, Version::Pending(owner)),
+        };
+    }
+
+    // Whether a commit that `snapshot` doesn't see wrote the same base record
+    pub fn conflicts(&self, base: RID, snapshot: &Snapshot) -> bool {
+        self.state
+            .lock()
+            .written
+            .get(&base)
+            .map_or(false, |ts| *ts >= snapshot.start)
+    }
+
+    pub fn commit(&self, write: Write, ts: u64, oldest: Option<u64>) {
+        let mut state = self.state.lock();
+
+        // With no snapshot running, every one from now on sees it
+        if oldest.is_none() {
+            match write {
+                Write::Insert(rid) => state.created.remove(&rid),
+                Write::Update { tail, .. } => {
+                    state.superseded.remove(&tail);
+                    state.created.remove(&tail)
+                }
+                Write::Delete(rid) => state.deleted.remove(&rid),
+            };
+
+            return;
+        }
+
+        match write {
+            Write::Insert(rid) => state.created.insert(rid, Version::Committed(ts)),
+            Write::Update { tail, .. } => state.created.insert(tail, Version::Committed(ts)),
+            Write::Delete(rid) => state.deleted.insert(rid, Version::Committed(ts)),
+        };
+
+        state.written.insert(write.base(), ts);
+
+        let size = state.created.len() + state.deleted.len() + state.written.len();
+
+        if size >= state.collect_at {
+            Self::collect_state(&mut state, oldest);
+            let size = state.created.len() + state.deleted.len() + state.written.len();
+            state.collect_at = (size * 2).max(1024);
+        }
+    }
+
+    pub fn abort(&self, write: Write) {
+        let mut state = self.state.lock();
+
+        match write {
+            Write::Insert(rid) => state.created.remove(&rid),
+            Write::Update { tail, .. } => {
+                state.superseded.remove(&tail);
+                state.created.remove(&tail)
+            }
+            Write::Delete(rid) => state.deleted.remove(&rid),
+        };
+    }
+
+    /*
+        Indexes only hold the latest values, so the entry an update replaces
+        is kept here for as long as a snapshot might still see the old value.
+    */
+    pub fn supersede(&self, tail: RID, column: usize, value: u64, base: RID) {
+        let mut state = self.state.lock();
+
+        if state.created.contains_key(&tail) {
+            state
+                .superseded
+                .entry(tail)
+                .or_default()
+                .push((column, value, base));
+        }
+    }
+
+    // Base records whose replaced index entry for `column` is in `range`
+    pub fn superseded(&self, column: usize, range: &RangeInclusive<u64>) -> Vec<RID> {
+        self.state
+            .lock()
+            .superseded
+            .values()
+            .flatten()
+            .filter(|(x, value, _)| *x == column && range.contains(value))
+            .map(|(_, _, base)| *base)
+            .collect()
+    }
+
+    // Whether `snapshot` sees the base or tail record `rid`
+    pub fn is_visible(&self, rid: RID, snapshot: &Snapshot) -> bool {
+        self.state
+            .lock()
+            .created
+            .get(&rid)
+            .map_or(true, |x| x.visible(snapshot))
+    }
+
+    // Whether `snapshot` sees the deletion of a base record that's been deleted
+    pub fn is_deleted(&self, rid: RID, snapshot: &Snapshot) -> bool {
+        self.state
+            .lock()
+            .deleted
+            .get(&rid)
+            .map_or(true, |x| x.visible(snapshot))
+    }
+
+    /*
+        Whether every snapshot, running or not, sees the tail record `tail`
+        of `base` and, if `base` was deleted, its deletion. Merges leave tail
+        records alone until then, since the base record can only hold one
+        version.
+    */
+    pub fn is_settled(&self, base: RID, tail: RID) -> bool {
+        let oldest = self.clock.oldest_snapshot();
+        let state = self.state.lock();
+
+        state
+            .created
+            .get(&tail)
+            .map_or(true, |x| x.visible_to_all(oldest))
+            && state
+                .deleted
+                .get(&base)
+                .map_or(true, |x| x.visible_to_all(oldest))
+    }
+
+    // Drops what every running snapshot already sees
+    pub fn collect(&self) {
+        let oldest = self.clock.oldest_snapshot();
+        Self::collect_state(&mut self.state.lock(), oldest);
+    }
+
+    fn collect_state(state: &mut VersionState, oldest: Option<u64>) {
+        state.created.retain(|_, x| !x.visible_to_all(oldest));
+        state.deleted.retain(|_, x| !x.visible_to_all(oldest));
+
+        let VersionState {
+            created,
+            superseded,
+            ..
+        } = state;
+        superseded.retain(|tail, _| created.contains_key(tail));
+
+        state
+            .written
+            .retain(|_, ts| oldest.map_or(false, |x| *ts >= x));
+    }
+
+    // Versions tracked, for stats
+    pub fn len(&self) -> usize {
+        let state = self.state.lock();
+        state.created.len() + state.deleted.len() + state.written.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -1,7 +1,10 @@
+use bytecheck::CheckBytes;
+use parking_lot::MutexGuard;
 use rkyv::{Archive, Deserialize, Serialize};
 
 use crate::{
-    bufferpool::{BufferPool, BufferPoolFrame},
+    bufferpool::{BufferPool, BufferPoolError, LockedBufferPool, PageGuard},
+    replacement_policy::AccessHint,
     rid::RID,
     METADATA_PAGE_HEADER, PAGE_SLOTS,
 };
@@ -77,49 +80,84 @@ impl Page {
         self.0[index]
     }
 
-    pub fn read_metadata(&self, bp: &mut BufferPool) -> u64 {
-        bp.get_page(self.0[METADATA_PAGE_HEADER]).slot(0)
+    pub fn read_metadata(&self, bp: &mut MutexGuard<BufferPool>) -> Result<u64, BufferPoolError> {
+        Ok(bp.get_page(self.0[METADATA_PAGE_HEADER])?.slot(0))
     }
 
-    pub fn write_metadata(&self, bp: &mut BufferPool, val: u64) {
-        bp.get_page(self.0[METADATA_PAGE_HEADER]).write_slot(0, val);
+    pub fn write_metadata(&self, bp: &mut MutexGuard<BufferPool>, val: u64) {
+        self.wait_for_column(bp, METADATA_PAGE_HEADER)
+            .write_slot(0, val);
     }
 
-    pub fn write_page_tps(&self, bp: &mut BufferPool, val: u64) {
+    pub fn write_page_tps(&self, bp: &mut MutexGuard<BufferPool>, val: u64) {
         self.write_metadata(bp, val);
     }
 
-    pub fn write_last_tail(&self, bp: &mut BufferPool, val: u64) {
+    pub fn write_last_tail(&self, bp: &mut MutexGuard<BufferPool>, val: u64) {
         self.write_metadata(bp, val);
     }
 
-    pub fn read_page_tps(&self, bp: &mut BufferPool) -> u64 {
+    pub fn read_page_tps(&self, bp: &mut MutexGuard<BufferPool>) -> Result<u64, BufferPoolError> {
         self.read_metadata(bp)
     }
 
-    pub fn read_last_tail(&self, bp: &mut BufferPool) -> u64 {
+    pub fn read_last_tail(&self, bp: &mut MutexGuard<BufferPool>) -> Result<u64, BufferPoolError> {
         self.read_metadata(bp)
     }
 
     #[inline(always)]
-    pub fn get_column(&self, bp: &mut BufferPool, index: usize) -> Arc<BufferPoolFrame> {
+    pub fn get_column(
+        &self,
+        bp: &mut MutexGuard<BufferPool>,
+        index: usize,
+    ) -> Result<PageGuard, BufferPoolError> {
         bp.get_page(self.0[index])
     }
-    pub fn get_column_mut(&self, bp: &mut BufferPool, index: usize) -> Arc<BufferPoolFrame> {
+    pub fn get_column_mut(
+        &self,
+        bp: &mut MutexGuard<BufferPool>,
+        index: usize,
+    ) -> Result<PageGuard, BufferPoolError> {
         bp.get_page(self.0[index])
     }
+    // Never gives up, see `LockedBufferPool::wait_for_page`
+    pub fn wait_for_column(&self, bp: &mut MutexGuard<BufferPool>, index: usize) -> PageGuard {
+        bp.wait_for_page(self.0[index])
+    }
+    #[inline(always)]
+    pub fn get_column_with_hint(
+        &self,
+        bp: &mut MutexGuard<BufferPool>,
+        index: usize,
+        hint: AccessHint,
+    ) -> Result<PageGuard, BufferPoolError> {
+        bp.get_page_with_hint(self.0[index], hint)
+    }
     #[inline(always)]
-    pub fn slot(&self, bp: &mut BufferPool, column: usize, rid: RID) -> u64 {
-        self.get_column(bp, column).slot(rid.slot())
+    pub fn slot(
+        &self,
+        bp: &mut MutexGuard<BufferPool>,
+        column: usize,
+        rid: RID,
+    ) -> Result<u64, BufferPoolError> {
+        Ok(self.get_column(bp, column)?.slot(rid.slot()))
     }
 
     #[inline(always)]
-    pub fn write_slot(&mut self, bp: &mut BufferPool, column: usize, rid: RID, value: u64) {
-        self.get_column(bp, column).write_slot(rid.slot(), value);
+    pub fn write_slot(
+        &mut self,
+        bp: &mut MutexGuard<BufferPool>,
+        column: usize,
+        rid: RID,
+        value: u64,
+    ) {
+        self.wait_for_column(bp, column)
+            .write_slot(rid.slot(), value);
     }
 }
 
 #[derive(Archive, Serialize, Deserialize, Debug)]
+#[archive_attr(derive(CheckBytes))]
 pub struct PageRange {
     pub next_tid: AtomicU64,
     pub current_tail_page: AtomicUsize,
@@ -1,25 +1,16 @@
-use std::{
-    fs::File,
-    hash::BuildHasherDefault,
-    io::{BufWriter, Read, Write},
-    path::{Path, PathBuf},
-    sync::Arc,
-};
+use std::{hash::BuildHasherDefault, sync::Arc};
 
-use rkyv::{
-    de::deserializers::SharedDeserializeMap,
-    ser::{
-        serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-        Serializer,
-    },
-    Deserialize,
-};
-use rustc_hash::{FxHashMap, FxHasher};
+use rkyv::AlignedVec;
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 
-use crate::rid::RID;
+use crate::{
+    error::CrabError,
+    rid::RID,
+    storage::{decode, Blob},
+};
 #[derive(Debug)]
 pub struct PageDirectory {
-    path: PathBuf,
+    blob: Blob,
     directory: FxHashMap<usize, Arc<[usize]>>,
 }
 
@@ -33,35 +24,6 @@ impl PageDirectory {
         self.directory.get(&page).map(Arc::clone)
     }
 
-    pub fn set(&mut self, rid: RID, page_ids: &[Option<usize>]) {
-        if let Some(current_vals) = self.directory.get_mut(&rid.page()) {
-            let mut cols_clone = Arc::<[usize]>::new_uninit_slice(current_vals.len());
-
-            for (i, potential_page) in page_ids.into_iter().enumerate() {
-                if let Some(new_page) = potential_page {
-                    Arc::get_mut(&mut cols_clone).unwrap()[i].write(*new_page);
-                } else {
-                    Arc::get_mut(&mut cols_clone).unwrap()[i].write(current_vals[i]);
-                }
-            }
-
-            self.directory
-                .insert(rid.page(), unsafe { cols_clone.assume_init() });
-
-            return;
-        }
-
-        let mut entry = Arc::<[usize]>::new_uninit_slice(page_ids.len());
-
-        for (i, x) in page_ids.iter().enumerate() {
-            Arc::get_mut(&mut entry).unwrap()[i]
-                .write(x.expect("Must provide all columns of page dir entry if new"));
-        }
-
-        self.directory
-            .insert(rid.page(), unsafe { entry.assume_init() });
-    }
-
     pub fn new_page(&mut self, page_num: usize, column_page_ids: Arc<[usize]>) {
         self.directory
             .try_insert(page_num, Arc::clone(&column_page_ids))
@@ -76,13 +38,24 @@ impl PageDirectory {
         self.directory.insert(page_num, Arc::clone(replacement))
     }
 
-    pub fn new(path: &Path) -> Self {
-        if !path.exists() {
-            File::create(path).unwrap();
-        }
+    pub fn remove_page(&mut self, page_num: usize) -> Option<Arc<[usize]>> {
+        self.directory.remove(&page_num)
+    }
+
+    // Column pages below `end` that no page references, except the header page
+    pub fn unreferenced_pages(&self, end: usize) -> Vec<usize> {
+        let referenced = self
+            .directory
+            .values()
+            .flat_map(|x| x.iter().copied())
+            .collect::<FxHashSet<usize>>();
+
+        (1..end).filter(|x| !referenced.contains(x)).collect()
+    }
 
+    pub fn new(blob: Blob) -> Self {
         PageDirectory {
-            path: path.into(),
+            blob,
             directory: FxHashMap::with_capacity_and_hasher(
                 80000,
                 BuildHasherDefault::<FxHasher>::default(),
@@ -90,51 +63,22 @@ impl PageDirectory {
         }
     }
 
-    pub fn load(path: &Path) -> Self {
-        if !path.exists() {
-            return PageDirectory::new(path);
-        }
-
-        let mut pd_file = File::options().read(true).open(path).unwrap();
-        let mut pd_bytes = Vec::new();
-
-        pd_file
-            .read_to_end(&mut pd_bytes)
-            .expect("Unable to read page directory file");
+    pub fn load(blob: Blob, generation: u64) -> Result<Self, CrabError> {
+        let pd_bytes = match blob.read_generation(generation)? {
+            Some(bytes) => bytes,
+            None => return Ok(PageDirectory::new(blob)),
+        };
 
-        let archived = unsafe { rkyv::archived_root::<FxHashMap<usize, Arc<[usize]>>>(&pd_bytes) };
+        let directory = decode::<FxHashMap<usize, Arc<[usize]>>>(blob.name(), &pd_bytes)?;
 
-        let directory = archived
-            .deserialize(&mut SharedDeserializeMap::new())
-            .expect("Failed to deserialize page directory");
-
-        PageDirectory {
-            path: path.into(),
-            directory,
-        }
+        Ok(PageDirectory { blob, directory })
     }
 
-    pub fn persist(&self) {
-        let pd_file = File::options()
-            .write(true)
-            .truncate(true)
-            .open(self.path.clone())
-            .expect("Unable to open page directory file");
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(BufWriter::new(pd_file)),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&self.directory)
-            .expect("Unable to serialize page directory");
-
-        let (buf, _, _) = serializer.into_components();
+    pub fn blob(&self) -> &Blob {
+        &self.blob
+    }
 
-        buf.into_inner()
-            .flush()
-            .expect("Failed to flush page directory");
+    pub fn to_bytes(&self) -> AlignedVec {
+        rkyv::to_bytes::<_, 4096>(&self.directory).expect("Unable to serialize page directory")
     }
 }
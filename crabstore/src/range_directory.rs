@@ -1,21 +1,14 @@
-use crate::{page::PageRange, rid::RID};
-use rkyv::{
-    de::deserializers::SharedDeserializeMap,
-    ser::{
-        serializers::{AllocScratch, CompositeSerializer, SharedSerializeMap, WriteSerializer},
-        Serializer,
-    },
-    Deserialize,
-};
+use rkyv::AlignedVec;
 
-use std::{
-    fs::File,
-    io::{BufWriter, Read, Write},
-    path::{Path, PathBuf},
+use crate::{
+    error::CrabError,
+    page::PageRange,
+    storage::{decode, Blob},
 };
+
 #[derive(Debug)]
 pub struct RangeDirectory {
-    path: PathBuf,
+    blob: Blob,
     directory: Vec<PageRange>,
 }
 
@@ -24,10 +17,6 @@ impl RangeDirectory {
         &self.directory[range]
     }
 
-    pub fn next_tid(&self, range: usize) -> RID {
-        self.directory[range].next_tid()
-    }
-
     pub fn next_range_id(&self) -> usize {
         self.directory.len()
     }
@@ -41,54 +30,28 @@ impl RangeDirectory {
         self.directory[range].next_tid = new_tail.next_tid;
     }
 
-    pub fn new(path: &Path) -> Self {
+    pub fn new(blob: Blob) -> Self {
         RangeDirectory {
-            path: path.into(),
+            blob,
             directory: Vec::new(),
         }
     }
 
-    pub fn load(path: &Path) -> Self {
-        let mut rd_file = File::options().read(true).open(path).unwrap();
-        let mut rd_bytes = Vec::new();
-
-        rd_file
-            .read_to_end(&mut rd_bytes)
-            .expect("Unable to read page directory file");
+    pub fn load(blob: Blob, generation: u64) -> Result<Self, CrabError> {
+        let rd_bytes = blob
+            .read_generation(generation)?
+            .ok_or_else(|| CrabError::Missing(blob.name().into()))?;
 
-        let archived = unsafe { rkyv::archived_root::<Vec<PageRange>>(&rd_bytes) };
-        let directory = archived
-            .deserialize(&mut SharedDeserializeMap::new())
-            .expect("Failed to deserialize page directory");
+        let directory = decode::<Vec<PageRange>>(blob.name(), &rd_bytes)?;
 
-        RangeDirectory {
-            path: path.into(),
-            directory,
-        }
+        Ok(RangeDirectory { blob, directory })
     }
 
-    pub fn persist(&self) {
-        let rd_file = File::options()
-            .write(true)
-            .truncate(true)
-            .create(true)
-            .open(self.path.clone())
-            .expect("Unable to open range directory file");
-
-        let mut serializer = CompositeSerializer::new(
-            WriteSerializer::new(BufWriter::new(rd_file)),
-            AllocScratch::default(),
-            SharedSerializeMap::new(),
-        );
-
-        serializer
-            .serialize_value(&self.directory)
-            .expect("Unable to serialize range directory");
-
-        let (buf, _, _) = serializer.into_components();
+    pub fn blob(&self) -> &Blob {
+        &self.blob
+    }
 
-        buf.into_inner()
-            .flush()
-            .expect("Failed to flush range directory");
+    pub fn to_bytes(&self) -> AlignedVec {
+        rkyv::to_bytes::<_, 4096>(&self.directory).expect("Unable to serialize range directory")
     }
 }
@@ -0,0 +1,198 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+};
+
+use parking_lot::{Condvar, Mutex};
+use rustc_hash::FxHashMap;
+
+use crate::{storage_backend::StorageBackend, PAGE_SIZE};
+
+#[derive(Debug, Default)]
+struct ReadAheadState {
+    cache: FxHashMap<usize, Box<[u8; PAGE_SIZE]>>,
+    order: VecDeque<usize>,
+    // Page id -> stale, set when the page is written while the read is in flight
+    in_flight: FxHashMap<usize, bool>,
+}
+
+#[derive(Debug)]
+struct ReadAheadShared {
+    state: Mutex<ReadAheadState>,
+    finished: Condvar,
+    capacity: usize,
+    reads: AtomicU64,
+    hits: AtomicU64,
+}
+
+type ReadAheadWorker = (JoinHandle<()>, Sender<Vec<usize>>);
+
+/*
+    Pages requested through `request` are read by a background thread into a
+    small cache that `take` consumes on a buffer pool miss. Every write to a
+    page has to go through `invalidate` so a cached or in flight copy can't
+    shadow newer data.
+*/
+#[derive(Debug)]
+pub struct ReadAhead {
+    shared: Arc<ReadAheadShared>,
+    worker: Mutex<Option<ReadAheadWorker>>,
+}
+
+impl ReadAhead {
+    pub fn new(capacity: usize) -> Self {
+        ReadAhead {
+            shared: Arc::new(ReadAheadShared {
+                state: Mutex::new(ReadAheadState::default()),
+                finished: Condvar::new(),
+                capacity,
+                reads: 0.into(),
+                hits: 0.into(),
+            }),
+            worker: Mutex::new(None),
+        }
+    }
+
+    pub fn request(&self, backend: &Arc<dyn StorageBackend>, pages: &[usize]) {
+        if self.shared.capacity == 0 {
+            return;
+        }
+
+        let mut state = self.shared.state.lock();
+
+        let pages = pages
+            .iter()
+            .copied()
+            .filter(|x| !state.cache.contains_key(x) && !state.in_flight.contains_key(x))
+            .take(self.shared.capacity)
+            .collect::<Vec<usize>>();
+
+        if pages.is_empty() {
+            return;
+        }
+
+        for page in pages.iter() {
+            state.in_flight.insert(*page, false);
+        }
+
+        drop(state);
+
+        let mut worker = self.worker.lock();
+
+        if worker.is_none() {
+            let backend = Arc::clone(backend);
+            let shared = Arc::clone(&self.shared);
+            let (tx, rx) = mpsc::channel::<Vec<usize>>();
+
+            let handle = thread::spawn(move || {
+                for pages in rx {
+                    ReadAhead::read(&shared, backend.as_ref(), &pages);
+                }
+            });
+
+            *worker = Some((handle, tx));
+        }
+
+        worker
+            .as_ref()
+            .unwrap()
+            .1
+            .send(pages)
+            .expect("Read ahead thread died");
+    }
+
+    fn read(shared: &ReadAheadShared, backend: &dyn StorageBackend, pages: &[usize]) {
+        let mut buffers = pages
+            .iter()
+            .map(|_| Box::new([0u8; PAGE_SIZE]))
+            .collect::<Vec<_>>();
+
+        let result = {
+            let mut requests = pages
+                .iter()
+                .copied()
+                .zip(buffers.iter_mut().map(|x| x.as_mut()))
+                .collect::<Vec<_>>();
+
+            backend.read_pages(&mut requests)
+        };
+
+        shared
+            .reads
+            .fetch_add(pages.len() as u64, Ordering::Relaxed);
+
+        let mut state = shared.state.lock();
+
+        for (page_id, page) in pages.iter().zip(buffers) {
+            let stale = state.in_flight.remove(page_id).unwrap_or(true);
+
+            if stale || result.is_err() {
+                continue;
+            }
+
+            if state.order.len() >= shared.capacity {
+                if let Some(oldest) = state.order.pop_front() {
+                    state.cache.remove(&oldest);
+                }
+            }
+
+            state.cache.insert(*page_id, page);
+            state.order.push_back(*page_id);
+        }
+
+        shared.finished.notify_all();
+    }
+
+    // Copies a prefetched page into `page`, waiting for it if the read is in flight
+    pub fn take(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> bool {
+        let mut state = self.shared.state.lock();
+
+        while state.in_flight.contains_key(&page_id) {
+            self.shared.finished.wait(&mut state);
+        }
+
+        match state.cache.remove(&page_id) {
+            Some(cached) => {
+                state.order.retain(|x| *x != page_id);
+                page.copy_from_slice(cached.as_slice());
+                self.shared.hits.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn invalidate(&self, page_id: usize) {
+        let mut state = self.shared.state.lock();
+
+        if state.cache.remove(&page_id).is_some() {
+            state.order.retain(|x| *x != page_id);
+        }
+
+        if let Some(stale) = state.in_flight.get_mut(&page_id) {
+            *stale = true;
+        }
+    }
+
+    pub fn reads(&self) -> u64 {
+        self.shared.reads.load(Ordering::Relaxed)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.shared.hits.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ReadAhead {
+    fn drop(&mut self) {
+        if let Some((handle, tx)) = self.worker.lock().take() {
+            drop(tx);
+            handle.join().expect("Failed to join read ahead thread");
+        }
+    }
+}
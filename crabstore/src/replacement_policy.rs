@@ -0,0 +1,382 @@
+use std::{collections::VecDeque, fmt::Debug, str::FromStr};
+
+/*
+    Sequential accesses come from scans. Policies should load these pages
+    so that they are the first to be evicted, and hits with a sequential hint
+    should not promote a frame.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessHint {
+    Random,
+    Sequential,
+}
+
+pub trait ReplacementPolicy: Send + Sync + Debug {
+    // A resident frame was hit
+    fn record_access(&mut self, frame: usize, hint: AccessHint);
+
+    // `page_id` was read into (or allocated in) `frame`
+    fn record_load(&mut self, frame: usize, page_id: usize, hint: AccessHint);
+
+    // `page_id` left `frame`, the frame is now empty
+    fn record_evict(&mut self, frame: usize, page_id: usize);
+
+    // Choose an occupied frame to evict so `incoming` can be loaded, only frames
+    // for which `evictable` returns true may be chosen.
+    fn find_victim(&mut self, incoming: usize, evictable: &dyn Fn(usize) -> bool) -> Option<usize>;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReplacementPolicyKind {
+    #[default]
+    Clock,
+    LruK(usize),
+    TwoQ,
+    Arc,
+}
+
+impl ReplacementPolicyKind {
+    pub fn build(self, size: usize) -> Box<dyn ReplacementPolicy> {
+        match self {
+            ReplacementPolicyKind::Clock => Box::new(Clock::new(size)),
+            ReplacementPolicyKind::LruK(k) => Box::new(LruK::new(size, k)),
+            ReplacementPolicyKind::TwoQ => Box::new(TwoQ::new(size)),
+            ReplacementPolicyKind::Arc => Box::new(AdaptiveReplacement::new(size)),
+        }
+    }
+}
+
+impl FromStr for ReplacementPolicyKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "clock" => Ok(ReplacementPolicyKind::Clock),
+            "lru-k" | "lruk" | "lru-2" => Ok(ReplacementPolicyKind::LruK(2)),
+            "2q" | "twoq" => Ok(ReplacementPolicyKind::TwoQ),
+            "arc" => Ok(ReplacementPolicyKind::Arc),
+            other => match other.strip_prefix("lru-") {
+                Some(k) => k
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|k| *k > 0)
+                    .map(ReplacementPolicyKind::LruK)
+                    .ok_or_else(|| format!("Invalid LRU-K policy \"{s}\"")),
+                None => Err(format!("Unknown replacement policy \"{s}\"")),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Clock {
+    refs: Vec<bool>,
+    hand: usize,
+}
+
+impl Clock {
+    pub fn new(size: usize) -> Self {
+        Clock {
+            refs: vec![false; size],
+            hand: 0,
+        }
+    }
+}
+
+impl ReplacementPolicy for Clock {
+    fn record_access(&mut self, frame: usize, hint: AccessHint) {
+        if hint == AccessHint::Random {
+            self.refs[frame] = true;
+        }
+    }
+
+    fn record_load(&mut self, frame: usize, _page_id: usize, hint: AccessHint) {
+        self.refs[frame] = hint == AccessHint::Random;
+    }
+
+    fn record_evict(&mut self, frame: usize, _page_id: usize) {
+        self.refs[frame] = false;
+    }
+
+    fn find_victim(
+        &mut self,
+        _incoming: usize,
+        evictable: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        // Two sweeps clear every reference bit, anything left after that is pinned
+        for _ in 0..(2 * self.refs.len()) {
+            let frame = self.hand;
+            self.hand = (self.hand + 1) % self.refs.len();
+
+            if !evictable(frame) {
+                continue;
+            }
+
+            if self.refs[frame] {
+                self.refs[frame] = false;
+                continue;
+            }
+
+            return Some(frame);
+        }
+
+        None
+    }
+}
+
+/*
+    Evicts the frame whose K-th most recent access is the oldest. Frames with
+    fewer than K accesses have an infinite backward distance and are evicted
+    first, in LRU order.
+*/
+#[derive(Debug)]
+pub struct LruK {
+    k: usize,
+    time: u64,
+    history: Vec<VecDeque<u64>>,
+    last_access: Vec<u64>,
+}
+
+impl LruK {
+    pub fn new(size: usize, k: usize) -> Self {
+        LruK {
+            k: k.max(1),
+            time: 0,
+            history: vec![VecDeque::new(); size],
+            last_access: vec![0; size],
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.time += 1;
+        self.time
+    }
+
+    fn touch(&mut self, frame: usize) {
+        let now = self.tick();
+        let history = &mut self.history[frame];
+
+        if history.len() == self.k {
+            history.pop_front();
+        }
+
+        history.push_back(now);
+        self.last_access[frame] = now;
+    }
+}
+
+impl ReplacementPolicy for LruK {
+    fn record_access(&mut self, frame: usize, hint: AccessHint) {
+        if hint == AccessHint::Random {
+            self.touch(frame);
+        }
+    }
+
+    fn record_load(&mut self, frame: usize, _page_id: usize, hint: AccessHint) {
+        self.history[frame].clear();
+
+        match hint {
+            AccessHint::Random => self.touch(frame),
+            AccessHint::Sequential => self.last_access[frame] = 0,
+        }
+    }
+
+    fn record_evict(&mut self, frame: usize, _page_id: usize) {
+        self.history[frame].clear();
+        self.last_access[frame] = 0;
+    }
+
+    fn find_victim(
+        &mut self,
+        _incoming: usize,
+        evictable: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        // (has K accesses, K-th most recent access, last access), smallest wins
+        (0..self.history.len())
+            .filter(|frame| evictable(*frame))
+            .min_by_key(|frame| {
+                let history = &self.history[*frame];
+                if history.len() < self.k {
+                    (false, 0, self.last_access[*frame])
+                } else {
+                    (true, history[0], self.last_access[*frame])
+                }
+            })
+            .map(|frame| {
+                self.history[frame].clear();
+                frame
+            })
+    }
+}
+
+/*
+    Full 2Q: new pages enter the A1in FIFO, pages re-referenced after falling
+    out of A1in (tracked by page id in the A1out ghost queue) go to the Am LRU.
+*/
+#[derive(Debug)]
+pub struct TwoQ {
+    a1_in: VecDeque<usize>,
+    a1_out: VecDeque<usize>,
+    am: VecDeque<usize>,
+    kin: usize,
+    kout: usize,
+}
+
+impl TwoQ {
+    pub fn new(size: usize) -> Self {
+        TwoQ {
+            a1_in: VecDeque::with_capacity(size),
+            a1_out: VecDeque::with_capacity(size),
+            am: VecDeque::with_capacity(size),
+            kin: (size / 4).max(1),
+            kout: (size / 2).max(1),
+        }
+    }
+}
+
+fn remove_from(queue: &mut VecDeque<usize>, value: usize) -> bool {
+    match queue.iter().position(|x| *x == value) {
+        Some(pos) => {
+            queue.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
+fn first_evictable(queue: &VecDeque<usize>, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+    queue.iter().copied().find(|frame| evictable(*frame))
+}
+
+impl ReplacementPolicy for TwoQ {
+    fn record_access(&mut self, frame: usize, hint: AccessHint) {
+        if hint == AccessHint::Random && remove_from(&mut self.am, frame) {
+            self.am.push_back(frame);
+        }
+    }
+
+    fn record_load(&mut self, frame: usize, page_id: usize, hint: AccessHint) {
+        if hint == AccessHint::Sequential {
+            self.a1_in.push_front(frame);
+        } else if remove_from(&mut self.a1_out, page_id) {
+            self.am.push_back(frame);
+        } else {
+            self.a1_in.push_back(frame);
+        }
+    }
+
+    fn record_evict(&mut self, frame: usize, page_id: usize) {
+        if remove_from(&mut self.a1_in, frame) {
+            if self.a1_out.len() >= self.kout {
+                self.a1_out.pop_front();
+            }
+            self.a1_out.push_back(page_id);
+        } else {
+            remove_from(&mut self.am, frame);
+        }
+    }
+
+    fn find_victim(
+        &mut self,
+        _incoming: usize,
+        evictable: &dyn Fn(usize) -> bool,
+    ) -> Option<usize> {
+        if self.a1_in.len() > self.kin {
+            first_evictable(&self.a1_in, evictable).or_else(|| first_evictable(&self.am, evictable))
+        } else {
+            first_evictable(&self.am, evictable).or_else(|| first_evictable(&self.a1_in, evictable))
+        }
+    }
+}
+
+/*
+    ARC (Megiddo & Modha). T1/T2 hold resident frames seen once/more than once,
+    B1/B2 are ghost lists of page ids evicted from T1/T2 and `target` is the
+    adaptive size of T1.
+*/
+#[derive(Debug)]
+pub struct AdaptiveReplacement {
+    capacity: usize,
+    target: usize,
+    t1: VecDeque<usize>,
+    t2: VecDeque<usize>,
+    b1: VecDeque<usize>,
+    b2: VecDeque<usize>,
+}
+
+impl AdaptiveReplacement {
+    pub fn new(size: usize) -> Self {
+        AdaptiveReplacement {
+            capacity: size,
+            target: 0,
+            t1: VecDeque::with_capacity(size),
+            t2: VecDeque::with_capacity(size),
+            b1: VecDeque::with_capacity(size),
+            b2: VecDeque::with_capacity(size),
+        }
+    }
+
+    fn trim_ghosts(&mut self) {
+        while self.t1.len() + self.b1.len() > self.capacity && !self.b1.is_empty() {
+            self.b1.pop_front();
+        }
+
+        while self.t1.len() + self.t2.len() + self.b1.len() + self.b2.len() > 2 * self.capacity
+            && !self.b2.is_empty()
+        {
+            self.b2.pop_front();
+        }
+    }
+}
+
+impl ReplacementPolicy for AdaptiveReplacement {
+    fn record_access(&mut self, frame: usize, hint: AccessHint) {
+        if hint == AccessHint::Sequential {
+            return;
+        }
+
+        if remove_from(&mut self.t1, frame) || remove_from(&mut self.t2, frame) {
+            self.t2.push_back(frame);
+        }
+    }
+
+    fn record_load(&mut self, frame: usize, page_id: usize, hint: AccessHint) {
+        if hint == AccessHint::Sequential {
+            self.t1.push_front(frame);
+        } else if remove_from(&mut self.b1, page_id) {
+            let delta = (self.b2.len() / (self.b1.len() + 1)).max(1);
+            self.target = (self.target + delta).min(self.capacity);
+            self.t2.push_back(frame);
+        } else if remove_from(&mut self.b2, page_id) {
+            let delta = (self.b1.len() / (self.b2.len() + 1)).max(1);
+            self.target = self.target.saturating_sub(delta);
+            self.t2.push_back(frame);
+        } else {
+            self.t1.push_back(frame);
+        }
+
+        self.trim_ghosts();
+    }
+
+    fn record_evict(&mut self, frame: usize, page_id: usize) {
+        if remove_from(&mut self.t1, frame) {
+            self.b1.push_back(page_id);
+        } else if remove_from(&mut self.t2, frame) {
+            self.b2.push_back(page_id);
+        }
+
+        self.trim_ghosts();
+    }
+
+    fn find_victim(&mut self, incoming: usize, evictable: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let prefer_t1 = !self.t1.is_empty()
+            && (self.t1.len() > self.target
+                || (self.b2.contains(&incoming) && self.t1.len() == self.target));
+
+        if prefer_t1 {
+            first_evictable(&self.t1, evictable).or_else(|| first_evictable(&self.t2, evictable))
+        } else {
+            first_evictable(&self.t2, evictable).or_else(|| first_evictable(&self.t1, evictable))
+        }
+    }
+}
@@ -1,3 +1,4 @@
+use bytecheck::CheckBytes;
 use rkyv::{Archive, Deserialize, Serialize};
 
 use crate::PAGE_RANGE_COUNT;
@@ -16,6 +17,7 @@ use crate::PAGE_RANGE_COUNT;
     PartialOrd,
     Hash,
 )]
+#[archive_attr(derive(CheckBytes))]
 pub struct RID(pub u64);
 
 impl RID {
@@ -0,0 +1,401 @@
+use std::{collections::HashMap, io, mem::size_of, path::Path, sync::Arc};
+
+use bytecheck::CheckBytes;
+use parking_lot::RwLock;
+use rkyv::{
+    ser::{serializers::BufferSerializer, Serializer},
+    AlignedVec, Archive, Deserialize, Serialize,
+};
+
+use crate::{
+    storage_backend::{FileBackend, StorageBackend},
+    PAGE_SIZE,
+};
+
+pub const SINGLE_FILE_MAGIC: [u8; 8] = *b"CRABFILE";
+pub const SINGLE_FILE_VERSION: u32 = 1;
+
+// Table data pages are handed out this many at a time
+pub const EXTENT_PAGES: usize = 64;
+
+#[derive(Archive, Deserialize, Serialize, Clone, Debug)]
+#[archive_attr(derive(CheckBytes))]
+struct Superblock {
+    magic: [u8; 8],
+    version: u32,
+    // Page run holding the serialized FileDirectory
+    directory_page: u64,
+    directory_pages: u64,
+    directory_len: u64,
+}
+
+// Consecutive pages starting at `start`, `len` is in bytes for blobs and pages otherwise
+#[derive(Archive, Deserialize, Serialize, Clone, Copy, Debug)]
+#[archive_attr(derive(CheckBytes))]
+struct Run {
+    start: u64,
+    len: u64,
+}
+
+#[derive(Archive, Deserialize, Serialize, Clone, Debug, Default)]
+#[archive_attr(derive(CheckBytes))]
+struct FileDirectory {
+    blobs: HashMap<String, Run>,
+    // Name -> first page of every extent, in logical page order
+    extents: HashMap<String, Vec<u64>>,
+    // Runs that can be reused
+    free: Vec<Run>,
+    // First page past the end of everything allocated
+    end: u64,
+}
+
+impl FileDirectory {
+    fn allocate(&mut self, pages: u64) -> u64 {
+        if let Some(i) = self.free.iter().position(|x| x.len >= pages) {
+            let Run { start, len } = self.free[i];
+
+            if len == pages {
+                self.free.remove(i);
+            } else {
+                self.free[i] = Run {
+                    start: start + pages,
+                    len: len - pages,
+                };
+            }
+
+            return start;
+        }
+
+        let start = self.end;
+        self.end += pages;
+        start
+    }
+
+    fn release(&mut self, start: u64, pages: u64) {
+        if pages > 0 {
+            self.free.push(Run { start, len: pages });
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FileState {
+    directory: FileDirectory,
+    // Where the directory the superblock points at lives, in pages
+    directory_run: Run,
+    // Extents were allocated since the directory was last written
+    dirty: bool,
+}
+
+fn page_count(bytes: usize) -> u64 {
+    ((bytes + PAGE_SIZE - 1) / PAGE_SIZE) as u64
+}
+
+/*
+    A whole database in one file. Page 0 is the superblock, which points at a
+    directory of every blob (catalog, page directories, indexes...) and of the
+    extents making up each table's data pages.
+
+    Nothing the superblock currently points at is ever overwritten. Blobs and
+    the directory are written to freshly allocated pages, and the space they
+    replace only becomes free in the new directory, so a crash before the
+    superblock is rewritten leaves the previous state intact.
+*/
+#[derive(Debug)]
+pub struct SingleFile {
+    pages: FileBackend,
+    // Page I/O only reads it, writers take it to allocate or write blobs
+    state: RwLock<FileState>,
+}
+
+impl SingleFile {
+    pub fn open(path: &Path, read_only: bool) -> io::Result<Self> {
+        let pages = if read_only {
+            FileBackend::open_read_only(path)?
+        } else {
+            FileBackend::open(path)?
+        };
+
+        let mut page = [0; PAGE_SIZE];
+        pages.read_page(0, &mut page)?;
+
+        if page.iter().all(|x| *x == 0) {
+            let file = SingleFile {
+                pages,
+                state: RwLock::new(FileState {
+                    directory: FileDirectory {
+                        end: 1,
+                        ..Default::default()
+                    },
+                    directory_run: Run { start: 0, len: 0 },
+                    dirty: true,
+                }),
+            };
+
+            if !read_only {
+                file.write_directory(&mut file.state.write(), None)?;
+            }
+
+            return Ok(file);
+        }
+
+        let mut superblock_bytes = AlignedVec::new();
+        superblock_bytes
+            .extend_from_slice(&page[0..size_of::<<Superblock as Archive>::Archived>()]);
+
+        let superblock = rkyv::from_bytes::<Superblock>(&superblock_bytes)
+            .map_err(|e| invalid_data(&format!("Invalid superblock: {e}")))?;
+
+        if superblock.magic != SINGLE_FILE_MAGIC {
+            return Err(invalid_data("Not a crabstore database file"));
+        }
+
+        if superblock.version != SINGLE_FILE_VERSION {
+            return Err(invalid_data("Unsupported database file version"));
+        }
+
+        let bytes = read_run(
+            &pages,
+            superblock.directory_page,
+            superblock.directory_len as usize,
+        )?;
+
+        let directory = rkyv::from_bytes::<FileDirectory>(&bytes)
+            .map_err(|e| invalid_data(&format!("Invalid file directory: {e}")))?;
+
+        Ok(SingleFile {
+            pages,
+            state: RwLock::new(FileState {
+                directory,
+                directory_run: Run {
+                    start: superblock.directory_page,
+                    len: superblock.directory_pages,
+                },
+                dirty: false,
+            }),
+        })
+    }
+
+    pub fn read_blob(&self, name: &str) -> io::Result<Option<AlignedVec>> {
+        let run = self.state.read().directory.blobs.get(name).copied();
+
+        match run {
+            Some(Run { start, len }) => Ok(Some(read_run(&self.pages, start, len as usize)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn write_blob(&self, name: &str, bytes: &[u8]) -> io::Result<()> {
+        let mut state = self.state.write();
+
+        let start = state.directory.allocate(page_count(bytes.len()));
+
+        for (i, chunk) in bytes.chunks(PAGE_SIZE).enumerate() {
+            let mut page = [0; PAGE_SIZE];
+            page[..chunk.len()].copy_from_slice(chunk);
+
+            self.pages.write_page(start as usize + i, &page)?;
+        }
+
+        let replaced = state
+            .directory
+            .blobs
+            .insert(
+                name.into(),
+                Run {
+                    start,
+                    len: bytes.len() as u64,
+                },
+            )
+            .map(|old| Run {
+                start: old.start,
+                len: page_count(old.len as usize),
+            });
+
+        self.write_directory(&mut state, replaced)
+    }
+
+    /*
+        Writes the directory to new pages, then points the superblock at it.
+        `replaced` and the old directory are only freed once the new
+        directory's pages are allocated, so it can't be written over them.
+    */
+    fn write_directory(&self, state: &mut FileState, replaced: Option<Run>) -> io::Result<()> {
+        let old = state.directory_run;
+
+        let mut bytes = rkyv::to_bytes::<_, 4096>(&state.directory)
+            .map_err(|_| invalid_data("Unable to serialize file directory"))?;
+
+        // Allocating and freeing both change the directory, leave a page of slack
+        let mut pages = page_count(bytes.len()) + 1;
+        let mut start = state.directory.allocate(pages);
+
+        state.directory.release(old.start, old.len);
+        if let Some(replaced) = replaced {
+            state.directory.release(replaced.start, replaced.len);
+        }
+
+        loop {
+            bytes = rkyv::to_bytes::<_, 4096>(&state.directory)
+                .map_err(|_| invalid_data("Unable to serialize file directory"))?;
+
+            if page_count(bytes.len()) <= pages {
+                break;
+            }
+
+            // Grow at the end, the free list now holds pages still in use
+            state.directory.release(start, pages);
+            pages = page_count(bytes.len()) + 1;
+            start = state.directory.end;
+            state.directory.end += pages;
+        }
+
+        for (i, chunk) in bytes.chunks(PAGE_SIZE).enumerate() {
+            let mut page = [0; PAGE_SIZE];
+            page[..chunk.len()].copy_from_slice(chunk);
+
+            self.pages.write_page(start as usize + i, &page)?;
+        }
+
+        self.pages.sync()?;
+
+        let superblock = Superblock {
+            magic: SINGLE_FILE_MAGIC,
+            version: SINGLE_FILE_VERSION,
+            directory_page: start,
+            directory_pages: pages,
+            directory_len: bytes.len() as u64,
+        };
+
+        let mut page = [0; PAGE_SIZE];
+        let mut serializer = BufferSerializer::new(&mut page);
+
+        serializer
+            .serialize_value(&superblock)
+            .expect("Unable to serialize superblock");
+
+        self.pages.write_page(0, &page)?;
+        self.pages.sync()?;
+
+        state.directory_run = Run { start, len: pages };
+        state.dirty = false;
+
+        Ok(())
+    }
+
+    fn physical_page(&self, name: &str, page_id: usize, allocate: bool) -> io::Result<Option<u64>> {
+        let extent = page_id / EXTENT_PAGES;
+        let offset = (page_id % EXTENT_PAGES) as u64;
+
+        let state = self.state.read();
+
+        match state
+            .directory
+            .extents
+            .get(name)
+            .and_then(|x| x.get(extent))
+        {
+            Some(start) => return Ok(Some(start + offset)),
+            None if !allocate => return Ok(None),
+            None => drop(state),
+        }
+
+        let mut state = self.state.write();
+
+        // Another write may have allocated it in the meantime
+        let extents = state.directory.extents.get(name).map_or(0, |x| x.len());
+
+        if extent >= extents {
+            for _ in extents..=extent {
+                let start = state.directory.allocate(EXTENT_PAGES as u64);
+
+                // Reused space still holds whatever was there before
+                let zeroes = [0; PAGE_SIZE];
+                let requests = (0..EXTENT_PAGES)
+                    .map(|i| (start as usize + i, &zeroes))
+                    .collect::<Vec<_>>();
+
+                self.pages.write_pages(&requests)?;
+
+                state
+                    .directory
+                    .extents
+                    .entry(name.into())
+                    .or_default()
+                    .push(start);
+            }
+
+            state.dirty = true;
+        }
+
+        let start = state.directory.extents[name][extent];
+
+        Ok(Some(start + offset))
+    }
+
+    pub fn pages(self: &Arc<Self>, name: &str) -> Arc<dyn StorageBackend> {
+        Arc::new(SingleFilePages {
+            file: Arc::clone(self),
+            name: name.into(),
+        })
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn read_run(pages: &FileBackend, start: u64, len: usize) -> io::Result<AlignedVec> {
+    let mut bytes = AlignedVec::with_capacity(len);
+    let mut page = [0; PAGE_SIZE];
+
+    for i in 0..page_count(len) {
+        pages.read_page((start + i) as usize, &mut page)?;
+
+        let remaining = len - bytes.len();
+        bytes.extend_from_slice(&page[..remaining.min(PAGE_SIZE)]);
+    }
+
+    Ok(bytes)
+}
+
+// One table's data pages inside a single file database
+#[derive(Debug)]
+pub struct SingleFilePages {
+    file: Arc<SingleFile>,
+    name: String,
+}
+
+impl StorageBackend for SingleFilePages {
+    fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<()> {
+        match self.file.physical_page(&self.name, page_id, false)? {
+            Some(physical) => self.file.pages.read_page(physical as usize, page),
+            None => {
+                page.fill(0);
+                Ok(())
+            }
+        }
+    }
+
+    fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> io::Result<()> {
+        let physical = self
+            .file
+            .physical_page(&self.name, page_id, true)?
+            .expect("Extent wasn't allocated");
+
+        self.file.pages.write_page(physical as usize, page)
+    }
+
+    // Also makes newly allocated extents durable
+    fn sync(&self) -> io::Result<()> {
+        if self.file.state.read().dirty {
+            let mut state = self.file.state.write();
+
+            if state.dirty {
+                return self.file.write_directory(&mut state, None);
+            }
+        }
+
+        self.file.pages.sync()
+    }
+}
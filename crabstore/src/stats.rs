@@ -0,0 +1,165 @@
+use std::{
+    ops::AddAssign,
+    time::{Duration, SystemTime},
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub size: usize,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    // Evictions that had to write the victim back first
+    pub dirty_evictions: u64,
+    // Pages written back by flush_all or the background writer
+    pub flushes: u64,
+    pub dirty_pages: usize,
+    pub pinned_pages: usize,
+}
+
+impl BufferPoolStats {
+    pub fn hit_rate(&self) -> f64 {
+        let accesses = self.hits + self.misses;
+
+        if accesses == 0 {
+            return 0.0;
+        }
+
+        self.hits as f64 / accesses as f64
+    }
+}
+
+impl AddAssign for BufferPoolStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.size += rhs.size;
+        self.hits += rhs.hits;
+        self.misses += rhs.misses;
+        self.evictions += rhs.evictions;
+        self.dirty_evictions += rhs.dirty_evictions;
+        self.flushes += rhs.flushes;
+        self.dirty_pages += rhs.dirty_pages;
+        self.pinned_pages += rhs.pinned_pages;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    // Pages read by the read ahead thread and how many of those were used
+    pub prefetch_reads: u64,
+    pub prefetch_hits: u64,
+    // Pages in the file and how many of those are free for reuse
+    pub pages: usize,
+    pub free_pages: usize,
+}
+
+impl AddAssign for DiskStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.reads += rhs.reads;
+        self.writes += rhs.writes;
+        self.syncs += rhs.syncs;
+        self.prefetch_reads += rhs.prefetch_reads;
+        self.prefetch_hits += rhs.prefetch_hits;
+        self.pages += rhs.pages;
+        self.free_pages += rhs.free_pages;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    pub ranges_merged: u64,
+    // Base records brought up to date with their latest tail record
+    pub records_consolidated: u64,
+    pub time_spent: Duration,
+    pub last_merge: Option<SystemTime>,
+    // Merged tail pages and replaced base columns handed back for reuse
+    pub pages_reclaimed: u64,
+    // Merges given up on because the table was persisted without finishing them
+    pub merges_abandoned: u64,
+    // Merges that panicked partway
+    pub merges_panicked: u64,
+    // The range and message of the latest of them
+    pub last_panic: Option<String>,
+}
+
+impl AddAssign for MergeStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.ranges_merged += rhs.ranges_merged;
+        self.records_consolidated += rhs.records_consolidated;
+        self.time_spent += rhs.time_spent;
+        self.last_merge = self.last_merge.max(rhs.last_merge);
+        self.pages_reclaimed += rhs.pages_reclaimed;
+        self.merges_abandoned += rhs.merges_abandoned;
+        self.merges_panicked += rhs.merges_panicked;
+
+        if rhs.last_panic.is_some() {
+            self.last_panic = rhs.last_panic;
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LockStats {
+    // Lock requests that had to wait for another transaction
+    pub waits: u64,
+    pub timeouts: u64,
+    // Requests given up on to break or avoid a deadlock
+    pub deadlocks: u64,
+    // Record locks traded for a lock on their whole range
+    pub escalations: u64,
+    // Records some transaction holds a lock on
+    pub locked_records: usize,
+}
+
+impl AddAssign for LockStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.waits += rhs.waits;
+        self.timeouts += rhs.timeouts;
+        self.deadlocks += rhs.deadlocks;
+        self.escalations += rhs.escalations;
+        self.locked_records += rhs.locked_records;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TableStats {
+    pub bufferpool: BufferPoolStats,
+    pub disk: DiskStats,
+    pub merge: MergeStats,
+    pub locks: LockStats,
+}
+
+impl AddAssign for TableStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.bufferpool += rhs.bufferpool;
+        self.disk += rhs.disk;
+        self.merge += rhs.merge;
+        self.locks += rhs.locks;
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DatabaseStats {
+    pub tables: Vec<(String, TableStats)>,
+}
+
+impl DatabaseStats {
+    pub fn total(&self) -> TableStats {
+        let mut total = TableStats::default();
+
+        for (_, stats) in self.tables.iter() {
+            total += stats.clone();
+        }
+
+        total
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableStats> {
+        self.tables
+            .iter()
+            .find(|(table, _)| table == name)
+            .map(|(_, stats)| stats)
+    }
+}
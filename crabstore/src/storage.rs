@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bytecheck::CheckBytes;
+use parking_lot::{Mutex, RwLock};
+use rkyv::{
+    de::deserializers::SharedDeserializeMap, validation::validators::DefaultValidator, AlignedVec,
+    Archive, Deserialize,
+};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    error::CrabError,
+    migration::FORMAT_VERSION,
+    single_file::SingleFile,
+    storage_backend::{MappedBackend, StorageBackend, StorageBackendKind},
+    PAGE_SIZE,
+};
+
+pub const CATALOG_FILE: &str = "crab_dt.CRAB";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TableFile {
+    Data,
+    PageDirectory,
+    Index,
+    RangeDirectory,
+    // Only in backups, the checkpoint they were last brought up to
+    Backup,
+}
+
+impl TableFile {
+    pub fn name(self, table: &str) -> String {
+        let suffix = match self {
+            TableFile::Data => "_db",
+            TableFile::PageDirectory => "_pd",
+            TableFile::Index => "_id",
+            TableFile::RangeDirectory => "_rd",
+            TableFile::Backup => "_bk",
+        };
+
+        format!("{table}{suffix}.CRAB")
+    }
+}
+
+/*
+    Where a database keeps its files. A directory holds one file per name,
+    memory keeps the same names in maps that live as long as the store, so an
+    in-memory database can still be closed and reopened. A single file packs
+    every name into one file behind a superblock.
+*/
+#[derive(Clone, Debug)]
+pub enum Storage {
+    Directory(PathBuf),
+    Memory(Arc<MemoryStore>),
+    File(Arc<SingleFile>),
+}
+
+impl Storage {
+    pub fn in_memory() -> Self {
+        Storage::Memory(Arc::new(MemoryStore::default()))
+    }
+
+    pub fn single_file(path: &Path, read_only: bool) -> io::Result<Self> {
+        if !read_only {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        Ok(Storage::File(Arc::new(SingleFile::open(path, read_only)?)))
+    }
+
+    pub fn create(&self) -> io::Result<()> {
+        match self {
+            Storage::Directory(directory) => fs::create_dir_all(directory),
+            Storage::Memory(_) | Storage::File(_) => Ok(()),
+        }
+    }
+
+    pub fn path(&self, name: &str) -> Option<PathBuf> {
+        match self {
+            Storage::Directory(directory) => Some(directory.join(name)),
+            Storage::Memory(_) | Storage::File(_) => None,
+        }
+    }
+
+    pub fn blob(&self, name: &str) -> Blob {
+        Blob {
+            storage: self.clone(),
+            name: name.into(),
+        }
+    }
+
+    pub fn open_pages(
+        &self,
+        name: &str,
+        kind: StorageBackendKind,
+    ) -> io::Result<Arc<dyn StorageBackend>> {
+        match self {
+            Storage::Directory(directory) => Ok(kind.open(&directory.join(name))?.into()),
+            Storage::Memory(store) => Ok(store.pages(name)),
+            Storage::File(file) => Ok(file.pages(name)),
+        }
+    }
+
+    // Only a directory has a file per table to map, the rest go through the buffer pool
+    pub fn open_pages_read_only(&self, name: &str) -> io::Result<Arc<dyn StorageBackend>> {
+        match self {
+            Storage::Directory(directory) => {
+                Ok(Arc::new(MappedBackend::open(&directory.join(name))?))
+            }
+            Storage::Memory(store) => Ok(store.pages(name)),
+            Storage::File(file) => Ok(file.pages(name)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blobs: Mutex<HashMap<String, Arc<AlignedVec>>>,
+    pages: Mutex<HashMap<String, Arc<MemoryBackend>>>,
+}
+
+impl MemoryStore {
+    fn pages(&self, name: &str) -> Arc<dyn StorageBackend> {
+        Arc::clone(self.pages.lock().entry(name.into()).or_default()) as Arc<dyn StorageBackend>
+    }
+}
+
+// Validates an archive read from `file` before deserializing it
+pub fn decode<T>(file: &str, bytes: &[u8]) -> Result<T, CrabError>
+where
+    T: Archive,
+    T::Archived: for<'a> CheckBytes<DefaultValidator<'a>> + Deserialize<T, SharedDeserializeMap>,
+{
+    rkyv::from_bytes::<T>(bytes).map_err(|e| CrabError::Corrupt {
+        file: file.into(),
+        error: e.to_string(),
+    })
+}
+
+/*
+    Blobs written before format versions, magic then generation. Files
+    without either header are the baseline's bare archives, read as version
+    0 from generation 0.
+*/
+const LEGACY_BLOB_MAGIC: [u8; 8] = *b"CRABBLOB";
+const LEGACY_BLOB_HEADER_SIZE: usize = 16;
+
+// Magic, format version, generation and padding, a multiple of 16 so the archive stays aligned
+const BLOB_MAGIC: [u8; 8] = *b"CRABBLB2";
+const BLOB_HEADER_SIZE: usize = 32;
+
+#[derive(Debug)]
+pub struct BlobContents {
+    pub version: u32,
+    pub generation: u64,
+    pub bytes: AlignedVec,
+}
+
+/*
+    A named, whole-file value such as a page directory or the catalog.
+    Every write records the format version and the generation it belongs
+    to, and replaces the old value atomically. Reads return None if it was
+    never written.
+*/
+#[derive(Clone, Debug)]
+pub struct Blob {
+    storage: Storage,
+    name: String,
+}
+
+impl Blob {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn read(&self) -> io::Result<Option<BlobContents>> {
+        let raw = match &self.storage {
+            Storage::Directory(directory) => read_file(&directory.join(&self.name))?,
+            Storage::Memory(store) => store
+                .blobs
+                .lock()
+                .get(&self.name)
+                .map(|x| x.as_ref().clone()),
+            Storage::File(file) => file.read_blob(&self.name)?,
+        };
+
+        let raw = match raw {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+
+        let (version, generation, header_size) = match raw.get(..8) {
+            Some(magic) if magic == BLOB_MAGIC && raw.len() >= BLOB_HEADER_SIZE => (
+                u32::from_le_bytes(raw[8..12].try_into().unwrap()),
+                u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+                BLOB_HEADER_SIZE,
+            ),
+            Some(magic) if magic == LEGACY_BLOB_MAGIC && raw.len() >= LEGACY_BLOB_HEADER_SIZE => (
+                1,
+                u64::from_le_bytes(raw[8..16].try_into().unwrap()),
+                LEGACY_BLOB_HEADER_SIZE,
+            ),
+            // The baseline created its files empty before it first wrote them
+            _ if raw.is_empty() => return Ok(None),
+            _ => (0, 0, 0),
+        };
+
+        let mut bytes = AlignedVec::with_capacity(raw.len() - header_size);
+        bytes.extend_from_slice(&raw[header_size..]);
+
+        Ok(Some(BlobContents {
+            version,
+            generation,
+            bytes,
+        }))
+    }
+
+    // Like `read`, but the blob has to be in the current format and from `generation`
+    pub fn read_generation(&self, generation: u64) -> Result<Option<AlignedVec>, CrabError> {
+        let contents = match self.read().map_err(|e| CrabError::io(&self.name, e))? {
+            Some(contents) => contents,
+            None => return Ok(None),
+        };
+
+        if contents.version != FORMAT_VERSION {
+            return Err(CrabError::Version {
+                file: self.name.clone(),
+                found: contents.version,
+            });
+        }
+
+        if contents.generation != generation {
+            return Err(CrabError::Inconsistent {
+                file: self.name.clone(),
+                expected: generation,
+                found: contents.generation,
+            });
+        }
+
+        Ok(Some(contents.bytes))
+    }
+
+    pub fn write(&self, generation: u64, bytes: &[u8]) -> io::Result<()> {
+        self.write_version(FORMAT_VERSION, generation, bytes)
+    }
+
+    // Migrations write each intermediate version's blobs with that version
+    pub(crate) fn write_version(
+        &self,
+        version: u32,
+        generation: u64,
+        bytes: &[u8],
+    ) -> io::Result<()> {
+        let mut raw = Vec::with_capacity(BLOB_HEADER_SIZE + bytes.len());
+        raw.extend_from_slice(&BLOB_MAGIC);
+        raw.extend_from_slice(&version.to_le_bytes());
+        raw.extend_from_slice(&[0; 4]);
+        raw.extend_from_slice(&generation.to_le_bytes());
+        raw.extend_from_slice(&[0; 8]);
+        raw.extend_from_slice(bytes);
+
+        match &self.storage {
+            Storage::Directory(directory) => write_file_atomic(directory, &self.name, &raw),
+            Storage::Memory(store) => {
+                let mut blob = AlignedVec::with_capacity(raw.len());
+                blob.extend_from_slice(&raw);
+
+                store.blobs.lock().insert(self.name.clone(), Arc::new(blob));
+
+                Ok(())
+            }
+            Storage::File(file) => file.write_blob(&self.name, &raw),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> io::Result<Option<AlignedVec>> {
+    let mut file = match File::options().read(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+
+    // rkyv needs the archive to be aligned
+    let mut aligned = AlignedVec::with_capacity(bytes.len());
+    aligned.extend_from_slice(&bytes);
+
+    Ok(Some(aligned))
+}
+
+/*
+    Writes a temporary file and renames it over `name`, so a crash leaves
+    either the old or the new contents but never a partial file. The
+    directory is synced too, otherwise the rename itself might not survive.
+*/
+fn write_file_atomic(directory: &Path, name: &str, bytes: &[u8]) -> io::Result<()> {
+    let temp = directory.join(format!("{name}.tmp"));
+
+    let mut file = File::options()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(&temp)?;
+
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(&temp, directory.join(name))?;
+
+    #[cfg(unix)]
+    File::open(directory)?.sync_all()?;
+
+    Ok(())
+}
+
+// Pages of an in-memory table, unwritten pages read as zeroes
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    pages: RwLock<FxHashMap<usize, Box<[u8; PAGE_SIZE]>>>,
+}
+
+impl StorageBackend for MemoryBackend {
+    fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<()> {
+        match self.pages.read().get(&page_id) {
+            Some(stored) => page.copy_from_slice(stored.as_slice()),
+            None => page.fill(0),
+        }
+
+        Ok(())
+    }
+
+    fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> io::Result<()> {
+        self.pages.write().insert(page_id, Box::new(*page));
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
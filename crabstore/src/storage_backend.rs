@@ -0,0 +1,451 @@
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+#[cfg(target_os = "linux")]
+use std::os::unix::prelude::FileExt;
+
+#[cfg(target_os = "windows")]
+use std::os::windows::prelude::FileExt;
+
+use memmap2::Mmap;
+
+use crate::PAGE_SIZE;
+
+/*
+    Page granular storage for a table file. Implementations must allow
+    concurrent calls, reads past the end of the file return zeroed pages.
+*/
+pub trait StorageBackend: Send + Sync + Debug {
+    fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<()>;
+
+    fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> io::Result<()>;
+
+    fn read_pages(&self, pages: &mut [(usize, &mut [u8; PAGE_SIZE])]) -> io::Result<()> {
+        for (page_id, page) in pages.iter_mut() {
+            self.read_page(*page_id, page)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_pages(&self, pages: &[(usize, &[u8; PAGE_SIZE])]) -> io::Result<()> {
+        for (page_id, page) in pages.iter() {
+            self.write_page(*page_id, page)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()>;
+
+    // The whole file mapped read-only, if the backend has one
+    fn mapped(&self) -> Option<Arc<Mmap>> {
+        None
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorageBackendKind {
+    #[default]
+    File,
+    #[cfg(target_os = "linux")]
+    Uring,
+}
+
+impl StorageBackendKind {
+    pub fn open(self, path: &Path) -> io::Result<Box<dyn StorageBackend>> {
+        Ok(match self {
+            StorageBackendKind::File => Box::new(FileBackend::open(path)?),
+            #[cfg(target_os = "linux")]
+            StorageBackendKind::Uring => Box::new(uring::UringBackend::open(path)?),
+        })
+    }
+}
+
+impl FromStr for StorageBackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "file" | "pread" => Ok(StorageBackendKind::File),
+            #[cfg(target_os = "linux")]
+            "uring" | "io_uring" | "io-uring" => Ok(StorageBackendKind::Uring),
+            _ => Err(format!("Unknown storage backend \"{s}\"")),
+        }
+    }
+}
+
+fn zero_tail(page: &mut [u8; PAGE_SIZE], read: usize) {
+    page[read..].fill(0);
+}
+
+/*
+    Positional reads and writes on a plain file. pread/pwrite don't touch the
+    file cursor, so no lock is needed around them.
+*/
+#[derive(Debug)]
+pub struct FileBackend {
+    file: File,
+}
+
+impl FileBackend {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(FileBackend {
+            file: OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)?,
+        })
+    }
+
+    pub fn open_read_only(path: &Path) -> io::Result<Self> {
+        Ok(FileBackend {
+            file: File::open(path)?,
+        })
+    }
+
+    #[cfg(target_os = "linux")]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.read_at(buf, offset)
+    }
+
+    #[cfg(target_os = "windows")]
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.file.seek_read(buf, offset)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.file.write_at(buf, offset)
+    }
+
+    #[cfg(target_os = "windows")]
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.file.seek_write(buf, offset)
+    }
+}
+
+impl StorageBackend for FileBackend {
+    fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<()> {
+        let offset = (page_id * PAGE_SIZE) as u64;
+        let mut read = 0;
+
+        while read < PAGE_SIZE {
+            match self.read_at(&mut page[read..], offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+
+        zero_tail(page, read);
+
+        Ok(())
+    }
+
+    fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> io::Result<()> {
+        let offset = (page_id * PAGE_SIZE) as u64;
+        let mut written = 0;
+
+        while written < PAGE_SIZE {
+            match self.write_at(&page[written..], offset + written as u64)? {
+                0 => return Err(io::ErrorKind::WriteZero.into()),
+                n => written += n,
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/*
+    Maps the table file read-only. Nothing is written and the file is opened
+    without write access, so any number of processes can map it at once.
+*/
+#[derive(Debug)]
+pub struct MappedBackend {
+    map: Arc<Mmap>,
+}
+
+impl MappedBackend {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+
+        // Safety: the mapping is only read, and nothing in this process writes
+        // the file while it is open read-only
+        let map = unsafe { Mmap::map(&file)? };
+
+        Ok(MappedBackend { map: Arc::new(map) })
+    }
+}
+
+impl StorageBackend for MappedBackend {
+    fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<()> {
+        let offset = (page_id * PAGE_SIZE).min(self.map.len());
+        let end = (offset + PAGE_SIZE).min(self.map.len());
+
+        page[..end - offset].copy_from_slice(&self.map[offset..end]);
+        zero_tail(page, end - offset);
+
+        Ok(())
+    }
+
+    fn write_page(&self, _page_id: usize, _page: &[u8; PAGE_SIZE]) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "Table file is mapped read-only",
+        ))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn mapped(&self) -> Option<Arc<Mmap>> {
+        Some(Arc::clone(&self.map))
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub mod uring {
+    use std::{
+        fs::{File, OpenOptions},
+        io, mem,
+        os::unix::{fs::OpenOptionsExt, io::AsRawFd},
+        path::Path,
+    };
+
+    use io_uring::{opcode, types, IoUring};
+    use parking_lot::Mutex;
+
+    use super::{zero_tail, StorageBackend};
+    use crate::PAGE_SIZE;
+
+    const RING_DEPTH: usize = 64;
+
+    // IORING_ENTER_GETEVENTS
+    const ENTER_GETEVENTS: u32 = 1;
+
+    // O_DIRECT needs buffers aligned to the logical block size
+    #[repr(C, align(4096))]
+    struct AlignedPage([u8; PAGE_SIZE]);
+
+    impl AlignedPage {
+        fn zeroed() -> Box<Self> {
+            Box::new(AlignedPage([0; PAGE_SIZE]))
+        }
+    }
+
+    // Gives up on buffers an unfinished operation may still use
+    fn leak(pages: &mut [(usize, Box<AlignedPage>)]) {
+        for (_, buffer) in pages {
+            mem::forget(mem::replace(buffer, AlignedPage::zeroed()));
+        }
+    }
+
+    enum Op {
+        Read,
+        Write,
+    }
+
+    /*
+        Bypasses the page cache with O_DIRECT and submits a whole batch of
+        page reads or writes to io_uring at once. Batches are serialized by
+        the ring lock, the pages within a batch are transferred concurrently.
+    */
+    pub struct UringBackend {
+        file: File,
+        ring: Mutex<IoUring>,
+    }
+
+    impl std::fmt::Debug for UringBackend {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_struct("UringBackend")
+                .field("file", &self.file)
+                .finish()
+        }
+    }
+
+    impl UringBackend {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            Ok(UringBackend {
+                file: OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .custom_flags(libc::O_DIRECT)
+                    .open(path)?,
+                ring: Mutex::new(IoUring::new(RING_DEPTH as u32)?),
+            })
+        }
+
+        // Runs one operation per buffer, returns the byte count of each
+        fn submit(
+            &self,
+            op: Op,
+            pages: &mut [(usize, Box<AlignedPage>)],
+        ) -> io::Result<Vec<usize>> {
+            let mut results = vec![0; pages.len()];
+            let fd = types::Fd(self.file.as_raw_fd());
+            let mut ring = self.ring.lock();
+
+            for (chunk_index, chunk) in pages.chunks_mut(RING_DEPTH).enumerate() {
+                let first = chunk_index * RING_DEPTH;
+
+                for (i, (page_id, buffer)) in chunk.iter_mut().enumerate() {
+                    let offset = (*page_id * PAGE_SIZE) as u64;
+
+                    let entry = match op {
+                        Op::Read => opcode::Read::new(fd, buffer.0.as_mut_ptr(), PAGE_SIZE as u32)
+                            .offset(offset)
+                            .build(),
+                        Op::Write => opcode::Write::new(fd, buffer.0.as_ptr(), PAGE_SIZE as u32)
+                            .offset(offset)
+                            .build(),
+                    }
+                    .user_data((first + i) as u64);
+
+                    // The buffers outlive the submission since we wait for every
+                    // completion below before returning
+                    unsafe {
+                        ring.submission()
+                            .push(&entry)
+                            .expect("Submission queue full");
+                    }
+                }
+
+                let mut pending = chunk.len();
+                let mut error = None;
+
+                while pending > 0 {
+                    let waited = match error {
+                        None => ring.submit_and_wait(pending),
+                        // Only wait, the entries the kernel refused stay queued
+                        Some(_) => unsafe {
+                            ring.submitter().enter::<libc::sigset_t>(
+                                0,
+                                pending as u32,
+                                ENTER_GETEVENTS,
+                                None,
+                            )
+                        },
+                    };
+
+                    match waited {
+                        Ok(_) => {}
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) if error.is_none() => {
+                            // io_uring_enter only fails when it took none of the
+                            // queued entries, wait for the ones it took earlier
+                            pending -= ring.submission().len();
+                            error = Some(e);
+                        }
+                        Err(e) => {
+                            // The kernel may still be transferring into them
+                            leak(chunk);
+                            *ring = IoUring::new(RING_DEPTH as u32)?;
+
+                            return Err(e);
+                        }
+                    }
+
+                    for completion in ring.completion() {
+                        let index = completion.user_data() as usize;
+
+                        if !(first..first + chunk.len()).contains(&index) {
+                            error.get_or_insert_with(|| {
+                                io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("Completion for unknown entry {index}"),
+                                )
+                            });
+                            continue;
+                        }
+
+                        pending -= 1;
+
+                        match completion.result() {
+                            result if result < 0 => {
+                                error.get_or_insert(io::Error::from_raw_os_error(-result));
+                            }
+                            result => results[index] = result as usize,
+                        }
+                    }
+                }
+
+                if let Some(error) = error {
+                    // Don't let a later batch submit the entries the kernel refused
+                    if !ring.submission().is_empty() {
+                        leak(chunk);
+                        *ring = IoUring::new(RING_DEPTH as u32)?;
+                    }
+
+                    return Err(error);
+                }
+            }
+
+            Ok(results)
+        }
+    }
+
+    impl StorageBackend for UringBackend {
+        fn read_page(&self, page_id: usize, page: &mut [u8; PAGE_SIZE]) -> io::Result<()> {
+            self.read_pages(&mut [(page_id, page)])
+        }
+
+        fn write_page(&self, page_id: usize, page: &[u8; PAGE_SIZE]) -> io::Result<()> {
+            self.write_pages(&[(page_id, page)])
+        }
+
+        fn read_pages(&self, pages: &mut [(usize, &mut [u8; PAGE_SIZE])]) -> io::Result<()> {
+            let mut buffers = pages
+                .iter()
+                .map(|(page_id, _)| (*page_id, AlignedPage::zeroed()))
+                .collect::<Vec<_>>();
+
+            let read = self.submit(Op::Read, &mut buffers)?;
+
+            for ((_, page), ((_, buffer), read)) in pages.iter_mut().zip(buffers.iter().zip(read)) {
+                page.copy_from_slice(&buffer.0);
+                zero_tail(page, read);
+            }
+
+            Ok(())
+        }
+
+        fn write_pages(&self, pages: &[(usize, &[u8; PAGE_SIZE])]) -> io::Result<()> {
+            let mut buffers = pages
+                .iter()
+                .map(|(page_id, page)| {
+                    let mut buffer = AlignedPage::zeroed();
+                    buffer.0 = **page;
+                    (*page_id, buffer)
+                })
+                .collect::<Vec<_>>();
+
+            if self
+                .submit(Op::Write, &mut buffers)?
+                .into_iter()
+                .any(|x| x != PAGE_SIZE)
+            {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+
+            Ok(())
+        }
+
+        fn sync(&self) -> io::Result<()> {
+            self.file.sync_data()
+        }
+    }
+}
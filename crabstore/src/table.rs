@@ -1,36 +1,44 @@
 use crate::{
-    bufferpool::{BufferPool, BufferPoolFrame},
+    backup::{BackupReport, BACKUP_BATCH},
+    bufferpool::{BufferPool, BufferPoolError, BufferPoolFrame, PageGuard},
+    config::Config,
     disk_manager::DiskManager,
-    lock_manager::{LockManager, LockType},
+    epoch::{EpochGuard, Epochs},
+    error::CrabError,
+    filling::FillingPages,
+    lock_manager::{LockManager, LockPolicy, LockTarget, LockType, Locker},
+    merge::MergePolicy,
+    merge_scheduler::MergeScheduler,
+    migration::FORMAT_VERSION,
+    mvcc::{self, Clock, Snapshot, UntrackedWrite, Versions, Write},
     page::PhysicalPage,
     range_directory::RangeDirectory,
     record::Record,
+    replacement_policy::AccessHint,
     rid::RID,
+    stats::{MergeStats, TableStats},
+    storage::{decode, Blob, BlobContents, Storage, TableFile},
+    storage_backend::{StorageBackend, StorageBackendKind},
     transaction::{IndexMutation, Transaction},
-    BUFFERPOOL_SIZE, METADATA_BASE_RID, METADATA_PAGE_HEADER, NUM_STATIC_COLUMNS, PAGE_RANGE_COUNT,
-    PAGE_SIZE, PAGE_SLOTS,
+    METADATA_BASE_RID, METADATA_PAGE_HEADER, PAGE_RANGE_COUNT, PAGE_SIZE, PAGE_SLOTS,
 };
 use crate::{index::Index, RID_INVALID};
 use crate::{
     page::{Page, PageRange},
     page_directory::PageDirectory,
 };
-use crate::{
-    record, METADATA_INDIRECTION, METADATA_RID, METADATA_SCHEMA_ENCODING, NUM_METADATA_COLUMNS,
-};
-use parking_lot::{lock_api::RawMutex, Mutex, RwLock};
+use crate::{METADATA_INDIRECTION, METADATA_RID, METADATA_SCHEMA_ENCODING, NUM_METADATA_COLUMNS};
+use bytecheck::CheckBytes;
+use parking_lot::{Mutex, MutexGuard, RwLock};
 use rkyv::{
     ser::{serializers::BufferSerializer, Serializer},
-    with::Lock,
-    Archive, Deserialize, Serialize,
+    AlignedVec, Archive, Deserialize, Serialize,
 };
-use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 use std::{
     borrow::BorrowMut,
     mem::size_of,
-    path::Path,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
     },
 };
@@ -38,34 +46,85 @@ use std::{
     fmt,
     ops::{RangeBounds, RangeInclusive},
 };
-use std::{
-    hash::BuildHasherDefault,
-    sync::mpsc::{channel, Sender},
-    thread::{self, JoinHandle},
-};
 
 #[derive(Archive, Deserialize, Serialize, Clone, Debug)]
+#[archive_attr(derive(CheckBytes))]
 pub struct TableHeaderPage {
-    num_columns: usize,
+    pub(crate) num_columns: usize,
     primary_key_index: usize,
     next_free_page: usize,
     next_rid: u64,
     next_tid: u64,
+    // Checkpoint that wrote this header, the table's blobs have to match it
+    pub(crate) generation: u64,
+    // Zero in headers written before format versions
+    pub(crate) version: u32,
+}
+
+impl TableHeaderPage {
+    // The header is archived at the start of page 0
+    pub(crate) fn from_page(page: &[u8; PAGE_SIZE], file: &str) -> Result<Self, CrabError> {
+        // Copied out so it's aligned
+        let mut bytes = AlignedVec::new();
+        bytes.extend_from_slice(&page[0..size_of::<<TableHeaderPage as Archive>::Archived>()]);
+
+        decode::<TableHeaderPage>(file, &bytes)
+    }
+
+    pub(crate) fn to_page(&self) -> [u8; PAGE_SIZE] {
+        let mut page = [0; PAGE_SIZE];
+        let mut serializer = BufferSerializer::new(&mut page);
+
+        serializer
+            .serialize_value(self)
+            .expect("Unable to serialize table header");
+
+        page
+    }
+
+    pub(crate) fn format_version(&self) -> u32 {
+        self.version.max(1)
+    }
 }
 
 pub struct Table {
     name: String,
-    num_columns: usize,
+    pub(crate) num_columns: usize,
     primary_key_index: usize,
     pub index: RwLock<Index>,
-    next_rid: AtomicU64,
+    // Hands out base RIDs, merges skip the base pages inserts are still filling
+    pub(crate) filling: Arc<FillingPages>,
     next_tid: AtomicU64,
-    page_dir: Arc<RwLock<PageDirectory>>,
-    range_dir: Arc<Mutex<RangeDirectory>>,
-    bufferpool: Arc<Mutex<BufferPool>>,
-    lock_manager: Arc<LockManager>,
-    disk: Arc<DiskManager>,
-    merge_thread_handle: Mutex<Option<(JoinHandle<()>, Sender<usize>)>>,
+    generation: AtomicU64,
+    pub(crate) page_dir: Arc<RwLock<PageDirectory>>,
+    pub(crate) range_dir: Arc<Mutex<RangeDirectory>>,
+    pub(crate) bufferpool: Arc<Mutex<BufferPool>>,
+    pub(crate) lock_manager: Arc<LockManager>,
+    pub(crate) disk: Arc<DiskManager>,
+    // The scheduler merging this table and the table's id with it
+    pub(crate) merge_scheduler: Mutex<Option<(Arc<MergeScheduler>, usize)>>,
+    pub(crate) merge_policy: MergePolicy,
+    pub(crate) merge_stats: Arc<Mutex<MergeStats>>,
+    // Set while running merges are being abandoned
+    pub(crate) abandon_merges: Arc<AtomicBool>,
+    finish_merges: bool,
+    // Counts queries so an idle merge policy can tell when the table is quiet
+    pub(crate) queries: Arc<AtomicU64>,
+    // Pinned by queries so merges don't reclaim pages they might still read
+    pub(crate) epochs: Arc<Epochs>,
+    // Commit timestamps of the versions running snapshots might not see
+    pub(crate) versions: Arc<Versions>,
+    prefetch_depth: usize,
+    // Dirty pages a checkpoint pins at once
+    flush_batch: usize,
+    read_only: bool,
+    storage: Storage,
+    // Held shared by mutating queries, a backup takes it exclusively while it checkpoints
+    write_gate: RwLock<()>,
+    // Backups of the table run one at a time
+    backup_lock: Mutex<()>,
+    // Checkpoints of the table run one at a time
+    checkpoint_lock: Mutex<()>,
 }
 
 impl Table {
@@ -73,77 +132,118 @@ impl Table {
         name: String,
         num_columns: usize,
         key_index: usize,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
+        storage: &Storage,
+        config: &Config,
+        clock: &Arc<Clock>,
     ) -> Table {
-        let page_dir = Arc::new(RwLock::new(PageDirectory::new(pd_file)));
-        let range_dir = Arc::new(Mutex::new(RangeDirectory::new(rd_file)));
+        let page_dir = Arc::new(RwLock::new(PageDirectory::new(
+            storage.blob(&TableFile::PageDirectory.name(&name)),
+        )));
+        let range_dir = Arc::new(Mutex::new(RangeDirectory::new(
+            storage.blob(&TableFile::RangeDirectory.name(&name)),
+        )));
 
-        let disk = Arc::new(DiskManager::new(db_file).unwrap());
-        let bufferpool = Arc::new(Mutex::new(BufferPool::new(
+        let disk = Arc::new(DiskManager::from_backend(
+            storage
+                .open_pages(&TableFile::Data.name(&name), config.storage_backend)
+                .expect("Failed to create table file"),
+        ));
+        let bufferpool = Arc::new(Mutex::new(BufferPool::from_config(
             Arc::clone(&disk),
-            BUFFERPOOL_SIZE,
+            config,
         )));
-        let merge_thread_handle =
-            Table::spawn_merge_thread(&page_dir, &range_dir, &disk, &bufferpool, num_columns);
+        let index = Index::new(
+            key_index,
+            num_columns,
+            storage.blob(&TableFile::Index.name(&name)),
+        );
 
         Table {
             name,
             num_columns,
             primary_key_index: key_index,
-            index: RwLock::new(Index::new(key_index, num_columns, id_file)),
-            next_rid: 0.into(),
+            index: RwLock::new(index),
+            filling: Default::default(),
             next_tid: (!0 - 1).into(),
+            generation: 0.into(),
             page_dir,
             range_dir,
             disk,
             bufferpool,
-            merge_thread_handle: Mutex::new(Some(merge_thread_handle)),
+            merge_scheduler: Mutex::new(None),
+            merge_policy: config.merge_policy,
+            merge_stats: Default::default(),
+            abandon_merges: Default::default(),
+            finish_merges: config.finish_merges,
+            queries: Default::default(),
+            epochs: Default::default(),
+            versions: Arc::new(Versions::new(clock)),
             lock_manager: Arc::new(LockManager::new()),
+            prefetch_depth: config.prefetch_depth,
+            flush_batch: config.flush_batch.max(1),
+            read_only: config.read_only,
+            storage: storage.clone(),
+            write_gate: RwLock::new(()),
+            backup_lock: Mutex::new(()),
+            checkpoint_lock: Mutex::new(()),
         }
     }
 
     pub fn load(
         name: &str,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
-    ) -> Self {
-        let disk = Arc::new(DiskManager::new(db_file).expect("Failed to open table file"));
+        storage: &Storage,
+        config: &Config,
+        clock: &Arc<Clock>,
+    ) -> Result<Self, CrabError> {
+        let data_file = TableFile::Data.name(name);
+        let backend = if config.read_only {
+            storage.open_pages_read_only(&data_file)
+        } else {
+            storage.open_pages(&data_file, config.storage_backend)
+        };
+
+        let disk = Arc::new(DiskManager::from_backend(
+            backend.map_err(|e| CrabError::io(&data_file, e))?,
+        ));
 
         let mut page = PhysicalPage::default();
 
         disk.read_page(0, &mut page.page);
 
-        let header = unsafe {
-            rkyv::from_bytes_unchecked::<TableHeaderPage>(
-                &page.page[0..size_of::<<TableHeaderPage as Archive>::Archived>()],
-            )
-            .expect("Failed to deserialize table header")
-        };
+        let header = TableHeaderPage::from_page(&page.page, &data_file)?;
+
+        if header.format_version() != FORMAT_VERSION {
+            return Err(CrabError::Version {
+                file: data_file,
+                found: header.format_version(),
+            });
+        }
 
         disk.set_free_page_pointer(header.next_free_page);
 
-        let index = RwLock::new(Index::load(id_file));
-        let page_dir = Arc::new(RwLock::new(PageDirectory::load(pd_file)));
-        let range_dir = Arc::new(Mutex::new(RangeDirectory::load(rd_file)));
-        let bufferpool = Arc::new(Mutex::new(BufferPool::new(
+        let index = RwLock::new(Index::load(
+            storage.blob(&TableFile::Index.name(name)),
+            header.generation,
+        )?);
+        let page_dir = Arc::new(RwLock::new(PageDirectory::load(
+            storage.blob(&TableFile::PageDirectory.name(name)),
+            header.generation,
+        )?));
+        let range_dir = Arc::new(Mutex::new(RangeDirectory::load(
+            storage.blob(&TableFile::RangeDirectory.name(name)),
+            header.generation,
+        )?));
+        let bufferpool = Arc::new(Mutex::new(BufferPool::from_config(
             Arc::clone(&disk),
-            BUFFERPOOL_SIZE,
+            config,
         )));
 
-        let merge_thread_handle = Table::spawn_merge_thread(
-            &page_dir,
-            &range_dir,
-            &disk,
-            &bufferpool,
-            header.num_columns,
-        );
+        // Pages merges replaced before the table was closed
+        if !config.read_only {
+            disk.set_free_pages(page_dir.read().unreferenced_pages(header.next_free_page));
+        }
 
-        Table {
+        Ok(Table {
             name: name.into(),
             num_columns: header.num_columns,
             primary_key_index: header.primary_key_index,
@@ -152,51 +252,328 @@ impl Table {
             range_dir,
             disk,
             bufferpool,
-            next_rid: header.next_rid.into(),
+            filling: Arc::new(FillingPages::new(header.next_rid)),
             next_tid: header.next_tid.into(),
-            merge_thread_handle: Mutex::new(Some(merge_thread_handle)),
+            generation: header.generation.into(),
+            merge_scheduler: Mutex::new(None),
+            merge_policy: config.merge_policy,
+            merge_stats: Default::default(),
+            abandon_merges: Default::default(),
+            finish_merges: config.finish_merges,
+            queries: Default::default(),
+            epochs: Default::default(),
+            versions: Arc::new(Versions::new(clock)),
             lock_manager: Arc::new(LockManager::new()),
-        }
+            prefetch_depth: config.prefetch_depth,
+            flush_batch: config.flush_batch.max(1),
+            read_only: config.read_only,
+            storage: storage.clone(),
+            write_gate: RwLock::new(()),
+            backup_lock: Mutex::new(()),
+            checkpoint_lock: Mutex::new(()),
+        })
     }
 
+    /*
+        Merges only touch what the checkpoint writes when they publish, so
+        running ones are either waited for or abandoned first, depending on
+        the config. Whatever they didn't get to is picked up again once the
+        table is loaded and attached to a merge scheduler.
+    */
     pub fn persist(&self) {
-        let merge_thread_handle =
-            std::mem::replace(&mut *self.merge_thread_handle.lock(), None).unwrap();
-
-        drop(merge_thread_handle.1);
-        merge_thread_handle
-            .0
-            .join()
-            .expect("Failed to join merge thread");
-
-        let header = TableHeaderPage {
-            num_columns: self.num_columns,
-            primary_key_index: self.primary_key_index,
-            next_rid: self.next_rid.load(Ordering::Relaxed),
-            next_tid: self.next_tid.load(Ordering::Relaxed),
-            next_free_page: self.disk.free_page_pointer(),
+        if self.finish_merges {
+            self.detach_merge_scheduler();
+        } else {
+            self.abandon_merges();
+        }
+
+        self.checkpoint();
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    pub fn writable(&self) -> Result<(), CrabError> {
+        if self.read_only {
+            return Err(CrabError::ReadOnly(self.name.clone()));
+        }
+
+        Ok(())
+    }
+
+    /*
+        Writes a checkpoint of the table. The header fields and the page, range
+        and index directories are captured together under the directory locks,
+        which are released again before anything is written, so queries don't
+        wait for the flush and fsyncs. Every dirty page is written back before
+        the metadata that references it, and the header page goes last so it
+        only ever points at a complete checkpoint.
+    */
+    pub fn checkpoint(&self) {
+        self.reclaim_pages();
+        self.checkpoint_then(|_| ());
+    }
+
+    // Checkpoints, then runs `f` with the generation before another checkpoint can start
+    fn checkpoint_then<R>(&self, f: impl FnOnce(u64) -> R) -> R {
+        let _checkpoints = self.checkpoint_lock.lock();
+
+        if self.read_only {
+            return f(self.generation.load(Ordering::Relaxed));
+        }
+
+        let generation = self.generation.load(Ordering::Relaxed) + 1;
+
+        let (header, blobs, released) = {
+            let index = self.index.read_recursive();
+            let range_dir = self.range_dir.lock();
+            let page_dir = self.page_dir.read_recursive();
+
+            // Anything released from here on may still be in the page directory captured below
+            let released = self.disk.take_released_pages();
+
+            let header = TableHeaderPage {
+                num_columns: self.num_columns,
+                primary_key_index: self.primary_key_index,
+                next_rid: self.filling.next_rid(),
+                next_tid: self.next_tid.load(Ordering::Relaxed),
+                next_free_page: self.disk.free_page_pointer(),
+                generation,
+                version: FORMAT_VERSION,
+            };
+
+            let blobs = [
+                (page_dir.blob().clone(), page_dir.to_bytes()),
+                (range_dir.blob().clone(), range_dir.to_bytes()),
+                (index.blob().clone(), index.to_bytes()),
+            ];
+
+            (header, blobs, released)
         };
 
-        let mut page = [0; PAGE_SIZE];
-        let mut serializer = BufferSerializer::new(&mut page);
+        self.flush_all_dirty();
+        self.disk.sync();
 
-        serializer
-            .serialize_value(&header)
-            .expect("Unable to serialize table header");
+        for (blob, bytes) in blobs {
+            blob.write(generation, &bytes)
+                .unwrap_or_else(|e| panic!("Failed to write {}: {e}", blob.name()));
+        }
+
+        // The header goes last, a crash before this leaves blobs newer than it
+        self.disk.write_page(0, &header.to_page());
+        self.disk.sync();
+
+        self.generation.store(generation, Ordering::Relaxed);
+        self.disk.recycle_pages(released);
+
+        f(generation)
+    }
+
+    /*
+        Copies a checkpoint of the table into `dest`. Mutating queries only
+        wait for the checkpoint, the copy runs beside them and reads pages
+        as they were when it was taken. Backing up to where this table was
+        last backed up only copies the pages written since, other
+        destinations are compared page by page and only differences written.
+    */
+    pub fn backup(&self, dest: &Storage) -> Result<BackupReport, CrabError> {
+        let _backups = self.backup_lock.lock();
+
+        let data_file = TableFile::Data.name(&self.name);
+        let target = dest
+            .open_pages(&data_file, StorageBackendKind::File)
+            .map_err(|e| CrabError::io(&data_file, e))?;
+        let marker = dest.blob(&TableFile::Backup.name(&self.name));
+        let incremental = self.backed_up_to(&marker, &*target)?;
+
+        let (generation, blobs, pages, copy) = {
+            let _writes = self.write_gate.write();
+
+            self.checkpoint_then(|generation| {
+                let blobs = [
+                    TableFile::PageDirectory,
+                    TableFile::RangeDirectory,
+                    TableFile::Index,
+                ]
+                .into_iter()
+                .map(|file| {
+                    let name = file.name(&self.name);
+                    let contents = self
+                        .storage
+                        .blob(&name)
+                        .read()
+                        .map_err(|e| CrabError::io(&name, e))?;
+
+                    Ok((name, contents))
+                })
+                .collect::<Result<Vec<_>, CrabError>>()?;
+
+                let pages = self.disk.free_page_pointer();
+                let copy = self.disk.backups().start(generation, pages, incremental);
+
+                Ok::<_, CrabError>((generation, blobs, pages, copy))
+            })?
+        };
 
-        self.disk.write_page(0, &page);
-        self.disk.flush();
+        let report = self.copy_backup(dest, &*target, blobs, &copy, incremental);
+        self.disk.backups().finish();
 
-        self.bufferpool.lock().flush_all();
+        let mut report = report?;
+        report.pages_unchanged = pages - report.pages_written;
 
-        let page_dir = self.page_dir.write();
-        page_dir.persist();
+        marker
+            .write(generation, &self.disk.backups().incarnation().to_le_bytes())
+            .map_err(|e| CrabError::io(marker.name(), e))?;
 
-        let range_dir = self.range_dir.lock();
-        range_dir.persist();
+        Ok(report)
+    }
+
+    // Whether `dest` still holds the checkpoint this table was last backed up with
+    fn backed_up_to(&self, marker: &Blob, target: &dyn StorageBackend) -> Result<bool, CrabError> {
+        let contents = match marker.read().map_err(|e| CrabError::io(marker.name(), e))? {
+            Some(contents) => contents,
+            None => return Ok(false),
+        };
+
+        let incarnation = match contents.bytes.as_slice().try_into() {
+            Ok(bytes) => u64::from_le_bytes(bytes),
+            Err(_) => return Ok(false),
+        };
+
+        if !self
+            .disk
+            .backups()
+            .backed_up(incarnation, contents.generation)
+        {
+            return Ok(false);
+        }
+
+        // Opening the backup as a database checkpoints it again
+        let data_file = TableFile::Data.name(&self.name);
+        let mut page = PhysicalPage::default();
+        target
+            .read_page(0, &mut page.page)
+            .map_err(|e| CrabError::io(&data_file, e))?;
+
+        Ok(TableHeaderPage::from_page(&page.page, &data_file)
+            .map_or(false, |header| header.generation == contents.generation))
+    }
+
+    fn copy_backup(
+        &self,
+        dest: &Storage,
+        target: &dyn StorageBackend,
+        blobs: Vec<(String, Option<BlobContents>)>,
+        copy: &[usize],
+        incremental: bool,
+    ) -> Result<BackupReport, CrabError> {
+        let mut report = BackupReport {
+            tables: vec![self.name.clone()],
+            ..Default::default()
+        };
+
+        for (name, contents) in blobs {
+            if let Some(contents) = contents {
+                dest.blob(&name)
+                    .write_version(contents.version, contents.generation, &contents.bytes)
+                    .map_err(|e| CrabError::io(&name, e))?;
+
+                report.blobs_written += 1;
+            }
+        }
+
+        let data_file = TableFile::Data.name(&self.name);
+        let mut source = vec![[0; PAGE_SIZE]; BACKUP_BATCH];
+        let mut existing = vec![[0; PAGE_SIZE]; BACKUP_BATCH];
+
+        for batch in copy.chunks(BACKUP_BATCH) {
+            let mut requests = batch
+                .iter()
+                .copied()
+                .zip(source.iter_mut())
+                .collect::<Vec<_>>();
+            self.disk
+                .read_backup_pages(&mut requests)
+                .map_err(|e| CrabError::io(&data_file, e))?;
+
+            // Pages written since the last backup to `dest` are all copied
+            if !incremental {
+                let mut requests = batch
+                    .iter()
+                    .copied()
+                    .zip(existing.iter_mut())
+                    .collect::<Vec<_>>();
+                target
+                    .read_pages(&mut requests)
+                    .map_err(|e| CrabError::io(&data_file, e))?;
+            }
+
+            let changed = batch
+                .iter()
+                .zip(source.iter().zip(existing.iter()))
+                .filter(|(_, (page, old))| incremental || page != old)
+                .map(|(page_id, (page, _))| (*page_id, page))
+                .collect::<Vec<_>>();
+
+            target
+                .write_pages(&changed)
+                .map_err(|e| CrabError::io(&data_file, e))?;
+
+            report.pages_written += changed.len();
+        }
+
+        target.sync().map_err(|e| CrabError::io(&data_file, e))?;
+
+        Ok(report)
+    }
 
-        let index = self.index.write();
-        index.persist();
+    // Writes back at most `max` dirty pages, returns how many were written
+    pub fn flush_dirty(&self, max: usize) -> usize {
+        let frames = self.bufferpool.lock().dirty_frames(max);
+
+        self.write_back(frames)
+    }
+
+    /*
+        Writes back every page that's dirty when it's called, `flush_batch`
+        at a time so a checkpoint never pins more pages than the background
+        writer does.
+    */
+    fn flush_all_dirty(&self) {
+        let mut next = Some(0);
+
+        while let Some(start) = next {
+            let (frames, end) = self
+                .bufferpool
+                .lock()
+                .dirty_frames_from(start, self.flush_batch);
+
+            self.write_back(frames);
+            next = end;
+        }
+    }
+
+    fn write_back(&self, frames: Vec<PageGuard>) -> usize {
+        let written = BufferPoolFrame::write_back_all(&frames, &self.disk);
+
+        // A page fault can be holding the pool lock while it waits for these to unpin
+        drop(frames);
+
+        if written > 0 {
+            self.bufferpool.lock().record_flushes(written);
+        }
+
+        written
+    }
+
+    pub fn stats(&self) -> TableStats {
+        TableStats {
+            bufferpool: self.bufferpool.lock().stats(),
+            disk: self.disk.stats(),
+            merge: self.merge_stats(),
+            locks: self.lock_manager.stats(),
+        }
     }
 
     pub fn next_tid(&self, range_id: usize) -> RID {
@@ -225,13 +602,7 @@ impl Table {
 
             range_dir.new_range_tail(range_id, new_tail);
 
-            let merge_thread_handle = self.merge_thread_handle.lock();
-            merge_thread_handle
-                .as_ref()
-                .expect("No merge handle")
-                .1
-                .send(range_id)
-                .expect("Unable to send range id to merge channel");
+            self.tail_page_full(range_id);
         }
 
         range_dir.get(range_id).next_tid()
@@ -243,15 +614,8 @@ impl Table {
             .fetch_sub(PAGE_SLOTS as u64, Ordering::Relaxed)
             .into();
 
-        let tail_reserve_start = self.disk.reserve_range(self.total_columns());
-        let mut column_pages = Arc::<[usize]>::new_uninit_slice(self.total_columns());
-
-        for (i, x) in (tail_reserve_start..(tail_reserve_start + self.total_columns())).enumerate()
-        {
-            Arc::get_mut(&mut column_pages).unwrap()[i].write(x);
-        }
-
-        let column_pages = unsafe { column_pages.assume_init() };
+        // Tail pages can reuse reclaimed ones, which are already cleared
+        let column_pages = Arc::<[usize]>::from(self.disk.allocate_pages(self.total_columns()));
 
         let mut page_dir = self.page_dir.write();
 
@@ -268,10 +632,20 @@ impl Table {
     }
 
     #[inline(always)]
-    fn get_page_by_id(&self, id: usize) -> Page {
+    pub(crate) fn get_page_by_id(&self, id: usize) -> Page {
         Page::new(self.page_dir.read().get_page(id).expect("Page get fail"))
     }
 
+    /*
+        Merges don't reclaim the tail pages and base columns they replace until
+        every query that pinned an epoch before then is done. Queries pin one
+        themselves, anything else following indirection or page ids across
+        calls has to hold its own.
+    */
+    pub fn pin_epoch(&self) -> EpochGuard {
+        self.epochs.pin()
+    }
+
     pub fn get_bufferpool(&self) -> Arc<Mutex<BufferPool>> {
         Arc::clone(&self.bufferpool)
     }
@@ -280,184 +654,162 @@ impl Table {
         Arc::clone(&self.lock_manager)
     }
 
-    fn find_row(&self, column_index: usize, value: u64) -> Option<RID> {
+    // Deleted records have their RID cleared
+    fn is_deleted(&self, rid: RID) -> Result<bool, BufferPoolError> {
+        Ok(self
+            .get_page(rid)
+            .get_column(&mut self.bufferpool.lock(), METADATA_RID)?
+            .slot(rid.slot())
+            == RID_INVALID)
+    }
+
+    fn find_row(&self, column_index: usize, value: u64) -> Result<Option<RID>, BufferPoolError> {
         match self.index.read().get_from_index(column_index, value) {
-            Some(vals) => vals
-                .iter()
-                .find(|x| {
-                    self.get_page(**x)
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(x.slot())
-                        != RID_INVALID
-                })
-                .copied(),
+            Some(vals) => {
+                for rid in vals {
+                    if !self.is_deleted(rid)? {
+                        return Ok(Some(rid));
+                    }
+                }
+
+                Ok(None)
+            }
             None => {
                 let mut rid: RID = 0.into();
+                let mut cursor = ScanCursor::new(self, &[column_index]);
 
-                let next_rid = self.next_rid.load(Ordering::Relaxed);
+                let next_rid = self.filling.next_rid();
 
                 while rid.raw() < next_rid {
-                    let page = self.get_page(rid);
-
-                    if page
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(rid.slot())
-                        == RID_INVALID
-                    {
-                        rid = rid.next();
-                        continue;
+                    if cursor.is_live(rid)? && cursor.value(rid, 0)? == value {
+                        return Ok(Some(rid));
                     }
 
-                    drop(page);
-
-                    let latest_rid = self.get_latest(rid);
-                    let latest_page = self.get_page(latest_rid);
-
-                    if latest_page
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + column_index,
-                        )
-                        .slot(latest_rid.slot())
-                        == value
-                    {
-                        return Some(rid);
-                    }
+                    rid = rid.next();
                 }
 
-                None
+                Ok(None)
             }
         }
     }
 
-    fn find_rows(&self, column_index: usize, value: u64) -> Vec<RID> {
+    fn find_rows(&self, column_index: usize, value: u64) -> Result<Vec<RID>, BufferPoolError> {
+        let mut rids = Vec::new();
+
         match self.index.read().get_from_index(column_index, value) {
-            Some(vals) => vals
-                .into_iter()
-                .filter(|x| {
-                    self.get_page(*x)
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(x.slot())
-                        != RID_INVALID
-                })
-                .collect(),
+            Some(vals) => {
+                for rid in vals {
+                    if !self.is_deleted(rid)? {
+                        rids.push(rid);
+                    }
+                }
+            }
             None => {
                 let mut rid: RID = 0.into();
-                let mut rids = Vec::new();
-                let next_rid = self.next_rid.load(Ordering::Relaxed);
+                let mut cursor = ScanCursor::new(self, &[column_index]);
+                let next_rid = self.filling.next_rid();
 
                 while rid.raw() < next_rid {
-                    let page = self.get_page(rid);
-
-                    if page
-                        .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                        .slot(rid.slot())
-                        == RID_INVALID
-                    {
-                        rid = rid.next();
-                        continue;
-                    }
-
-                    let latest_rid = self.get_latest(rid);
-
-                    if self
-                        .get_page(latest_rid)
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + column_index,
-                        )
-                        .slot(latest_rid.slot())
-                        == value
-                    {
+                    if cursor.is_live(rid)? && cursor.value(rid, 0)? == value {
                         rids.push(rid);
                     }
 
                     rid = rid.next();
                 }
-
-                rids
             }
         }
+
+        Ok(rids)
     }
 
     fn find_rows_range(
         &self,
         column_index: usize,
         range: impl RangeBounds<u64> + Clone,
-    ) -> Vec<RID> {
+    ) -> Result<Vec<RID>, BufferPoolError> {
+        let mut rids: Vec<RID> = Vec::new();
+
         match self
             .index
             .read()
             .range_from_index(column_index, range.clone())
         {
-            Some(vals) => vals,
+            Some(vals) => {
+                for rid in vals {
+                    if !self.is_deleted(rid)? {
+                        rids.push(rid);
+                    }
+                }
+            }
             None => {
-                let mut rids: Vec<RID> = Vec::new();
                 let mut rid: RID = 0.into();
-                let next_rid = self.next_rid.load(Ordering::Relaxed);
+                let mut cursor = ScanCursor::new(self, &[self.primary_key_index]);
+                let next_rid = self.filling.next_rid();
 
                 while rid.raw() < next_rid {
-                    let key = self
-                        .get_page(rid)
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + self.primary_key_index,
-                        )
-                        .slot(rid.slot());
-
-                    if range.contains(&key) {
+                    if cursor.is_live(rid)? && range.contains(&cursor.value(rid, 0)?) {
                         rids.push(rid);
                     }
 
                     rid = rid.next();
                 }
-
-                rids
             }
         }
+
+        Ok(rids)
     }
 
-    pub fn is_latest(&self, rid: RID) -> bool {
+    pub fn is_latest(&self, rid: RID) -> Result<bool, BufferPoolError> {
         let mut bp = self.bufferpool.lock();
-        self.get_page(rid).read_page_tps(bp.borrow_mut())
+        Ok(self.get_page(rid).read_page_tps(&mut bp)?
             <= self
                 .get_page(rid)
-                .get_column(bp.borrow_mut(), METADATA_INDIRECTION)
-                .slot(rid.slot())
+                .get_column(&mut bp, METADATA_INDIRECTION)?
+                .slot(rid.slot()))
+    }
+
+    pub fn get_latest(&self, rid: RID) -> Result<RID, BufferPoolError> {
+        self.get_latest_with_hint(rid, AccessHint::Random)
     }
 
-    pub fn get_latest(&self, rid: RID) -> RID {
+    pub fn get_latest_with_hint(&self, rid: RID, hint: AccessHint) -> Result<RID, BufferPoolError> {
         let page = self.get_page(rid);
 
         let mut bp = self.bufferpool.lock();
 
         let indir = page
-            .get_column(bp.borrow_mut(), METADATA_INDIRECTION)
+            .get_column_with_hint(&mut bp, METADATA_INDIRECTION, hint)?
             .slot(rid.slot());
 
-        if indir == RID_INVALID || page.read_page_tps(bp.borrow_mut()) <= indir {
-            rid
+        if indir == RID_INVALID || page.read_page_tps(&mut bp)? <= indir {
+            Ok(rid)
         } else {
-            indir.into()
+            Ok(indir.into())
         }
     }
 
-    pub fn get_latest_with_bp(&self, bp: &mut BufferPool, rid: RID) -> RID {
+    pub fn get_latest_with_bp(
+        &self,
+        bp: &mut MutexGuard<BufferPool>,
+        rid: RID,
+    ) -> Result<RID, BufferPoolError> {
         let page = self.get_page(rid);
 
-        let indir = page
-            .get_column(bp.borrow_mut(), METADATA_INDIRECTION)
-            .slot(rid.slot());
+        let indir = page.get_column(bp, METADATA_INDIRECTION)?.slot(rid.slot());
 
-        if indir == RID_INVALID || page.read_page_tps(bp.borrow_mut()) <= indir {
-            rid
+        if indir == RID_INVALID || page.read_page_tps(bp)? <= indir {
+            Ok(rid)
         } else {
-            indir.into()
+            Ok(indir.into())
         }
     }
 
-    pub fn merge_values(&self, base_rid: RID, columns: &[Option<u64>]) -> Vec<u64> {
-        let rid = self.get_latest(base_rid);
+    pub fn merge_values(
+        &self,
+        base_rid: RID,
+        columns: &[Option<u64>],
+    ) -> Result<Vec<u64>, BufferPoolError> {
+        let rid = self.get_latest(base_rid)?;
         let page = self.get_page(rid);
 
         let mut bp = self.bufferpool.lock();
@@ -465,10 +817,10 @@ impl Table {
             .iter()
             .enumerate()
             .map(|(i, x)| match x {
-                None => page
-                    .get_column(&mut bp, NUM_METADATA_COLUMNS + i)
-                    .slot(rid.slot()),
-                Some(val) => *val,
+                None => Ok(page
+                    .get_column(&mut bp, NUM_METADATA_COLUMNS + i)?
+                    .slot(rid.slot())),
+                Some(val) => Ok(*val),
             })
             .collect()
     }
@@ -491,70 +843,69 @@ impl Table {
         column_index: usize,
         included_columns: &[usize],
         mut transaction: Option<&mut Transaction>,
-    ) -> Vec<Record> {
-        let vals: Vec<RID> = self.find_rows(column_index, search_value);
+    ) -> Result<Vec<Record>, CrabError> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let _epoch = self.pin_epoch();
+
+        if let Some(snapshot) = transaction.as_ref().and_then(|t| t.snapshot()) {
+            return self.snapshot_select(search_value, column_index, included_columns, snapshot);
+        }
+
+        // No other record gets the value before the transaction ends
+        if let Some(t) = transaction.borrow_mut() {
+            if !t.try_lock_keys_with_abort(
+                &self.lock_manager,
+                column_index,
+                search_value..=search_value,
+                LockType::Shared,
+            ) {
+                return Ok(Vec::new());
+            }
+        }
+
+        let vals: Vec<RID> = self.find_rows(column_index, search_value)?;
 
         if let Some(t) = transaction.borrow_mut() {
             for rid in vals.iter() {
                 if !t.try_lock_with_abort(&self.lock_manager, *rid, LockType::Shared) {
-                    return Vec::new();
+                    return Ok(Vec::new());
                 }
             }
         }
 
-        vals.into_iter()
-            .map(|rid| {
-                let rid = self.get_latest(rid);
-                let page = self.get_page(rid);
+        let mut records = Vec::with_capacity(vals.len());
 
-                let result_cols = included_columns
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(i, x)| {
-                        if *x != 0 {
-                            Some(
-                                page.get_column(
-                                    self.bufferpool.lock().borrow_mut(),
-                                    NUM_METADATA_COLUMNS + i,
-                                )
-                                .slot(rid.slot()),
-                            )
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<u64>>();
+        for rid in vals {
+            let rid = self.get_latest(rid)?;
+            let page = self.get_page(rid);
+            let mut result_cols = Vec::with_capacity(included_columns.len());
 
-                Record {
-                    rid: rid.raw(),
-                    columns: result_cols,
+            for (i, x) in included_columns.iter().enumerate() {
+                if *x != 0 {
+                    result_cols.push(
+                        page.get_column(
+                            self.bufferpool.lock().borrow_mut(),
+                            NUM_METADATA_COLUMNS + i,
+                        )?
+                        .slot(rid.slot()),
+                    );
                 }
-            })
-            .collect()
-    }
-
-    pub fn insert_query(&self, values: &[u64], mut transaction: Option<&mut Transaction>) {
-        if self
-            .find_row(self.primary_key_index, values[self.primary_key_index])
-            .is_some()
-        {
-            if let Some(t) = transaction.borrow_mut() {
-                t.set_aborted(false);
             }
-            return;
-        }
-
-        let rid: RID = self.next_rid.fetch_add(1, Ordering::Relaxed).into();
 
-        if let Some(t) = transaction.borrow_mut() {
-            if !t.try_lock_with_abort(&self.lock_manager, rid, LockType::Exclusive) {
-                return;
-            }
+            records.push(Record {
+                rid: rid.raw(),
+                columns: result_cols,
+            });
         }
 
+        Ok(records)
+    }
+
+    // Column pages of the base page holding `rid`, allocating its page range on first use
+    fn base_columns(&self, rid: RID) -> Arc<[usize]> {
         let page_dir = self.page_dir.read();
 
-        let page: Arc<[usize]> = match page_dir.get(rid) {
+        match page_dir.get(rid) {
             None => {
                 drop(page_dir);
                 let mut page_dir = self.page_dir.write();
@@ -562,6 +913,10 @@ impl Table {
                 if page_dir.get(rid).is_none() {
                     let reserve_count = self.total_columns() * PAGE_RANGE_COUNT;
                     let reserved = self.disk.reserve_range(reserve_count);
+                    let mut header = PhysicalPage::default();
+                    let mut headers = Vec::with_capacity(PAGE_RANGE_COUNT);
+
+                    header.write_slot(0, RID_INVALID);
 
                     for i in 0..PAGE_RANGE_COUNT {
                         let page_id = (rid.page_range() * PAGE_RANGE_COUNT) + i;
@@ -578,13 +933,13 @@ impl Table {
 
                         let column_pages = unsafe { column_pages.assume_init() };
 
-                        self.bufferpool
-                            .lock()
-                            .get_page(column_pages[METADATA_PAGE_HEADER])
-                            .write_slot(0, RID_INVALID);
-
+                        headers.push((column_pages[METADATA_PAGE_HEADER], &header.page));
                         page_dir.new_page(page_id, column_pages);
                     }
+
+                    // Queries holding pins can be waiting for the page directory, so
+                    // faulting the headers in here could wait on them forever
+                    self.disk.write_pages(&headers);
                 }
 
                 page_dir
@@ -592,27 +947,75 @@ impl Table {
                     .expect("Allocated new pages but no mapping in directory")
             }
             Some(cols) => cols,
-        };
+        }
+    }
+
+    pub fn insert_query(
+        &self,
+        values: &[u64],
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<(), CrabError> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let _writes = self.write_gate.read_recursive();
+
+        if let Err(e) = self.writable() {
+            if let Some(t) = transaction.borrow_mut() {
+                t.set_aborted(false);
+            }
+            return Err(e);
+        }
+
+        if self
+            .find_row(self.primary_key_index, values[self.primary_key_index])?
+            .is_some()
+        {
+            if let Some(t) = transaction.borrow_mut() {
+                t.set_aborted(false);
+            }
+            return Ok(());
+        }
+
+        // Waits for transactions that read any of its values to finish
+        if let Some(t) = transaction.borrow_mut() {
+            if !self.lock_written_keys(t, values.iter().copied().enumerate()) {
+                return Ok(());
+            }
+        }
+
+        // Held until the record is written, merges leave its base page alone meanwhile
+        let reserved = self.filling.reserve(1);
+        let rid = reserved.first();
+
+        if let Some(t) = transaction.borrow_mut() {
+            if !t.try_lock_with_abort(&self.lock_manager, rid, LockType::Exclusive) {
+                return Ok(());
+            }
+        }
+
+        let write = Write::Insert(rid);
+        let untracked = self.begin_version(write, transaction.as_deref_mut());
+
+        let page = self.base_columns(rid);
 
         if let Some(t) = transaction.borrow_mut() {
             t.log_write(METADATA_RID, rid, RID_INVALID);
         }
 
         let page = Page::new(page);
-        page.get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
+        page.wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
             .write_slot(rid.slot(), RID_INVALID);
 
-        page.get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
+        page.wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
             .write_slot(rid.slot(), rid.raw());
 
-        page.get_column(
+        page.wait_for_column(
             self.bufferpool.lock().borrow_mut(),
             METADATA_SCHEMA_ENCODING,
         )
         .write_slot(rid.slot(), 0);
 
         for (i, val) in values.iter().enumerate() {
-            page.get_column(
+            page.wait_for_column(
                 self.bufferpool.lock().borrow_mut(),
                 NUM_METADATA_COLUMNS + i,
             )
@@ -620,17 +1023,196 @@ impl Table {
         }
 
         let mut index = self.index.write();
-        for i in 0..self.num_columns {
+        for (i, value) in values.iter().enumerate().take(self.num_columns) {
             if let Some(t) = transaction.borrow_mut() {
                 t.log_index_write(IndexMutation::Add {
                     rid,
-                    value: values[i],
+                    value: *value,
                     column: i,
                 });
             }
 
-            index.update_index(i, values[i], rid);
+            index.update_index(i, *value, rid);
+        }
+
+        drop(index);
+
+        if transaction.is_none() {
+            self.commit_version(write, untracked);
+        }
+
+        Ok(())
+    }
+
+    /*
+        Inserts every row or, if any row is malformed or its key is taken,
+        none of them. Rows get consecutive RIDs and are written a base page
+        and a column at a time, then each index gets its entries in one
+        sorted batch. Holding the index lock throughout keeps checkpoints
+        from capturing part of the batch.
+    */
+    pub fn bulk_insert(&self, rows: &[Vec<u64>]) -> Result<(), CrabError> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+
+        /*
+            Locked like a transaction writing the whole table, so none reads
+            part of the batch. Taken before the write gate, transactions
+            holding locks on the table may be waiting for it.
+        */
+        let owner = mvcc::next_owner();
+        let locker = Locker {
+            owner,
+            age: owner,
+            policy: LockPolicy::default(),
+        };
+        let handle = self
+            .lock_manager
+            .lock(LockTarget::Table, LockType::Exclusive, &locker)
+            .ok_or_else(|| CrabError::Locked(self.name.clone()))?;
+
+        let result = self.write_batch(rows, owner);
+        self.lock_manager.unlock(&handle);
+        result
+    }
+
+    fn write_batch(&self, rows: &[Vec<u64>], owner: u64) -> Result<(), CrabError> {
+        let _writes = self.write_gate.write();
+        self.writable()?;
+
+        if let Some(row) = rows.iter().find(|x| x.len() != self.num_columns) {
+            return Err(CrabError::Mapping(format!(
+                "rows of \"{}\" have {} values, found {}",
+                self.name,
+                self.num_columns,
+                row.len()
+            )));
+        }
+
+        let mut index = self.index.write();
+
+        let mut keys = rows
+            .iter()
+            .map(|x| x[self.primary_key_index])
+            .collect::<Vec<u64>>();
+        keys.sort_unstable();
+
+        let duplicate = match keys.windows(2).find(|x| x[0] == x[1]) {
+            Some(x) => Some(x[0]),
+            None => self.existing_key(&index, &keys)?,
+        };
+
+        if let Some(key) = duplicate {
+            return Err(CrabError::DuplicateKey {
+                table: self.name.clone(),
+                key,
+            });
+        }
+
+        let reserved = self.filling.reserve(rows.len() as u64);
+        let start = reserved.first().raw();
+        let mut written = 0;
+
+        let untracked = self.versions.clock().untracked_write();
+        let inserts = (start..start + rows.len() as u64).map(|x| Write::Insert(x.into()));
+
+        if untracked.is_none() {
+            for write in inserts.clone() {
+                self.versions.begin_write(write, owner);
+            }
+        }
+
+        while written < rows.len() {
+            let first: RID = (start + written as u64).into();
+            let batch = &rows[written..rows.len().min(written + PAGE_SLOTS - first.slot())];
+            let page = Page::new(self.base_columns(first));
+
+            let metadata = [
+                (METADATA_INDIRECTION, RID_INVALID),
+                (METADATA_SCHEMA_ENCODING, 0),
+            ];
+
+            for (column, value) in metadata {
+                let guard = page.wait_for_column(&mut self.bufferpool.lock(), column);
+
+                for slot in first.slot()..first.slot() + batch.len() {
+                    guard.write_slot(slot, value);
+                }
+            }
+
+            for column in 0..self.num_columns {
+                let guard = page
+                    .wait_for_column(&mut self.bufferpool.lock(), NUM_METADATA_COLUMNS + column);
+
+                for (i, row) in batch.iter().enumerate() {
+                    guard.write_slot(first.slot() + i, row[column]);
+                }
+            }
+
+            // Written last, a slot only holds its own RID once the record is complete
+            let guard = page.wait_for_column(&mut self.bufferpool.lock(), METADATA_RID);
+
+            for i in 0..batch.len() {
+                guard.write_slot(first.slot() + i, first.raw() + i as u64);
+            }
+
+            written += batch.len();
+        }
+
+        for column in 0..self.num_columns {
+            if !index.has_index(column) {
+                continue;
+            }
+
+            let mut entries = rows
+                .iter()
+                .enumerate()
+                .map(|(i, row)| (row[column], RID::from(start + i as u64)))
+                .collect::<Vec<_>>();
+
+            index.bulk_update_index(column, &mut entries);
+        }
+
+        // One timestamp for the whole batch, snapshots see all of it or none
+        if untracked.is_none() {
+            self.versions.clock().commit(|ts, oldest| {
+                for write in inserts {
+                    self.versions.commit(write, ts, oldest);
+                }
+                true
+            });
+        }
+
+        Ok(())
+    }
+
+    // First of the sorted `keys` that a live record already has
+    fn existing_key(&self, index: &Index, keys: &[u64]) -> Result<Option<u64>, CrabError> {
+        if index.has_index(self.primary_key_index) {
+            for key in keys {
+                let rids = index
+                    .get_from_index(self.primary_key_index, *key)
+                    .unwrap_or_default();
+
+                for rid in rids {
+                    if !self.is_deleted(rid)? {
+                        return Ok(Some(*key));
+                    }
+                }
+            }
+
+            return Ok(None);
         }
+
+        // Without a key index, one scan beats one per key
+        let mut found = None;
+
+        self.scan(&[self.primary_key_index], |values| {
+            if found.is_none() && keys.binary_search(&values[0]).is_ok() {
+                found = Some(values[0]);
+            }
+        })?;
+
+        Ok(found)
     }
 
     pub fn sum_query(
@@ -639,30 +1221,115 @@ impl Table {
         end_range: u64,
         column_index: usize,
         mut transaction: Option<&mut Transaction>,
-    ) -> u64 {
-        let range = self.find_rows_range(column_index, RangeInclusive::new(start_range, end_range));
+    ) -> Result<u64, CrabError> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let _epoch = self.pin_epoch();
 
+        if let Some(snapshot) = transaction.as_ref().and_then(|t| t.snapshot()) {
+            return self.snapshot_sum(start_range..=end_range, column_index, snapshot);
+        }
+
+        // Nothing gets inserted or updated into the key range before the transaction ends
         if let Some(t) = transaction.borrow_mut() {
-            for rid in range.iter() {
-                if !t.try_lock_with_abort(&self.lock_manager, *rid, LockType::Shared) {
-                    return 0;
-                }
+            if !t.try_lock_keys_with_abort(
+                &self.lock_manager,
+                self.range_column(column_index),
+                start_range..=end_range,
+                LockType::Shared,
+            ) {
+                return Ok(0);
             }
         }
 
+        let mut locked = Vec::new();
+
+        // Records can move in or out of the key range until their page ranges are locked
+        let mut rids = loop {
+            let rids =
+                self.find_rows_range(column_index, RangeInclusive::new(start_range, end_range))?;
+
+            let t = match transaction.borrow_mut() {
+                Some(t) => t,
+                None => break rids,
+            };
+
+            let mut ranges = rids
+                .iter()
+                .map(|x| x.page_range())
+                .filter(|x| !locked.contains(x))
+                .collect::<Vec<_>>();
+
+            if ranges.is_empty() {
+                break rids;
+            }
+
+            ranges.sort_unstable();
+            ranges.dedup();
+
+            for range in ranges {
+                if !t.try_lock_target_with_abort(
+                    &self.lock_manager,
+                    LockTarget::Range(range),
+                    LockType::Shared,
+                ) {
+                    return Ok(0);
+                }
+
+                locked.push(range);
+            }
+        };
+
+        // Visit the base pages in order so the cursor can read ahead
+        rids.sort_unstable();
+
+        let mut cursor = ScanCursor::new(self, &[column_index]);
         let mut sum: u64 = 0;
-        for rid in range.iter() {
-            let latest = self.get_latest_with_bp(&mut self.bufferpool.lock(), *rid);
-            sum += self
-                .get_page(latest)
-                .get_column(
-                    &mut self.bufferpool.lock(),
-                    NUM_METADATA_COLUMNS + column_index,
-                )
-                .slot(latest.slot());
+
+        for rid in rids {
+            sum += cursor.value(rid, 0)?;
+        }
+
+        Ok(sum)
+    }
+
+    // Sums take the key range on the summed column if it's indexed, on the primary key otherwise
+    fn range_column(&self, column_index: usize) -> usize {
+        if self.index.read().has_index(column_index) {
+            column_index
+        } else {
+            self.primary_key_index
+        }
+    }
+
+    // Calls `f` with the latest values of `columns` for every live record, in RID order
+    pub fn scan(&self, columns: &[usize], mut f: impl FnMut(&[u64])) -> Result<(), CrabError> {
+        let _epoch = self.pin_epoch();
+        let next_rid = self.filling.next_rid();
+        let mut cursor = ScanCursor::new(self, columns);
+        let mut values = vec![0; columns.len()];
+
+        for page in 0..(next_rid as usize + PAGE_SLOTS - 1) / PAGE_SLOTS {
+            // An aborted insert can reserve a RID in a range that was never allocated
+            if self.page_dir.read().get_page(page).is_none() {
+                continue;
+            }
+
+            let first = (page * PAGE_SLOTS) as u64;
+
+            for rid in (first..next_rid.min(first + PAGE_SLOTS as u64)).map(RID::from) {
+                if !cursor.is_live(rid)? {
+                    continue;
+                }
+
+                for (i, value) in values.iter_mut().enumerate() {
+                    *value = cursor.value(rid, i)?;
+                }
+
+                f(&values);
+            }
         }
 
-        sum
+        Ok(())
     }
 
     pub fn update_query(
@@ -670,57 +1337,121 @@ impl Table {
         key: u64,
         values: &[Option<u64>],
         mut transaction: Option<&mut Transaction>,
-    ) -> bool {
-        let row = self.find_row(self.primary_key_index, key);
+    ) -> Result<bool, CrabError> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let _epoch = self.pin_epoch();
+        let _writes = self.write_gate.read_recursive();
+
+        if let Err(e) = self.writable() {
+            if let Some(t) = transaction.borrow_mut() {
+                t.set_aborted(false);
+            }
+            return Err(e);
+        }
+
+        let row = self.find_row(self.primary_key_index, key)?;
 
         if let Some(pk) = values[self.primary_key_index] {
-            if self.find_row(self.primary_key_index, pk).is_some() {
+            if self.find_row(self.primary_key_index, pk)?.is_some() {
                 if let Some(t) = transaction.borrow_mut() {
                     t.set_aborted(false);
                 }
-                return false;
+                return Ok(false);
             }
         }
 
         if row.is_none() {
-            return false;
+            return Ok(false);
         }
 
         let base_rid = row.unwrap();
 
         if let Some(t) = transaction.borrow_mut() {
-            if !t.try_lock_with_abort(&self.lock_manager, base_rid, LockType::Exclusive) {
-                return false;
+            if !t.try_lock_with_abort(&self.lock_manager, base_rid, LockType::Exclusive)
+                || self.write_conflict(base_rid, t)
+            {
+                return Ok(false);
+            }
+
+            // The values it replaces too, they're back if the transaction rolls back
+            let current = self.merge_values(base_rid, &vec![None; self.num_columns])?;
+            let changed = values
+                .iter()
+                .zip(current)
+                .enumerate()
+                .filter_map(|(i, (new, old))| new.filter(|x| *x != old).map(|x| [(i, old), (i, x)]))
+                .flatten();
+
+            if !self.lock_written_keys(t, changed) {
+                return Ok(false);
             }
         }
 
         let base_page = self.get_page(base_rid);
-        let updated_values = self.merge_values(base_rid, values);
+        let updated_values = self.merge_values(base_rid, values)?;
 
         let old_latest_rid: RID = self
             .get_page(base_rid)
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
+            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)?
             .slot(base_rid.slot())
             .into();
 
-        let base_latest = self.get_latest(base_rid);
+        let base_latest = self.get_latest(base_rid)?;
         let old_schema_encoding = self
             .get_page(base_latest)
             .get_column(
                 self.bufferpool.lock().borrow_mut(),
                 METADATA_SCHEMA_ENCODING,
-            )
+            )?
             .slot(base_latest.slot());
 
+        // Index entries the update replaces. Everything is read before anything is
+        // written, so running out of frames can't leave an update half done.
+        let mut replaced = Vec::with_capacity(values.len());
+
+        for (i, v) in values.iter().enumerate() {
+            let old_value = if v.is_none() {
+                None
+            } else if (old_schema_encoding & (1 << i)) == 1 || old_latest_rid.is_invalid() {
+                Some(
+                    base_page
+                        .get_column(
+                            self.bufferpool.lock().borrow_mut(),
+                            NUM_METADATA_COLUMNS + i,
+                        )?
+                        .slot(base_rid.slot()),
+                )
+            } else if !old_latest_rid.is_invalid() && (old_schema_encoding & (1 << i)) == 1 {
+                Some(
+                    self.get_page(old_latest_rid)
+                        .get_column(
+                            self.bufferpool.lock().borrow_mut(),
+                            NUM_METADATA_COLUMNS + i,
+                        )?
+                        .slot(old_latest_rid.slot()),
+                )
+            } else {
+                None
+            };
+
+            replaced.push(old_value);
+        }
+
         let tail_rid = self.next_tid(base_rid.page_range());
         let tail_page = self.get_page(tail_rid);
 
+        let write = Write::Update {
+            base: base_rid,
+            tail: tail_rid,
+        };
+        let untracked = self.begin_version(write, transaction.as_deref_mut());
+
         tail_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_BASE_RID)
+            .wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_BASE_RID)
             .write_slot(tail_rid.slot(), base_rid.raw());
 
         tail_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
+            .wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
             .write_slot(
                 tail_rid.slot(),
                 if old_latest_rid.is_invalid() {
@@ -731,85 +1462,61 @@ impl Table {
             );
 
         tail_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
+            .wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
             .write_slot(tail_rid.slot(), tail_rid.raw());
 
         //print!("Update vals: {:?}\n", columns);
 
         for (i, val) in updated_values.iter().enumerate() {
             tail_page
-                .get_column(
+                .wait_for_column(
                     self.bufferpool.lock().borrow_mut(),
                     NUM_METADATA_COLUMNS + i,
                 )
                 .write_slot(tail_rid.slot(), *val);
 
-            //print!("Base Page: {:?}\n",&base_page.get_column(crate::NUM_METADATA_COLUMNS + i).page[0..50],);
-            //print!("Tail Page: {:?}\n",&page.get_column(crate::NUM_METADATA_COLUMNS + i).page[0..50]);
+            //print!("Base Page: {:?}\n",&base_page.get_column(crate::NUM_METADATA_COLUMNS + i)?.page[0..50],);
+            //print!("Tail Page: {:?}\n",&page.get_column(crate::NUM_METADATA_COLUMNS + i)?.page[0..50]);
         }
 
         let mut schema_encoding: u64 = 0;
 
         for (i, v) in values.iter().enumerate() {
-            if !v.is_none() {
+            if let Some(value) = v {
                 schema_encoding |= 1 << i;
                 let mut index = self.index.write();
 
                 if let Some(t) = transaction.borrow_mut() {
                     t.log_index_write(IndexMutation::Add {
                         rid: base_rid,
-                        value: v.unwrap(),
+                        value: *value,
                         column: i,
                     });
                 }
 
-                index.update_index(i, v.unwrap(), base_rid);
-                if (old_schema_encoding & (1 << i)) == 1 || old_latest_rid.is_invalid() {
-                    let val = base_page
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + i,
-                        )
-                        .slot(base_rid.slot());
-
-                    if let Some(t) = transaction.borrow_mut() {
-                        t.log_index_write(IndexMutation::Remove {
-                            rid: base_rid,
-                            old_value: val,
-                            column: i,
-                        });
-                    }
-
-                    index.remove_index(i, val, base_rid);
-                } else if !old_latest_rid.is_invalid() && (old_schema_encoding & (1 << i)) == 1 {
-                    let mut index = self.index.write();
-                    let val = self
-                        .get_page(old_latest_rid)
-                        .get_column(
-                            self.bufferpool.lock().borrow_mut(),
-                            NUM_METADATA_COLUMNS + i,
-                        )
-                        .slot(old_latest_rid.slot());
+                index.update_index(i, *value, base_rid);
 
+                if let Some(old_value) = replaced[i] {
                     if let Some(t) = transaction.borrow_mut() {
                         t.log_index_write(IndexMutation::Remove {
                             rid: base_rid,
-                            old_value: val,
+                            old_value,
                             column: i,
                         });
                     }
 
-                    index.remove_index(i, val, base_rid);
+                    index.remove_index(i, old_value, base_rid);
+                    self.versions.supersede(tail_rid, i, old_value, base_rid);
                 }
             }
         }
 
         tail_page
-            .get_column(
+            .wait_for_column(
                 self.bufferpool.lock().borrow_mut(),
                 METADATA_SCHEMA_ENCODING,
             )
-            .write_slot(base_rid.slot(), schema_encoding);
+            .write_slot(tail_rid.slot(), schema_encoding);
 
         //print!("Update called\n");
 
@@ -819,48 +1526,93 @@ impl Table {
         }
 
         base_page
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
+            .wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
             .write_slot(base_rid.slot(), tail_rid.raw());
 
-        true
+        if transaction.is_none() {
+            self.commit_version(write, untracked);
+        }
+
+        Ok(true)
     }
 
-    pub fn delete_query(&self, key: u64, mut transaction: Option<&mut Transaction>) -> bool {
-        let row = self.find_row(self.primary_key_index, key);
+    pub fn delete_query(
+        &self,
+        key: u64,
+        mut transaction: Option<&mut Transaction>,
+    ) -> Result<bool, CrabError> {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        let _epoch = self.pin_epoch();
+        let _writes = self.write_gate.read_recursive();
+
+        if let Err(e) = self.writable() {
+            if let Some(t) = transaction.borrow_mut() {
+                t.set_aborted(false);
+            }
+            return Err(e);
+        }
+
+        let row = self.find_row(self.primary_key_index, key)?;
 
         if row.is_none() {
-            return false;
+            return Ok(false);
         }
 
         let row = row.unwrap();
 
         if let Some(t) = transaction.borrow_mut() {
-            if !t.try_lock_with_abort(&self.lock_manager, row, LockType::Exclusive) {
-                return false;
+            if !t.try_lock_with_abort(&self.lock_manager, row, LockType::Exclusive)
+                || self.write_conflict(row, t)
+            {
+                return Ok(false);
+            }
+
+            // Its values are back if the transaction rolls back
+            let current = self.merge_values(row, &vec![None; self.num_columns])?;
+
+            if !self.lock_written_keys(t, current.into_iter().enumerate()) {
+                return Ok(false);
             }
         }
 
         let mut next_tail: RID = self
             .get_page(row)
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
+            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)?
             .slot(row.slot())
             .into();
 
-        while next_tail.raw() != RID_INVALID && next_tail.raw() != row.raw() {
+        // Merged tail records are already in the base record and may have been reclaimed
+        let tps = self
+            .get_page(row)
+            .read_page_tps(self.bufferpool.lock().borrow_mut())?;
+
+        // Read before anything is written, so running out of frames can't leave a delete half done
+        let mut tails = Vec::new();
+
+        while next_tail.raw() != RID_INVALID
+            && next_tail.raw() != row.raw()
+            && next_tail.raw() < tps
+        {
             let next = self
                 .get_page(next_tail)
-                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)
+                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_INDIRECTION)?
                 .slot(next_tail.slot());
 
+            tails.push((next_tail, next));
+            next_tail = next.into();
+        }
+
+        let write = Write::Delete(row);
+        let untracked = self.begin_version(write, transaction.as_deref_mut());
+
+        for (tail, next) in tails {
             if let Some(t) = transaction.borrow_mut() {
-                t.log_write(METADATA_INDIRECTION, next_tail, next);
+                t.log_write(METADATA_INDIRECTION, tail, next);
             }
 
-            self.get_page(next_tail)
-                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                .write_slot(next_tail.slot(), RID_INVALID);
-
-            next_tail = next.into();
+            self.get_page(tail)
+                .wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
+                .write_slot(tail.slot(), RID_INVALID);
         }
 
         if let Some(t) = transaction.borrow_mut() {
@@ -868,40 +1620,238 @@ impl Table {
         }
 
         self.get_page(row)
-            .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
+            .wait_for_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
             .write_slot(row.slot(), RID_INVALID);
 
-        true
+        if transaction.is_none() {
+            self.commit_version(write, untracked);
+        }
+
+        Ok(true)
+    }
+
+    /*
+        Pending until the transaction commits, or until the caller commits it
+        when there's none. Writes outside a transaction aren't tracked at all
+        while no snapshot is running, they hold on to what this returns until
+        they're done instead.
+    */
+    fn begin_version(
+        &self,
+        write: Write,
+        transaction: Option<&mut Transaction>,
+    ) -> Option<UntrackedWrite<'_>> {
+        match transaction {
+            Some(t) => t.log_version(&self.versions, write),
+            None => match self.versions.clock().untracked_write() {
+                Some(untracked) => return Some(untracked),
+                None => self.versions.begin_write(write, mvcc::NO_OWNER),
+            },
+        }
+
+        None
+    }
+
+    fn commit_version(&self, write: Write, untracked: Option<UntrackedWrite<'_>>) {
+        if untracked.is_some() {
+            return;
+        }
+
+        self.versions.clock().commit(|ts, oldest| {
+            self.versions.commit(write, ts, oldest);
+            true
+        });
+    }
+
+    // Column and value pairs a write adds or removes, locked so reads of them wait for it
+    fn lock_written_keys(
+        &self,
+        transaction: &mut Transaction,
+        keys: impl IntoIterator<Item = (usize, u64)>,
+    ) -> bool {
+        keys.into_iter().all(|(column, key)| {
+            transaction.try_lock_keys_with_abort(
+                &self.lock_manager,
+                column,
+                key..=key,
+                LockType::IntentionExclusive,
+            )
+        })
+    }
+
+    // First committer wins, a snapshot can't write over a commit it doesn't see
+    fn write_conflict(&self, base_rid: RID, transaction: &mut Transaction) -> bool {
+        let conflict = transaction
+            .snapshot()
+            .map_or(false, |x| self.versions.conflicts(base_rid, x));
+
+        if conflict {
+            transaction.set_aborted(true);
+        }
+
+        conflict
+    }
+
+    /*
+        Version of the base record `rid` that `snapshot` sees, None if it
+        doesn't see the record at all. Walks back from the latest tail record
+        to the newest one the snapshot sees. Merges only consolidate tail
+        records every snapshot sees, so once the walk reaches merged ones the
+        base record stands in for them.
+    */
+    fn snapshot_version(
+        &self,
+        rid: RID,
+        snapshot: &Snapshot,
+    ) -> Result<Option<RID>, BufferPoolError> {
+        if !self.versions.is_visible(rid, snapshot) {
+            return Ok(None);
+        }
+
+        // An aborted insert can reserve a RID in a range that was never allocated
+        let page = match self.page_dir.read().get(rid) {
+            Some(columns) => Page::new(columns),
+            None => return Ok(None),
+        };
+        let mut bp = self.bufferpool.lock();
+
+        let live = page.get_column(&mut bp, METADATA_RID)?.slot(rid.slot()) != RID_INVALID;
+        let tps = page.read_page_tps(&mut bp)?;
+        let mut version: RID = page
+            .get_column(&mut bp, METADATA_INDIRECTION)?
+            .slot(rid.slot())
+            .into();
+
+        drop(bp);
+
+        if !live && self.versions.is_deleted(rid, snapshot) {
+            return Ok(None);
+        }
+
+        // The oldest tail record of a chain points back at the base record
+        while version.is_tail() && !version.is_invalid() && version.raw() < tps {
+            if self.versions.is_visible(version, snapshot) {
+                return Ok(Some(version));
+            }
+
+            version = self
+                .get_page(version)
+                .get_column(&mut self.bufferpool.lock(), METADATA_INDIRECTION)?
+                .slot(version.slot())
+                .into();
+        }
+
+        Ok(Some(rid))
+    }
+
+    fn value_at(&self, version: RID, column: usize) -> Result<u64, BufferPoolError> {
+        Ok(self
+            .get_page(version)
+            .get_column(&mut self.bufferpool.lock(), NUM_METADATA_COLUMNS + column)?
+            .slot(version.slot()))
+    }
+
+    /*
+        Records that might have a value of `column` in `range`, every one
+        without an index. Indexes only hold the latest values, so the entries
+        updates replaced while snapshots were running are added back.
+    */
+    fn snapshot_candidates(&self, column: usize, range: RangeInclusive<u64>) -> Vec<RID> {
+        let mut rids = match self.index.read().range_from_index(column, range.clone()) {
+            Some(rids) => rids,
+            None => return (0..self.filling.next_rid()).map(RID::from).collect(),
+        };
+
+        rids.extend(self.versions.superseded(column, &range));
+        rids.sort_unstable();
+        rids.dedup();
+        rids
     }
 
-    pub fn build_index(&self, column_num: usize) {
+    /*
+        Candidates are checked against the version the snapshot sees, so
+        records whose value changed to the one searched for since the
+        snapshot started are left out.
+    */
+    fn snapshot_select(
+        &self,
+        search_value: u64,
+        column_index: usize,
+        included_columns: &[usize],
+        snapshot: &Snapshot,
+    ) -> Result<Vec<Record>, CrabError> {
+        let mut records = Vec::new();
+
+        for rid in self.snapshot_candidates(column_index, search_value..=search_value) {
+            let version = match self.snapshot_version(rid, snapshot)? {
+                Some(version) => version,
+                None => continue,
+            };
+
+            if self.value_at(version, column_index)? != search_value {
+                continue;
+            }
+
+            records.push(Record {
+                rid: version.raw(),
+                columns: included_columns
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, x)| **x != 0)
+                    .map(|(i, _)| self.value_at(version, i))
+                    .collect::<Result<_, _>>()?,
+            });
+        }
+
+        Ok(records)
+    }
+
+    /*
+        Same records as sum_query, the key range is over the primary key
+        unless `column_index` is indexed.
+    */
+    fn snapshot_sum(
+        &self,
+        range: RangeInclusive<u64>,
+        column_index: usize,
+        snapshot: &Snapshot,
+    ) -> Result<u64, CrabError> {
+        let range_column = self.range_column(column_index);
+        let mut sum = 0;
+
+        for rid in self.snapshot_candidates(range_column, range.clone()) {
+            let version = match self.snapshot_version(rid, snapshot)? {
+                Some(version) => version,
+                None => continue,
+            };
+
+            if range.contains(&self.value_at(version, range_column)?) {
+                sum += self.value_at(version, column_index)?;
+            }
+        }
+
+        Ok(sum)
+    }
+
+    // Scans the column before creating the index, so a failed scan leaves no partial index
+    pub fn build_index(&self, column_num: usize) -> Result<(), CrabError> {
         let mut index = self.index.write();
-        index.create_index(column_num);
+        let mut entries = Vec::new();
         let mut rid: RID = 0.into();
-        let max_rid = self.next_rid.load(Ordering::Relaxed);
+        let mut cursor = ScanCursor::new(self, &[column_num]);
+        let max_rid = self.filling.next_rid();
         while rid.raw() < max_rid {
-            if self
-                .get_page(rid)
-                .get_column(self.bufferpool.lock().borrow_mut(), METADATA_RID)
-                .slot(rid.slot())
-                == RID_INVALID
-            {
-                continue;
+            if cursor.is_live(rid)? {
+                entries.push((cursor.value(rid, 0)?, rid));
             }
 
-            let latest = self.get_latest(rid);
-            index.update_index(
-                column_num,
-                self.get_page(latest)
-                    .get_column(
-                        self.bufferpool.lock().borrow_mut(),
-                        NUM_METADATA_COLUMNS + column_num,
-                    )
-                    .slot(latest.slot()),
-                rid,
-            );
             rid = rid.next();
         }
+
+        index.create_index(column_num);
+        index.bulk_update_index(column_num, &mut entries);
+
+        Ok(())
     }
 
     pub fn drop_index(&self, column_num: usize) {
@@ -914,8 +1864,141 @@ impl fmt::Display for Table {
         writeln!(f, "[Table \"{}\"]", self.name)?;
         writeln!(f, "{} Columns: ", self.num_columns)?;
         writeln!(f, "PK: {}", self.primary_key_index)?;
-        writeln!(f, "Current RID: {}", self.next_rid.load(Ordering::Relaxed))?;
-        writeln!(f, "Current TID: {}", self.next_rid.load(Ordering::Relaxed))?;
+        writeln!(f, "Current RID: {}", self.filling.next_rid())?;
+        writeln!(f, "Current TID: {}", self.filling.next_rid())?;
         writeln!(f)
     }
 }
+
+const SCAN_RID: usize = 0;
+const SCAN_INDIRECTION: usize = 1;
+const SCAN_PAGE_HEADER: usize = 2;
+const SCAN_DATA: usize = 3;
+
+/*
+    Walks base records for a scan. The metadata and projected column pages of
+    the current base page stay pinned until the scan moves to another page, so
+    the buffer pool is locked once per page instead of once per slot. Moving to
+    a new page reads ahead the next `prefetch_depth` base pages.
+*/
+struct ScanCursor<'a> {
+    table: &'a Table,
+    columns: Vec<usize>,
+    page: usize,
+    prefetched_until: usize,
+    guards: Vec<PageGuard>,
+}
+
+impl<'a> ScanCursor<'a> {
+    fn new(table: &'a Table, data_columns: &[usize]) -> Self {
+        let mut columns = vec![METADATA_RID, METADATA_INDIRECTION, METADATA_PAGE_HEADER];
+        columns.extend(data_columns.iter().map(|x| NUM_METADATA_COLUMNS + x));
+
+        ScanCursor {
+            table,
+            columns,
+            page: !0,
+            prefetched_until: 0,
+            guards: Vec::new(),
+        }
+    }
+
+    fn load(&mut self, page: usize) -> Result<(), BufferPoolError> {
+        self.guards.clear();
+        self.page = !0;
+
+        let page_dir = self.table.page_dir.read();
+
+        let start = (page + 1).max(self.prefetched_until);
+        let end = page + 1 + self.table.prefetch_depth;
+
+        let prefetch = (start..end)
+            .filter_map(|x| page_dir.get_page(x))
+            .flat_map(|cols| self.columns.iter().map(move |x| cols[*x]))
+            .collect::<Vec<usize>>();
+
+        self.prefetched_until = self.prefetched_until.max(end);
+
+        // An aborted insert can reserve a RID in a range that was never
+        // allocated, its page has no records
+        let columns = match page_dir.get_page(page) {
+            Some(columns) => Page::new(columns),
+            None => {
+                self.page = page;
+                return Ok(());
+            }
+        };
+
+        drop(page_dir);
+
+        let mut bp = self.table.bufferpool.lock();
+
+        bp.prefetch(&prefetch);
+
+        // Loading the whole page or nothing, so a failed load is retried
+        let guards = self
+            .columns
+            .iter()
+            .map(|x| columns.get_column_with_hint(&mut bp, *x, AccessHint::Sequential))
+            .collect::<Result<_, _>>()?;
+
+        self.guards = guards;
+        self.page = page;
+
+        Ok(())
+    }
+
+    fn column(&mut self, rid: RID, position: usize) -> Result<&PageGuard, BufferPoolError> {
+        if rid.page() != self.page {
+            self.load(rid.page())?;
+        }
+
+        Ok(&self.guards[position])
+    }
+
+    // Deleted records and slots reserved by aborted inserts don't hold their own RID
+    fn is_live(&mut self, rid: RID) -> Result<bool, BufferPoolError> {
+        if rid.page() != self.page {
+            self.load(rid.page())?;
+        }
+
+        Ok(match self.guards.get(SCAN_RID) {
+            Some(guard) => guard.slot(rid.slot()) == rid.raw(),
+            None => false,
+        })
+    }
+
+    fn latest(&mut self, rid: RID) -> Result<RID, BufferPoolError> {
+        let indir = self.column(rid, SCAN_INDIRECTION)?.slot(rid.slot());
+
+        if indir == RID_INVALID || self.column(rid, SCAN_PAGE_HEADER)?.slot(0) <= indir {
+            Ok(rid)
+        } else {
+            Ok(indir.into())
+        }
+    }
+
+    // Value of the `position`-th projected column in the base record
+    fn base_value(&mut self, rid: RID, position: usize) -> Result<u64, BufferPoolError> {
+        Ok(self.column(rid, SCAN_DATA + position)?.slot(rid.slot()))
+    }
+
+    // Value of the `position`-th projected column in the latest version
+    fn value(&mut self, rid: RID, position: usize) -> Result<u64, BufferPoolError> {
+        let latest = self.latest(rid)?;
+
+        if latest == rid {
+            return self.base_value(rid, position);
+        }
+
+        Ok(self
+            .table
+            .get_page(latest)
+            .get_column_with_hint(
+                &mut self.table.bufferpool.lock(),
+                self.columns[SCAN_DATA + position],
+                AccessHint::Sequential,
+            )?
+            .slot(latest.slot()))
+    }
+}
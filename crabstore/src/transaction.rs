@@ -1,13 +1,14 @@
-use std::{
-    borrow::{Borrow, BorrowMut},
-    cell::RefCell,
-    sync::Arc,
-};
+use std::{borrow::BorrowMut, ops::RangeInclusive, str::FromStr, sync::Arc};
 
-use rustc_hash::FxHashSet;
+use rustc_hash::FxHashMap;
 
 use crate::{
-    lock_manager::{LockHandle, LockManager, LockType},
+    error::CrabError,
+    lock_manager::{
+        LockHandle, LockManager, LockPolicy, LockTarget, LockType, Locker, KEY_BLOCK_BITS,
+    },
+    mvcc::{self, Clock, Snapshot, Versions, Write},
+    record::Record,
     rid::RID,
     table::Table,
 };
@@ -47,6 +48,31 @@ pub enum QueryStatus {
     AbortedNotRetryable,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Isolation {
+    // Strict two-phase locking, a query that can't get a lock aborts the transaction
+    #[default]
+    TwoPhaseLocking,
+    /*
+        Reads take no locks and see what was committed before the transaction
+        started. Writes still lock, and the transaction aborts at commit if
+        another one committed a write to the same record since it started.
+    */
+    Snapshot,
+}
+
+impl FromStr for Isolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "2pl" | "two_phase_locking" => Ok(Isolation::TwoPhaseLocking),
+            "snapshot" | "si" => Ok(Isolation::Snapshot),
+            _ => Err(format!("Unknown isolation level \"{s}\"")),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub enum Query {
     Select(u64, usize, Box<[usize]>),
@@ -56,125 +82,284 @@ pub enum Query {
     Delete(u64),
 }
 
+// What a query returned, insert, update and delete report whether they wrote anything
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueryResult {
+    Records(Vec<Record>),
+    Sum(u64),
+    Written(bool),
+}
+
 #[derive(Clone)]
 struct ExecutedQuery {
-    pub num_locks: usize,
     pub num_muts: usize,
+    pub table: Arc<Table>,
 }
 
 impl ExecutedQuery {
-    fn new(num_locks: usize, num_muts: usize) -> Self {
+    fn new(num_muts: usize, table: &Arc<Table>) -> Self {
         ExecutedQuery {
-            num_locks,
             num_muts,
+            table: Arc::clone(table),
         }
     }
 }
 
+// Record locks a transaction takes in one range before it tries locking the whole range instead
+const ESCALATION_THRESHOLD: usize = 64;
+
+// Key blocks a read locks one by one, it locks the whole column for more
+const KEY_BLOCK_LIMIT: u64 = 64;
+
+// Locks are told apart by the lock manager they came from, there's one per table
+type LockKey = (usize, LockTarget);
+
+/*
+    Locking is strict two-phase, every lock is held until the transaction
+    ends. Reads lock the keys they look for besides the records they find,
+    writes lock the keys they add or remove. Snapshot transactions read
+    without locks.
+*/
 pub struct Transaction {
     query_log: Vec<ExecutedQuery>,
     queries: Vec<(Query, Arc<Table>)>,
     write_log: Vec<Mutation>,
-    locks_acquired: Vec<LockHandle>,
+    locks_acquired: FxHashMap<LockKey, (Arc<LockManager>, LockHandle)>,
+    // Lock manager and range -> record locks taken in it and the strongest of them
+    range_locks: FxHashMap<(usize, usize), (usize, LockType)>,
     current_writes: usize,
-    current_locks: usize,
     current_status: QueryStatus,
+    isolation: Isolation,
+    // Of the database the transaction runs on, known once it's given a table
+    clock: Option<Arc<Clock>>,
+    // Set while a snapshot transaction runs
+    snapshot: Option<Snapshot>,
+    // Owner of this run's pending versions and locks
+    owner: u64,
+    // Owner of the first run, kept across retries so conflicts favour older transactions
+    age: u64,
+    lock_policy: LockPolicy,
+    versions: Vec<(Arc<Versions>, Write)>,
+}
+
+impl Default for Transaction {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Transaction {
     pub fn new() -> Self {
+        Self::with_isolation(Isolation::default())
+    }
+
+    pub fn with_isolation(isolation: Isolation) -> Self {
         Transaction {
             query_log: Vec::new(),
             queries: Vec::new(),
             write_log: Vec::new(),
-            locks_acquired: Vec::new(),
+            locks_acquired: FxHashMap::default(),
+            range_locks: FxHashMap::default(),
             current_writes: 0,
-            current_locks: 0,
             current_status: QueryStatus::Idle,
+            isolation,
+            clock: None,
+            snapshot: None,
+            owner: mvcc::NO_OWNER,
+            age: mvcc::NO_OWNER,
+            lock_policy: LockPolicy::default(),
+            versions: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_clock(isolation: Isolation, clock: &Arc<Clock>) -> Self {
+        Transaction {
+            clock: Some(Arc::clone(clock)),
+            ..Self::with_isolation(isolation)
         }
     }
 
+    pub fn isolation(&self) -> Isolation {
+        self.isolation
+    }
+
+    // What the transaction does when a record it wants to lock is locked by another one
+    pub fn set_lock_policy(&mut self, policy: LockPolicy) {
+        self.lock_policy = policy;
+    }
+
+    pub fn lock_policy(&self) -> LockPolicy {
+        self.lock_policy
+    }
+
     pub fn add_query(&mut self, query: Query, table: &Arc<Table>) {
         self.queries.push((query, table.clone()));
     }
 
     pub fn run(&mut self) -> bool {
+        // Rolling back writes to tail records, which merges mustn't reclaim meanwhile
+        let _epochs = self
+            .queries
+            .iter()
+            .map(|x| x.1.pin_epoch())
+            .collect::<Vec<_>>();
+
+        self.begin();
+
+        for (query, table) in self.queries.clone().iter() {
+            if self.execute(query, table).is_none() {
+                return false;
+            }
+        }
+
+        self.finish()
+    }
+
+    /*
+        Starts running the transaction one query at a time instead of all at
+        once with `run`. Queries go through `execute` and `finish` commits,
+        the queries added with `add_query` aren't run. A snapshot transaction
+        that wasn't created with `CrabStore::transaction` and has no queries
+        added doesn't know its database yet, it takes its snapshot at the
+        first query.
+    */
+    pub fn begin(&mut self) {
         self.write_log.reserve(self.queries.len());
-        self.locks_acquired.reserve(self.queries.len() * 2);
+        self.locks_acquired.reserve(self.queries.len() * 3);
         self.current_status = QueryStatus::Executing;
+        self.owner = mvcc::next_owner();
 
-        for query in self.queries.clone().iter() {
-            self.current_locks = 0;
-            self.current_writes = 0;
-
-            match &query.0 {
-                Query::Select(search_val, col_idx, selected) => {
-                    query
-                        .1
-                        .select_query(*search_val, *col_idx, selected, Some(self));
+        if self.age == mvcc::NO_OWNER {
+            self.age = self.owner;
+        }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Sum(start, end, val) => {
-                    query.1.sum_query(*start, *end, *val, Some(self));
+        if let Some((_, table)) = self.queries.first() {
+            self.clock
+                .get_or_insert_with(|| Arc::clone(table.versions.clock()));
+        }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Insert(vals) => {
-                    query.1.insert_query(vals, Some(self));
+        self.start_snapshot();
+    }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Update(key, vals) => {
-                    query.1.update_query(*key, vals, Some(self));
+    fn start_snapshot(&mut self) {
+        if let (Isolation::Snapshot, None, Some(clock)) =
+            (self.isolation, &self.snapshot, &self.clock)
+        {
+            self.snapshot = Some(Snapshot::begin(clock, self.owner));
+        }
+    }
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
-                Query::Delete(key) => {
-                    query.1.delete_query(*key, Some(self));
+    // None if the query aborted the transaction, which has been rolled back
+    pub fn execute(&mut self, query: &Query, table: &Arc<Table>) -> Option<QueryResult> {
+        self.current_writes = 0;
 
-                    self.query_log
-                        .push(ExecutedQuery::new(self.current_locks, self.current_writes));
-                }
+        match &self.clock {
+            Some(clock) if !Arc::ptr_eq(clock, table.versions.clock()) => {
+                // Timestamps of different databases can't be compared
+                self.set_aborted(false);
+                self.rollback();
+                return None;
             }
+            Some(_) => {}
+            None => {
+                self.clock = Some(Arc::clone(table.versions.clock()));
+                self.start_snapshot();
+            }
+        }
 
-            // println!(
-            //     "Current writes: {} Current locks: {} ({} acquired) , Thread Id: {:?}",
-            //     self.current_writes,
-            //     self.current_locks,
-            //     self.locks_acquired.len(),
-            //     std::thread::current().id()
-            // );
-
-            match self.current_status {
-                QueryStatus::AbortedNotRetryable | QueryStatus::AbortedRetryable => {
-                    self.rollback();
-                    return false;
-                }
-                _ => {}
+        let result = match query {
+            Query::Select(search_val, col_idx, selected) => table
+                .select_query(*search_val, *col_idx, selected, Some(self))
+                .map(QueryResult::Records),
+            Query::Sum(start, end, val) => table
+                .sum_query(*start, *end, *val, Some(self))
+                .map(QueryResult::Sum),
+            Query::Insert(vals) => table
+                .insert_query(vals, Some(self))
+                .map(|_| QueryResult::Written(self.current_writes > 0)),
+            Query::Update(key, vals) => table
+                .update_query(*key, vals, Some(self))
+                .map(QueryResult::Written),
+            Query::Delete(key) => table
+                .delete_query(*key, Some(self))
+                .map(QueryResult::Written),
+        };
+
+        // Running out of frames is worth retrying once other queries unpin theirs
+        if let Err(e) = &result {
+            self.set_aborted(matches!(e, CrabError::BufferPool(_)));
+        }
+
+        self.query_log
+            .push(ExecutedQuery::new(self.current_writes, table));
+
+        match self.current_status {
+            QueryStatus::AbortedNotRetryable | QueryStatus::AbortedRetryable => {
+                self.rollback();
+                None
             }
+            _ => result.ok(),
+        }
+    }
+
+    // Commits, or rolls back if a snapshot transaction lost a write-write conflict
+    pub fn finish(&mut self) -> bool {
+        if !self.commit_versions() {
+            self.set_aborted(true);
+            self.rollback();
+            return false;
         }
 
         self.commit();
         true
     }
 
-    fn commit(&mut self) {
-        self.write_log.clear();
+    // Rolls back a transaction started with `begin`
+    pub fn abort(&mut self) {
+        self.set_aborted(false);
+        self.rollback();
+    }
 
-        for idx in (0..self.query_log.len()).rev() {
-            let table = Arc::clone(&self.queries[idx].1);
-            let entry = self.query_log.remove(idx);
+    /*
+        Makes the run's writes visible to snapshots that start from now on,
+        after checking that no write to the same records committed since a
+        snapshot transaction started. Fails without committing anything if
+        one did.
+    */
+    fn commit_versions(&mut self) -> bool {
+        let snapshot = self.snapshot.as_ref();
+        let versions = &self.versions;
+
+        // Nothing was run
+        let clock = match &self.clock {
+            Some(clock) => clock,
+            None => return true,
+        };
+
+        clock.commit(|ts, oldest| {
+            if let Some(snapshot) = snapshot {
+                if versions
+                    .iter()
+                    .any(|(x, write)| x.conflicts(write.base(), snapshot))
+                {
+                    return false;
+                }
+            }
 
-            for _ in 0..entry.num_locks {
-                let lock = self.locks_acquired.remove(self.locks_acquired.len() - 1);
-                table.get_lock_manager().unlock(&lock);
+            for (x, write) in versions.iter() {
+                x.commit(*write, ts, oldest);
             }
-        }
+
+            true
+        })
+    }
+
+    fn commit(&mut self) {
+        self.write_log.clear();
+        self.versions.clear();
+        self.snapshot = None;
+        self.query_log.clear();
+        self.release_locks();
 
         self.current_status = QueryStatus::Idle;
 
@@ -185,8 +370,8 @@ impl Transaction {
 
     fn rollback(&mut self) {
         for idx in (0..(self.query_log.len())).rev() {
-            let table = Arc::clone(&self.queries[idx].1);
             let entry = self.query_log.remove(idx);
+            let table = &entry.table;
 
             for _ in 0..entry.num_muts {
                 let write_entry = self.write_log.remove(self.write_log.len() - 1);
@@ -205,7 +390,7 @@ impl Transaction {
                     Mutation::Record(write_entry) => {
                         table
                             .get_page(write_entry.modified_entry)
-                            .get_column(
+                            .wait_for_column(
                                 table.get_bufferpool().lock().borrow_mut(),
                                 write_entry.modified_column,
                             )
@@ -216,13 +401,17 @@ impl Transaction {
                     }
                 }
             }
+        }
 
-            for _ in 0..entry.num_locks {
-                let lock = self.locks_acquired.remove(self.locks_acquired.len() - 1);
-                table.get_lock_manager().unlock(&lock);
-            }
+        self.release_locks();
+
+        // Only once the records are back the way they were
+        for (versions, write) in self.versions.drain(..) {
+            versions.abort(write);
         }
 
+        self.snapshot = None;
+
         assert!(self.query_log.is_empty());
         assert!(self.write_log.is_empty());
         assert!(self.locks_acquired.is_empty());
@@ -245,6 +434,17 @@ impl Transaction {
         self.write_log.push(Mutation::Index(mutation));
     }
 
+    // Snapshot the transaction reads, None under two-phase locking
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+
+    // Marks `write` pending until the transaction commits or rolls back
+    pub fn log_version(&mut self, versions: &Arc<Versions>, write: Write) {
+        versions.begin_write(write, self.owner);
+        self.versions.push((Arc::clone(versions), write));
+    }
+
     pub fn log_write(&mut self, modified_column: usize, modified_entry: RID, original_value: u64) {
         self.current_writes += 1;
         self.write_log.push(Mutation::Record(RecordMutation {
@@ -254,49 +454,227 @@ impl Transaction {
         }));
     }
 
-    fn try_lock(&mut self, locks: &LockManager, rid: RID, lock_type: LockType) -> bool {
-        let lock = self.locks_acquired.iter_mut().find(|x| x.rid == rid);
+    fn release_locks(&mut self) {
+        for (_, (locks, handle)) in self.locks_acquired.drain() {
+            locks.unlock(&handle);
+        }
+
+        self.range_locks.clear();
+    }
+
+    fn locker(&self) -> Locker {
+        Locker {
+            owner: self.owner,
+            age: self.age,
+            policy: self.lock_policy,
+        }
+    }
+
+    // What the transaction holds `target` with, if anything
+    fn held(&self, locks: &Arc<LockManager>, target: LockTarget) -> Option<LockType> {
+        self.locks_acquired
+            .get(&(Arc::as_ptr(locks) as usize, target))
+            .map(|x| x.1.lock_type)
+    }
+
+    // Locks `target` alone, true if it was already held strongly enough
+    fn try_lock(
+        &mut self,
+        locks: &Arc<LockManager>,
+        target: LockTarget,
+        lock_type: LockType,
+    ) -> bool {
+        let locker = self.locker();
+
+        if let Some((_, l)) = self
+            .locks_acquired
+            .get_mut(&(Arc::as_ptr(locks) as usize, target))
+        {
+            return l.lock_type.covers(lock_type) || locks.upgrade(l, lock_type, &locker);
+        }
+
+        match locks.lock(target, lock_type, &locker) {
+            Some(handle) => {
+                self.locks_acquired.insert(
+                    (Arc::as_ptr(locks) as usize, target),
+                    (Arc::clone(locks), handle),
+                );
+                true
+            }
+            None => false,
+        }
+    }
+
+    /*
+        Locks the record `rid`, after taking the intention lock on its table
+        and range. A shared or exclusive lock on either already covers the
+        record, so it isn't locked on its own.
+    */
+    fn lock_record(&mut self, locks: &Arc<LockManager>, rid: RID, lock_type: LockType) -> bool {
+        let range = LockTarget::Range(rid.page_range());
+
+        for target in [LockTarget::Table, range] {
+            if !self.try_lock(locks, target, lock_type.intention()) {
+                return false;
+            }
 
-        if let Some(l) = lock {
-            if lock_type == LockType::Exclusive && l.lock_type == LockType::Shared {
-                return locks.upgrade_shared(l);
-            } else {
+            if self
+                .held(locks, target)
+                .map_or(false, |x| x.covers(lock_type))
+            {
                 return true;
             }
         }
 
-        if let Some(handle) = locks.try_lock(rid, lock_type) {
-            self.current_locks += 1;
-            self.locks_acquired.push(handle);
+        let record = LockTarget::Record(rid);
+        let new = self.held(locks, record).is_none();
 
-            /*
-                            println!(
-                                "Thread {:?} acquired a {:?} lock on {}, now at {} locks",
-                                std::thread::current().id(),
-                                lock_type,
-                                rid.raw(),
-                                self.locks_acquired.len()
-                            );
-            */
-            true
-        } else {
-            false
+        if !self.try_lock(locks, record, lock_type) {
+            return false;
+        }
+
+        let key = (Arc::as_ptr(locks) as usize, rid.page_range());
+        let count = self.range_locks.entry(key).or_insert((0, LockType::Shared));
+
+        count.1 = count.1.join(lock_type);
+
+        if new {
+            count.0 += 1;
+
+            if count.0 % ESCALATION_THRESHOLD == 0 {
+                let lock_type = count.1;
+                self.escalate(locks, rid.page_range(), lock_type);
+            }
+        }
+
+        true
+    }
+
+    /*
+        Locks the values `keys` of column `column_index`, after taking the
+        intention lock on the table and column. Ranges lock the key blocks
+        they cover, or the column if that's too many of them.
+    */
+    fn lock_keys(
+        &mut self,
+        locks: &Arc<LockManager>,
+        column_index: usize,
+        keys: RangeInclusive<u64>,
+        lock_type: LockType,
+    ) -> bool {
+        let blocks = (keys.start() >> KEY_BLOCK_BITS)..=(keys.end() >> KEY_BLOCK_BITS);
+        let whole = blocks.end().saturating_sub(*blocks.start()) >= KEY_BLOCK_LIMIT;
+
+        let column = LockTarget::Column(column_index);
+        // Only a shared or exclusive lock covers what's below it
+        let covered = |t: &Self, target| {
+            t.held(locks, target)
+                .map_or(false, |x| x != x.intention() && x.covers(lock_type))
+        };
+
+        if !self.try_lock(locks, LockTarget::Table, lock_type.intention()) {
+            return false;
         }
+
+        if covered(self, LockTarget::Table) {
+            return true;
+        }
+
+        if whole {
+            return self.try_lock(locks, column, lock_type);
+        }
+
+        if !self.try_lock(locks, column, lock_type.intention()) {
+            return false;
+        }
+
+        if covered(self, column) {
+            return true;
+        }
+
+        if keys.start() != keys.end() {
+            return blocks
+                .into_iter()
+                .all(|x| self.try_lock(locks, LockTarget::Keys(column_index, x), lock_type));
+        }
+
+        // A single key is locked on its own, writes to other keys in its block go ahead
+        let block = LockTarget::Keys(column_index, *blocks.start());
+
+        if !self.try_lock(locks, block, lock_type.intention()) {
+            return false;
+        }
+
+        covered(self, block)
+            || self.try_lock(
+                locks,
+                LockTarget::Key(column_index, *keys.start()),
+                lock_type,
+            )
+    }
+
+    // Trades the record locks taken in `range` for one lock on the whole range, if it can
+    fn escalate(&mut self, locks: &Arc<LockManager>, range: usize, lock_type: LockType) {
+        let locker = self.locker();
+        let manager = Arc::as_ptr(locks) as usize;
+
+        let escalated = match self
+            .locks_acquired
+            .get_mut(&(manager, LockTarget::Range(range)))
+        {
+            Some((_, handle)) => locks.escalate(handle, lock_type, &locker),
+            None => false,
+        };
+
+        if !escalated {
+            return;
+        }
+
+        self.locks_acquired
+            .retain(|(x, target), (_, handle)| match target {
+                LockTarget::Record(rid) if *x == manager && rid.page_range() == range => {
+                    locks.unlock(handle);
+                    false
+                }
+                _ => true,
+            });
     }
 
     pub fn try_lock_with_abort(
         &mut self,
-        locks: &LockManager,
+        locks: &Arc<LockManager>,
         rid: RID,
         lock_type: LockType,
     ) -> bool {
-        if !self.try_lock(locks, rid, lock_type) {
-            // println!(
-            //     "Thread {:?} failed to lock {:?} on RID {:?}",
-            //     std::thread::current().id(),
-            //     lock_type,
-            //     rid
-            // );
+        if !self.lock_record(locks, rid, lock_type) {
+            self.set_aborted(true);
+            return false;
+        }
+        true
+    }
+
+    pub fn try_lock_keys_with_abort(
+        &mut self,
+        locks: &Arc<LockManager>,
+        column: usize,
+        keys: RangeInclusive<u64>,
+        lock_type: LockType,
+    ) -> bool {
+        if !self.lock_keys(locks, column, keys, lock_type) {
+            self.set_aborted(true);
+            return false;
+        }
+        true
+    }
+
+    // Locks `target` on its own, for queries that don't go one record at a time
+    pub fn try_lock_target_with_abort(
+        &mut self,
+        locks: &Arc<LockManager>,
+        target: LockTarget,
+        lock_type: LockType,
+    ) -> bool {
+        if !self.try_lock(locks, target, lock_type) {
             self.set_aborted(true);
             return false;
         }
@@ -1,11 +1,4 @@
-use std::{
-    collections::VecDeque,
-    process::id,
-    sync::{
-        atomic::{AtomicBool, AtomicUsize},
-        Arc,
-    },
-};
+use std::{collections::VecDeque, sync::Arc};
 
 use parking_lot::RwLock;
 
@@ -15,7 +8,6 @@ pub struct TransactionWorker {
     transactions: Arc<RwLock<VecDeque<Transaction>>>,
     thread: Option<std::thread::JoinHandle<()>>,
     stats: Arc<RwLock<Vec<bool>>>,
-    result: usize,
 }
 
 impl TransactionWorker {
@@ -49,7 +41,6 @@ impl TransactionWorker {
             transactions,
             thread: None,
             stats: Arc::new(RwLock::new(Vec::new())),
-            result: 0,
         }
     }
 
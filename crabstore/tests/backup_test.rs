@@ -0,0 +1,157 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use crabcore::{crabstore::CrabStore, table::Table};
+use tempfile::tempdir;
+
+// Keys are inserted in order, so a consistent copy holds exactly 0..n
+fn check_prefix(table: &Table) -> u64 {
+    let mut n = 0;
+
+    while let Some(record) = table.select_query(n, 0, &[1, 1, 1], None).unwrap().pop() {
+        assert_eq!(record.columns, [n, n * 2, n * 3]);
+        n += 1;
+    }
+
+    for key in n..n + 100 {
+        assert!(table
+            .select_query(key, 0, &[1, 1, 1], None)
+            .unwrap()
+            .is_empty());
+    }
+
+    n
+}
+
+#[test]
+fn backup_while_writing() {
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let restore_dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.create_table("Busy", 3, 0).unwrap();
+    let other = crabstore.create_table("Quiet", 2, 0).unwrap();
+
+    for i in 0..1000 {
+        other.insert_query(&[i, i + 1], None).unwrap();
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let writer = {
+        let table = Arc::clone(&table);
+        let stop = Arc::clone(&stop);
+
+        thread::spawn(move || {
+            let mut i = 0;
+
+            while !stop.load(Ordering::Relaxed) || i < 2000 {
+                table.insert_query(&[i, i * 2, i * 3], None).unwrap();
+                i += 1;
+            }
+
+            i
+        })
+    };
+
+    let first = crabstore.backup(backup_dir.path()).unwrap();
+    assert_eq!(first.tables, ["Busy", "Quiet"]);
+    assert_eq!(first.blobs_written, 6);
+    assert!(first.pages_written > 0);
+
+    // Only pages changed since the first backup are copied again
+    let second = crabstore.backup(backup_dir.path()).unwrap();
+    assert!(second.pages_unchanged > 0);
+
+    stop.store(true, Ordering::Relaxed);
+    let inserted = writer.join().unwrap();
+
+    // The backup is a database of its own
+    let mut copy = CrabStore::new(backup_dir.path().into());
+    copy.open_read_only();
+
+    assert!(check_prefix(&copy.get_table("Busy")) < inserted);
+    assert_eq!(
+        copy.get_table("Quiet")
+            .select_query(999, 0, &[1, 1], None)
+            .unwrap()[0]
+            .columns,
+        [999, 1000]
+    );
+    copy.close();
+
+    crabstore.drop_table("Quiet");
+    crabstore.backup(backup_dir.path()).unwrap();
+
+    drop(table);
+    drop(other);
+    crabstore.close();
+
+    let restored = CrabStore::restore(backup_dir.path(), restore_dir.path()).unwrap();
+    assert_eq!(restored.tables, ["Busy"]);
+    assert!(!restore_dir.path().join("Quiet_db.CRAB").exists());
+
+    let mut crabstore = CrabStore::new(restore_dir.path().into());
+    crabstore.open();
+
+    assert_eq!(check_prefix(&crabstore.get_table("Busy")), inserted);
+    crabstore.close();
+}
+
+#[test]
+fn incremental_backups() {
+    let dir = tempdir().unwrap();
+    let backup_dir = tempdir().unwrap();
+    let other_dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.create_table("Grades", 3, 0).unwrap();
+
+    for i in 0..5000 {
+        table.insert_query(&[i, i * 2, i * 3], None).unwrap();
+    }
+
+    let first = crabstore.backup(backup_dir.path()).unwrap();
+    assert!(first.pages_written > 0);
+
+    // Only the header page the checkpoint rewrote, nothing is compared
+    let unchanged = crabstore.backup(backup_dir.path()).unwrap();
+    assert_eq!(unchanged.pages_written, 1);
+
+    table
+        .update_query(42, &[None, Some(7), None], None)
+        .unwrap();
+
+    let second = crabstore.backup(backup_dir.path()).unwrap();
+    assert!(second.pages_written > 1);
+    assert!(second.pages_written < first.pages_written);
+
+    // Somewhere the table wasn't backed up to last is compared page by page
+    let other = crabstore.backup(other_dir.path()).unwrap();
+    assert!(other.pages_written > first.pages_written);
+
+    drop(table);
+    crabstore.close();
+
+    let mut copy = CrabStore::new(backup_dir.path().into());
+    copy.open_read_only();
+
+    assert_eq!(
+        copy.get_table("Grades")
+            .select_query(42, 0, &[1, 1, 1], None)
+            .unwrap()[0]
+            .columns,
+        [42, 7, 126]
+    );
+    copy.close();
+}
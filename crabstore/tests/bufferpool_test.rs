@@ -0,0 +1,425 @@
+use std::{sync::Arc, thread, time::Duration};
+
+use crabcore::{
+    bufferpool::{BufferPool, BufferPoolError, LockedBufferPool},
+    config::Config,
+    crabstore::CrabStore,
+    disk_manager::DiskManager,
+    error::CrabError,
+    replacement_policy::{AccessHint, ReplacementPolicyKind},
+    transaction::{Query, QueryStatus, Transaction},
+};
+use parking_lot::Mutex;
+use tempfile::tempdir;
+
+fn all(_: usize) -> bool {
+    true
+}
+
+#[test]
+fn parse_policy_names() {
+    assert_eq!("clock".parse(), Ok(ReplacementPolicyKind::Clock));
+    assert_eq!("LRU-3".parse(), Ok(ReplacementPolicyKind::LruK(3)));
+    assert_eq!("2q".parse(), Ok(ReplacementPolicyKind::TwoQ));
+    assert_eq!("arc".parse(), Ok(ReplacementPolicyKind::Arc));
+    assert!("lru-0".parse::<ReplacementPolicyKind>().is_err());
+    assert!("fifo".parse::<ReplacementPolicyKind>().is_err());
+}
+
+#[test]
+fn sequential_pages_are_evicted_first() {
+    for kind in [
+        ReplacementPolicyKind::Clock,
+        ReplacementPolicyKind::LruK(2),
+        ReplacementPolicyKind::TwoQ,
+        ReplacementPolicyKind::Arc,
+    ] {
+        let mut policy = kind.build(4);
+
+        for frame in 0..3 {
+            policy.record_load(frame, frame, AccessHint::Random);
+            policy.record_access(frame, AccessHint::Random);
+        }
+        policy.record_load(3, 3, AccessHint::Sequential);
+
+        assert_eq!(policy.find_victim(10, &all), Some(3), "{kind:?}");
+    }
+}
+
+#[test]
+fn pinned_frames_are_skipped() {
+    for kind in [
+        ReplacementPolicyKind::Clock,
+        ReplacementPolicyKind::LruK(2),
+        ReplacementPolicyKind::TwoQ,
+        ReplacementPolicyKind::Arc,
+    ] {
+        let mut policy = kind.build(2);
+        policy.record_load(0, 0, AccessHint::Random);
+        policy.record_load(1, 1, AccessHint::Random);
+
+        assert_eq!(policy.find_victim(2, &|f| f == 1), Some(1), "{kind:?}");
+        assert_eq!(policy.find_victim(2, &|_| false), None, "{kind:?}");
+    }
+}
+
+#[test]
+fn table_with_each_policy() {
+    for kind in [
+        ReplacementPolicyKind::Clock,
+        ReplacementPolicyKind::LruK(2),
+        ReplacementPolicyKind::TwoQ,
+        ReplacementPolicyKind::Arc,
+    ] {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            bufferpool_size: 64,
+            replacement_policy: kind,
+            ..Default::default()
+        };
+
+        let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+        crabstore.open();
+
+        let table = crabstore.create_table("policy", 3, 0).unwrap();
+        let num_records = 3000;
+
+        for i in 0..num_records {
+            table.insert_query(&[i, i * 2, 1], None).unwrap();
+        }
+
+        for i in (0..num_records).step_by(3) {
+            table.update_query(i, &[None, None, Some(2)], None).unwrap();
+        }
+
+        assert_eq!(
+            table.sum_query(0, num_records - 1, 1, None).unwrap(),
+            num_records * (num_records - 1)
+        );
+        assert_eq!(
+            table.sum_query(0, num_records - 1, 2, None).unwrap(),
+            num_records + num_records / 3
+        );
+        assert_eq!(
+            table.select_query(1500, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [1500, 3000, 2]
+        );
+
+        drop(table);
+        crabstore.close();
+    }
+}
+
+#[test]
+fn exhausted_pool_returns_error() {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("exhausted.CRAB")).unwrap());
+    let pool = Mutex::new(BufferPool::new(disk, 2));
+    let mut bp = pool.lock();
+    bp.set_evict_timeout(Duration::from_millis(50));
+
+    let first = bp.get_page(1).unwrap();
+    let second = bp.get_page(2).unwrap();
+
+    assert_eq!(bp.pinned_frames(), 2);
+    assert!(matches!(
+        bp.get_page(3),
+        Err(BufferPoolError::Exhausted { frames: 2, .. })
+    ));
+    assert!(matches!(
+        bp.new_page(),
+        Err(BufferPoolError::Exhausted { frames: 2, .. })
+    ));
+
+    drop(first);
+
+    assert!(bp.get_page(3).is_ok());
+    assert_eq!(bp.pinned_frames(), 1);
+    drop(second);
+}
+
+#[test]
+fn queries_fail_when_frames_run_out() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        bufferpool_size: 16,
+        evict_timeout: Duration::from_millis(50),
+        flush_interval: None,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    let table = crabstore.create_table("exhausted", 3, 0).unwrap();
+
+    for i in 0..100 {
+        table.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    let bp = table.get_bufferpool();
+    let pinned = (0..16)
+        .map(|_| bp.lock().new_page().unwrap())
+        .collect::<Vec<_>>();
+
+    assert!(matches!(
+        table.select_query(5, 0, &[1, 1, 1], None),
+        Err(CrabError::BufferPool(BufferPoolError::Exhausted { .. }))
+    ));
+    assert!(matches!(
+        table.sum_query(0, 99, 1, None),
+        Err(CrabError::BufferPool(BufferPoolError::Exhausted { .. }))
+    ));
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Update(5, Box::new([None, Some(50), None])), &table);
+
+    assert!(!transaction.run());
+    assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+
+    drop(pinned);
+
+    let mut transaction = Transaction::new();
+    transaction.add_query(Query::Update(5, Box::new([None, Some(50), None])), &table);
+
+    assert!(transaction.run());
+    assert_eq!(
+        table.select_query(5, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [5, 50, 5]
+    );
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn eviction_waits_for_unpin() {
+    let dir = tempdir().unwrap();
+    let disk = Arc::new(DiskManager::new(&dir.path().join("wait.CRAB")).unwrap());
+    let bp = Arc::new(Mutex::new(BufferPool::new(disk, 1)));
+
+    let pinned = bp.lock().get_page(1).unwrap();
+    pinned.write_slot(0, 42);
+
+    let unpinner = {
+        let bp = Arc::clone(&bp);
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+
+            // The waiting fault below mustn't be holding the pool
+            assert_eq!(bp.lock().pinned_frames(), 1);
+            drop(pinned);
+        })
+    };
+
+    let page = bp.lock().get_page(2).expect("Frame was never released");
+    drop(page);
+    unpinner.join().unwrap();
+
+    assert_eq!(bp.lock().get_page(1).unwrap().slot(0), 42);
+}
+
+#[test]
+fn bufferpool_counters() {
+    let dir = tempdir().expect("Failed to get temp directory");
+    let disk = Arc::new(DiskManager::new(&dir.path().join("stats.CRAB")).unwrap());
+    let pool = Mutex::new(BufferPool::new(Arc::clone(&disk), 2));
+    let mut bp = pool.lock();
+
+    let pages = (0..3)
+        .map(|_| {
+            let page = bp.new_page().unwrap();
+            page.write_slot(0, 1);
+            page.get_page_id()
+        })
+        .collect::<Vec<_>>();
+
+    // Third new page evicted the (dirty) first one
+    let stats = bp.stats();
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.dirty_evictions, 1);
+    assert_eq!(stats.dirty_pages, 2);
+    assert_eq!(disk.stats().writes, 1);
+
+    drop(bp.get_page(pages[2]).unwrap());
+    drop(bp.get_page(pages[0]).unwrap());
+
+    let stats = bp.stats();
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses, 1);
+    assert_eq!(stats.hit_rate(), 0.5);
+    assert_eq!(disk.stats().reads, 1);
+
+    bp.flush_all();
+
+    let stats = bp.stats();
+    assert_eq!(stats.dirty_pages, 0);
+    assert_eq!(stats.flushes, 1);
+    assert_eq!(disk.stats().syncs, 1);
+}
+
+#[test]
+fn database_stats() {
+    let dir = tempdir().expect("Failed to get temp directory");
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        Config {
+            flush_interval: None,
+            ..Default::default()
+        },
+    );
+    db.open();
+
+    let a = db.create_table("a", 3, 0).unwrap();
+    db.create_table("b", 2, 0).unwrap();
+
+    for i in 0..100 {
+        a.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    let stats = db.stats();
+    let a_stats = stats.table("a").unwrap();
+
+    assert_eq!(stats.tables.len(), 2);
+    assert!(a_stats.bufferpool.dirty_pages > 0);
+    assert!(a_stats.bufferpool.hits > 0);
+    assert_eq!(
+        stats.total().bufferpool.hits,
+        a_stats.bufferpool.hits + stats.table("b").unwrap().bufferpool.hits
+    );
+
+    a.checkpoint();
+
+    let a_stats = db.stats().table("a").cloned().unwrap();
+    assert_eq!(a_stats.bufferpool.dirty_pages, 0);
+    assert!(a_stats.bufferpool.flushes > 0);
+    assert!(a_stats.disk.writes >= a_stats.bufferpool.flushes);
+
+    db.close();
+}
+
+#[test]
+fn checkpoints_flush_in_batches() {
+    let dir = tempdir().expect("Failed to get temp directory");
+    let disk = Arc::new(DiskManager::new(&dir.path().join("batches.CRAB")).unwrap());
+    let pool = Mutex::new(BufferPool::new(Arc::clone(&disk), 4));
+    let mut bp = pool.lock();
+
+    for _ in 0..3 {
+        bp.new_page().unwrap().write_slot(0, 1);
+    }
+
+    let (batch, next) = bp.dirty_frames_from(0, 2);
+    assert_eq!(batch.len(), 2);
+    assert_eq!(bp.stats().pinned_pages, 2);
+
+    drop(batch);
+
+    let (batch, next) = bp.dirty_frames_from(next.unwrap(), 2);
+    assert_eq!(batch.len(), 1);
+    assert_eq!(next, None);
+
+    drop(batch);
+    drop(bp);
+
+    let dir = tempdir().expect("Failed to get temp directory");
+    let mut db = CrabStore::with_config(
+        dir.path().into(),
+        Config {
+            bufferpool_size: 64,
+            flush_interval: None,
+            flush_batch: 1,
+            ..Default::default()
+        },
+    );
+    db.open();
+
+    let table = db.create_table("batches", 3, 0).unwrap();
+
+    for i in 0..2000 {
+        table.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    let dirty = db.stats().table("batches").unwrap().bufferpool.dirty_pages;
+    assert!(dirty > 1);
+
+    table.checkpoint();
+
+    let stats = db.stats().table("batches").cloned().unwrap();
+    assert_eq!(stats.bufferpool.dirty_pages, 0);
+    assert_eq!(stats.bufferpool.pinned_pages, 0);
+
+    drop(table);
+    db.close();
+}
+
+#[test]
+fn prefetched_pages_are_not_stale() {
+    let dir = tempdir().expect("Failed to get temp directory");
+    let disk = Arc::new(DiskManager::new(&dir.path().join("prefetch.CRAB")).unwrap());
+    let pool = Mutex::new(BufferPool::new(Arc::clone(&disk), 2));
+    let mut bp = pool.lock();
+
+    let pages = (0..4)
+        .map(|i| {
+            let page = bp.new_page().unwrap();
+            page.write_slot(0, i);
+            page.get_page_id()
+        })
+        .collect::<Vec<_>>();
+
+    bp.flush_all();
+    bp.prefetch(&pages);
+
+    // Overwrite a page after it was queued for read ahead
+    let page = bp.get_page(pages[1]).unwrap();
+    page.write_slot(0, 100);
+    drop(page);
+    bp.flush_all();
+
+    let values = pages
+        .iter()
+        .map(|x| bp.get_page(*x).unwrap().slot(0))
+        .collect::<Vec<_>>();
+
+    assert_eq!(values, [0, 100, 2, 3]);
+    assert!(disk.stats().prefetch_hits > 0);
+}
+
+#[test]
+fn scans_read_ahead() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        bufferpool_size: 32,
+        flush_interval: None,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    let table = crabstore.create_table("scan", 3, 0).unwrap();
+    let num_records = 10000;
+
+    for i in 0..num_records {
+        table.insert_query(&[i, i % 7, 1], None).unwrap();
+    }
+
+    table.build_index(1).unwrap();
+    assert_eq!(
+        table.select_query(3, 1, &[1, 1, 1], None).unwrap().len(),
+        1429
+    );
+    assert_eq!(
+        table.sum_query(0, num_records - 1, 2, None).unwrap(),
+        num_records
+    );
+
+    let stats = table.stats();
+    assert!(stats.disk.prefetch_hits > 0);
+    assert!(stats.disk.prefetch_hits <= stats.disk.prefetch_reads);
+
+    drop(table);
+    crabstore.close();
+}
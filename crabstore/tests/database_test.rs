@@ -1,10 +1,10 @@
 #![feature(test)]
+#![allow(clippy::needless_range_loop)]
 extern crate test;
-use crabcore::{crabstore::CrabStore, record::Record};
+use crabcore::{config::Config, crabstore::CrabStore, error::CrabError, record::Record};
 use rand::prelude::*;
-use std::{collections::HashMap, path::Path};
+use std::{collections::HashMap, fs, path::Path, thread, time::Duration};
 use tempfile::tempdir;
-use test::Bencher;
 
 #[test]
 fn verify() {
@@ -14,22 +14,22 @@ fn verify() {
 
     let mut crabstore = CrabStore::new(dir.path().into());
     crabstore.open();
-    let grades = crabstore.create_table("Grades", 4, 0);
+    let grades = crabstore.create_table("Grades", 4, 0).unwrap();
 
     for i in 0..num_records {
-        grades.insert_query(&[i, 2, 3, 4], None);
+        grades.insert_query(&[i, 2, 3, 4], None).unwrap();
     }
 
-    let sum = grades.sum_query(0, num_records, 1, None);
+    let sum = grades.sum_query(0, num_records, 1, None).unwrap();
     assert_eq!(sum, 2 * num_records);
-    let sum = grades.sum_query(0, num_records, 2, None);
+    let sum = grades.sum_query(0, num_records, 2, None).unwrap();
     assert_eq!(sum, 3 * num_records);
 
-    let selected = grades.select_query(19999, 0, &[1, 1, 1, 1], None);
+    let selected = grades.select_query(19999, 0, &[1, 1, 1, 1], None).unwrap();
     assert_eq!(selected[0].columns, &[19999, 2, 3, 4]);
 
     for i in 0..num_records {
-        let old_values = &grades.select_query(i, 0, &[1, 1, 1, 1], None)[0].columns;
+        let old_values = &grades.select_query(i, 0, &[1, 1, 1, 1], None).unwrap()[0].columns;
         let mut new_values = old_values
             .iter()
             .map(|x| Some(x + i))
@@ -37,10 +37,10 @@ fn verify() {
 
         new_values[0] = None;
 
-        grades.update_query(i, &new_values, None);
+        grades.update_query(i, &new_values, None).unwrap();
     }
 
-    let selected = grades.select_query(19965, 0, &[1, 1, 1, 1], None);
+    let selected = grades.select_query(19965, 0, &[1, 1, 1, 1], None).unwrap();
     assert_eq!(selected[0].columns, [19965, 19967, 19968, 19969]);
     drop(grades);
 
@@ -74,14 +74,14 @@ fn correctness_tester1() {
     let mut crabstore = CrabStore::new(dir.path().into());
     crabstore.open();
 
-    let table = crabstore.create_table("test", 5, 0);
+    let table = crabstore.create_table("test", 5, 0).unwrap();
 
     for record in records {
-        table.insert_query(&record, None);
+        table.insert_query(&record, None).unwrap();
     }
 
-    table.build_index(2);
-    let result = regorganize_result(table.select_query(1, 2, &[1, 1, 1, 1, 1], None));
+    table.build_index(2).unwrap();
+    let result = regorganize_result(table.select_query(1, 2, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 4);
     assert!(result.iter().any(|x| x.eq(&records[0])));
     assert!(result.iter().any(|x| x.eq(&records[1])));
@@ -89,33 +89,37 @@ fn correctness_tester1() {
     assert!(result.iter().any(|x| x.eq(&records[7])));
 
     table.drop_index(2);
-    let result = regorganize_result(table.select_query(3, 2, &[1, 1, 1, 1, 1], None));
+    let result = regorganize_result(table.select_query(3, 2, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 1);
     assert!(result.iter().any(|x| x.eq(&records[2])));
 
-    let result = regorganize_result(table.select_query(1, 2, &[1, 1, 1, 1, 1], None));
+    let result = regorganize_result(table.select_query(1, 2, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 4);
     assert!(result.iter().any(|x| x.eq(&records[0])));
     assert!(result.iter().any(|x| x.eq(&records[1])));
     assert!(result.iter().any(|x| x.eq(&records[5])));
     assert!(result.iter().any(|x| x.eq(&records[7])));
 
-    let result = regorganize_result(table.select_query(10, 2, &[1, 1, 1, 1, 1], None));
+    let result = regorganize_result(table.select_query(10, 2, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 0);
 
-    table.update_query(8, &[None, Some(2), Some(2), Some(2), Some(2)], None);
-    let result = regorganize_result(table.select_query(8, 2, &[1, 1, 1, 1, 1], None));
+    table
+        .update_query(8, &[None, Some(2), Some(2), Some(2), Some(2)], None)
+        .unwrap();
+    let result = regorganize_result(table.select_query(8, 2, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 0);
 
-    table.update_query(7, &[Some(8), Some(2), Some(2), Some(2), Some(2)], None);
-    let result = regorganize_result(table.select_query(7, 0, &[1, 1, 1, 1, 1], None));
+    table
+        .update_query(7, &[Some(8), Some(2), Some(2), Some(2), Some(2)], None)
+        .unwrap();
+    let result = regorganize_result(table.select_query(7, 0, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 0);
 
-    table.delete_query(5, None);
-    let result = regorganize_result(table.select_query(5, 0, &[1, 1, 1, 1, 1], None));
+    table.delete_query(5, None).unwrap();
+    let result = regorganize_result(table.select_query(5, 0, &[1, 1, 1, 1, 1], None).unwrap());
     assert_eq!(result.len(), 0);
 
-    let table2 = crabstore.create_table("test2", 5, 0);
+    let table2 = crabstore.create_table("test2", 5, 0).unwrap();
     let records2 = [
         [1, 1, 1, 2, 1],
         [2, 1, 1, 1, 2],
@@ -128,10 +132,10 @@ fn correctness_tester1() {
     ];
 
     for record in records2.iter() {
-        table2.insert_query(record, None);
+        table2.insert_query(record, None).unwrap();
     }
 
-    let result = regorganize_result(table2.select_query(1, 0, &[1, 1, 1, 1, 1], None));
+    let result = regorganize_result(table2.select_query(1, 0, &[1, 1, 1, 1, 1], None).unwrap());
 
     assert_eq!(result.len(), 1);
     assert!(result.iter().any(|x| x.eq(&records2[0])));
@@ -154,25 +158,26 @@ fn correctness_tester2() {
     let mut crabstore = CrabStore::new(dir.path().into());
     crabstore.open();
 
-    let table = crabstore.create_table("test3", 5, 2);
+    let table = crabstore.create_table("test3", 5, 2).unwrap();
 
     for record in records.iter() {
-        table.insert_query(record, None);
+        table.insert_query(record, None).unwrap();
     }
 
-    let result = table.sum_query(3, 5, 4, None);
+    let result = table.sum_query(3, 5, 4, None).unwrap();
     assert_eq!(result, 5);
 }
 
 const NUMBER_OF_RECORDS: u64 = 1000;
+#[allow(dead_code)]
 const NUMBER_OF_AGGREGATES: u64 = 100;
 const NUMBER_OF_UPDATES: u64 = 1;
 
-fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, keys: &Vec<u64>) {
+fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, keys: &[u64]) {
     let mut crabstore = CrabStore::new(directory.to_path_buf());
     crabstore.open();
 
-    let table = crabstore.create_table("Grades", 5, 0);
+    let table = crabstore.create_table("Grades", 5, 0).unwrap();
 
     let mut rand = StdRng::seed_from_u64(3562901);
 
@@ -185,12 +190,12 @@ fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, ke
             rand.gen_range(0..20),
             rand.gen_range(0..20),
         ];
-        table.insert_query(&record, None);
+        table.insert_query(&record, None).unwrap();
         records.insert(key, record);
     }
 
     for key in keys.iter() {
-        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0].columns;
         for (i, column) in record.iter().enumerate() {
             assert_eq!(*column, records.get(key).unwrap()[i]);
         }
@@ -199,14 +204,13 @@ fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, ke
     for _ in 0..NUMBER_OF_UPDATES {
         for key in keys.iter() {
             let mut updated_columns = [None, None, None, None, None];
-            let original = records.get(key).unwrap().clone();
             for i in 1..table.columns() {
                 let val = rand.gen_range(0..20);
                 updated_columns[i] = Some(val);
                 records.get_mut(key).unwrap()[i] = val;
             }
-            table.update_query(*key, &updated_columns, None);
-            let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0];
+            table.update_query(*key, &updated_columns, None).unwrap();
+            let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0];
             for (i, val) in record.columns.iter().enumerate() {
                 assert_eq!(*val, records.get(key).unwrap()[i]);
             }
@@ -228,14 +232,14 @@ fn durability_tester1(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, ke
     crabstore.close();
 }
 
-fn durability_tester2(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, keys: &Vec<u64>) {
+fn durability_tester2(directory: &Path, records: &mut HashMap<u64, Vec<u64>>, keys: &[u64]) {
     let mut crabstore = CrabStore::new(directory.to_path_buf());
     crabstore.open();
 
     let table = crabstore.get_table("Grades");
 
     for key in keys.iter() {
-        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0].columns;
         for (i, column) in record.iter().enumerate() {
             assert_eq!(*column, records.get(key).unwrap()[i]);
         }
@@ -282,3 +286,234 @@ fn durability_tester() {
     durability_tester1(dir.path(), &mut records, &keys);
     durability_tester2(dir.path(), &mut records, &keys);
 }
+
+#[test]
+fn checkpoint_survives_crash() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        flush_interval: Some(Duration::from_millis(10)),
+        checkpoint_interval: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.create_table("Checkpointed", 3, 0).unwrap();
+
+    for i in 0..2000 {
+        table.insert_query(&[i, i + 1, i + 2], None).unwrap();
+    }
+
+    for i in 0..2000 {
+        table
+            .update_query(i, &[None, Some(i * 10), None], None)
+            .unwrap();
+    }
+
+    thread::sleep(Duration::from_millis(300));
+
+    // No close(), everything after the last checkpoint is lost
+    drop(table);
+    drop(crabstore);
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    let table = crabstore.get_table("Checkpointed");
+
+    for i in 0..2000 {
+        assert_eq!(
+            table.select_query(i, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [i, i * 10, i + 2]
+        );
+    }
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn mismatched_generation_is_detected() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        checkpoint_interval: None,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.create_table("Generations", 3, 0).unwrap();
+
+    for i in 0..500 {
+        table.insert_query(&[i, i + 1, i + 2], None).unwrap();
+    }
+
+    crabstore.checkpoint();
+
+    let page_dir = dir.path().join("Generations_pd.CRAB");
+    let stale = dir.path().join("stale_pd");
+    fs::copy(&page_dir, &stale).unwrap();
+
+    for i in 500..1000 {
+        table.insert_query(&[i, i + 1, i + 2], None).unwrap();
+    }
+
+    drop(table);
+    crabstore.close();
+
+    // Every file was replaced by a rename, nothing half written is left over
+    assert!(fs::read_dir(dir.path()).unwrap().all(|x| !x
+        .unwrap()
+        .file_name()
+        .to_string_lossy()
+        .ends_with(".tmp")));
+
+    // A crash between writing the page directory and the header looks like this
+    fs::copy(&stale, &page_dir).unwrap();
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+
+    assert_eq!(
+        crabstore.try_open(),
+        Err(CrabError::Inconsistent {
+            file: "Generations_pd.CRAB".into(),
+            expected: 2,
+            found: 1,
+        })
+    );
+}
+
+#[test]
+fn corrupt_files_are_reported() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.create_table("Corrupt", 3, 0).unwrap();
+
+    for i in 0..500 {
+        table.insert_query(&[i, i + 1, i + 2], None).unwrap();
+    }
+
+    drop(table);
+    crabstore.close();
+
+    for file in [
+        "crab_dt.CRAB",
+        "Corrupt_pd.CRAB",
+        "Corrupt_rd.CRAB",
+        "Corrupt_id.CRAB",
+    ] {
+        let path = dir.path().join(file);
+        let original = fs::read(&path).unwrap();
+
+        // Keep the blob header, scramble the archive after it
+        let mut corrupted = original.clone();
+        corrupted[32..].iter_mut().for_each(|x| *x = 0xAB);
+        fs::write(&path, &corrupted).unwrap();
+
+        let mut crabstore = CrabStore::new(dir.path().into());
+
+        match crabstore.try_open() {
+            Err(CrabError::Corrupt { file: name, .. }) => assert_eq!(name, file),
+            other => panic!("Expected {file} to be reported as corrupt, got {other:?}"),
+        }
+
+        fs::write(&path, &original).unwrap();
+    }
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    assert_eq!(
+        crabstore
+            .get_table("Corrupt")
+            .select_query(42, 0, &[1, 1, 1], None)
+            .unwrap()[0]
+            .columns,
+        [42, 43, 44]
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn bulk_insert() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+    let table = crabstore.create_table("Bulk", 3, 0).unwrap();
+    table.build_index(2).unwrap();
+
+    // Start part way into a page
+    for i in 0..100 {
+        table.insert_query(&[i, i, i % 10], None).unwrap();
+    }
+
+    let rows = (100..20000).map(|i| vec![i, i, i % 10]).collect::<Vec<_>>();
+    table.bulk_insert(&rows).unwrap();
+
+    assert_eq!(
+        table.sum_query(0, 19999, 1, None).unwrap(),
+        19999 * 20000 / 2
+    );
+    assert_eq!(
+        table.select_query(7, 2, &[1, 1, 1], None).unwrap().len(),
+        2000
+    );
+    assert_eq!(
+        table.select_query(12345, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [12345, 12345, 5]
+    );
+
+    // Nothing from a batch with a taken or repeated key goes in
+    assert_eq!(
+        table.bulk_insert(&[vec![20000, 0, 0], vec![5, 0, 0]]),
+        Err(CrabError::DuplicateKey {
+            table: "Bulk".into(),
+            key: 5
+        })
+    );
+    assert_eq!(
+        table.bulk_insert(&[vec![30000, 0, 0], vec![30000, 1, 0]]),
+        Err(CrabError::DuplicateKey {
+            table: "Bulk".into(),
+            key: 30000
+        })
+    );
+    assert!(matches!(
+        table.bulk_insert(&[vec![40000, 0]]),
+        Err(CrabError::Mapping(_))
+    ));
+    assert!(table
+        .select_query(20000, 0, &[1, 1, 1], None)
+        .unwrap()
+        .is_empty());
+
+    // Deleted keys can be reused
+    table.delete_query(10, None).unwrap();
+    table.bulk_insert(&[vec![10, 1, 1]]).unwrap();
+
+    drop(table);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+    let table = crabstore.get_table("Bulk");
+
+    assert_eq!(
+        regorganize_result(table.select_query(10, 0, &[1, 1, 1], None).unwrap()),
+        [[10, 1, 1]]
+    );
+    assert_eq!(
+        table.select_query(19999, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [19999, 19999, 9]
+    );
+
+    drop(table);
+    crabstore.close();
+}
@@ -0,0 +1,167 @@
+use std::{fs, process::Command};
+
+use crabcore::{
+    crabstore::CrabStore,
+    dump::{DumpFormat, DumpOptions},
+    error::CrabError,
+};
+use tempfile::tempdir;
+
+#[test]
+fn csv_round_trip() {
+    let dir = tempdir().unwrap();
+    let csv = dir.path().join("rows.csv");
+
+    // Key is the second field, the table's key is column 0
+    fs::write(&csv, "b,a,c\n10,1,100\n20, 2,200\n\n30,3,\"300\"\n").unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().join("db"));
+    crabstore.open();
+    let table = crabstore.create_table("Imported", 3, 0).unwrap();
+
+    let options = DumpOptions {
+        format: DumpFormat::Csv,
+        columns: vec![1, 0, 2],
+        header: true,
+    };
+
+    assert_eq!(crabstore.import("Imported", &csv, &options), Ok(3));
+    assert_eq!(
+        table.select_query(2, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [2, 20, 200]
+    );
+
+    table
+        .update_query(3, &[None, Some(31), None], None)
+        .unwrap();
+    table.delete_query(1, None).unwrap();
+
+    let out = dir.path().join("out.csv");
+    let options = DumpOptions {
+        columns: vec![2, 0],
+        header: true,
+        ..Default::default()
+    };
+
+    assert_eq!(crabstore.export("Imported", &out, &options), Ok(2));
+    assert_eq!(
+        fs::read_to_string(&out).unwrap(),
+        "column2,column0\n200,2\n300,3\n"
+    );
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn native_round_trip() {
+    let dir = tempdir().unwrap();
+    let dump = dir.path().join("rows.crab");
+
+    let mut crabstore = CrabStore::new(dir.path().join("db"));
+    crabstore.open();
+    let table = crabstore.create_table("Source", 2, 0).unwrap();
+
+    // More than one block
+    for i in 0..20000 {
+        table.insert_query(&[i, i * 7], None).unwrap();
+    }
+    table.update_query(5, &[None, Some(1)], None).unwrap();
+
+    let options = DumpOptions {
+        format: DumpFormat::Native,
+        ..Default::default()
+    };
+
+    assert_eq!(crabstore.export("Source", &dump, &options), Ok(20000));
+
+    let copy = crabstore.create_table("Copy", 2, 0).unwrap();
+    assert_eq!(crabstore.import("Copy", &dump, &options), Ok(20000));
+
+    assert_eq!(
+        copy.sum_query(0, 19999, 1, None).unwrap(),
+        table.sum_query(0, 19999, 1, None).unwrap()
+    );
+    assert_eq!(
+        copy.select_query(5, 0, &[1, 1], None).unwrap()[0].columns,
+        [5, 1]
+    );
+
+    drop((table, copy));
+    crabstore.close();
+}
+
+#[test]
+fn bad_imports_are_reported() {
+    let dir = tempdir().unwrap();
+    let csv = dir.path().join("rows.csv");
+    fs::write(&csv, "1,2\n3,x\n").unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().join("db"));
+    crabstore.open();
+    crabstore.create_table("Target", 2, 0).unwrap();
+
+    assert!(matches!(
+        crabstore.import("Target", &csv, &Default::default()),
+        Err(CrabError::Parse { line: 2, .. })
+    ));
+
+    let options = DumpOptions {
+        columns: vec![0, 0],
+        ..Default::default()
+    };
+    assert!(matches!(
+        crabstore.import("Target", &csv, &options),
+        Err(CrabError::Mapping(_))
+    ));
+
+    let options = DumpOptions {
+        format: DumpFormat::Native,
+        ..Default::default()
+    };
+    assert!(matches!(
+        crabstore.import("Target", &csv, &options),
+        Err(CrabError::Corrupt { .. })
+    ));
+
+    assert_eq!(
+        crabstore.import("Missing", &csv, &Default::default()),
+        Err(CrabError::Missing("Missing".into()))
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn command_line() {
+    let dir = tempdir().unwrap();
+    let database = dir.path().join("db");
+    let csv = dir.path().join("rows.csv");
+    let out = dir.path().join("out.csv");
+
+    fs::write(&csv, "5,50\n6,60\n").unwrap();
+
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_crabstore"))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let db = database.to_str().unwrap();
+
+    let import = run(&["import", db, "Cli", csv.to_str().unwrap(), "--key", "1"]);
+    assert!(import.status.success(), "{import:?}");
+    assert_eq!(String::from_utf8_lossy(&import.stdout), "imported 2 rows\n");
+
+    let export = run(&["export", db, "Cli", out.to_str().unwrap(), "--columns", "1"]);
+    assert!(export.status.success(), "{export:?}");
+    assert_eq!(fs::read_to_string(&out).unwrap(), "50\n60\n");
+
+    let mut crabstore = CrabStore::new(database.clone());
+    crabstore.open();
+    assert_eq!(crabstore.get_table("Cli").primary_key(), 1);
+    crabstore.close();
+
+    assert!(!run(&["import", db]).status.success());
+}
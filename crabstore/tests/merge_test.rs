@@ -1,10 +1,25 @@
 #![feature(test)]
+#![allow(clippy::needless_range_loop)]
 extern crate test;
-use crabcore::crabstore::CrabStore;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crabcore::{
+    config::Config,
+    crabstore::CrabStore,
+    merge::MergePolicy,
+    table::Table,
+    transaction::{Query, Transaction},
+};
 use rand::prelude::*;
-use std::{collections::HashMap, path::Path};
 use tempfile::tempdir;
-use test::Bencher;
 
 #[test]
 fn merge_test() {
@@ -14,23 +29,25 @@ fn merge_test() {
     let mut crabstore = CrabStore::new(dir.path().into());
     crabstore.open();
 
-    let table = crabstore.create_table("merge", 5, 0);
+    let table = crabstore.create_table("merge", 5, 0).unwrap();
     let update_nums = [2, 4, 8, 16];
     let records_num = 10000;
     let sample_count = 200;
     let select_repeat = 200;
 
     for i in 0..records_num {
-        table.insert_query(
-            &[
-                i,
-                (i + 100) % records_num,
-                (i + 200) % records_num,
-                (i + 300) % records_num,
-                (i + 400) % records_num,
-            ],
-            None,
-        );
+        table
+            .insert_query(
+                &[
+                    i,
+                    (i + 100) % records_num,
+                    (i + 200) % records_num,
+                    (i + 300) % records_num,
+                    (i + 400) % records_num,
+                ],
+                None,
+            )
+            .unwrap();
     }
 
     for index in 0..update_nums.len() {
@@ -49,7 +66,7 @@ fn merge_test() {
                     update_record[4 - idx] = None;
                 }
 
-                table.update_query(i, &update_record, None);
+                table.update_query(i, &update_record, None).unwrap();
             }
         }
         let keys = (0..records_num).choose_multiple(&mut rand, sample_count);
@@ -57,12 +74,533 @@ fn merge_test() {
         while time < select_repeat {
             time += 1;
             for key in keys.iter() {
-                table.select_query(*key, 0, &[1, 1, 1, 1, 1], None);
+                table.select_query(*key, 0, &[1, 1, 1, 1, 1], None).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn parse_merge_policies() {
+    assert_eq!("tail_pages:8".parse(), Ok(MergePolicy::TailPages(8)));
+    assert_eq!(
+        "interval:250".parse(),
+        Ok(MergePolicy::Interval(Duration::from_millis(250)))
+    );
+    assert_eq!(
+        "Idle:1000".parse(),
+        Ok(MergePolicy::Idle(Duration::from_millis(1000)))
+    );
+    assert_eq!("manual".parse(), Ok(MergePolicy::Manual));
+    assert!("interval".parse::<MergePolicy>().is_err());
+    assert!("sometimes".parse::<MergePolicy>().is_err());
+}
+
+// Updates every record `rounds` times, column 1 ends up as key * 10 + rounds
+fn update_rounds(table: &Table, records: u64, rounds: u64) {
+    for round in 1..=rounds {
+        for i in 0..records {
+            table
+                .update_query(i, &[None, Some(i * 10 + round), None], None)
+                .unwrap();
+        }
+    }
+}
+
+fn check_values(table: &Table, records: u64, rounds: u64) {
+    for i in 0..records {
+        assert_eq!(
+            table.select_query(i, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [i, i * 10 + rounds, i],
+            "record {i}"
+        );
+    }
+}
+
+#[test]
+fn force_merge() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::with_config(
+        dir.path().into(),
+        Config {
+            merge_policy: MergePolicy::Manual,
+            ..Default::default()
+        },
+    );
+    crabstore.open();
+
+    let table = crabstore.create_table("Forced", 3, 0).unwrap();
+
+    for i in 0..1000 {
+        table.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    update_rounds(&table, 1000, 3);
+    assert_eq!(table.merge_stats().ranges_merged, 0);
+
+    // Full tail pages hold every update but the last partial page's
+    let consolidated = table.merge_all();
+    assert!(consolidated > 0);
+
+    let stats = table.merge_stats();
+    assert_eq!(stats.ranges_merged, 1);
+    assert_eq!(stats.records_consolidated, consolidated as u64);
+    assert!(stats.last_merge.is_some());
+    check_values(&table, 1000, 3);
+
+    // Nothing new to merge
+    assert_eq!(table.force_merge(0), 0);
+    assert_eq!(table.force_merge(100), 0);
+
+    update_rounds(&table, 1000, 2);
+    assert!(table.force_merge(0) > 0);
+    assert_eq!(table.merge_stats().ranges_merged, 2);
+    check_values(&table, 1000, 2);
+
+    assert_eq!(crabstore.stats().total().merge.ranges_merged, 2);
+
+    drop(table);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+    check_values(&crabstore.get_table("Forced"), 1000, 2);
+    crabstore.close();
+}
+
+#[test]
+fn shared_merge_scheduler() {
+    let mut crabstore = CrabStore::in_memory_with_config(Config {
+        merge_policy: MergePolicy::TailPages(1),
+        merge_workers: 2,
+        ..Default::default()
+    });
+    crabstore.open();
+
+    let tables = (0..16)
+        .map(|x| crabstore.create_table(&format!("Shared{x}"), 3, 0).unwrap())
+        .collect::<Vec<_>>();
+
+    // More tables merging at once than there are workers
+    thread::scope(|s| {
+        for table in tables.iter() {
+            s.spawn(move || {
+                for i in 0..1000 {
+                    table.insert_query(&[i, i, i], None).unwrap();
+                }
+
+                update_rounds(table, 1000, 3);
+                table.merge_all();
+                check_values(table, 1000, 3);
+            });
+        }
+    });
+
+    for table in tables.iter() {
+        assert!(table.merge_stats().ranges_merged > 0);
+    }
+
+    crabstore.close();
+
+    // Closing the database stopped the scheduler
+    assert_eq!(tables[0].merge_all(), 0);
+}
+
+#[test]
+fn reclaim_merged_pages() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        merge_policy: MergePolicy::Manual,
+        flush_interval: None,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.create_table("Reclaim", 3, 0).unwrap();
+
+    for i in 0..1000 {
+        table.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    update_rounds(&table, 1000, 3);
+
+    // A query still running from before the merge keeps everything it could read
+    let epoch = table.pin_epoch();
+    assert!(table.merge_all() > 0);
+    assert_eq!(table.merge_stats().pages_reclaimed, 0);
+    check_values(&table, 1000, 3);
+
+    drop(epoch);
+    assert!(table.reclaim_pages() > 0);
+    check_values(&table, 1000, 3);
+
+    // Once a checkpoint stops referencing them, reclaimed pages are reused
+    let mut pages = Vec::new();
+
+    for round in 0..6 {
+        table.checkpoint();
+        update_rounds(&table, 1000, 3);
+        table.merge_all();
+        check_values(&table, 1000, 3);
+
+        assert!(table.stats().disk.free_pages > 0, "round {round}");
+        pages.push(table.stats().disk.pages);
+    }
+
+    assert_eq!(pages[3], pages[5], "{pages:?}");
+
+    drop(table);
+    crabstore.close();
+
+    // Reopening finds the free pages again
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    let table = crabstore.get_table("Reclaim");
+    check_values(&table, 1000, 3);
+    assert!(table.stats().disk.free_pages > 0);
+
+    update_rounds(&table, 1000, 3);
+    table.merge_all();
+    check_values(&table, 1000, 3);
+    assert_eq!(table.stats().disk.pages, pages[5]);
+
+    drop(table);
+    crabstore.close();
+}
+
+// Every key's select and a handful of sums against what the table should hold, None for deleted keys
+fn check_model(table: &Table, model: &HashMap<u64, Option<[u64; 4]>>, context: &str) {
+    for (key, expected) in model.iter() {
+        let found = table.select_query(*key, 0, &[1, 1, 1, 1], None).unwrap();
+
+        match expected {
+            Some(values) => assert_eq!(found[0].columns, values, "key {key}, {context}"),
+            None => assert!(found.is_empty(), "deleted key {key}, {context}"),
+        }
+    }
+
+    for (start, end) in [(0, 99), (250, 1750), (0, 1999)] {
+        for column in 1..4 {
+            let expected: u64 = (start..=end)
+                .filter_map(|x| model[&x].map(|values| values[column]))
+                .sum();
+
+            assert_eq!(
+                table.sum_query(start, end, column, None).unwrap(),
+                expected,
+                "sum of column {column} over {start}..={end}, {context}"
+            );
+        }
+    }
+}
+
+#[test]
+fn randomized_merge() {
+    let dir = tempdir().unwrap();
+    let mut rand = StdRng::seed_from_u64(16502);
+    let config = Config {
+        merge_policy: MergePolicy::Manual,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.create_table("Randomized", 4, 0).unwrap();
+    let mut model = HashMap::new();
+
+    for key in 0..2000 {
+        table.insert_query(&[key, key, key, key], None).unwrap();
+        model.insert(key, Some([key, key, key, key]));
+    }
+
+    let mut next_key = 2000;
+
+    for round in 0..6 {
+        // Merging in the background while inserts fill the newest base pages
+        let merging = AtomicBool::new(true);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                while merging.load(Ordering::Relaxed) {
+                    table.merge_all();
+                }
+            });
+
+            for _ in 0..4000 {
+                random_query(&table, &mut rand, &mut model, &mut next_key);
+            }
+
+            merging.store(false, Ordering::Relaxed);
+        });
+
+        check_model(&table, &model, &format!("round {round} before merging"));
+        table.merge_all();
+        check_model(&table, &model, &format!("round {round} after merging"));
+    }
+
+    assert!(table.merge_stats().records_consolidated > 0);
+
+    drop(table);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+    check_model(&crabstore.get_table("Randomized"), &model, "reopened");
+    crabstore.close();
+}
+
+// Every live record of the table as scans and selects see it, sorted by key
+fn table_contents(table: &Table, keys: u64) -> (Vec<Vec<u64>>, Vec<Vec<u64>>) {
+    let mut scanned = Vec::new();
+    table
+        .scan(&[0, 1, 2, 3], |x| scanned.push(x.to_vec()))
+        .unwrap();
+    scanned.sort();
+
+    let selected = (0..keys)
+        .flat_map(|x| table.select_query(x, 0, &[1, 1, 1, 1], None).unwrap())
+        .map(|x| x.columns)
+        .collect::<Vec<_>>();
+
+    assert_eq!(scanned, selected);
+
+    (scanned, selected)
+}
+
+#[test]
+fn merged_records_match_unmerged() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        merge_policy: MergePolicy::Manual,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    // The same queries go to both tables, only one of them is ever merged
+    let tables = [
+        crabstore.create_table("Merged", 4, 0).unwrap(),
+        crabstore.create_table("Unmerged", 4, 0).unwrap(),
+    ];
+    let mut models = [HashMap::new(), HashMap::new()];
+    let mut next_keys = [2000, 2000];
+
+    for table in tables.iter() {
+        for key in 0..2000 {
+            table.insert_query(&[key, key, key, key], None).unwrap();
+        }
+    }
+
+    for (model, next_key) in models.iter_mut().zip(next_keys.iter()) {
+        model.extend((0..*next_key).map(|x| (x, Some([x; 4]))));
+    }
+
+    for round in 0..4 {
+        for ((table, model), next_key) in tables
+            .iter()
+            .zip(models.iter_mut())
+            .zip(next_keys.iter_mut())
+        {
+            let mut rand = StdRng::seed_from_u64(round);
+
+            for _ in 0..4000 {
+                random_query(table, &mut rand, model, next_key);
+            }
+        }
+
+        assert!(tables[0].merge_all() > 0);
+
+        assert_eq!(
+            table_contents(&tables[0], next_keys[0]),
+            table_contents(&tables[1], next_keys[1]),
+            "round {round}"
+        );
+    }
+
+    assert_eq!(tables[1].merge_stats().ranges_merged, 0);
+
+    drop(tables);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    assert_eq!(
+        table_contents(&crabstore.get_table("Merged"), next_keys[0]),
+        table_contents(&crabstore.get_table("Unmerged"), next_keys[1])
+    );
+
+    crabstore.close();
+}
+
+// Updates, deletes or inserts a random record, applying the same to `model`
+fn random_query(
+    table: &Arc<Table>,
+    rand: &mut StdRng,
+    model: &mut HashMap<u64, Option<[u64; 4]>>,
+    next_key: &mut u64,
+) {
+    let key = rand.gen_range(0..*next_key);
+    let mut update = [None; 4];
+
+    for column in 1..4 {
+        if rand.gen_bool(0.5) {
+            update[column] = Some(rand.gen_range(0..1_000_000));
+        }
+    }
+
+    match rand.gen_range(0..100) {
+        // Setting a key that already exists aborts, rolling back the update before it
+        0..=9 => {
+            let mut transaction = Transaction::new();
+            transaction.add_query(Query::Update(key, update.into()), table);
+            transaction.add_query(
+                Query::Update(key, [Some(key), None, None, None].into()),
+                table,
+            );
+
+            assert_eq!(transaction.run(), model[&key].is_none());
+        }
+        10..=12 => {
+            table.delete_query(key, None).unwrap();
+            model.insert(key, None);
+        }
+        13..=24 => {
+            let values = [*next_key, key, key, key];
+
+            table.insert_query(&values, None).unwrap();
+            model.insert(*next_key, Some(values));
+            *next_key += 1;
+        }
+        _ => {
+            table.update_query(key, &update, None).unwrap();
+
+            if let Some(values) = model.get_mut(&key).unwrap() {
+                for column in 1..4 {
+                    values[column] = update[column].unwrap_or(values[column]);
+                }
             }
         }
     }
 }
 
+fn wait_for_merge(table: &Table) -> bool {
+    let started = Instant::now();
+
+    while started.elapsed() < Duration::from_secs(10) {
+        if table.merge_stats().ranges_merged > 0 {
+            return true;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    false
+}
+
+#[test]
+fn merge_policies() {
+    for policy in [
+        MergePolicy::TailPages(1),
+        MergePolicy::Interval(Duration::from_millis(20)),
+        MergePolicy::Idle(Duration::from_millis(20)),
+    ] {
+        let mut crabstore = CrabStore::in_memory_with_config(Config {
+            merge_policy: policy,
+            ..Default::default()
+        });
+        crabstore.open();
+
+        let table = crabstore.create_table("Policy", 3, 0).unwrap();
+
+        for i in 0..1000 {
+            table.insert_query(&[i, i, i], None).unwrap();
+        }
+
+        update_rounds(&table, 1000, 2);
+
+        assert!(wait_for_merge(&table), "{policy:?} never merged");
+        check_values(&table, 1000, 2);
+
+        drop(table);
+        crabstore.close();
+    }
+}
+
+#[test]
+fn resume_merges_on_load() {
+    let dir = tempdir().unwrap();
+    let config = Config {
+        merge_policy: MergePolicy::Manual,
+        finish_merges: false,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.create_table("Resumed", 3, 0).unwrap();
+
+    for i in 0..1000 {
+        table.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    update_rounds(&table, 1000, 3);
+
+    let unmerged = table.unmerged_tail_pages(0);
+    assert!(unmerged > 1);
+
+    // Whether the merge publishes or gives up, closing leaves a consistent table behind
+    thread::scope(|s| {
+        s.spawn(|| table.merge_all());
+        // Usually catches the merge part way
+        thread::sleep(Duration::from_millis(2));
+        table.persist();
+    });
+
+    let stats = table.merge_stats();
+    assert!(stats.ranges_merged + stats.merges_abandoned <= 1);
+    check_values(&table, 1000, 3);
+
+    drop(table);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.get_table("Resumed");
+    check_values(&table, 1000, 3);
+    update_rounds(&table, 1000, 1);
+    assert!(table.unmerged_tail_pages(0) > 0);
+
+    drop(table);
+    crabstore.close();
+
+    // Ranges with enough unmerged tail pages are merged once the table is loaded
+    let mut crabstore = CrabStore::with_config(
+        dir.path().into(),
+        Config {
+            merge_policy: MergePolicy::TailPages(2),
+            ..config
+        },
+    );
+    crabstore.open();
+
+    let table = crabstore.get_table("Resumed");
+    assert!(wait_for_merge(&table));
+    assert_eq!(table.unmerged_tail_pages(0), 0);
+    check_values(&table, 1000, 1);
+
+    drop(table);
+    crabstore.close();
+}
+
 /*
 #[bench]
 fn merge_bench(b: &mut Bencher) {
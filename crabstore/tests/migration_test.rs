@@ -0,0 +1,318 @@
+use std::{fs, path::Path};
+
+use crabcore::{
+    config::Config, crabstore::CrabStore, error::CrabError, merge::MergePolicy,
+    migration::FORMAT_VERSION, table::Table,
+};
+use tempfile::tempdir;
+
+// Table header fields before the version, all archived as u64
+const HEADER_VERSION_OFFSET: usize = 6 * 8;
+
+// Rewrites a closed database the way version 1 stored it
+fn downgrade_to_v1(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let mut bytes = fs::read(&path).unwrap();
+
+        if name.ends_with("_db.CRAB") {
+            bytes[HEADER_VERSION_OFFSET..HEADER_VERSION_OFFSET + 4].fill(0);
+        } else {
+            // Magic then generation, without the version
+            let mut legacy = b"CRABBLOB".to_vec();
+            legacy.extend_from_slice(&bytes[16..24]);
+            legacy.extend_from_slice(&bytes[32..]);
+            bytes = legacy;
+        }
+
+        fs::write(&path, bytes).unwrap();
+    }
+}
+
+// Marks a closed database as version 2, whose tail schema encodings can't be trusted
+fn downgrade_to_v2(dir: &Path) {
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let mut bytes = fs::read(&path).unwrap();
+        let offset = if name.ends_with("_db.CRAB") {
+            HEADER_VERSION_OFFSET
+        } else {
+            8
+        };
+
+        bytes[offset..offset + 4].copy_from_slice(&2u32.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+    }
+}
+
+#[test]
+fn current_database_needs_no_migration() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+    crabstore
+        .create_table("Current", 2, 0)
+        .unwrap()
+        .insert_query(&[1, 2], None)
+        .unwrap();
+    crabstore.close();
+
+    let report = crabstore.plan_migration().unwrap();
+
+    assert!(report.is_empty());
+    assert!(report.actions.is_empty());
+    assert_eq!(report.to, FORMAT_VERSION);
+}
+
+/*
+    Written by the baseline build, with
+
+        let grades = crabstore.create_table("Grades", 4, 0);
+        for i in 0..1000 {
+            grades.insert_query(&[i, i * 2, i % 100, 7], None);
+        }
+        for i in (0..1000).step_by(3) {
+            grades.update_query(i, &[None, Some(i * 2 + 1), None, None], None);
+        }
+        for i in (0..1000).step_by(5) {
+            grades.update_query(i, &[None, None, None, Some(8)], None);
+        }
+        for i in 10..20 {
+            grades.delete_query(i, None);
+        }
+
+    then closed.
+*/
+const BASELINE_FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/baseline");
+
+fn baseline_grade(key: u64) -> Option<[u64; 4]> {
+    let updated = if key % 3 == 0 { key * 2 + 1 } else { key * 2 };
+    let last = if key % 5 == 0 { 8 } else { 7 };
+
+    (!(10..20).contains(&key)).then_some([key, updated, key % 100, last])
+}
+
+#[test]
+fn migrate_from_baseline() {
+    let dir = tempdir().unwrap();
+
+    for entry in fs::read_dir(BASELINE_FIXTURE).unwrap() {
+        let path = entry.unwrap().path();
+        fs::copy(&path, dir.path().join(path.file_name().unwrap())).unwrap();
+    }
+
+    let report = CrabStore::new(dir.path().into()).plan_migration().unwrap();
+
+    assert_eq!((report.from, report.to), (0, FORMAT_VERSION));
+    for file in ["Grades_pd.CRAB", "Grades_rd.CRAB", "Grades_id.CRAB"] {
+        assert!(
+            report.actions.contains(&format!("add a header to {file}")),
+            "{file} missing from {:?}",
+            report.actions
+        );
+    }
+
+    let config = Config {
+        merge_policy: MergePolicy::Manual,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.get_table("Grades");
+
+    let check = |table: &Table| {
+        for key in 0..1000 {
+            let found = table.select_query(key, 0, &[1, 1, 1, 1], None).unwrap();
+
+            match baseline_grade(key) {
+                Some(values) => assert_eq!(found[0].columns, values, "key {key}"),
+                None => assert!(found.is_empty(), "deleted key {key}"),
+            }
+        }
+
+        assert_eq!(
+            table.sum_query(0, 999, 3, None).unwrap(),
+            (0..1000)
+                .filter_map(baseline_grade)
+                .map(|x| x[3])
+                .sum::<u64>()
+        );
+    };
+
+    check(&table);
+
+    // Tail records the baseline wrote merge like new ones
+    table.merge_all();
+    check(&table);
+
+    table
+        .update_query(500, &[None, None, Some(1), None], None)
+        .unwrap();
+
+    drop(table);
+    crabstore.close();
+
+    assert!(CrabStore::new(dir.path().into())
+        .plan_migration()
+        .unwrap()
+        .is_empty());
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    assert_eq!(
+        crabstore
+            .get_table("Grades")
+            .select_query(500, 0, &[1, 1, 1, 1], None)
+            .unwrap()[0]
+            .columns,
+        [500, 1000, 1, 8]
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn migrate_from_v1() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.create_table("Legacy", 3, 0).unwrap();
+
+    for i in 0..1000 {
+        table.insert_query(&[i, i * 2, i * 3], None).unwrap();
+    }
+
+    drop(table);
+    crabstore.close();
+
+    downgrade_to_v1(dir.path());
+
+    let snapshot = |dir: &Path| {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|x| {
+                let path = x.unwrap().path();
+                (path.clone(), fs::read(path).unwrap())
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+
+    let before = snapshot(dir.path());
+
+    // A dry run reports every file and leaves them alone
+    let crabstore = CrabStore::new(dir.path().into());
+    let report = crabstore.plan_migration().unwrap();
+
+    assert_eq!((report.from, report.to), (1, FORMAT_VERSION));
+    assert!(report.dry_run);
+    for file in [
+        "Legacy_pd.CRAB",
+        "Legacy_rd.CRAB",
+        "Legacy_id.CRAB",
+        "header page of Legacy_db.CRAB",
+        "crab_dt.CRAB",
+    ] {
+        assert!(
+            report.actions.contains(&format!("rewrite {file}")),
+            "{file} missing from {:?}",
+            report.actions
+        );
+    }
+    assert_eq!(snapshot(dir.path()), before);
+
+    // Read-only opens can't upgrade
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.config.read_only = true;
+    assert_eq!(
+        crabstore.try_open(),
+        Err(CrabError::Version {
+            file: "crab_dt.CRAB".into(),
+            found: 1
+        })
+    );
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.get_table("Legacy");
+    assert_eq!(
+        table.select_query(500, 0, &[1, 1, 1], None).unwrap()[0].columns,
+        [500, 1000, 1500]
+    );
+    assert_eq!(table.sum_query(0, 999, 1, None).unwrap(), 999 * 1000);
+
+    drop(table);
+    crabstore.close();
+
+    assert!(CrabStore::new(dir.path().into())
+        .plan_migration()
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn migrate_from_v2() {
+    let dir = tempdir().unwrap();
+
+    let config = Config {
+        merge_policy: MergePolicy::Manual,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+    crabstore.open();
+
+    let table = crabstore.create_table("Unmerged", 3, 0).unwrap();
+
+    for i in 0..1000 {
+        table.insert_query(&[i, i, i], None).unwrap();
+    }
+
+    for round in 1..=3 {
+        for i in 0..1000 {
+            table
+                .update_query(i, &[None, Some(i + round), None], None)
+                .unwrap();
+        }
+    }
+
+    drop(table);
+    crabstore.close();
+
+    downgrade_to_v2(dir.path());
+
+    let report = CrabStore::new(dir.path().into()).plan_migration().unwrap();
+
+    assert_eq!((report.from, report.to), (2, FORMAT_VERSION));
+    assert!(report
+        .actions
+        .iter()
+        .any(|x| x.contains("tail schema encoding pages of Unmerged_db.CRAB")));
+
+    let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+    crabstore.open();
+
+    // Tail records written before the upgrade merge every column
+    let table = crabstore.get_table("Unmerged");
+    assert!(table.merge_all() > 0);
+
+    for i in 0..1000 {
+        assert_eq!(
+            table.select_query(i, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            [i, i + 3, i]
+        );
+    }
+
+    drop(table);
+    crabstore.close();
+}
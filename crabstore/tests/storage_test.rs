@@ -0,0 +1,402 @@
+use std::{sync::Arc, thread};
+
+use crabcore::{
+    bufferpool::BufferPoolError,
+    config::Config,
+    crabstore::CrabStore,
+    error::CrabError,
+    storage::{decode, Storage},
+    storage_backend::{StorageBackend, StorageBackendKind},
+};
+use tempfile::tempdir;
+
+const PAGE_SIZE: usize = 4096;
+
+fn backends() -> Vec<StorageBackendKind> {
+    #[cfg(target_os = "linux")]
+    return vec![StorageBackendKind::File, StorageBackendKind::Uring];
+
+    #[cfg(not(target_os = "linux"))]
+    return vec![StorageBackendKind::File];
+}
+
+#[test]
+fn parse_backend_names() {
+    assert_eq!("file".parse(), Ok(StorageBackendKind::File));
+    #[cfg(target_os = "linux")]
+    assert_eq!("io_uring".parse(), Ok(StorageBackendKind::Uring));
+    assert!("tape".parse::<StorageBackendKind>().is_err());
+}
+
+#[test]
+fn page_round_trip() {
+    for kind in backends() {
+        let dir = tempdir().unwrap();
+        let backend: Arc<dyn StorageBackend> =
+            kind.open(&dir.path().join("pages.CRAB")).unwrap().into();
+
+        let pages = (0..100u8).map(|i| [i; PAGE_SIZE]).collect::<Vec<_>>();
+        let requests = pages
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (i * 2, x))
+            .collect::<Vec<_>>();
+
+        backend.write_pages(&requests).unwrap();
+        backend.write_page(1, &[255; PAGE_SIZE]).unwrap();
+        backend.sync().unwrap();
+
+        let mut page = [1; PAGE_SIZE];
+        backend.read_page(1, &mut page).unwrap();
+        assert_eq!(page, [255; PAGE_SIZE], "{kind:?}");
+
+        // Past the end of the file
+        backend.read_page(1000, &mut page).unwrap();
+        assert_eq!(page, [0; PAGE_SIZE], "{kind:?}");
+
+        let handles = (0..4)
+            .map(|t| {
+                let backend = Arc::clone(&backend);
+                thread::spawn(move || {
+                    let mut buffers = vec![[0; PAGE_SIZE]; 25];
+                    let mut requests = buffers
+                        .iter_mut()
+                        .enumerate()
+                        .map(|(i, x)| ((t * 25 + i) * 2, x))
+                        .collect::<Vec<_>>();
+
+                    backend.read_pages(&mut requests).unwrap();
+
+                    for (i, page) in buffers.iter().enumerate() {
+                        assert_eq!(page[0] as usize, t * 25 + i);
+                        assert_eq!(page[PAGE_SIZE - 1] as usize, t * 25 + i);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+}
+
+#[test]
+fn table_on_each_backend() {
+    for kind in backends() {
+        let dir = tempdir().unwrap();
+        let config = Config {
+            bufferpool_size: 32,
+            storage_backend: kind,
+            ..Default::default()
+        };
+
+        let mut crabstore = CrabStore::with_config(dir.path().into(), config.clone());
+        crabstore.open();
+
+        let table = crabstore.create_table("backend", 3, 0).unwrap();
+        let num_records = 5000;
+
+        for i in 0..num_records {
+            table.insert_query(&[i, i * 2, 1], None).unwrap();
+        }
+
+        for i in (0..num_records).step_by(5) {
+            table.update_query(i, &[None, None, Some(3)], None).unwrap();
+        }
+
+        drop(table);
+        crabstore.close();
+
+        let mut crabstore = CrabStore::with_config(dir.path().into(), config);
+        crabstore.open();
+
+        let table = crabstore.get_table("backend");
+
+        assert_eq!(
+            table.sum_query(0, num_records - 1, 1, None).unwrap(),
+            num_records * (num_records - 1),
+            "{kind:?}"
+        );
+        assert_eq!(
+            table.sum_query(0, num_records - 1, 2, None).unwrap(),
+            num_records + 2 * (num_records / 5),
+            "{kind:?}"
+        );
+
+        drop(table);
+        crabstore.close();
+    }
+}
+
+#[test]
+fn read_only_open() {
+    let dir = tempdir().unwrap();
+    let num_records = 2000;
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.create_table("mapped", 3, 0).unwrap();
+
+    for i in 0..num_records {
+        table.insert_query(&[i, i * 2, 1], None).unwrap();
+    }
+
+    for i in (0..num_records).step_by(4) {
+        table.update_query(i, &[None, None, Some(5)], None).unwrap();
+    }
+
+    drop(table);
+    crabstore.close();
+
+    // Two readers on the same files at once
+    let mut readers = (0..2)
+        .map(|_| {
+            let mut crabstore = CrabStore::new(dir.path().into());
+            crabstore.open_read_only();
+            crabstore
+        })
+        .collect::<Vec<_>>();
+
+    for crabstore in readers.iter_mut() {
+        let table = crabstore.get_table("mapped");
+
+        assert!(crabstore.writable().is_err());
+        assert!(table.writable().is_err());
+
+        assert_eq!(
+            table.sum_query(0, num_records - 1, 1, None).unwrap(),
+            num_records * (num_records - 1)
+        );
+        assert_eq!(
+            table.sum_query(0, num_records - 1, 2, None).unwrap(),
+            num_records + 4 * (num_records / 4)
+        );
+        assert_eq!(
+            table.select_query(8, 0, &[1, 1, 1], None).unwrap()[0].columns,
+            vec![8, 16, 5]
+        );
+
+        assert!(matches!(
+            table.insert_query(&[num_records, 0, 0], None),
+            Err(CrabError::ReadOnly(_))
+        ));
+        assert!(table
+            .select_query(num_records, 0, &[1, 1, 1], None)
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            table.update_query(0, &[None, Some(7), None], None),
+            Err(CrabError::ReadOnly(_))
+        ));
+        assert!(matches!(
+            table.delete_query(1, None),
+            Err(CrabError::ReadOnly(_))
+        ));
+        assert!(matches!(
+            crabstore.create_table("created", 2, 0),
+            Err(CrabError::ReadOnly(_))
+        ));
+
+        assert_eq!(table.stats().disk.writes, 0);
+    }
+
+    for crabstore in readers.iter_mut() {
+        crabstore.close();
+    }
+
+    // Nothing was written, the database still opens for writing
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.get_table("mapped");
+    assert_eq!(
+        table.sum_query(0, num_records - 1, 1, None).unwrap(),
+        num_records * (num_records - 1)
+    );
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn read_only_truncated_file() {
+    let dir = tempdir().unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open();
+
+    let table = crabstore.create_table("truncated", 2, 0).unwrap();
+
+    for i in 0..100 {
+        table.insert_query(&[i, i * 2], None).unwrap();
+    }
+
+    drop(table);
+    crabstore.close();
+
+    // Cuts off the first base page's indirection column, right after the header page
+    std::fs::File::options()
+        .write(true)
+        .open(CrabStore::table_filename(dir.path(), "truncated"))
+        .unwrap()
+        .set_len((PAGE_SIZE + PAGE_SIZE / 2) as u64)
+        .unwrap();
+
+    let mut crabstore = CrabStore::new(dir.path().into());
+    crabstore.open_read_only();
+
+    assert!(matches!(
+        crabstore.get_table("truncated").sum_query(0, 99, 1, None),
+        Err(CrabError::BufferPool(BufferPoolError::Truncated {
+            page_id: 1
+        }))
+    ));
+
+    crabstore.close();
+}
+
+#[test]
+fn single_file_database() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("crabs.db");
+    let num_records = 3000;
+
+    let mut crabstore = CrabStore::single_file(path.clone());
+    crabstore.open();
+
+    let first = crabstore.create_table("first", 3, 0).unwrap();
+    let second = crabstore.create_table("second", 2, 0).unwrap();
+
+    for i in 0..num_records {
+        first.insert_query(&[i, i * 2, 1], None).unwrap();
+        second.insert_query(&[i, 7], None).unwrap();
+    }
+
+    drop((first, second));
+    crabstore.close();
+
+    // Only the one file, and it opens like any other database
+    assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+    for round in 0..3 {
+        let mut crabstore = CrabStore::new(path.clone());
+        crabstore.open();
+
+        let first = crabstore.get_table("first");
+        let second = crabstore.get_table("second");
+
+        assert_eq!(
+            first.sum_query(0, num_records - 1, 1, None).unwrap(),
+            num_records * (num_records - 1)
+        );
+        assert_eq!(
+            first.sum_query(0, num_records - 1, 2, None).unwrap(),
+            num_records + round * (num_records / 3)
+        );
+        assert_eq!(
+            second.sum_query(0, num_records - 1, 1, None).unwrap(),
+            7 * num_records
+        );
+
+        for i in (0..num_records).step_by(3) {
+            first
+                .update_query(i, &[None, None, Some(round + 2)], None)
+                .unwrap();
+        }
+
+        drop((first, second));
+        crabstore.close();
+    }
+
+    let mut crabstore = CrabStore::new(path);
+    crabstore.open_read_only();
+
+    assert_eq!(
+        crabstore
+            .get_table("first")
+            .sum_query(0, num_records - 1, 2, None)
+            .unwrap(),
+        num_records + 3 * (num_records / 3)
+    );
+
+    crabstore.close();
+}
+
+#[test]
+fn single_file_concurrent_tables() {
+    let dir = tempdir().unwrap();
+    let path = dir.path().join("busy.db");
+    let num_records = 5000;
+
+    // A small pool keeps every table faulting pages in and out
+    let config = Config {
+        bufferpool_size: 64,
+        ..Default::default()
+    };
+
+    let mut crabstore = CrabStore::single_file_with_config(path.clone(), config.clone());
+    crabstore.open();
+
+    let tables = (0..4)
+        .map(|i| crabstore.create_table(&format!("t{i}"), 2, 0).unwrap())
+        .collect::<Vec<_>>();
+
+    thread::scope(|s| {
+        for table in tables.iter() {
+            s.spawn(move || {
+                for i in 0..num_records {
+                    table.insert_query(&[i, 1], None).unwrap();
+                }
+
+                for i in (0..num_records).step_by(2) {
+                    table.update_query(i, &[None, Some(2)], None).unwrap();
+                }
+            });
+        }
+    });
+
+    drop(tables);
+    crabstore.close();
+
+    let mut crabstore = CrabStore::with_config(path, config);
+    crabstore.open();
+
+    for i in 0..4 {
+        assert_eq!(
+            crabstore
+                .get_table(&format!("t{i}"))
+                .sum_query(0, num_records - 1, 1, None)
+                .unwrap(),
+            num_records / 2 * 3
+        );
+    }
+
+    crabstore.close();
+}
+
+#[test]
+fn headerless_blobs_are_version_0() {
+    let dir = tempdir().unwrap();
+    let storage = Storage::Directory(dir.path().into());
+    let tables = vec!["Grades".to_string()];
+
+    // The baseline wrote bare archives
+    std::fs::write(
+        dir.path().join("crab_dt.CRAB"),
+        rkyv::to_bytes::<_, 256>(&tables).unwrap(),
+    )
+    .unwrap();
+
+    let contents = storage.blob("crab_dt.CRAB").read().unwrap().unwrap();
+    assert_eq!((contents.version, contents.generation), (0, 0));
+    assert_eq!(
+        decode::<Vec<String>>("crab_dt.CRAB", &contents.bytes).unwrap(),
+        tables
+    );
+
+    // Created but never written
+    std::fs::write(dir.path().join("Grades_pd.CRAB"), []).unwrap();
+    assert!(storage.blob("Grades_pd.CRAB").read().unwrap().is_none());
+}
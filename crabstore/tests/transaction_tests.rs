@@ -1,15 +1,27 @@
 #![feature(test)]
 extern crate test;
-use core::num;
 use crabcore::{
+    config::Config,
     crabstore::CrabStore,
-    transaction::{Query, Transaction},
+    error::CrabError,
+    lock_manager::LockPolicy,
+    merge::MergePolicy,
+    table::Table,
+    transaction::{Isolation, Query, QueryResult, QueryStatus, Transaction},
     transaction_worker::TransactionWorker,
 };
 use rand::prelude::*;
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
 use tempfile::tempdir;
-use test::Bencher;
 
 #[test]
 fn transaction_test() {
@@ -52,7 +64,10 @@ fn transaction_test2(dir: &Path) {
     }
 
     for key in keys.iter() {
-        let record = &grades.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &grades
+            .select_query(*key, 0, &[1, 1, 1, 1, 1], None)
+            .unwrap()[0]
+            .columns;
 
         for (i, col) in record.iter().enumerate() {
             assert_eq!(*col, records.get(key).unwrap()[i]);
@@ -65,7 +80,7 @@ fn transaction_test2(dir: &Path) {
         workers.push(TransactionWorker::new());
     }
 
-    for i in (0..NUMBER_OF_OPERATIONS_PER_RECORD).rev() {
+    for _ in (0..NUMBER_OF_OPERATIONS_PER_RECORD).rev() {
         for key in keys.iter() {
             let mut updated_cols = [None, None, None, None, None];
             for i in 2..grades.columns() {
@@ -100,7 +115,10 @@ fn transaction_test2(dir: &Path) {
     let mut score = keys.len();
 
     for key in keys.iter() {
-        let record = &grades.select_query(*key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &grades
+            .select_query(*key, 0, &[1, 1, 1, 1, 1], None)
+            .unwrap()[0]
+            .columns;
 
         for (i, col) in record.iter().enumerate() {
             if *col != records.get(key).unwrap()[i] {
@@ -126,13 +144,13 @@ fn transaction_test1(dir: &Path) {
 
     let mut crabstore = CrabStore::new(dir.into());
 
-    let grades = crabstore.create_table("Grades", 5, 0);
+    let grades = crabstore.create_table("Grades", 5, 0).unwrap();
 
     let mut records: HashMap<u64, Vec<u64>> = HashMap::new();
 
-    grades.build_index(2);
-    grades.build_index(3);
-    grades.build_index(4);
+    grades.build_index(2).unwrap();
+    grades.build_index(3).unwrap();
+    grades.build_index(4).unwrap();
 
     let mut keys: Vec<u64> = Vec::new();
     let mut insert_transactions = Vec::new();
@@ -176,7 +194,7 @@ fn transaction_test1(dir: &Path) {
     }
 
     for key in keys {
-        let record = &grades.select_query(key, 0, &[1, 1, 1, 1, 1], None)[0].columns;
+        let record = &grades.select_query(key, 0, &[1, 1, 1, 1, 1], None).unwrap()[0].columns;
 
         for (i, col) in record.iter().enumerate() {
             assert_eq!(*col, records.get(&key).unwrap()[i]);
@@ -185,3 +203,726 @@ fn transaction_test1(dir: &Path) {
 
     crabstore.close();
 }
+
+fn select(transaction: &mut Transaction, table: &Arc<Table>, key: u64) -> Option<Vec<u64>> {
+    match transaction.execute(&Query::Select(key, 0, [1, 1].into()), table) {
+        Some(QueryResult::Records(records)) => Some(
+            records
+                .into_iter()
+                .next()
+                .map(|x| x.columns)
+                .unwrap_or_default(),
+        ),
+        _ => None,
+    }
+}
+
+fn sum(transaction: &mut Transaction, table: &Arc<Table>) -> Option<u64> {
+    match transaction.execute(&Query::Sum(0, 999, 1), table) {
+        Some(QueryResult::Sum(sum)) => Some(sum),
+        _ => None,
+    }
+}
+
+fn snapshot_table(crabstore: &mut CrabStore, name: &str) -> Arc<Table> {
+    let table = crabstore.create_table(name, 2, 0).unwrap();
+
+    for i in 0..100 {
+        table.insert_query(&[i, 100], None).unwrap();
+    }
+
+    table
+}
+
+#[test]
+fn snapshot_reads() {
+    let mut crabstore = CrabStore::in_memory_with_config(Config {
+        merge_policy: MergePolicy::Manual,
+        ..Default::default()
+    });
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Snapshot");
+
+    let mut snapshot = crabstore.transaction(Isolation::Snapshot);
+    snapshot.begin();
+    assert_eq!(sum(&mut snapshot, &table), Some(10000));
+
+    // Committed after the snapshot started
+    table.update_query(5, &[None, Some(0)], None).unwrap();
+    table.delete_query(6, None).unwrap();
+    table.insert_query(&[100, 100], None).unwrap();
+
+    let mut writer = Transaction::new();
+    writer.begin();
+    writer.execute(&Query::Update(7, [None, Some(7)].into()), &table);
+
+    // Two-phase locking readers run into the writer's locks, snapshots don't
+    let mut locking = Transaction::new();
+    locking.set_lock_policy(LockPolicy::NoWait);
+    locking.begin();
+    assert_eq!(select(&mut locking, &table, 7), None);
+    assert_eq!(locking.get_status(), QueryStatus::AbortedRetryable);
+
+    assert_eq!(select(&mut snapshot, &table, 7), Some(vec![7, 100]));
+    assert!(writer.finish());
+
+    // Merging doesn't consolidate versions a running snapshot doesn't see
+    for round in 0..12 {
+        for i in 0..100 {
+            table.update_query(i, &[None, Some(round)], None).unwrap();
+        }
+    }
+
+    assert_eq!(table.merge_all(), 0);
+
+    assert_eq!(select(&mut snapshot, &table, 5), Some(vec![5, 100]));
+    assert_eq!(select(&mut snapshot, &table, 6), Some(vec![6, 100]));
+    assert_eq!(select(&mut snapshot, &table, 100), Some(vec![]));
+    assert_eq!(sum(&mut snapshot, &table), Some(10000));
+    assert!(snapshot.finish());
+
+    let mut later = crabstore.transaction(Isolation::Snapshot);
+    later.begin();
+    assert_eq!(select(&mut later, &table, 5), Some(vec![5, 11]));
+    assert_eq!(select(&mut later, &table, 6), Some(vec![]));
+    assert_eq!(select(&mut later, &table, 100), Some(vec![100, 100]));
+    assert_eq!(sum(&mut later, &table), Some(99 * 11 + 100));
+    assert!(later.finish());
+
+    // Once no snapshot needs the old versions, they're merged
+    assert!(table.merge_all() > 0);
+    assert_eq!(
+        table.select_query(5, 0, &[1, 1], None).unwrap()[0].columns,
+        [5, 11]
+    );
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn snapshot_reads_indexed_updates() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = crabstore.create_table("Indexed", 2, 0).unwrap();
+
+    for i in 0..100 {
+        table.insert_query(&[i, i * 10], None).unwrap();
+    }
+
+    table.build_index(1).unwrap();
+
+    let mut snapshot = crabstore.transaction(Isolation::Snapshot);
+    snapshot.begin();
+
+    // The index loses the entries for 50 and 60 while the snapshot still sees them
+    table.update_query(5, &[None, Some(555)], None).unwrap();
+
+    let mut writer = Transaction::new();
+    writer.add_query(Query::Update(6, [None, Some(666)].into()), &table);
+    assert!(writer.run());
+
+    let by_value = |transaction: &mut Transaction, value| match transaction
+        .execute(&Query::Select(value, 1, [1, 1].into()), &table)
+    {
+        Some(QueryResult::Records(records)) => {
+            Some(records.into_iter().map(|x| x.columns).collect::<Vec<_>>())
+        }
+        _ => None,
+    };
+
+    assert_eq!(by_value(&mut snapshot, 50), Some(vec![vec![5, 50]]));
+    assert_eq!(by_value(&mut snapshot, 60), Some(vec![vec![6, 60]]));
+    assert_eq!(by_value(&mut snapshot, 555), Some(vec![]));
+    assert_eq!(
+        snapshot.execute(&Query::Sum(40, 70, 1), &table),
+        Some(QueryResult::Sum(40 + 50 + 60 + 70))
+    );
+    assert!(snapshot.finish());
+
+    let mut later = crabstore.transaction(Isolation::Snapshot);
+    later.begin();
+    assert_eq!(by_value(&mut later, 50), Some(vec![]));
+    assert_eq!(by_value(&mut later, 555), Some(vec![vec![5, 555]]));
+    assert_eq!(
+        later.execute(&Query::Sum(40, 70, 1), &table),
+        Some(QueryResult::Sum(40 + 70))
+    );
+    assert!(later.finish());
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn snapshots_start_during_untracked_writes() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Untracked");
+    let writing = AtomicBool::new(true);
+
+    let sums = thread::scope(|s| {
+        for writer in 0..4 {
+            let (table, writing) = (&table, &writing);
+
+            s.spawn(move || {
+                let mut round = writer;
+
+                while writing.load(Ordering::Relaxed) {
+                    table
+                        .update_query(round % 100, &[None, Some(round)], None)
+                        .unwrap();
+                    round += 4;
+                }
+            });
+        }
+
+        let sums = (0..200)
+            .map(|_| {
+                let mut snapshot = crabstore.transaction(Isolation::Snapshot);
+                snapshot.begin();
+
+                let sums = (sum(&mut snapshot, &table), sum(&mut snapshot, &table));
+                snapshot.finish();
+                sums
+            })
+            .collect::<Vec<_>>();
+
+        writing.store(false, Ordering::Relaxed);
+        sums
+    });
+
+    // Writes that started before a snapshot either finish first or stay hidden from it
+    for (first, second) in sums {
+        assert!(first.is_some());
+        assert_eq!(first, second);
+    }
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn snapshot_write_conflicts() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Conflicts");
+
+    assert_eq!(Transaction::new().isolation(), Isolation::TwoPhaseLocking);
+    assert_eq!("snapshot".parse(), Ok(Isolation::Snapshot));
+    assert!("serializable".parse::<Isolation>().is_err());
+
+    // The first to commit wins
+    let mut first = crabstore.transaction(Isolation::Snapshot);
+    let mut second = crabstore.transaction(Isolation::Snapshot);
+    first.begin();
+    second.begin();
+
+    let update = |value| Query::Update(1, [None, Some(value)].into());
+
+    assert_eq!(
+        first.execute(&update(1), &table),
+        Some(QueryResult::Written(true))
+    );
+    assert!(first.finish());
+
+    assert_eq!(second.execute(&update(2), &table), None);
+    assert_eq!(second.get_status(), QueryStatus::AbortedRetryable);
+
+    // Writes to different records don't conflict
+    let mut third = crabstore.transaction(Isolation::Snapshot);
+    let mut fourth = crabstore.transaction(Isolation::Snapshot);
+    third.begin();
+    fourth.begin();
+    third.execute(&Query::Update(2, [None, Some(2)].into()), &table);
+    fourth.execute(&Query::Update(3, [None, Some(3)].into()), &table);
+    assert!(third.finish());
+    assert!(fourth.finish());
+
+    // Validated again at commit, writes outside transactions don't lock
+    let mut fifth = crabstore.transaction(Isolation::Snapshot);
+    fifth.begin();
+    fifth.execute(&Query::Update(4, [None, Some(4)].into()), &table);
+    table.delete_query(4, None).unwrap();
+    assert!(!fifth.finish());
+    assert_eq!(fifth.get_status(), QueryStatus::AbortedRetryable);
+
+    // Retrying takes a new snapshot
+    let mut retried = Transaction::with_isolation(Isolation::Snapshot);
+    retried.add_query(update(5), &table);
+    assert!(retried.run());
+
+    for (key, value) in [(1, 5), (2, 2), (3, 3)] {
+        assert_eq!(
+            table.select_query(key, 0, &[1, 1], None).unwrap()[0].columns,
+            [key, value]
+        );
+    }
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn bulk_inserts_commit_at_once() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Bulk");
+
+    let mut snapshot = crabstore.transaction(Isolation::Snapshot);
+    snapshot.begin();
+
+    table
+        .bulk_insert(&(100..1000).map(|x| vec![x, 1]).collect::<Vec<_>>())
+        .unwrap();
+
+    // Started before the batch, none of it is seen
+    assert_eq!(sum(&mut snapshot, &table), Some(10000));
+    assert!(snapshot.finish());
+
+    let mut later = crabstore.transaction(Isolation::Snapshot);
+    later.begin();
+    assert_eq!(sum(&mut later, &table), Some(10000 + 900));
+    assert!(later.finish());
+
+    // Transactions writing the table hold it off until they finish
+    let mut writer = Transaction::new();
+    writer.begin();
+    writer.execute(&Query::Update(5, [None, Some(5)].into()), &table);
+
+    assert_eq!(
+        table.bulk_insert(&[vec![1000, 1]]),
+        Err(CrabError::Locked("Bulk".into()))
+    );
+    assert!(writer.finish());
+
+    table.bulk_insert(&[vec![1000, 1]]).unwrap();
+    assert_eq!(table.stats().locks.locked_records, 0);
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn lock_waits() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Waits");
+
+    assert_eq!(
+        "wait_die:100".parse(),
+        Ok(LockPolicy::WaitDie(Duration::from_millis(100)))
+    );
+    assert!("wait".parse::<LockPolicy>().is_err());
+
+    let mut holder = Transaction::new();
+    holder.begin();
+    holder.execute(&Query::Update(1, [None, Some(7)].into()), &table);
+
+    // Gets the lock once the holder commits
+    let waiter = thread::spawn({
+        let table = Arc::clone(&table);
+
+        move || {
+            let mut waiter = Transaction::new();
+            waiter.set_lock_policy(LockPolicy::Wait(Duration::from_secs(10)));
+            waiter.begin();
+            let selected = select(&mut waiter, &table, 1);
+            waiter.finish();
+            selected
+        }
+    });
+
+    thread::sleep(Duration::from_millis(50));
+    assert!(holder.finish());
+    assert_eq!(waiter.join().unwrap(), Some(vec![1, 7]));
+
+    // Gives up behind a holder that doesn't commit in time
+    let mut holder = Transaction::new();
+    holder.begin();
+    holder.execute(&Query::Update(1, [None, Some(8)].into()), &table);
+
+    let mut impatient = Transaction::new();
+    impatient.set_lock_policy(LockPolicy::Wait(Duration::from_millis(50)));
+    impatient.begin();
+    assert_eq!(select(&mut impatient, &table, 1), None);
+    assert_eq!(impatient.get_status(), QueryStatus::AbortedRetryable);
+
+    let mut no_wait = Transaction::new();
+    no_wait.set_lock_policy(LockPolicy::NoWait);
+    no_wait.begin();
+    assert_eq!(select(&mut no_wait, &table, 1), None);
+    assert!(holder.finish());
+
+    let stats = table.stats().locks;
+    assert!(stats.waits >= 2);
+    assert!(stats.timeouts >= 1);
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn deadlock_victims() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Deadlocks");
+    let update = |key, value| Query::Update(key, [None, Some(value)].into());
+
+    for (round, policy) in [
+        LockPolicy::Wait(Duration::from_secs(10)),
+        LockPolicy::WaitDie(Duration::from_secs(10)),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let round = round as u64;
+        let mut older = Transaction::new();
+        let mut younger = Transaction::new();
+        older.set_lock_policy(policy);
+        younger.set_lock_policy(policy);
+        older.begin();
+        younger.begin();
+
+        older.execute(&update(1, round), &table);
+        younger.execute(&update(2, 10 + round), &table);
+
+        // Each wants the other's record, the younger one gives up
+        let older = thread::spawn({
+            let table = Arc::clone(&table);
+
+            move || {
+                let written = older.execute(&update(2, round), &table);
+                (written, older.finish())
+            }
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(younger.execute(&update(1, 10 + round), &table), None);
+        assert_eq!(younger.get_status(), QueryStatus::AbortedRetryable);
+
+        assert_eq!(
+            older.join().unwrap(),
+            (Some(QueryResult::Written(true)), true)
+        );
+
+        for key in [1, 2] {
+            assert_eq!(
+                table.select_query(key, 0, &[1, 1], None).unwrap()[0].columns,
+                [key, round]
+            );
+        }
+    }
+
+    assert!(table.stats().locks.deadlocks >= 2);
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn lock_table_shrinks() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Shrinks");
+
+    let mut reader = Transaction::new();
+    reader.begin();
+
+    for key in 0..10 {
+        assert_eq!(select(&mut reader, &table, key), Some(vec![key, 100]));
+    }
+
+    assert_eq!(table.stats().locks.locked_records, 10);
+    assert!(reader.finish());
+    assert_eq!(table.stats().locks.locked_records, 0);
+
+    // Lockers on every thread, each retrying until it commits
+    let threads = (0..8)
+        .map(|thread| {
+            let table = Arc::clone(&table);
+
+            thread::spawn(move || {
+                let mut rng = StdRng::seed_from_u64(thread);
+
+                for _ in 0..50 {
+                    let mut transaction = Transaction::new();
+
+                    for _ in 0..4 {
+                        let key = rng.gen_range(0..100);
+                        transaction.add_query(Query::Update(key, [None, Some(1)].into()), &table);
+                        transaction.add_query(Query::Select(key, 0, [1, 1].into()), &table);
+                    }
+
+                    while !transaction.run() {
+                        assert_eq!(transaction.get_status(), QueryStatus::AbortedRetryable);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    assert_eq!(table.stats().locks.locked_records, 0);
+    assert!(table.sum_query(0, 99, 1, None).unwrap() < 10000);
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn hierarchical_locks() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Hierarchy");
+    let no_wait = || {
+        let mut transaction = Transaction::new();
+        transaction.set_lock_policy(LockPolicy::NoWait);
+        transaction.begin();
+        transaction
+    };
+
+    // A sum locks the ranges it reads instead of every record
+    let mut summer = no_wait();
+    assert_eq!(sum(&mut summer, &table), Some(10000));
+    assert_eq!(table.stats().locks.locked_records, 0);
+
+    // Readers go ahead, nothing can be written or inserted into what it summed
+    let mut reader = no_wait();
+    assert_eq!(sum(&mut reader, &table), Some(10000));
+    assert_eq!(select(&mut reader, &table, 5), Some(vec![5, 100]));
+    assert!(reader.finish());
+    assert_eq!(sum(&mut summer, &table), Some(10000));
+
+    let mut inserter = no_wait();
+    assert_eq!(
+        inserter.execute(&Query::Insert([500, 1].into()), &table),
+        None
+    );
+    assert_eq!(inserter.get_status(), QueryStatus::AbortedRetryable);
+
+    let mut updater = no_wait();
+    assert_eq!(
+        updater.execute(&Query::Update(5, [None, Some(1)].into()), &table),
+        None
+    );
+    assert!(summer.finish());
+
+    let mut inserter = no_wait();
+    assert_eq!(
+        inserter.execute(&Query::Insert([500, 1].into()), &table),
+        Some(QueryResult::Written(true))
+    );
+    assert!(inserter.finish());
+
+    // Reading most of a range trades the record locks for one on the range
+    let mut reader = no_wait();
+
+    for key in 0..100 {
+        assert_eq!(select(&mut reader, &table, key), Some(vec![key, 100]));
+    }
+
+    let stats = table.stats().locks;
+    assert_eq!(stats.escalations, 1);
+    assert!(stats.locked_records < 100);
+
+    let mut other = no_wait();
+    assert_eq!(select(&mut other, &table, 99), Some(vec![99, 100]));
+    assert_eq!(
+        other.execute(&Query::Update(99, [None, Some(1)].into()), &table),
+        None
+    );
+
+    // Writing to an escalated range needs it exclusively
+    let update = Query::Update(99, [None, Some(1)].into());
+    assert_eq!(
+        reader.execute(&update, &table),
+        Some(QueryResult::Written(true))
+    );
+    assert!(reader.finish());
+    assert_eq!(table.stats().locks.locked_records, 0);
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn sums_lock_the_ranges_they_read() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = crabstore.create_table("Ranges", 2, 0).unwrap();
+    // Records in a page range, 16 base pages of 512
+    let range_size = 8192;
+
+    table
+        .bulk_insert(&(0..range_size * 2).map(|x| vec![x, 1]).collect::<Vec<_>>())
+        .unwrap();
+
+    let no_wait = || {
+        let mut transaction = Transaction::new();
+        transaction.set_lock_policy(LockPolicy::NoWait);
+        transaction.begin();
+        transaction
+    };
+    let update = |key| Query::Update(key, [None, Some(2)].into());
+
+    // Tail pages are allocated one range at a time, in order
+    let mut writer = no_wait();
+    assert_eq!(
+        writer.execute(&Query::Update(0, [None, Some(1)].into()), &table),
+        Some(QueryResult::Written(true))
+    );
+    assert!(writer.finish());
+
+    let mut summer = no_wait();
+    assert_eq!(
+        summer.execute(&Query::Sum(0, 99, 1), &table),
+        Some(QueryResult::Sum(100))
+    );
+
+    // Only the range summed is locked, and the keys summed
+    let mut writer = no_wait();
+    assert_eq!(
+        writer.execute(&update(range_size + 5), &table),
+        Some(QueryResult::Written(true))
+    );
+    assert!(writer.finish());
+
+    let mut writer = no_wait();
+    assert_eq!(writer.execute(&update(5), &table), None);
+
+    // Keys outside the range summed can still be inserted
+    let mut inserter = no_wait();
+    assert_eq!(
+        inserter.execute(&Query::Insert([range_size * 2, 1].into()), &table),
+        Some(QueryResult::Written(true))
+    );
+    assert!(inserter.finish());
+
+    assert_eq!(
+        summer.execute(&Query::Sum(0, 99, 1), &table),
+        Some(QueryResult::Sum(100))
+    );
+    assert!(summer.finish());
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn repeated_sums_are_stable() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = crabstore.create_table("Repeated", 2, 0).unwrap();
+    let range_size = 8192;
+
+    // Even keys only, two ranges of them
+    table
+        .bulk_insert(
+            &(0..range_size * 2)
+                .map(|x| vec![x * 2, 1])
+                .collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+    // Tail pages are allocated one range at a time, in order
+    table.update_query(0, &[None, Some(1)], None).unwrap();
+
+    let mut summer = Transaction::new();
+    summer.begin();
+
+    let sum = |summer: &mut Transaction| summer.execute(&Query::Sum(0, 99, 1), &table);
+    let first = sum(&mut summer);
+    assert_eq!(first, Some(QueryResult::Sum(50)));
+
+    // Inserted into the key range, and moved into it from a range the sum didn't read
+    let writers = (0..10)
+        .flat_map(|i| {
+            let mut inserter = Transaction::new();
+            inserter.add_query(Query::Insert([i * 2 + 1, 1].into()), &table);
+
+            let mut updater = Transaction::new();
+            updater.add_query(
+                Query::Update(range_size * 2 + i * 2, [Some(i * 2 + 21), None].into()),
+                &table,
+            );
+
+            [inserter, updater]
+        })
+        .collect::<Vec<_>>();
+
+    thread::scope(|s| {
+        let handles = writers
+            .into_iter()
+            .map(|mut writer| s.spawn(move || while !writer.run() {}))
+            .collect::<Vec<_>>();
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(sum(&mut summer), first);
+        assert!(summer.finish());
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    });
+
+    let mut later = Transaction::new();
+    later.begin();
+    assert_eq!(sum(&mut later), Some(QueryResult::Sum(50 + 20)));
+    assert!(later.finish());
+
+    drop(table);
+    crabstore.close();
+}
+
+#[test]
+fn aborted_inserts() {
+    let mut crabstore = CrabStore::in_memory();
+    crabstore.open();
+
+    let table = snapshot_table(&mut crabstore, "Aborted");
+
+    let mut reader = Transaction::new();
+    reader.set_lock_policy(LockPolicy::NoWait);
+    reader.begin();
+
+    for key in 0..100 {
+        assert_eq!(select(&mut reader, &table, key), Some(vec![key, 100]));
+    }
+
+    // The insert reserves a slot before it finds the escalated range locked
+    let mut inserter = Transaction::new();
+    inserter.set_lock_policy(LockPolicy::NoWait);
+    inserter.begin();
+    assert_eq!(
+        inserter.execute(&Query::Insert([500, 1].into()), &table),
+        None
+    );
+    assert_eq!(inserter.get_status(), QueryStatus::AbortedRetryable);
+
+    // Scans skip the reserved slot
+    assert_eq!(sum(&mut reader, &table), Some(10000));
+    assert!(reader.finish());
+
+    let mut summer = Transaction::new();
+    summer.begin();
+    assert_eq!(sum(&mut summer, &table), Some(10000));
+    assert!(summer.finish());
+
+    drop(table);
+    crabstore.close();
+}
@@ -1,8 +1,12 @@
 use std::{path::PathBuf, str::FromStr, sync::Arc};
 
-use crabcore::crabstore::CrabStore;
+use crabcore::{crabstore::CrabStore, dump::DumpOptions, error::CrabError};
 use parking_lot::Mutex;
-use pyo3::prelude::*;
+use pyo3::{
+    exceptions::{PyIOError, PyPermissionError, PyValueError},
+    prelude::*,
+    types::PyDict,
+};
 
 use super::tablepy::TablePy;
 
@@ -10,6 +14,12 @@ use super::tablepy::TablePy;
 #[pyclass]
 pub struct CrabStorePy(Arc<Mutex<CrabStore>>);
 
+impl Default for CrabStorePy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[pymethods]
 impl CrabStorePy {
     #[new]
@@ -22,13 +32,23 @@ impl CrabStorePy {
         name: String,
         num_columns: usize,
         key_index: usize,
-    ) -> Py<TablePy> {
-        let table = self.0.lock().create_table(&name, num_columns, key_index);
-        Python::with_gil(|py| Py::new(py, TablePy(table))).unwrap()
+    ) -> PyResult<Py<TablePy>> {
+        let table = self
+            .0
+            .lock()
+            .create_table(&name, num_columns, key_index)
+            .map_err(|e| PyPermissionError::new_err(e.to_string()))?;
+        Python::with_gil(|py| Py::new(py, TablePy(table)))
     }
 
-    pub fn drop_table(&mut self, name: String) {
-        self.0.lock().drop_table(&name);
+    pub fn drop_table(&mut self, name: String) -> PyResult<()> {
+        let mut crabstore = self.0.lock();
+        crabstore
+            .writable()
+            .map_err(|e| PyPermissionError::new_err(e.to_string()))?;
+
+        crabstore.drop_table(&name);
+        Ok(())
     }
 
     pub fn get_table(&self, name: String) -> Py<TablePy> {
@@ -36,13 +56,155 @@ impl CrabStorePy {
         Python::with_gil(|py| Py::new(py, TablePy(table))).unwrap()
     }
 
-    pub fn open(&mut self, path: String) {
+    pub fn open(&mut self, path: String) -> PyResult<()> {
         let mut crabstore = self.0.lock();
         crabstore.directory = PathBuf::from_str(&path).unwrap();
+        crabstore
+            .try_open()
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    pub fn open_single_file(&mut self, path: String) {
+        let mut crabstore = self.0.lock();
+        *crabstore = CrabStore::single_file_with_config(
+            PathBuf::from_str(&path).unwrap(),
+            crabstore.config.clone(),
+        );
         crabstore.open();
     }
 
+    pub fn open_read_only(&mut self, path: String) {
+        let mut crabstore = self.0.lock();
+        crabstore.directory = PathBuf::from_str(&path).unwrap();
+        crabstore.open_read_only();
+    }
+
+    pub fn open_in_memory(&mut self) {
+        let mut crabstore = self.0.lock();
+        *crabstore = CrabStore::in_memory_with_config(crabstore.config.clone());
+        crabstore.open();
+    }
+
+    // Files opening `path` would migrate, without changing them
+    pub fn plan_migration(&self, path: String) -> PyResult<Vec<String>> {
+        let crabstore = CrabStore::with_config(
+            PathBuf::from_str(&path).unwrap(),
+            self.0.lock().config.clone(),
+        );
+
+        crabstore
+            .plan_migration()
+            .map(|report| report.actions)
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    // Number of pages copied, unchanged pages from an earlier backup to `path` are skipped
+    pub fn backup(&self, path: String) -> PyResult<usize> {
+        self.0
+            .lock()
+            .backup(&PathBuf::from_str(&path).unwrap())
+            .map(|report| report.pages_written)
+            .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    // Loads a csv or native dump into an existing table, returns the number of rows read
+    #[pyo3(signature = (table, path, format = "csv".to_string(), columns = Vec::new(), header = false))]
+    pub fn import_file(
+        &self,
+        table: String,
+        path: String,
+        format: String,
+        columns: Vec<usize>,
+        header: bool,
+    ) -> PyResult<usize> {
+        let options = DumpOptions {
+            format: format.parse().map_err(PyValueError::new_err)?,
+            columns,
+            header,
+        };
+
+        self.0
+            .lock()
+            .import(&table, &PathBuf::from_str(&path).unwrap(), &options)
+            .map_err(dump_error)
+    }
+
+    #[pyo3(signature = (table, path, format = "csv".to_string(), columns = Vec::new(), header = false))]
+    pub fn export_file(
+        &self,
+        table: String,
+        path: String,
+        format: String,
+        columns: Vec<usize>,
+        header: bool,
+    ) -> PyResult<usize> {
+        let options = DumpOptions {
+            format: format.parse().map_err(PyValueError::new_err)?,
+            columns,
+            header,
+        };
+
+        self.0
+            .lock()
+            .export(&table, &PathBuf::from_str(&path).unwrap(), &options)
+            .map_err(dump_error)
+    }
+
+    #[staticmethod]
+    pub fn restore(backup: String, path: String) -> PyResult<()> {
+        CrabStore::restore(
+            &PathBuf::from_str(&backup).unwrap(),
+            &PathBuf::from_str(&path).unwrap(),
+        )
+        .map(|_| ())
+        .map_err(|e| PyIOError::new_err(e.to_string()))
+    }
+
+    pub fn set_replacement_policy(&mut self, policy: String) -> PyResult<()> {
+        self.0.lock().config.replacement_policy = policy.parse().map_err(PyValueError::new_err)?;
+        Ok(())
+    }
+
+    // "tail_pages:4", "interval:500", "idle:500" (milliseconds) or "manual"
+    pub fn set_merge_policy(&mut self, policy: String) -> PyResult<()> {
+        self.0.lock().config.merge_policy = policy.parse().map_err(PyValueError::new_err)?;
+        Ok(())
+    }
+
+    // Whether closing waits for running merges or abandons them
+    pub fn set_finish_merges(&mut self, finish: bool) {
+        self.0.lock().config.finish_merges = finish;
+    }
+
+    pub fn set_storage_backend(&mut self, backend: String) -> PyResult<()> {
+        self.0.lock().config.storage_backend = backend.parse().map_err(PyValueError::new_err)?;
+        Ok(())
+    }
+
+    pub fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        let stats = self.0.lock().stats();
+        let dict = PyDict::new(py);
+
+        for (name, table) in stats.tables.iter() {
+            dict.set_item(name, TablePy::stats_dict(py, table)?)?;
+        }
+
+        dict.set_item("total", TablePy::stats_dict(py, &stats.total())?)?;
+
+        Ok(dict)
+    }
+
     pub fn close(&mut self) {
         self.0.lock().close();
     }
 }
+
+fn dump_error(error: CrabError) -> PyErr {
+    match error {
+        CrabError::ReadOnly(_) => PyPermissionError::new_err(error.to_string()),
+        CrabError::Parse { .. } | CrabError::Mapping(_) | CrabError::Missing(_) => {
+            PyValueError::new_err(error.to_string())
+        }
+        _ => PyIOError::new_err(error.to_string()),
+    }
+}
@@ -1,9 +1,13 @@
-use std::{path::Path, sync::Arc};
+use std::{sync::Arc, time::UNIX_EPOCH};
 
-use crabcore::table::Table;
+use crabcore::{
+    config::Config, error::CrabError, merge_scheduler::MergeScheduler, mvcc::Clock,
+    stats::TableStats, storage::Storage, table::Table,
+};
 use pyo3::{
+    exceptions::{PyPermissionError, PyRuntimeError, PyValueError},
     prelude::*,
-    types::{PyList, PyTuple},
+    types::{PyDict, PyList, PyTuple},
 };
 
 use super::recordpy::RecordPy;
@@ -16,32 +20,82 @@ impl TablePy {
         name: String,
         num_columns: usize,
         key_index: usize,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
-    ) -> Self {
-        Self(Arc::new(Table::new(
-            name,
-            num_columns,
-            key_index,
-            db_file,
-            pd_file,
-            id_file,
-            rd_file,
-        )))
-    }
-
-    pub fn load(
-        name: &str,
-        db_file: &Path,
-        pd_file: &Path,
-        id_file: &Path,
-        rd_file: &Path,
+        storage: &Storage,
+        config: &Config,
     ) -> Self {
-        Self(Arc::new(Table::load(
-            name, db_file, pd_file, id_file, rd_file,
-        )))
+        Self::standalone(
+            Table::new(
+                name,
+                num_columns,
+                key_index,
+                storage,
+                config,
+                &Arc::new(Clock::default()),
+            ),
+            config,
+        )
+    }
+
+    pub fn load(name: &str, storage: &Storage, config: &Config) -> Self {
+        Self::standalone(
+            Table::load(name, storage, config, &Arc::new(Clock::default()))
+                .unwrap_or_else(|e| panic!("{e}")),
+            config,
+        )
+    }
+
+    // Tables outside a database get a merge scheduler and clock of their own
+    fn standalone(table: Table, config: &Config) -> Self {
+        table.attach_merge_scheduler(&Arc::new(MergeScheduler::new(config.merge_workers)));
+        Self(Arc::new(table))
+    }
+
+    fn writable(&self) -> PyResult<()> {
+        self.0.writable().map_err(query_error)
+    }
+
+    pub fn stats_dict<'py>(py: Python<'py>, stats: &TableStats) -> PyResult<&'py PyDict> {
+        let dict = PyDict::new(py);
+        let bufferpool = &stats.bufferpool;
+
+        dict.set_item("bufferpool_size", bufferpool.size)?;
+        dict.set_item("hits", bufferpool.hits)?;
+        dict.set_item("misses", bufferpool.misses)?;
+        dict.set_item("hit_rate", bufferpool.hit_rate())?;
+        dict.set_item("evictions", bufferpool.evictions)?;
+        dict.set_item("dirty_evictions", bufferpool.dirty_evictions)?;
+        dict.set_item("flushes", bufferpool.flushes)?;
+        dict.set_item("dirty_pages", bufferpool.dirty_pages)?;
+        dict.set_item("pinned_pages", bufferpool.pinned_pages)?;
+        dict.set_item("disk_reads", stats.disk.reads)?;
+        dict.set_item("disk_writes", stats.disk.writes)?;
+        dict.set_item("disk_syncs", stats.disk.syncs)?;
+        dict.set_item("prefetch_reads", stats.disk.prefetch_reads)?;
+        dict.set_item("prefetch_hits", stats.disk.prefetch_hits)?;
+        dict.set_item("pages", stats.disk.pages)?;
+        dict.set_item("free_pages", stats.disk.free_pages)?;
+        dict.set_item("ranges_merged", stats.merge.ranges_merged)?;
+        dict.set_item("records_consolidated", stats.merge.records_consolidated)?;
+        dict.set_item("pages_reclaimed", stats.merge.pages_reclaimed)?;
+        dict.set_item("merges_abandoned", stats.merge.merges_abandoned)?;
+        dict.set_item("merges_panicked", stats.merge.merges_panicked)?;
+        dict.set_item("last_merge_panic", stats.merge.last_panic.as_deref())?;
+        dict.set_item("merge_seconds", stats.merge.time_spent.as_secs_f64())?;
+        dict.set_item(
+            "last_merge",
+            stats
+                .merge
+                .last_merge
+                .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+                .map(|x| x.as_secs_f64()),
+        )?;
+        dict.set_item("lock_waits", stats.locks.waits)?;
+        dict.set_item("lock_timeouts", stats.locks.timeouts)?;
+        dict.set_item("deadlocks", stats.locks.deadlocks)?;
+        dict.set_item("lock_escalations", stats.locks.escalations)?;
+        dict.set_item("locked_records", stats.locks.locked_records)?;
+
+        Ok(dict)
     }
 }
 
@@ -58,8 +112,9 @@ impl TablePy {
         start_range: u64,
         end_range: u64,
         column_index: usize,
-    ) -> u64 {
+    ) -> PyResult<u64> {
         py.allow_threads(move || self.0.sum_query(start_range, end_range, column_index, None))
+            .map_err(query_error)
     }
 
     pub fn select(
@@ -68,9 +123,11 @@ impl TablePy {
         search_value: u64,
         column_index: usize,
         columns: &PyList,
-    ) -> Py<PyList> {
+    ) -> PyResult<Py<PyList>> {
         if column_index >= self.0.columns() {
-            return Python::with_gil(|py| -> Py<PyList> { PyList::empty(py).into() });
+            return Ok(Python::with_gil(|py| -> Py<PyList> {
+                PyList::empty(py).into()
+            }));
         }
 
         let included_columns: Vec<usize> = columns
@@ -80,14 +137,14 @@ impl TablePy {
             .map(|(i, _x)| i)
             .collect();
 
-        let mut results = vec![];
-        py.allow_threads(|| {
-            results = self
-                .0
-                .select_query(search_value, column_index, &included_columns, None);
-        });
+        let results = py
+            .allow_threads(|| {
+                self.0
+                    .select_query(search_value, column_index, &included_columns, None)
+            })
+            .map_err(query_error)?;
 
-        Python::with_gil(|py| -> Py<PyList> {
+        Ok(Python::with_gil(|py| -> Py<PyList> {
             let selected_records: Py<PyList> = PyList::empty(py).into();
             for result in results {
                 selected_records
@@ -96,34 +153,57 @@ impl TablePy {
                     .expect("Failed to append to python list");
             }
             selected_records
-        })
+        }))
     }
 
-    pub fn update(&self, py: Python<'_>, key: u64, values: &PyTuple) -> bool {
+    pub fn update(&self, py: Python<'_>, key: u64, values: &PyTuple) -> PyResult<bool> {
+        self.writable()?;
+
         let vals: Vec<Option<u64>> = values
             .iter()
             .map(|val| val.extract::<Option<u64>>().unwrap())
             .collect::<Vec<Option<u64>>>();
 
         py.allow_threads(move || self.0.update_query(key, &vals, None))
+            .map_err(query_error)
     }
 
-    pub fn delete(&self, py: Python<'_>, key: u64) -> bool {
+    pub fn delete(&self, py: Python<'_>, key: u64) -> PyResult<bool> {
+        self.writable()?;
+
         py.allow_threads(move || self.0.delete_query(key, None))
+            .map_err(query_error)
     }
 
     #[pyo3(signature = (*values))]
-    pub fn insert(&self, py: Python<'_>, values: &PyTuple) {
+    pub fn insert(&self, py: Python<'_>, values: &PyTuple) -> PyResult<()> {
+        self.writable()?;
+
         let vals = values
             .iter()
             .map(|v| v.extract::<u64>().unwrap())
             .collect::<Vec<u64>>();
 
-        py.allow_threads(move || self.0.insert_query(&vals, None));
+        py.allow_threads(move || self.0.insert_query(&vals, None))
+            .map_err(query_error)
     }
 
-    pub fn build_index(&self, column_num: usize) {
-        self.0.build_index(column_num);
+    // All rows go in or, if a key is taken or repeated, none do
+    pub fn bulk_insert(&self, py: Python<'_>, rows: Vec<Vec<u64>>) -> PyResult<()> {
+        py.allow_threads(move || self.0.bulk_insert(&rows))
+            .map_err(query_error)
+    }
+
+    pub fn force_merge(&self, py: Python<'_>, range: usize) -> usize {
+        py.allow_threads(|| self.0.force_merge(range))
+    }
+
+    pub fn merge_all(&self, py: Python<'_>) -> usize {
+        py.allow_threads(|| self.0.merge_all())
+    }
+
+    pub fn build_index(&self, column_num: usize) -> PyResult<()> {
+        self.0.build_index(column_num).map_err(query_error)
     }
 
     pub fn drop_index(&self, column_num: usize) {
@@ -133,4 +213,18 @@ impl TablePy {
     pub fn persist(&self) {
         self.0.persist();
     }
+
+    pub fn stats<'py>(&self, py: Python<'py>) -> PyResult<&'py PyDict> {
+        TablePy::stats_dict(py, &self.0.stats())
+    }
+}
+
+fn query_error(error: CrabError) -> PyErr {
+    match error {
+        CrabError::ReadOnly(_) => PyPermissionError::new_err(error.to_string()),
+        CrabError::BufferPool(_) | CrabError::Locked(_) => {
+            PyRuntimeError::new_err(error.to_string())
+        }
+        _ => PyValueError::new_err(error.to_string()),
+    }
 }
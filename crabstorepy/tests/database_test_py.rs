@@ -1,13 +1,6 @@
 #![feature(test)]
 extern crate test;
-use ::crabstore as crabstorewrap;
 use crabstore::crabstore;
-use crabstorewrap::{crabstorepy::CrabStorePy, recordpy::RecordPy, tablepy::TablePy};
-
-use std::{
-    path::{Path, PathBuf},
-    str::FromStr,
-};
 
 use pyo3::prelude::*;
 fn build_environment() {